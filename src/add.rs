@@ -0,0 +1,190 @@
+//! Appends items installed by hand to `system.toml`. The file is edited in
+//! place, rather than round-tripped through [`crate::system::System`], so its
+//! comments and formatting are kept.
+
+use anyhow::{anyhow, bail};
+use toml_edit::{Array, ArrayOfTables, DocumentMut, InlineTable, Item, Table, Value, table, value};
+
+use crate::mas::App;
+
+/// Adds a formula, or a cask, to `[brew]`. Returns false if it was already
+/// there.
+pub fn add_brew(config: &mut DocumentMut, name: &str, cask: bool) -> anyhow::Result<bool> {
+    let field = if cask { "casks" } else { "formulae" };
+    // Formulae installed from a URL are declared as `{ url, name }`.
+    push_unique(&mut section(config, "brew")[field], field, name, |entry| {
+        entry.as_str() == Some(name)
+            || entry
+                .as_inline_table()
+                .and_then(|entry| entry.get("name"))
+                .and_then(Value::as_str)
+                == Some(name)
+    })
+}
+
+/// Adds an extension to `[vscode]`. Extension identifiers are case
+/// insensitive. Returns false if it was already there.
+pub fn add_vscode(config: &mut DocumentMut, id: &str) -> anyhow::Result<bool> {
+    push_unique(
+        &mut section(config, "vscode")["extensions"],
+        "extensions",
+        id,
+        |entry| {
+            entry
+                .as_str()
+                .or_else(|| {
+                    entry
+                        .as_inline_table()
+                        .and_then(|entry| entry.get("id"))
+                        .and_then(Value::as_str)
+                })
+                .is_some_and(|existing| existing.eq_ignore_ascii_case(id))
+        },
+    )
+}
+
+/// Adds an app to `[mas]`, as another `[[mas.apps]]` table, or to the inline
+/// `apps` array if that's how the file declares them. Returns false if an app
+/// with the same ID was already there.
+pub fn add_mas(config: &mut DocumentMut, app: &App) -> anyhow::Result<bool> {
+    let apps = &mut section(config, "mas")["apps"];
+    if apps.is_none() {
+        *apps = Item::ArrayOfTables(ArrayOfTables::new());
+    }
+    let has_id = |id: Option<&Item>| id.and_then(Item::as_str) == Some(app.id.as_str());
+
+    match apps {
+        Item::ArrayOfTables(apps) => {
+            if apps.iter().any(|existing| has_id(existing.get("id"))) {
+                return Ok(false);
+            }
+            let mut table = Table::new();
+            table["name"] = value(&app.name);
+            table["id"] = value(&app.id);
+            apps.push(table);
+        }
+        Item::Value(Value::Array(apps)) => {
+            let exists = apps.iter().any(|existing| {
+                existing
+                    .as_inline_table()
+                    .and_then(|existing| existing.get("id"))
+                    .and_then(Value::as_str)
+                    == Some(app.id.as_str())
+            });
+            if exists {
+                return Ok(false);
+            }
+            let mut table = InlineTable::new();
+            table.insert("name", app.name.as_str().into());
+            table.insert("id", app.id.as_str().into());
+            push_formatted(apps, table.into());
+        }
+        _ => bail!("`mas.apps` should be an array of tables"),
+    }
+
+    Ok(true)
+}
+
+/// The `[name]` table, added to the end of the file if it's missing, since
+/// indexing would add it as an inline table at the top.
+fn section<'a>(config: &'a mut DocumentMut, name: &str) -> &'a mut Item {
+    config.entry(name).or_insert(table())
+}
+
+/// Appends `entry` to the array at `item`, creating it if it's missing, unless
+/// an element already matches `exists`.
+fn push_unique(
+    item: &mut Item,
+    field: &str,
+    entry: &str,
+    exists: impl Fn(&Value) -> bool,
+) -> anyhow::Result<bool> {
+    if item.is_none() {
+        *item = value(Array::new());
+    }
+    let array = item
+        .as_array_mut()
+        .ok_or_else(|| anyhow!("`{field}` should be an array"))?;
+    if array.iter().any(exists) {
+        return Ok(false);
+    }
+
+    push_formatted(array, entry.into());
+    Ok(true)
+}
+
+/// Pushes `entry`, indented like the existing elements, so it lands on its own
+/// line in arrays written one element per line. Only the indentation is
+/// copied, not any comments above the last element.
+fn push_formatted(array: &mut Array, mut entry: Value) {
+    let indent = array
+        .iter()
+        .last()
+        .and_then(|last| last.decor().prefix())
+        .and_then(|prefix| prefix.as_str())
+        .and_then(|prefix| prefix.rfind('\n').map(|i| prefix[i..].to_string()));
+    if let Some(indent) = indent {
+        entry.decor_mut().set_prefix(indent);
+    }
+    array.push_formatted(entry);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_keeps_comments_and_skips_existing_entries() {
+        let mut config: DocumentMut = r#"# My machine.
+[brew]
+formulae = [
+    "fish",
+    # Editors.
+    "neovim",
+]
+
+[[mas.apps]]
+name = "Amphetamine"
+id = "937984704"
+"#
+        .parse()
+        .unwrap();
+
+        assert!(add_brew(&mut config, "ripgrep", false).unwrap());
+        assert!(!add_brew(&mut config, "fish", false).unwrap());
+        assert!(add_brew(&mut config, "slack", true).unwrap());
+        assert!(add_vscode(&mut config, "vscodevim.vim").unwrap());
+        assert!(!add_vscode(&mut config, "VSCodeVim.Vim").unwrap());
+        let xcode = App {
+            name: "Xcode".to_string(),
+            id: "497799835".to_string(),
+        };
+        assert!(add_mas(&mut config, &xcode).unwrap());
+        assert!(!add_mas(&mut config, &xcode).unwrap());
+
+        assert_eq!(
+            r#"# My machine.
+[brew]
+formulae = [
+    "fish",
+    # Editors.
+    "neovim",
+    "ripgrep",
+]
+casks = ["slack"]
+
+[[mas.apps]]
+name = "Amphetamine"
+id = "937984704"
+
+[[mas.apps]]
+name = "Xcode"
+id = "497799835"
+
+[vscode]
+extensions = ["vscodevim.vim"]
+"#,
+            config.to_string()
+        );
+    }
+}
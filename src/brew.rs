@@ -1,32 +1,347 @@
-use std::{collections::HashSet, process::Command, str::from_utf8};
+use std::{
+    collections::{HashMap, HashSet},
+    env::home_dir,
+    path::{Path, PathBuf},
+    str::from_utf8,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+};
 
-use serde::Deserialize;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
 
-use crate::{errors::SetupError, system_utils::command};
+use crate::{
+    dotfiles::tilde_expand_path,
+    errors::{OnFailure, SetupError, failures},
+    output::{event, print_compared_sets, unchanged},
+    report::Changes,
+    runner::CommandRunner,
+    system::Section,
+    system_utils::{Retry, Seen, command},
+    validate::{ConfigProblem, warn_duplicates},
+};
 
 const BREW_PROGRAM_NAME: &str = "brew";
 
+/// Where the Homebrew installer puts `brew` on Apple Silicon and Intel Macs
+/// respectively.
+pub const BREW_KNOWN_PATHS: [&str; 2] = ["/opt/homebrew/bin/brew", "/usr/local/bin/brew"];
+
 /// Represents the Homebrew configuration, specifying which formulae and casks to install.
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
 pub struct Brew {
-    formulae: Option<Vec<String>>,
+    /// Third-party repositories to `brew tap` before installing anything,
+    /// like `homebrew/cask-fonts`.
+    taps: Option<Vec<String>>,
+    formulae: Option<Vec<Formula>>,
     casks: Option<Vec<String>>,
+    /// Formulae that run as background services, and should be kept running
+    /// with `brew services`.
+    services: Option<Vec<String>>,
+    /// Extra flags passed to every `brew install`, like `--quiet` or
+    /// `--verbose`.
+    install_args: Option<Vec<String>>,
+    /// Formulae held at their installed version with `brew pin`, so
+    /// `brew upgrade` leaves them alone. When set, any other pinned formulae
+    /// are unpinned.
+    pinned: Option<Vec<String>>,
+    /// Keg-only formulae to `brew link --force` anyway, putting them on the
+    /// `PATH` over the versions macOS ships with.
+    link_keg_only: Option<Vec<String>>,
+    when: Option<String>,
+}
+
+/// A formula to install: either a name, like `fish`, or the URL or local path
+/// of a formula's `.rb` file. The name a URL or path is installed under is
+/// taken from its file name, unless given explicitly with the `{ url, name }`
+/// form. The table form can also give options to install it with, like
+/// `{ name = "neovim", head = true }`.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(untagged)]
+pub enum Formula {
+    Name(String),
+    #[serde(rename_all = "kebab-case")]
+    Table {
+        name: String,
+        /// The URL or path of the formula's `.rb` file, otherwise it's
+        /// installed by `name`.
+        url: Option<String>,
+        /// Install the latest development version, with `--HEAD`.
+        head: Option<bool>,
+        /// Build it from source, with `--build-from-source`, rather than
+        /// pouring a bottle.
+        build_from_source: Option<bool>,
+        /// Other flags to pass to `brew install` for just this formula.
+        args: Option<Vec<String>>,
+    },
+}
+
+impl Formula {
+    /// The name the formula is listed under once installed.
+    pub fn name(&self) -> &str {
+        match self {
+            Formula::Name(name) if is_formula_source(name) => {
+                let file_name = name.rsplit('/').next().unwrap_or(name);
+                file_name.strip_suffix(".rb").unwrap_or(file_name)
+            }
+            Formula::Name(name) | Formula::Table { name, .. } => name,
+        }
+    }
+
+    /// What to pass to `brew install` to install the formula.
+    pub fn source(&self) -> &str {
+        match self {
+            Formula::Name(source)
+            | Formula::Table {
+                url: Some(source), ..
+            }
+            | Formula::Table { name: source, .. } => source,
+        }
+    }
+
+    /// The path of the formula's `.rb` file, if it's installed from a local
+    /// file, rather than by name or from a URL.
+    fn local_path(&self) -> Option<&str> {
+        match self {
+            Formula::Name(source) if is_formula_source(source) && !source.contains("://") => {
+                Some(source)
+            }
+            Formula::Table {
+                url: Some(source), ..
+            } if !source.contains("://") => Some(source),
+            _ => None,
+        }
+    }
+
+    /// The flags to pass to `brew install` for this formula alone.
+    fn install_flags(&self) -> Vec<&str> {
+        let Formula::Table {
+            head,
+            build_from_source,
+            args,
+            ..
+        } = self
+        else {
+            return Vec::new();
+        };
+
+        let mut flags = Vec::new();
+        if *head == Some(true) {
+            flags.push("--HEAD");
+        }
+        if *build_from_source == Some(true) {
+            flags.push("--build-from-source");
+        }
+        flags.extend(args.iter().flatten().map(String::as_str));
+        flags
+    }
+}
+
+/// Whether a formula entry is a URL or path to a formula file, rather than
+/// the name of a formula in a tap.
+fn is_formula_source(entry: &str) -> bool {
+    entry.contains("://") || entry.ends_with(".rb")
+}
+
+impl Brew {
+    /// Checks for duplicate entries, that install args are only flags, and
+    /// that pinned formulae are declared.
+    pub(crate) fn validate(&self, problems: &mut Vec<ConfigProblem>) {
+        warn_duplicates(
+            "brew.formulae",
+            self.formulae.iter().flatten().map(Formula::name),
+            problems,
+        );
+
+        let fields = [
+            ("brew.taps", &self.taps),
+            ("brew.casks", &self.casks),
+            ("brew.services", &self.services),
+            ("brew.pinned", &self.pinned),
+            ("brew.link-keg-only", &self.link_keg_only),
+        ];
+        for (field, entries) in fields {
+            warn_duplicates(
+                field,
+                entries.iter().flatten().map(String::as_str),
+                problems,
+            );
+        }
+
+        for formula in self.formulae.iter().flatten() {
+            if let Formula::Table {
+                name,
+                args: Some(args),
+                ..
+            } = formula
+                && let Some(arg) = args.iter().find(|arg| !arg.starts_with('-'))
+            {
+                problems.push(ConfigProblem::error(
+                    "brew.formulae",
+                    format!("`{name}` has `{arg}` in its args, which is not a flag"),
+                ));
+            }
+        }
+
+        for arg in self.install_args.iter().flatten() {
+            if !arg.starts_with('-') {
+                problems.push(ConfigProblem::error(
+                    "brew.install-args",
+                    format!("`{arg}` is not a flag, declare packages in formulae or casks"),
+                ));
+            }
+        }
+
+        let formulae: HashSet<_> = self.formulae.iter().flatten().map(Formula::name).collect();
+        let declared = [
+            ("brew.pinned", &self.pinned),
+            ("brew.link-keg-only", &self.link_keg_only),
+        ];
+        for (field, entries) in declared {
+            for formula in entries.iter().flatten() {
+                if !formulae.contains(formula.as_str()) {
+                    problems.push(ConfigProblem::warning(
+                        field,
+                        format!("`{formula}` is not in brew.formulae, so it may not be installed"),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// A configuration declaring exactly the `installed` packages.
+    pub fn from_installed(installed: &InstalledBrewPackages) -> Self {
+        let sorted = |packages: &HashSet<String>| {
+            let mut packages: Vec<_> = packages.iter().cloned().collect();
+            packages.sort_unstable();
+            packages
+        };
+
+        Brew {
+            taps: None,
+            formulae: Some(
+                sorted(&installed.formulae)
+                    .into_iter()
+                    .map(Formula::Name)
+                    .collect(),
+            ),
+            casks: Some(sorted(&installed.casks)),
+            services: None,
+            install_args: None,
+            pinned: None,
+            link_keg_only: None,
+            when: None,
+        }
+    }
+
+    /// Makes the paths of local formula files absolute, so they don't depend
+    /// on where omiros is run from: `~/` is expanded, and relative paths are
+    /// relative to the directory containing `system.toml`.
+    pub fn resolve_formula_paths(&mut self, system_config_dir: &Path) -> Result<(), SetupError> {
+        let home = home_dir().ok_or_else(|| {
+            SetupError::ConfigError("Could not determine home directory.".to_string())
+        })?;
+        self.resolve_formula_paths_in(system_config_dir, &home)
+    }
+
+    fn resolve_formula_paths_in(
+        &mut self,
+        system_config_dir: &Path,
+        home: &Path,
+    ) -> Result<(), SetupError> {
+        for formula in self.formulae.iter_mut().flatten() {
+            let Some(path) = formula.local_path() else {
+                continue;
+            };
+            let resolved = absolute_formula_path(path, system_config_dir, home)?
+                .display()
+                .to_string();
+            if let Formula::Name(source)
+            | Formula::Table {
+                url: Some(source), ..
+            } = formula
+            {
+                *source = resolved;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any formula is installed from a file inside `dir`. Both are
+    /// made absolute the same way first, so it doesn't matter whether
+    /// [`Brew::resolve_formula_paths`] has already been called.
+    pub fn has_formula_files_in(&self, dir: &Path) -> bool {
+        home_dir().is_some_and(|home| self.has_formula_files_in_dir(dir, &home))
+    }
+
+    fn has_formula_files_in_dir(&self, dir: &Path, home: &Path) -> bool {
+        let Ok(dir) = absolute_formula_path(&dir.to_string_lossy(), Path::new(""), home) else {
+            return false;
+        };
+        self.formulae
+            .iter()
+            .flatten()
+            .filter_map(Formula::local_path)
+            .filter_map(|path| absolute_formula_path(path, Path::new(""), home).ok())
+            .any(|path| path.starts_with(&dir))
+    }
+
+    /// The formulae that should be pinned, if pins are managed at all.
+    pub fn pinned(&self) -> Option<&[String]> {
+        self.pinned.as_deref()
+    }
+
+    /// The taps that should be added.
+    pub fn taps(&self) -> &[String] {
+        self.taps.as_deref().unwrap_or_default()
+    }
+
+    /// The services that should be kept running.
+    pub fn services(&self) -> &[String] {
+        self.services.as_deref().unwrap_or_default()
+    }
+}
+
+impl Section for Brew {
+    fn when(&self) -> Option<&str> {
+        self.when.as_deref()
+    }
 }
 
 /// Represents the set of currently installed Homebrew packages.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct InstalledBrewPackages {
     formulae: HashSet<String>,
     casks: HashSet<String>,
 }
 
+/// The name a cask is compared by, since `brew list --casks` doesn't always
+/// print the name it was installed with. Drops any tap, like
+/// `homebrew/cask-fonts/`, and the `font-` prefix, and treats versioned casks
+/// like `firefox@developer-edition` the same as their older
+/// `firefox-developer-edition` names.
+fn normalize_cask(name: &str) -> String {
+    let name = name.trim().rsplit('/').next().unwrap_or_default();
+    let name = name.to_lowercase().replace('@', "-");
+    match name.strip_prefix("font-") {
+        Some(font) => font.to_string(),
+        None => name,
+    }
+}
+
 /// Represents the set of missing Homebrew packages that need to be installed.
 #[derive(Debug)]
 pub struct MissingBrewPackages<'a> {
     /// The list of missing formulae.
-    pub formulae: Vec<&'a str>,
+    pub formulae: Vec<&'a Formula>,
     /// The list of missing casks.
     pub casks: Vec<&'a str>,
+    /// Extra flags to pass to every `brew install`.
+    pub install_args: &'a [String],
 }
 
 /// Compares the desired Homebrew packages with the installed packages to determine which ones are missing.
@@ -37,19 +352,39 @@ pub fn find_missing_packages<'a>(
     let mut missing = MissingBrewPackages {
         formulae: Vec::new(),
         casks: Vec::new(),
+        install_args: desired.install_args.as_deref().unwrap_or_default(),
     };
 
+    print_compared_sets(
+        "brew formulae",
+        desired.formulae.iter().flatten().map(Formula::name),
+        installed.formulae.iter().map(String::as_str),
+    );
+    print_compared_sets(
+        "brew casks",
+        desired.casks.iter().flatten().map(String::as_str),
+        installed.casks.iter().map(String::as_str),
+    );
+
     if let Some(formulae) = &desired.formulae {
+        let mut seen = Seen::new("brew formula");
         for formula in formulae {
-            if !installed.formulae.contains(formula) {
+            if !installed.formulae.contains(formula.name()) && seen.first(formula.name()) {
                 missing.formulae.push(formula);
             }
         }
     }
 
     if let Some(casks) = &desired.casks {
+        let installed_casks: HashSet<_> = installed
+            .casks
+            .iter()
+            .map(|cask| normalize_cask(cask))
+            .collect();
+        let mut seen = Seen::new("brew cask");
         for cask in casks {
-            if !installed.casks.contains(cask) {
+            let cask_name = normalize_cask(cask);
+            if !installed_casks.contains(&cask_name) && seen.first(&cask_name) {
                 missing.casks.push(cask);
             }
         }
@@ -58,50 +393,1677 @@ pub fn find_missing_packages<'a>(
     missing
 }
 
+/// Installed Homebrew packages that aren't declared in the configuration.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ExtraBrewPackages<'a> {
+    /// Formulae from `brew leaves`, so dependencies of other formulae are
+    /// never included.
+    pub formulae: Vec<&'a str>,
+    pub casks: Vec<&'a str>,
+}
+
+/// Compares the installed Homebrew packages with the desired ones to
+/// determine which were installed but aren't declared, sorted by name.
+pub fn find_extra_packages<'a>(
+    desired: &'a Brew,
+    installed: &'a InstalledBrewPackages,
+) -> ExtraBrewPackages<'a> {
+    let formulae: HashSet<_> = desired
+        .formulae
+        .iter()
+        .flatten()
+        .map(Formula::name)
+        .collect();
+    let casks: HashSet<_> = desired
+        .casks
+        .iter()
+        .flatten()
+        .map(|cask| normalize_cask(cask))
+        .collect();
+
+    let mut extra = ExtraBrewPackages {
+        formulae: installed
+            .formulae
+            .iter()
+            .map(String::as_str)
+            .filter(|formula| !formulae.contains(formula))
+            .collect(),
+        casks: installed
+            .casks
+            .iter()
+            .map(String::as_str)
+            .filter(|cask| !casks.contains(&normalize_cask(cask)))
+            .collect(),
+    };
+    extra.formulae.sort_unstable();
+    extra.casks.sort_unstable();
+    extra
+}
+
+/// Uninstalls the installed Homebrew packages that aren't declared, stopping at
+/// the first failure or attempting every package depending on `on_failure`.
+pub fn uninstall_extra_packages(
+    runner: &dyn CommandRunner,
+    extra: &ExtraBrewPackages,
+    on_failure: OnFailure,
+) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    let mut failed = Vec::new();
+
+    for (kind, packages, args) in [
+        ("formula", &extra.formulae, &["uninstall"][..]),
+        ("cask", &extra.casks, &["uninstall", "--cask"][..]),
+    ] {
+        for package in packages {
+            event!(
+                "uninstall",
+                package,
+                started,
+                "🗑️  Uninstalling {kind}: {package}"
+            );
+            let args = [args, &[package]].concat();
+            let status = runner.status(BREW_PROGRAM_NAME, &args)?;
+            if !status.success() {
+                event!(
+                    "uninstall",
+                    package,
+                    failed,
+                    "❌ Failed to uninstall {kind}: {package}"
+                );
+                on_failure.record(format!("brew {kind} {package} uninstall"), &mut failed)?;
+            } else {
+                event!("uninstall", package, ok, "✅ Uninstalled {kind}: {package}");
+                changes.other_change(format!("Uninstalled brew {kind} {package}"));
+            }
+        }
+    }
+
+    failures(&failed)?;
+
+    Ok(changes)
+}
+
+/// Declared Homebrew packages with a newer version available, as listed by
+/// `brew outdated --json=v2`.
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct OutdatedBrewPackages {
+    #[serde(default)]
+    pub formulae: Vec<OutdatedPackage>,
+    #[serde(default)]
+    pub casks: Vec<OutdatedPackage>,
+}
+
+/// A formula or cask with a newer version available.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+pub struct OutdatedPackage {
+    pub name: String,
+    pub installed_versions: Vec<String>,
+    pub current_version: String,
+    /// Pinned formulae are listed, but `brew upgrade` leaves them alone.
+    #[serde(default)]
+    pub pinned: bool,
+}
+
+/// Retrieves the declared packages that have a newer version available. Casks
+/// that update themselves, marked `auto_updates`, aren't listed by `brew
+/// outdated` without `--greedy`, so they're left to update themselves, and
+/// pinned formulae are left out too.
+pub fn get_outdated_packages(
+    runner: &dyn CommandRunner,
+    desired: &Brew,
+) -> Result<OutdatedBrewPackages, SetupError> {
+    let output = runner.output(BREW_PROGRAM_NAME, &["outdated", "--json=v2"])?;
+    if !output.success() {
+        return Err(SetupError::InstallFailed(format!(
+            "brew outdated failed: {}",
+            from_utf8(&output.stderr)?.trim()
+        )));
+    }
+
+    let mut outdated: OutdatedBrewPackages =
+        serde_json::from_slice(&output.stdout).map_err(|e| {
+            SetupError::InstallFailed(format!("could not parse brew outdated output: {e}"))
+        })?;
+
+    // Tap formulae can be listed with or without their tap.
+    let short_name = |name: &str| name.rsplit('/').next().unwrap_or(name).to_string();
+    let formulae: HashSet<_> = desired
+        .formulae
+        .iter()
+        .flatten()
+        .map(|formula| short_name(formula.name()))
+        .collect();
+    let casks: HashSet<_> = desired
+        .casks
+        .iter()
+        .flatten()
+        .map(|cask| normalize_cask(cask))
+        .collect();
+    outdated
+        .formulae
+        .retain(|formula| !formula.pinned && formulae.contains(&short_name(&formula.name)));
+    outdated
+        .casks
+        .retain(|cask| casks.contains(&normalize_cask(&cask.name)));
+
+    Ok(outdated)
+}
+
+/// Upgrades the outdated packages with `brew upgrade`, stopping at the first
+/// failure or attempting every package depending on `on_failure`.
+pub fn upgrade_packages(
+    runner: &dyn CommandRunner,
+    outdated: &OutdatedBrewPackages,
+    on_failure: OnFailure,
+) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    if outdated.formulae.is_empty() && outdated.casks.is_empty() {
+        unchanged!("All brew packages are up to date.");
+        return Ok(changes);
+    }
+
+    let mut failed = Vec::new();
+    for (kind, packages, args) in [
+        ("formula", &outdated.formulae, &["upgrade"][..]),
+        ("cask", &outdated.casks, &["upgrade", "--cask"][..]),
+    ] {
+        for package in packages {
+            let name = package.name.as_str();
+            event!(
+                "upgrade",
+                name,
+                started,
+                "Upgrading {kind}: {name} ({} -> {})",
+                package.installed_versions.join(", "),
+                package.current_version
+            );
+            let args = [args, &[name]].concat();
+            let status = runner.status(BREW_PROGRAM_NAME, &args)?;
+            if !status.success() {
+                event!(
+                    "upgrade",
+                    name,
+                    failed,
+                    "❌ Failed to upgrade {kind}: {name}"
+                );
+                on_failure.record(format!("brew {kind} {name} upgrade"), &mut failed)?;
+            } else {
+                event!("upgrade", name, ok, "✅ Upgraded {kind}: {name}");
+                changes.other_change(format!(
+                    "Upgraded brew {kind} {} to {}",
+                    package.name, package.current_version
+                ));
+            }
+        }
+    }
+
+    failures(&failed)?;
+
+    Ok(changes)
+}
+
 /// Retrieves the list of currently installed Homebrew packages.
-pub fn get_installed_brew_packages() -> Result<InstalledBrewPackages, SetupError> {
-    let formulae_output = Command::new(BREW_PROGRAM_NAME).args(["leaves"]).output()?;
-    let formulae = from_utf8(&formulae_output.stdout)?
+pub fn get_installed_brew_packages(
+    runner: &dyn CommandRunner,
+) -> Result<InstalledBrewPackages, SetupError> {
+    Ok(InstalledBrewPackages {
+        formulae: list_packages(runner, &["leaves"])?,
+        casks: list_packages(runner, &["list", "--casks"])?,
+    })
+}
+
+/// Runs a brew command that lists one package per line. If brew fails, that's
+/// an error, rather than an empty list that would make every package look
+/// missing.
+fn list_packages(runner: &dyn CommandRunner, args: &[&str]) -> Result<HashSet<String>, SetupError> {
+    let output = runner.output(BREW_PROGRAM_NAME, args)?;
+    if !output.success() {
+        return Err(SetupError::InstallFailed(format!(
+            "brew {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(from_utf8(&output.stdout)?
         .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
         .map(String::from)
-        .collect();
+        .collect())
+}
 
-    let casks_output = Command::new(BREW_PROGRAM_NAME)
-        .args(["list", "--casks"])
-        .output()?;
-    let casks = from_utf8(&casks_output.stdout)?
+/// Retrieves the formulae that were installed explicitly, rather than pulled
+/// in as a dependency of something else.
+pub fn get_formulae_installed_on_request(
+    runner: &dyn CommandRunner,
+) -> Result<HashSet<String>, SetupError> {
+    let output = runner.output(BREW_PROGRAM_NAME, &["leaves", "--installed-on-request"])?;
+    if !output.success() {
+        return Err(SetupError::InstallFailed(format!(
+            "brew leaves --installed-on-request failed: {}",
+            from_utf8(&output.stderr)?.trim()
+        )));
+    }
+
+    Ok(from_utf8(&output.stdout)?
         .lines()
         .map(String::from)
+        .collect())
+}
+
+/// Retrieves the installed formulae that depend on `formula`, directly or
+/// through other dependencies.
+fn get_installed_dependents(
+    runner: &dyn CommandRunner,
+    formula: &str,
+) -> Result<HashSet<String>, SetupError> {
+    let output = runner.output(
+        BREW_PROGRAM_NAME,
+        &["uses", "--installed", "--recursive", formula],
+    )?;
+    if !output.success() {
+        return Err(SetupError::InstallFailed(format!(
+            "brew uses --installed {formula} failed: {}",
+            from_utf8(&output.stderr)?.trim()
+        )));
+    }
+
+    Ok(from_utf8(&output.stdout)?
+        .lines()
+        .map(String::from)
+        .collect())
+}
+
+/// Returns the formulae that could be uninstalled without breaking anything:
+/// those installed on request but not declared, that no formula being kept
+/// depends on. A formula that's both installed on request and a dependency,
+/// like `openssl@3`, is kept as long as anything still uses it.
+pub fn find_prunable_formulae(
+    runner: &dyn CommandRunner,
+    desired: &Brew,
+    installed_on_request: &HashSet<String>,
+) -> Result<Vec<String>, SetupError> {
+    let declared: HashSet<_> = desired
+        .formulae
+        .iter()
+        .flatten()
+        .map(Formula::name)
         .collect();
 
-    Ok(InstalledBrewPackages { formulae, casks })
+    let mut dependents = HashMap::new();
+    for formula in installed_on_request {
+        if !declared.contains(formula.as_str()) {
+            dependents.insert(formula, get_installed_dependents(runner, formula)?);
+        }
+    }
+
+    // Keeping one formula can mean keeping the formulae it depends on, so
+    // drop candidates until none is used by anything outside the set.
+    let mut prunable: HashSet<_> = dependents.keys().copied().collect();
+    loop {
+        let needed: Vec<_> = prunable
+            .iter()
+            .copied()
+            .filter(|formula| dependents[formula].iter().any(|d| !prunable.contains(d)))
+            .collect();
+        if needed.is_empty() {
+            break;
+        }
+        for formula in needed {
+            unchanged!("ℹ️  Keeping {formula}, other installed formulae depend on it");
+            prunable.remove(formula);
+        }
+    }
+
+    let mut prunable: Vec<_> = prunable.into_iter().cloned().collect();
+    prunable.sort_unstable();
+    Ok(prunable)
+}
+
+/// Uninstalls what isn't declared: casks, and the formulae from
+/// [`find_prunable_formulae`], so a formula installed on request that
+/// something else still depends on is never removed.
+pub fn prune_packages(
+    runner: &dyn CommandRunner,
+    desired: &Brew,
+    installed: &InstalledBrewPackages,
+    on_failure: OnFailure,
+) -> Result<Changes, SetupError> {
+    let installed_on_request = get_formulae_installed_on_request(runner)?;
+    let prunable = find_prunable_formulae(runner, desired, &installed_on_request)?;
+
+    let mut extra = find_extra_packages(desired, installed);
+    extra.formulae = prunable.iter().map(String::as_str).collect();
+    uninstall_extra_packages(runner, &extra, on_failure)
 }
 
 /// Checks if Homebrew is installed and available in the system's PATH.
-pub fn check_brew_installed() -> Result<(), SetupError> {
-    command(BREW_PROGRAM_NAME).map_err(|_| SetupError::BrewNotFound)?;
+pub fn check_brew_installed(runner: &dyn CommandRunner) -> Result<(), SetupError> {
+    command(runner, BREW_PROGRAM_NAME).map_err(|_| SetupError::BrewNotFound)?;
     Ok(())
 }
 
-/// Installs the missing Homebrew packages.
-pub fn install_missing_packages(missing: &MissingBrewPackages) -> Result<(), SetupError> {
-    for formula in &missing.formulae {
-        println!("Installing formula: {formula}");
-        let status = Command::new("brew").args(["install", formula]).status()?;
+/// Checks that the Xcode Command Line Tools, which Homebrew needs to build
+/// anything, are installed. When they aren't and `install` is set, this kicks
+/// off the installer, which runs in its own window. Returns true if the tools
+/// are ready to use, otherwise the `[brew]` section should be skipped.
+pub fn check_command_line_tools(
+    runner: &dyn CommandRunner,
+    install: bool,
+) -> Result<bool, SetupError> {
+    let output = runner.output("xcode-select", &["-p"])?;
+    if output.success() {
+        unchanged!(
+            "✅ Xcode Command Line Tools found at {}",
+            from_utf8(&output.stdout)?.trim()
+        );
+        return Ok(true);
+    }
+
+    warn!("❌ Xcode Command Line Tools are not installed, skipping `[brew]`");
+    if install {
+        runner.status("xcode-select", &["--install"])?;
+        info!("🔧 Started the Command Line Tools installer, run omiros again once it finishes");
+    } else {
+        info!("💡 Install them by running: xcode-select --install");
+        info!("   or pass --install-xcode-tools to have omiros start the installer");
+    }
+
+    Ok(false)
+}
+
+/// Builds the arguments to `brew` to install a single package, with the
+/// configured install args, then the package's own flags, ahead of the
+/// package.
+fn install_args<'a>(extra_args: &'a [String], flags: &[&'a str], name: &'a str) -> Vec<&'a str> {
+    let mut args = vec!["install"];
+    args.extend(extra_args.iter().map(String::as_str));
+    args.extend(flags);
+    args.push(name);
+    args
+}
+
+/// `path` with `~/` expanded, relative to `base`, and then made absolute
+/// against the current directory.
+fn absolute_formula_path(path: &str, base: &Path, home: &Path) -> Result<PathBuf, SetupError> {
+    Ok(std::path::absolute(
+        base.join(tilde_expand_path(Path::new(path), home)?),
+    )?)
+}
+
+/// Reports a failed install as a failed brew install.
+fn brew_install_failed(e: SetupError) -> SetupError {
+    match e {
+        SetupError::InstallFailed(message) => SetupError::BrewInstallFailed(message),
+        e => e,
+    }
+}
+
+/// Installs the missing Homebrew packages, stopping at the first failure or
+/// attempting every package depending on `on_failure`. Formulae are installed
+/// `jobs` at a time, casks one at a time since they can prompt for a password.
+/// Each failed install is retried as `retry` allows first.
+pub fn install_missing_packages(
+    runner: &dyn CommandRunner,
+    missing: &MissingBrewPackages,
+    on_failure: OnFailure,
+    jobs: usize,
+    retry: Retry,
+) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    let mut failed = Vec::new();
+
+    let serial_formulae: &[&Formula] = if jobs > 1 && missing.formulae.len() > 1 {
+        let (failed_formulae, installed) =
+            install_formulae_in_parallel(runner, missing, on_failure, jobs, retry)?;
+        changes.merge(installed);
+        for formula in failed_formulae {
+            on_failure
+                .record(format!("brew formula {formula}"), &mut failed)
+                .map_err(brew_install_failed)?;
+        }
+        &[]
+    } else {
+        &missing.formulae
+    };
+
+    let formulae = serial_formulae.iter().map(|formula| {
+        let args = install_args(
+            missing.install_args,
+            &formula.install_flags(),
+            formula.source(),
+        );
+        ("formula", formula.source(), args, false)
+    });
+    let casks = missing.casks.iter().map(|&cask| {
+        let args = install_args(missing.install_args, &["--cask"], cask);
+        ("cask", cask, args, true)
+    });
+    for (kind, package, args, cask) in formulae.chain(casks) {
+        event!(
+            "install",
+            package,
+            started,
+            "Installing {kind}: {package} (brew {})",
+            args.join(" ")
+        );
+        let status = retry.run(&format!("Installing {kind} {package}"), || {
+            runner.status(BREW_PROGRAM_NAME, &args)
+        })?;
+        if status.success() {
+            event!("install", package, ok, "✅ Installed {kind}: {package}");
+            changes.package_installed("brew", package);
+        } else if !(cask && cask_already_installed(runner, package)?) {
+            event!(
+                "install",
+                package,
+                failed,
+                "❌ Failed to install {kind}: {package}"
+            );
+            on_failure
+                .record(format!("brew {kind} {package}"), &mut failed)
+                .map_err(brew_install_failed)?;
+        }
+    }
+
+    failures(&failed).map_err(brew_install_failed)?;
+
+    Ok(changes)
+}
+
+/// Installs the missing formulae `jobs` at a time. Each install's output is
+/// captured and printed in one go once it finishes, so installs running side
+/// by side don't interleave. Their dependencies are installed first, one at a
+/// time, so installs that share one don't race for its lock. Returns the
+/// formulae that failed, in the order they're declared, and the changes from
+/// the rest. With [`OnFailure::FailFast`] no more installs are started after
+/// one fails.
+fn install_formulae_in_parallel<'a>(
+    runner: &dyn CommandRunner,
+    missing: &MissingBrewPackages<'a>,
+    on_failure: OnFailure,
+    jobs: usize,
+    retry: Retry,
+) -> Result<(Vec<&'a str>, Changes), SetupError> {
+    let mut args = vec!["install", "--only-dependencies"];
+    args.extend(missing.formulae.iter().map(|formula| formula.source()));
+    info!(
+        "Installing dependencies of {} formulae",
+        missing.formulae.len()
+    );
+    let output = retry.run("Installing dependencies", || {
+        runner.status(BREW_PROGRAM_NAME, &args)
+    })?;
+    if !output.success() {
+        // Each formula's own install reports what's wrong with it.
+        warn!("⚠️  Could not install every dependency up front");
+    }
+
+    let queue = Mutex::new(missing.formulae.iter());
+    let failed = Mutex::new(Vec::new());
+    let changes = Mutex::new(Changes::default());
+    let stop = AtomicBool::new(false);
+
+    let install = || -> Result<(), SetupError> {
+        loop {
+            if stop.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            let Some(&formula) = queue.lock().unwrap().next() else {
+                return Ok(());
+            };
+            let flags = formula.install_flags();
+
+            let formula = formula.source();
+            let args = install_args(missing.install_args, &flags, formula);
+            let output = retry.run(&format!("Installing formula {formula}"), || {
+                runner.output(BREW_PROGRAM_NAME, &args)
+            })?;
+
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let mut report = format!("Installing formula: {formula} (brew {})", args.join(" "));
+            for line in stdout.lines().chain(stderr.lines()) {
+                report.push_str("\n   ");
+                report.push_str(line);
+            }
+            info!("{report}");
+
+            if !output.success() {
+                event!(
+                    "install",
+                    formula,
+                    failed,
+                    "❌ Failed to install formula: {formula}"
+                );
+                failed.lock().unwrap().push(formula);
+                if on_failure == OnFailure::FailFast {
+                    stop.store(true, Ordering::Relaxed);
+                }
+            } else {
+                event!("install", formula, ok, "✅ Installed formula: {formula}");
+                changes.lock().unwrap().package_installed("brew", formula);
+            }
+        }
+    };
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.min(missing.formulae.len()))
+            .map(|_| scope.spawn(install))
+            .collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("brew install thread panicked"))
+    })?;
+
+    let mut failed = failed.into_inner().unwrap();
+    failed.sort_by_key(|formula| missing.formulae.iter().position(|f| f.source() == *formula));
+    Ok((failed, changes.into_inner().unwrap()))
+}
+
+/// Checks whether a cask that failed to install was in fact already installed,
+/// under another name, which brew refuses as "already installed". Then it
+/// counts as installed, and the name brew knows it by is suggested instead.
+fn cask_already_installed(runner: &dyn CommandRunner, cask: &str) -> Result<bool, SetupError> {
+    let output = runner.output(BREW_PROGRAM_NAME, &["info", "--json=v2", "--cask", cask])?;
+    if !output.success() {
+        return Ok(false);
+    }
+    let info: BrewInfo = serde_json::from_slice(&output.stdout)
+        .map_err(|e| SetupError::InstallFailed(format!("could not parse brew info output: {e}")))?;
+    let Some(installed) = info.casks.iter().find(|info| info.installed.is_some()) else {
+        return Ok(false);
+    };
+
+    unchanged!("✅ {cask} is already installed");
+    if installed.token != cask {
+        info!(
+            "💡 brew lists {cask} as {}, use that name in brew.casks instead",
+            installed.token
+        );
+    }
+    Ok(true)
+}
+
+/// The parts of `brew info --json=v2` needed to spot keg-only formulae and
+/// installed casks.
+#[derive(Deserialize)]
+struct BrewInfo {
+    #[serde(default)]
+    formulae: Vec<FormulaInfo>,
+    #[serde(default)]
+    casks: Vec<CaskInfo>,
+}
+
+#[derive(Deserialize)]
+struct CaskInfo {
+    /// The cask's current name, which `brew list --casks` prints.
+    token: String,
+    /// The installed version, if any.
+    installed: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FormulaInfo {
+    name: String,
+    keg_only: bool,
+    /// The version that's linked into the Homebrew prefix, if any.
+    linked_keg: Option<String>,
+    caveats: Option<String>,
+    installed: Vec<serde::de::IgnoredAny>,
+}
+
+/// Links the installed keg-only formulae declared in `link-keg-only`, and
+/// prints brew's caveats for any other keg-only formulae that were just
+/// installed, since they won't be on the `PATH` without changes to it.
+pub fn handle_keg_only_formulae(
+    runner: &dyn CommandRunner,
+    desired: &Brew,
+    missing: &MissingBrewPackages,
+) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    let formulae = desired.formulae.as_deref().unwrap_or_default();
+    if formulae.is_empty() {
+        return Ok(changes);
+    }
+
+    let mut args = vec!["info", "--json=v2", "--formula"];
+    args.extend(formulae.iter().map(Formula::name));
+    let output = runner.output(BREW_PROGRAM_NAME, &args)?;
+    if !output.success() {
+        warn!(
+            "⚠️  Could not check for keg-only formulae: {}",
+            from_utf8(&output.stderr)?.trim()
+        );
+        return Ok(changes);
+    }
+    let info: BrewInfo = serde_json::from_slice(&output.stdout)
+        .map_err(|e| SetupError::InstallFailed(format!("could not parse brew info output: {e}")))?;
+
+    let link = desired.link_keg_only.as_deref().unwrap_or_default();
+    let just_installed: HashSet<_> = missing.formulae.iter().map(|f| f.name()).collect();
+
+    let mut failed = Vec::new();
+    for formula in info.formulae {
+        if !formula.keg_only || formula.installed.is_empty() || formula.linked_keg.is_some() {
+            continue;
+        }
+        let name = formula.name.as_str();
+
+        if link.iter().any(|linked| linked == name) {
+            let status = runner.status(BREW_PROGRAM_NAME, &["link", "--force", name])?;
+            if status.success() {
+                event!("link", name, ok, "🔗 Linked keg-only {name}");
+                changes.other_change(format!("Linked keg-only formula {name}"));
+            } else {
+                event!("link", name, failed, "❌ Failed to link {name}");
+                failed.push(name.to_string());
+            }
+        } else if just_installed.contains(name) {
+            info!("💡 {name} is keg-only, so it isn't linked onto your PATH");
+            if let Some(caveats) = formula.caveats {
+                for line in caveats.lines() {
+                    info!("   {line}");
+                }
+            }
+            info!("💡 Add it to brew.link-keg-only to have omiros link it");
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(changes)
+    } else {
+        Err(SetupError::InstallFailed(format!(
+            "failed to link keg-only formulae: {}",
+            failed.join(", ")
+        )))
+    }
+}
+
+/// Retrieves the status of every service `brew services` knows about, keyed by
+/// formula name. Only installed formulae that provide a service are listed.
+pub fn get_service_statuses(
+    runner: &dyn CommandRunner,
+) -> Result<HashMap<String, String>, SetupError> {
+    let output = runner.output(BREW_PROGRAM_NAME, &["services", "list"])?;
+    if !output.success() {
+        return Err(SetupError::InstallFailed(format!(
+            "brew services list failed: {}",
+            from_utf8(&output.stderr)?.trim()
+        )));
+    }
+
+    Ok(parse_services_list(from_utf8(&output.stdout)?))
+}
+
+/// Parses the output of `brew services list`, which looks like:
+///
+/// ```text
+/// Name          Status  User File
+/// postgresql@16 started me   ~/Library/LaunchAgents/homebrew.mxcl.postgresql@16.plist
+/// redis         none
+/// ```
+fn parse_services_list(output: &str) -> HashMap<String, String> {
+    output
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next()?;
+            let status = fields.next()?;
+            Some((name.to_string(), status.to_string()))
+        })
+        .collect()
+}
+
+/// Returns the declared services that aren't running. Services for formulae
+/// that aren't installed are skipped with a warning, since there's nothing to
+/// start.
+pub fn find_stopped_services<'a>(
+    desired: &'a Brew,
+    statuses: &HashMap<String, String>,
+) -> Vec<&'a str> {
+    let mut stopped = Vec::new();
+
+    for service in desired.services() {
+        match statuses.get(service).map(String::as_str) {
+            Some("started") => unchanged!("ℹ️  Service {service} already started"),
+            Some(_) => stopped.push(service.as_str()),
+            None => warn!("⚠️  Service {service} is not installed, skipping"),
+        }
+    }
+
+    stopped
+}
+
+/// Starts each of the given services, reporting every service that failed to
+/// start rather than stopping at the first.
+pub fn start_services(
+    runner: &dyn CommandRunner,
+    services: &[&str],
+) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    let mut failed = Vec::new();
+
+    for service in services {
+        event!(
+            "start-service",
+            service,
+            started,
+            "Starting service: {service}"
+        );
+        let status = runner.status(BREW_PROGRAM_NAME, &["services", "start", service])?;
         if !status.success() {
-            return Err(SetupError::BrewInstallFailed);
+            event!(
+                "start-service",
+                service,
+                failed,
+                "❌ Failed to start service: {service}"
+            );
+            failed.push(*service);
+        } else {
+            event!(
+                "start-service",
+                service,
+                ok,
+                "✅ Started service: {service}"
+            );
+            changes.other_change(format!("Started brew service {service}"));
         }
     }
 
-    for cask in &missing.casks {
-        println!("Installing cask: {cask}");
-        let status = Command::new("brew")
-            .args(["install", "--cask", cask])
-            .status()?;
+    if failed.is_empty() {
+        Ok(changes)
+    } else {
+        Err(SetupError::InstallFailed(format!(
+            "failed to start brew services: {}",
+            failed.join(", ")
+        )))
+    }
+}
+
+/// Retrieves the taps that are currently added, lowercased since tap names
+/// are case insensitive.
+pub fn get_installed_taps(runner: &dyn CommandRunner) -> Result<HashSet<String>, SetupError> {
+    let output = runner.output(BREW_PROGRAM_NAME, &["tap"])?;
+    if !output.success() {
+        return Err(SetupError::InstallFailed(format!(
+            "brew tap failed: {}",
+            from_utf8(&output.stderr)?.trim()
+        )));
+    }
+
+    Ok(from_utf8(&output.stdout)?
+        .lines()
+        .map(|tap| tap.trim().to_lowercase())
+        .filter(|tap| !tap.is_empty())
+        .collect())
+}
+
+/// Taps each declared tap that isn't in `installed` yet, stopping at the first
+/// that fails, since the formulae and casks from it couldn't be installed.
+pub fn tap_missing_taps(
+    runner: &dyn CommandRunner,
+    desired: &Brew,
+    installed: &HashSet<String>,
+) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    for tap in desired.taps() {
+        if installed.contains(&tap.to_lowercase()) {
+            unchanged!("ℹ️  Tap {tap} already added");
+            continue;
+        }
+
+        event!("tap", tap, started, "Tapping {tap}");
+        let status = runner.status(BREW_PROGRAM_NAME, &["tap", tap])?;
         if !status.success() {
-            return Err(SetupError::BrewInstallFailed);
+            event!("tap", tap, failed, "❌ Failed to tap {tap}");
+            return Err(SetupError::BrewInstallFailed(format!(
+                "failed to tap {tap}"
+            )));
         }
+        event!("tap", tap, ok, "✅ Tapped {tap}");
+        changes.other_change(format!("Tapped {tap}"));
     }
 
-    Ok(())
+    Ok(changes)
+}
+
+/// Retrieves the formulae that are currently pinned.
+pub fn get_pinned_formulae(runner: &dyn CommandRunner) -> Result<HashSet<String>, SetupError> {
+    let output = runner.output(BREW_PROGRAM_NAME, &["list", "--pinned"])?;
+    if !output.success() {
+        return Err(SetupError::InstallFailed(format!(
+            "brew list --pinned failed: {}",
+            from_utf8(&output.stderr)?.trim()
+        )));
+    }
+
+    Ok(from_utf8(&output.stdout)?
+        .lines()
+        .map(String::from)
+        .collect())
+}
+
+/// Pins the declared formulae that aren't pinned, and unpins the pinned
+/// formulae that aren't declared. Does nothing unless `pinned` is configured.
+/// Every formula is attempted, and all failures are reported together.
+pub fn reconcile_pins(
+    runner: &dyn CommandRunner,
+    desired: &Brew,
+    pinned: &HashSet<String>,
+) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    let Some(desired_pins) = &desired.pinned else {
+        return Ok(changes);
+    };
+
+    let to_pin: Vec<_> = desired_pins
+        .iter()
+        .filter(|formula| !pinned.contains(*formula))
+        .map(String::as_str)
+        .collect();
+    let mut to_unpin: Vec<_> = pinned
+        .iter()
+        .filter(|formula| !desired_pins.contains(formula))
+        .map(String::as_str)
+        .collect();
+    to_unpin.sort_unstable();
+
+    let mut failed = Vec::new();
+    for (command, done, formulae) in [("pin", "Pinned", &to_pin), ("unpin", "Unpinned", &to_unpin)]
+    {
+        for formula in formulae {
+            let status = runner.status(BREW_PROGRAM_NAME, &[command, formula])?;
+            if status.success() {
+                event!(command, formula, ok, "📌 {done} {formula}");
+                changes.other_change(format!("{done} {formula}"));
+            } else {
+                event!(command, formula, failed, "❌ Failed to {command} {formula}");
+                failed.push(format!("{command} {formula}"));
+            }
+        }
+    }
+
+    if to_pin.is_empty() && to_unpin.is_empty() {
+        unchanged!("ℹ️  Pinned formulae already up to date");
+    }
+
+    if failed.is_empty() {
+        Ok(changes)
+    } else {
+        Err(SetupError::InstallFailed(format!(
+            "failed to reconcile brew pins: {}",
+            failed.join(", ")
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        output::capture_warnings,
+        runner::{CommandOutput, MockRunner},
+    };
+    use rstest::rstest;
+
+    /// Formulae declared by name.
+    fn names(names: &[&str]) -> Vec<Formula> {
+        names
+            .iter()
+            .map(|name| Formula::Name(name.to_string()))
+            .collect()
+    }
+
+    /// What each missing formula would be installed from.
+    fn sources<'a>(missing: &MissingBrewPackages<'a>) -> Vec<&'a str> {
+        missing.formulae.iter().map(|f| f.source()).collect()
+    }
+
+    #[test]
+    fn check_command_line_tools_only_installs_when_asked() {
+        let missing = || {
+            MockRunner::new().stub(
+                &["xcode-select", "-p"],
+                CommandOutput::failed(2, "unable to get active developer directory"),
+            )
+        };
+        let install = vec!["xcode-select".to_string(), "--install".to_string()];
+
+        let runner = missing();
+        assert!(!check_command_line_tools(&runner, false).unwrap());
+        assert!(!runner.calls().contains(&install));
+
+        let runner = missing();
+        assert!(!check_command_line_tools(&runner, true).unwrap());
+        assert!(runner.calls().contains(&install));
+    }
+
+    #[test]
+    fn install_args_puts_extra_args_before_the_package() {
+        let extra_args = vec!["--quiet".to_string()];
+
+        assert_eq!(
+            vec!["install", "--quiet", "fish"],
+            install_args(&extra_args, &[], "fish")
+        );
+        assert_eq!(
+            vec!["install", "--quiet", "--cask", "slack"],
+            install_args(&extra_args, &["--cask"], "slack")
+        );
+        assert_eq!(vec!["install", "fish"], install_args(&[], &[], "fish"));
+    }
+
+    #[test]
+    fn formulae_can_be_names_or_tables_with_options() {
+        let desired: Brew = toml::from_str(
+            r#"
+            formulae = [
+                "fish",
+                { name = "neovim", head = true },
+                { name = "tool", url = "https://example.com/tool.rb", build-from-source = true },
+                { name = "ffmpeg", args = ["--with-fdk-aac"] },
+            ]
+            "#,
+        )
+        .unwrap();
+        let formulae = desired.formulae.as_deref().unwrap();
+
+        let names: Vec<_> = formulae.iter().map(Formula::name).collect();
+        assert_eq!(vec!["fish", "neovim", "tool", "ffmpeg"], names);
+        let sources: Vec<_> = formulae.iter().map(Formula::source).collect();
+        assert_eq!(
+            vec!["fish", "neovim", "https://example.com/tool.rb", "ffmpeg"],
+            sources
+        );
+    }
+
+    #[test]
+    fn formula_options_become_install_flags() {
+        let desired: Brew = toml::from_str(
+            r#"
+            install-args = ["--quiet"]
+            formulae = [
+                "fish",
+                { name = "neovim", head = true, build-from-source = true, args = ["--debug"] },
+            ]
+            "#,
+        )
+        .unwrap();
+        let installed = InstalledBrewPackages {
+            formulae: HashSet::new(),
+            casks: HashSet::new(),
+        };
+        let runner = MockRunner::new();
+
+        let missing = find_missing_packages(&desired, &installed);
+        install_missing_packages(&runner, &missing, OnFailure::FailFast, 1, Retry::new(0)).unwrap();
+
+        assert_eq!(
+            vec![
+                vec!["brew", "install", "--quiet", "fish"],
+                vec![
+                    "brew",
+                    "install",
+                    "--quiet",
+                    "--HEAD",
+                    "--build-from-source",
+                    "--debug",
+                    "neovim"
+                ],
+            ],
+            runner.calls()
+        );
+    }
+
+    #[test]
+    fn formulae_with_options_are_matched_by_name() {
+        let desired: Brew =
+            toml::from_str(r#"formulae = [{ name = "neovim", head = true }]"#).unwrap();
+        let installed = InstalledBrewPackages {
+            formulae: HashSet::from(["neovim".to_string()]),
+            casks: HashSet::new(),
+        };
+
+        assert!(
+            find_missing_packages(&desired, &installed)
+                .formulae
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn formulae_from_urls_and_paths_are_matched_by_name() {
+        let desired: Brew = toml::from_str(
+            r#"
+            formulae = [
+                "fish",
+                "https://raw.githubusercontent.com/me/homebrew-tap/main/Formula/tool.rb",
+                "./Formula/local.rb",
+                { url = "https://example.com/formula.rb", name = "renamed" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let installed = InstalledBrewPackages {
+            formulae: HashSet::from(["fish".to_string(), "tool".to_string()]),
+            casks: HashSet::new(),
+        };
+
+        let missing = find_missing_packages(&desired, &installed);
+
+        assert_eq!(
+            vec!["./Formula/local.rb", "https://example.com/formula.rb"],
+            sources(&missing)
+        );
+    }
+
+    #[test]
+    fn local_formula_paths_are_resolved_against_the_config_dir_and_home() {
+        let mut desired: Brew = toml::from_str(
+            r#"
+            formulae = [
+                "fish",
+                "https://example.com/tool.rb",
+                "Formula/local.rb",
+                "~/formulae/mine.rb",
+                { url = "/opt/formulae/abs.rb", name = "abs" },
+                { url = "Formula/renamed.rb", name = "renamed" },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        desired
+            .resolve_formula_paths_in(Path::new("/config"), Path::new("/home/me"))
+            .unwrap();
+
+        let missing = find_missing_packages(&desired, &InstalledBrewPackages::default());
+        assert_eq!(
+            vec![
+                "fish",
+                "https://example.com/tool.rb",
+                "/config/Formula/local.rb",
+                "/home/me/formulae/mine.rb",
+                "/opt/formulae/abs.rb",
+                "/config/Formula/renamed.rb",
+            ],
+            sources(&missing)
+        );
+        assert_eq!(
+            vec!["fish", "tool", "local", "mine", "abs", "renamed"],
+            missing
+                .formulae
+                .iter()
+                .map(|f| f.name())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn formula_files_are_found_in_relative_and_home_directories() {
+        let home = Path::new("/home/me");
+        let mut resolved: Brew =
+            toml::from_str(r#"formulae = ["dotfiles/Formula/tool.rb"]"#).unwrap();
+        resolved
+            .resolve_formula_paths_in(Path::new("config"), home)
+            .unwrap();
+        let unresolved: Brew = toml::from_str(r#"formulae = ["~/dotfiles/tool.rb"]"#).unwrap();
+
+        assert!(resolved.has_formula_files_in_dir(Path::new("config/dotfiles"), home));
+        assert!(!resolved.has_formula_files_in_dir(Path::new("dotfiles"), home));
+        assert!(unresolved.has_formula_files_in_dir(Path::new("/home/me/dotfiles"), home));
+    }
+
+    #[test]
+    fn duplicate_packages_are_only_missing_once() {
+        let desired: Brew = toml::from_str(
+            r#"
+            formulae = ["git", "fish", "git"]
+            casks = ["slack", "Slack", "zoom"]
+            "#,
+        )
+        .unwrap();
+        let installed = InstalledBrewPackages {
+            formulae: HashSet::from(["fish".to_string()]),
+            casks: HashSet::new(),
+        };
+
+        let mut missing = None;
+        let warnings =
+            capture_warnings(|| missing = Some(find_missing_packages(&desired, &installed)));
+        let missing = missing.unwrap();
+
+        assert_eq!(vec!["git"], sources(&missing));
+        assert_eq!(vec!["slack", "zoom"], missing.casks);
+        assert_eq!(
+            vec![
+                "⚠️  brew formula `git` is listed more than once, only installing it once",
+                "⚠️  brew cask `slack` is listed more than once, only installing it once",
+            ],
+            warnings
+        );
+    }
+
+    #[test]
+    fn no_installed_casks_is_an_empty_set() {
+        let runner = MockRunner::new()
+            .stub(&["brew", "leaves"], CommandOutput::ok("git\n"))
+            .stub(&["brew", "list", "--casks"], CommandOutput::ok(""));
+
+        let installed = get_installed_brew_packages(&runner).unwrap();
+
+        assert_eq!(HashSet::from(["git".to_string()]), installed.formulae);
+        assert!(installed.casks.is_empty());
+    }
+
+    #[rstest]
+    #[case::leaves(&["brew", "leaves"])]
+    #[case::casks(&["brew", "list", "--casks"])]
+    fn failing_to_list_packages_is_an_error(#[case] argv: &[&str]) {
+        let runner =
+            MockRunner::new().stub(argv, CommandOutput::failed(1, "Error: Permission denied"));
+
+        let err = get_installed_brew_packages(&runner)
+            .unwrap_err()
+            .to_string();
+
+        assert!(
+            err.contains(&argv[1..].join(" ")) && err.contains("Permission denied"),
+            "unexpected error: {err}"
+        );
+    }
+
+    #[test]
+    fn casks_are_matched_by_normalized_name() {
+        let desired: Brew = toml::from_str(
+            r#"
+            casks = [
+                "homebrew/cask-fonts/font-fira-code",
+                "firefox-developer-edition",
+                "Slack",
+                "zoom",
+            ]
+            "#,
+        )
+        .unwrap();
+        let runner = MockRunner::new().stub(
+            &["brew", "list", "--casks"],
+            CommandOutput::ok("firefox@developer-edition\nfont-fira-code\nslack\n"),
+        );
+        let installed = get_installed_brew_packages(&runner).unwrap();
+
+        let missing = find_missing_packages(&desired, &installed);
+
+        assert_eq!(vec!["zoom"], missing.casks);
+    }
+
+    #[test]
+    fn find_extra_packages_returns_undeclared_leaves_and_casks() {
+        let desired: Brew = toml::from_str(
+            r#"
+            formulae = ["fish", { url = "https://example.com/tool.rb", name = "tool" }]
+            casks = ["firefox-developer-edition"]
+            "#,
+        )
+        .unwrap();
+        let installed = InstalledBrewPackages {
+            formulae: HashSet::from(["fish", "tool", "wget", "jq"].map(String::from)),
+            casks: HashSet::from(["firefox@developer-edition", "zoom"].map(String::from)),
+        };
+
+        let extra = find_extra_packages(&desired, &installed);
+
+        assert_eq!(
+            ExtraBrewPackages {
+                formulae: vec!["jq", "wget"],
+                casks: vec!["zoom"],
+            },
+            extra
+        );
+
+        let runner = MockRunner::new();
+        uninstall_extra_packages(&runner, &extra, OnFailure::KeepGoing).unwrap();
+        assert_eq!(
+            vec![
+                vec!["brew", "uninstall", "jq"],
+                vec!["brew", "uninstall", "wget"],
+                vec!["brew", "uninstall", "--cask", "zoom"],
+            ],
+            runner.calls()
+        );
+    }
+
+    #[rstest]
+    #[case::fail_fast(OnFailure::FailFast, 1)]
+    #[case::keep_going(OnFailure::KeepGoing, 2)]
+    fn uninstall_extra_packages_stops_at_the_first_failure_when_failing_fast(
+        #[case] on_failure: OnFailure,
+        #[case] attempts: usize,
+    ) {
+        let extra = ExtraBrewPackages {
+            formulae: vec!["jq", "wget"],
+            casks: vec![],
+        };
+        let runner = MockRunner::new()
+            .stub(&["brew", "uninstall", "jq"], CommandOutput::failed(1, ""))
+            .stub(&["brew", "uninstall", "wget"], CommandOutput::failed(1, ""));
+
+        assert!(uninstall_extra_packages(&runner, &extra, on_failure).is_err());
+        assert_eq!(attempts, runner.calls().len());
+    }
+
+    #[test]
+    fn parallel_installs_attempt_every_formula_and_report_all_failures() {
+        let runner = MockRunner::new()
+            .stub(
+                &["brew", "install", "jq"],
+                CommandOutput::failed(1, "Error: jq: no bottle available"),
+            )
+            .stub(
+                &["brew", "install", "wget"],
+                CommandOutput::failed(1, "Error: wget: checksum mismatch"),
+            );
+        let formulae = names(&["fish", "jq", "neovim", "wget", "ripgrep"]);
+        let missing = MissingBrewPackages {
+            formulae: formulae.iter().collect(),
+            casks: Vec::new(),
+            install_args: &[],
+        };
+
+        let error =
+            install_missing_packages(&runner, &missing, OnFailure::KeepGoing, 3, Retry::new(0))
+                .unwrap_err()
+                .to_string();
+
+        assert!(
+            error.contains("brew formula jq, brew formula wget"),
+            "{error}"
+        );
+        let mut calls = runner.calls();
+        calls.sort();
+        assert_eq!(
+            vec![
+                vec![
+                    "brew",
+                    "install",
+                    "--only-dependencies",
+                    "fish",
+                    "jq",
+                    "neovim",
+                    "wget",
+                    "ripgrep",
+                ],
+                vec!["brew", "install", "fish"],
+                vec!["brew", "install", "jq"],
+                vec!["brew", "install", "neovim"],
+                vec!["brew", "install", "ripgrep"],
+                vec!["brew", "install", "wget"],
+            ],
+            calls
+        );
+    }
+
+    #[test]
+    fn already_installed_casks_count_as_installed() {
+        let info = r#"{"formulae": [], "casks": [
+            {"token": "temurin@17", "installed": "17.0.11,9"}
+        ]}"#;
+        let runner = MockRunner::new()
+            .stub(
+                &["brew", "install", "--cask", "temurin17"],
+                CommandOutput::failed(1, ""),
+            )
+            .stub(
+                &["brew", "install", "--cask", "zoom"],
+                CommandOutput::failed(1, ""),
+            )
+            .stub(
+                &["brew", "info", "--json=v2", "--cask", "temurin17"],
+                CommandOutput::ok(info),
+            )
+            .stub(
+                &["brew", "info", "--json=v2", "--cask", "zoom"],
+                CommandOutput::ok(r#"{"casks": [{"token": "zoom", "installed": null}]}"#),
+            );
+        let missing = MissingBrewPackages {
+            formulae: Vec::new(),
+            casks: vec!["temurin17"],
+            install_args: &[],
+        };
+
+        install_missing_packages(&runner, &missing, OnFailure::FailFast, 1, Retry::new(0)).unwrap();
+
+        let missing = MissingBrewPackages {
+            casks: vec!["zoom"],
+            ..missing
+        };
+        assert!(
+            install_missing_packages(&runner, &missing, OnFailure::FailFast, 1, Retry::new(0))
+                .is_err()
+        );
+    }
+
+    #[rstest]
+    #[case::serial(1)]
+    #[case::parallel(2)]
+    fn keeping_going_reports_every_failed_install(#[case] jobs: usize) {
+        let runner = MockRunner::new()
+            .stub(&["brew", "install", "fish"], CommandOutput::failed(1, ""))
+            .stub(&["brew", "install", "jq"], CommandOutput::failed(1, ""))
+            .stub(
+                &["brew", "install", "--cask", "zoom"],
+                CommandOutput::failed(1, ""),
+            )
+            .stub(
+                &["brew", "info", "--json=v2", "--cask", "zoom"],
+                CommandOutput::failed(1, ""),
+            );
+        let formulae = names(&["fish", "git", "jq"]);
+        let missing = MissingBrewPackages {
+            formulae: formulae.iter().collect(),
+            casks: vec!["zoom", "slack"],
+            install_args: &[],
+        };
+
+        let err =
+            install_missing_packages(&runner, &missing, OnFailure::KeepGoing, jobs, Retry::new(0))
+                .unwrap_err()
+                .to_string();
+
+        assert!(
+            err.contains("3 failure(s)")
+                && err.contains("brew formula fish")
+                && err.contains("brew formula jq")
+                && err.contains("brew cask zoom"),
+            "unexpected error: {err}"
+        );
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            &["brew", "install", "--cask", "slack"]
+        );
+        assert!(matches!(
+            install_missing_packages(&runner, &missing, OnFailure::FailFast, jobs, Retry::new(0)),
+            Err(SetupError::BrewInstallFailed(_))
+        ));
+    }
+
+    #[test]
+    fn get_outdated_packages_only_lists_declared_unpinned_packages() {
+        let desired: Brew = toml::from_str(
+            r#"
+            formulae = ["git", "neovim", "me/tools/tool"]
+            casks = ["firefox"]
+            "#,
+        )
+        .unwrap();
+        let outdated = r#"{
+            "formulae": [
+                {"name": "git", "installed_versions": ["2.43.0"], "current_version": "2.44.0", "pinned": false, "pinned_version": null},
+                {"name": "neovim", "installed_versions": ["0.9.4"], "current_version": "0.9.5", "pinned": true, "pinned_version": "0.9.4"},
+                {"name": "tool", "installed_versions": ["1.0"], "current_version": "1.1", "pinned": false, "pinned_version": null},
+                {"name": "openssl@3", "installed_versions": ["3.2.0"], "current_version": "3.2.1", "pinned": false, "pinned_version": null}
+            ],
+            "casks": [
+                {"name": "firefox", "installed_versions": ["122.0"], "current_version": "123.0"},
+                {"name": "zoom", "installed_versions": ["5.17.0"], "current_version": "5.17.5"}
+            ]
+        }"#;
+        let runner = MockRunner::new().stub(
+            &["brew", "outdated", "--json=v2"],
+            CommandOutput::ok(outdated),
+        );
+
+        let outdated = get_outdated_packages(&runner, &desired).unwrap();
+
+        let names = |packages: &[OutdatedPackage]| -> Vec<String> {
+            packages.iter().map(|p| p.name.clone()).collect()
+        };
+        assert_eq!(vec!["git", "tool"], names(&outdated.formulae));
+        assert_eq!(vec!["firefox"], names(&outdated.casks));
+    }
+
+    #[test]
+    fn upgrade_packages_upgrades_formulae_and_casks() {
+        let outdated: OutdatedBrewPackages = serde_json::from_str(
+            r#"{
+                "formulae": [{"name": "git", "installed_versions": ["2.43.0"], "current_version": "2.44.0"}],
+                "casks": [{"name": "firefox", "installed_versions": ["122.0"], "current_version": "123.0"}]
+            }"#,
+        )
+        .unwrap();
+        let runner = MockRunner::new();
+
+        upgrade_packages(&runner, &outdated, OnFailure::KeepGoing).unwrap();
+
+        assert_eq!(
+            vec![
+                vec!["brew", "upgrade", "git"],
+                vec!["brew", "upgrade", "--cask", "firefox"],
+            ],
+            runner.calls()
+        );
+    }
+
+    #[test]
+    fn tap_missing_taps_only_taps_whats_missing() {
+        let desired: Brew =
+            toml::from_str(r#"taps = ["Homebrew/cask-fonts", "me/tools", "broken/tap"]"#).unwrap();
+        let runner = MockRunner::new()
+            .stub(
+                &["brew", "tap"],
+                CommandOutput::ok("homebrew/cask-fonts\nhomebrew/services\n"),
+            )
+            .stub(
+                &["brew", "tap", "broken/tap"],
+                CommandOutput::failed(1, "Repository not found"),
+            );
+
+        let installed = get_installed_taps(&runner).unwrap();
+        let result = tap_missing_taps(&runner, &desired, &installed);
+
+        assert!(matches!(result, Err(SetupError::BrewInstallFailed(_))));
+        assert_eq!(
+            vec![
+                vec!["brew", "tap"],
+                vec!["brew", "tap", "me/tools"],
+                vec!["brew", "tap", "broken/tap"],
+            ],
+            runner.calls()
+        );
+    }
+
+    #[test]
+    fn reconcile_pins_pins_declared_and_unpins_the_rest() {
+        let desired: Brew = toml::from_str(r#"pinned = ["postgresql@16", "node"]"#).unwrap();
+        let pinned = HashSet::from(["node".to_string(), "python@3.12".to_string()]);
+        let runner = MockRunner::new();
+
+        reconcile_pins(&runner, &desired, &pinned).unwrap();
+
+        assert_eq!(
+            vec![
+                vec!["brew", "pin", "postgresql@16"],
+                vec!["brew", "unpin", "python@3.12"],
+            ],
+            runner.calls()
+        );
+    }
+
+    #[test]
+    fn reconcile_pins_leaves_pins_alone_unless_configured() {
+        let desired: Brew = toml::from_str(r#"formulae = ["node"]"#).unwrap();
+        let pinned = HashSet::from(["node".to_string()]);
+        let runner = MockRunner::new();
+
+        reconcile_pins(&runner, &desired, &pinned).unwrap();
+
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn find_prunable_formulae_keeps_dependencies_of_kept_formulae() {
+        // git -> openssl@3 <- curl, and jq -> oniguruma, where jq is undeclared
+        // and both openssl@3 and oniguruma were also installed on request.
+        let desired: Brew = toml::from_str(r#"formulae = ["git"]"#).unwrap();
+        let installed_on_request: HashSet<_> = ["git", "openssl@3", "curl", "jq", "oniguruma"]
+            .map(String::from)
+            .into();
+        let uses = |formula| ["brew", "uses", "--installed", "--recursive", formula];
+        let runner = MockRunner::new()
+            .stub(
+                &uses("openssl@3"),
+                CommandOutput::ok(
+                    "curl
+git
+",
+                ),
+            )
+            .stub(
+                &uses("oniguruma"),
+                CommandOutput::ok(
+                    "jq
+",
+                ),
+            );
+
+        let prunable = find_prunable_formulae(&runner, &desired, &installed_on_request).unwrap();
+
+        assert_eq!(vec!["curl", "jq", "oniguruma"], prunable);
+    }
+
+    #[test]
+    fn find_prunable_formulae_keeps_chains_needed_by_kept_formulae() {
+        // zlib <- libpng <- git, with only git declared.
+        let desired: Brew = toml::from_str(r#"formulae = ["git"]"#).unwrap();
+        let installed_on_request: HashSet<_> = ["git", "libpng", "zlib"].map(String::from).into();
+        let uses = |formula| ["brew", "uses", "--installed", "--recursive", formula];
+        let runner = MockRunner::new()
+            .stub(
+                &uses("libpng"),
+                CommandOutput::ok(
+                    "git
+",
+                ),
+            )
+            .stub(
+                &uses("zlib"),
+                CommandOutput::ok(
+                    "libpng
+git
+",
+                ),
+            );
+
+        let prunable = find_prunable_formulae(&runner, &desired, &installed_on_request).unwrap();
+
+        assert!(prunable.is_empty());
+    }
+
+    #[test]
+    fn prune_packages_keeps_formulae_that_kept_formulae_depend_on() {
+        // git -> openssl@3, both installed on request, with only git declared,
+        // and jq and zoom installed but undeclared.
+        let desired: Brew = toml::from_str(r#"formulae = ["git"]"#).unwrap();
+        let uses = |formula| ["brew", "uses", "--installed", "--recursive", formula];
+        let runner = MockRunner::new()
+            .stub(
+                &["brew", "leaves", "--installed-on-request"],
+                CommandOutput::ok("git\nopenssl@3\njq\n"),
+            )
+            .stub(&uses("openssl@3"), CommandOutput::ok("git\n"));
+        let installed = InstalledBrewPackages {
+            formulae: HashSet::from(["git", "openssl@3", "jq"].map(String::from)),
+            casks: HashSet::from(["zoom".to_string()]),
+        };
+
+        prune_packages(&runner, &desired, &installed, OnFailure::KeepGoing).unwrap();
+
+        let uninstalls: Vec<_> = runner
+            .calls()
+            .into_iter()
+            .filter(|call| call[1] == "uninstall")
+            .collect();
+        assert_eq!(
+            vec![
+                vec!["brew", "uninstall", "jq"],
+                vec!["brew", "uninstall", "--cask", "zoom"],
+            ],
+            uninstalls
+        );
+    }
+
+    #[test]
+    fn handle_keg_only_formulae_links_only_declared_unlinked_kegs() {
+        let desired: Brew = toml::from_str(
+            r#"
+            formulae = ["curl", "sqlite", "git", "openssl@3"]
+            link-keg-only = ["curl", "openssl@3"]
+            "#,
+        )
+        .unwrap();
+        let info = r#"{"formulae": [
+            {"name": "curl", "keg_only": true, "linked_keg": null, "caveats": null, "installed": [{}]},
+            {"name": "sqlite", "keg_only": true, "linked_keg": null, "caveats": "sqlite is keg-only", "installed": [{}]},
+            {"name": "git", "keg_only": false, "linked_keg": "2.45.0", "caveats": null, "installed": [{}]},
+            {"name": "openssl@3", "keg_only": true, "linked_keg": "3.3.0", "caveats": null, "installed": [{}]}
+        ], "casks": []}"#;
+        let runner = MockRunner::new().stub(
+            &[
+                "brew",
+                "info",
+                "--json=v2",
+                "--formula",
+                "curl",
+                "sqlite",
+                "git",
+                "openssl@3",
+            ],
+            CommandOutput::ok(info),
+        );
+        let formulae = names(&["sqlite"]);
+        let missing = MissingBrewPackages {
+            formulae: formulae.iter().collect(),
+            casks: Vec::new(),
+            install_args: &[],
+        };
+
+        handle_keg_only_formulae(&runner, &desired, &missing).unwrap();
+
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            &["brew", "link", "--force", "curl"]
+        );
+        assert_eq!(runner.calls().len(), 2);
+    }
+
+    #[test]
+    fn parse_services_list_reads_name_and_status() {
+        let output = "\
+Name          Status  User File
+postgresql@16 started me   ~/Library/LaunchAgents/homebrew.mxcl.postgresql@16.plist
+redis         none
+unbound       error  256 root ~/Library/LaunchAgents/homebrew.mxcl.unbound.plist
+";
+
+        let statuses = parse_services_list(output);
+
+        assert_eq!(3, statuses.len());
+        assert_eq!("started", statuses["postgresql@16"]);
+        assert_eq!("none", statuses["redis"]);
+        assert_eq!("error", statuses["unbound"]);
+    }
+
+    #[test]
+    fn find_stopped_services_skips_started_and_uninstalled_services() {
+        let desired: Brew =
+            toml::from_str(r#"services = ["postgresql@16", "redis", "mysql"]"#).unwrap();
+        let statuses = parse_services_list(
+            "Name Status User File\npostgresql@16 started me file\nredis none\n",
+        );
+
+        assert_eq!(vec!["redis"], find_stopped_services(&desired, &statuses));
+    }
 }
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::{OnFailure, SetupError, failures},
+    output::{event, print_compared_sets, unchanged},
+    report::Changes,
+    runner::CommandRunner,
+    system::Section,
+    system_utils::{Seen, command},
+    validate::{ConfigProblem, warn_duplicates},
+};
+
+const CARGO_PROGRAM_NAME: &str = "cargo";
+
+/// Represents the `cargo install` configuration, specifying which crates to
+/// install binaries from.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct CargoInstall {
+    /// Crates to install, by name or with a version or git source.
+    pub crates: Vec<Crate>,
+    /// Shell command that must exit zero for this section to be applied.
+    pub when: Option<String>,
+}
+
+/// A crate to install: either a name, like `ripgrep`, or a table pinning a
+/// version, or installing from a git repository instead of crates.io. Unknown
+/// keys in the table are rejected, so a typo doesn't quietly drop a pin.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum Crate {
+    Name(String),
+    Source {
+        name: String,
+        /// The exact version to install, reinstalled when the installed
+        /// version is a different one.
+        version: Option<String>,
+        git: Option<String>,
+    },
+}
+
+impl Crate {
+    /// The name the crate is listed under by `cargo install --list`.
+    pub fn name(&self) -> &str {
+        match self {
+            Crate::Name(name) | Crate::Source { name, .. } => name,
+        }
+    }
+
+    /// The arguments to pass to `cargo install` to install the crate.
+    fn install_args(&self) -> Vec<&str> {
+        let mut args = vec!["install"];
+        if let Crate::Source { version, git, .. } = self {
+            if let Some(version) = version {
+                args.extend(["--version", version]);
+            }
+            if let Some(git) = git {
+                args.extend(["--git", git]);
+            }
+        }
+        args.push(self.name());
+        args
+    }
+
+    /// Whether `installed`, the version from `cargo install --list`, is the
+    /// one the crate is pinned to, or any version if it isn't pinned.
+    fn is_satisfied_by(&self, installed: &str) -> bool {
+        match self {
+            Crate::Source {
+                version: Some(version),
+                ..
+            } => version.strip_prefix('=').unwrap_or(version) == installed,
+            _ => true,
+        }
+    }
+}
+
+impl CargoInstall {
+    /// Checks for duplicate crates, and crates given both a version and a git
+    /// repository, which `cargo install` can't combine.
+    pub(crate) fn validate(&self, problems: &mut Vec<ConfigProblem>) {
+        warn_duplicates(
+            "cargo.crates",
+            self.crates.iter().map(Crate::name),
+            problems,
+        );
+
+        for krate in &self.crates {
+            if let Crate::Source {
+                name,
+                version: Some(_),
+                git: Some(_),
+            } = krate
+            {
+                problems.push(ConfigProblem::error(
+                    "cargo.crates",
+                    format!("`{name}` has both a `version` and a `git` source"),
+                ));
+            }
+        }
+    }
+}
+
+impl Section for CargoInstall {
+    fn when(&self) -> Option<&str> {
+        self.when.as_deref()
+    }
+}
+
+/// Checks if `cargo` is installed and available in the system's PATH.
+pub fn check_cargo_installed(runner: &dyn CommandRunner) -> Result<(), SetupError> {
+    let _ = command(runner, CARGO_PROGRAM_NAME)?;
+
+    Ok(())
+}
+
+/// Retrieves the crates installed with `cargo install`, by name, with their
+/// versions.
+pub fn get_installed_crates(
+    runner: &dyn CommandRunner,
+) -> Result<HashMap<String, String>, SetupError> {
+    let output = runner.output(CARGO_PROGRAM_NAME, &["install", "--list"])?;
+
+    if !output.success() {
+        return Err(SetupError::InstallFailed(format!(
+            "cargo install --list failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(parse_cargo_install_list(&String::from_utf8(output.stdout)?))
+}
+
+/// Parses the output of `cargo install --list`, where each crate is a line
+/// like `ripgrep v14.1.0:`, or `tool v0.1.0 (https://github.com/me/tool#abc):`
+/// for git sources, followed by its binaries indented on the next lines. The
+/// versions are returned without their `v`.
+fn parse_cargo_install_list(list: &str) -> HashMap<String, String> {
+    list.lines()
+        .filter(|line| !line.starts_with(char::is_whitespace))
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let name = words.next()?;
+            let version = words.next().unwrap_or_default().trim_end_matches(':');
+            Some((
+                name.to_string(),
+                version.strip_prefix('v').unwrap_or(version).to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// Compares the desired crates with the installed crates to determine which
+/// ones are missing, or installed at a different version than the one they're
+/// pinned to.
+pub fn find_missing_crates<'a>(
+    desired: &'a CargoInstall,
+    installed: &HashMap<String, String>,
+) -> Vec<&'a Crate> {
+    print_compared_sets(
+        "cargo crates",
+        desired.crates.iter().map(Crate::name),
+        installed.keys().map(String::as_str),
+    );
+
+    let mut seen = Seen::new("cargo crate");
+    desired
+        .crates
+        .iter()
+        .filter(|krate| {
+            !installed
+                .get(krate.name())
+                .is_some_and(|version| krate.is_satisfied_by(version))
+                && seen.first(krate.name())
+        })
+        .collect()
+}
+
+/// Installs the missing crates, stopping at the first failure or attempting
+/// every crate depending on `on_failure`.
+pub fn install_missing_crates(
+    runner: &dyn CommandRunner,
+    missing: &[&Crate],
+    on_failure: OnFailure,
+) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    if missing.is_empty() {
+        unchanged!("All cargo crates are installed.");
+        return Ok(changes);
+    }
+
+    let mut failed = Vec::new();
+    for krate in missing {
+        let name = krate.name();
+        event!("install", name, started, "Installing cargo crate: {name}");
+        let status = runner.status(CARGO_PROGRAM_NAME, &krate.install_args())?;
+        if !status.success() {
+            event!(
+                "install",
+                name,
+                failed,
+                "❌ Failed to install cargo crate: {name}"
+            );
+            on_failure.record(format!("cargo crate {name}"), &mut failed)?;
+        } else {
+            event!("install", name, ok, "✅ Installed cargo crate: {name}");
+            changes.package_installed("cargo", name);
+        }
+    }
+
+    failures(&failed)?;
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::MockRunner;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::empty("", &[])]
+    #[case::registry(
+        "bat v0.24.0:\n    bat\nripgrep v14.1.0:\n    rg\n",
+        &[("bat", "0.24.0"), ("ripgrep", "14.1.0")]
+    )]
+    #[case::several_binaries(
+        "cargo-edit v0.12.2:\n    cargo-add\n    cargo-rm\n    cargo-set-version\n    cargo-upgrade\n",
+        &[("cargo-edit", "0.12.2")]
+    )]
+    #[case::git_source(
+        "tool v0.1.0 (https://github.com/me/tool#1a2b3c4d):\n    tool\n",
+        &[("tool", "0.1.0")]
+    )]
+    fn parses_cargo_install_list(#[case] list: &str, #[case] expected: &[(&str, &str)]) {
+        let installed = parse_cargo_install_list(list);
+
+        let expected: HashMap<_, _> = expected
+            .iter()
+            .map(|(name, version)| (name.to_string(), version.to_string()))
+            .collect();
+        assert_eq!(expected, installed);
+    }
+
+    #[test]
+    fn crates_pinned_to_another_version_are_reinstalled() {
+        let desired: CargoInstall = toml::from_str(
+            r#"
+            crates = [
+                "bat",
+                { name = "ripgrep", version = "14.1.0" },
+                { name = "cargo-edit", version = "=0.13.0" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let installed = parse_cargo_install_list(
+            "bat v0.24.0:\n    bat\ncargo-edit v0.12.2:\n    cargo-add\nripgrep v14.1.0:\n    rg\n",
+        );
+
+        let missing: Vec<_> = find_missing_crates(&desired, &installed)
+            .into_iter()
+            .map(Crate::name)
+            .collect();
+
+        assert_eq!(vec!["cargo-edit"], missing);
+    }
+
+    #[test]
+    fn unknown_crate_keys_are_rejected() {
+        let result: Result<CargoInstall, _> =
+            toml::from_str(r#"crates = [{ name = "ripgrep", verison = "14.1.0" }]"#);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn installs_missing_crates_with_their_version_or_git_source() {
+        let desired: CargoInstall = toml::from_str(
+            r#"
+            crates = [
+                "ripgrep",
+                { name = "cargo-edit", version = "0.12.2" },
+                { name = "tool", git = "https://github.com/me/tool" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let installed = parse_cargo_install_list("ripgrep v14.1.0:\n    rg\n");
+        let runner = MockRunner::new();
+
+        let missing = find_missing_crates(&desired, &installed);
+        let changes = install_missing_crates(&runner, &missing, OnFailure::KeepGoing).unwrap();
+
+        assert_eq!(
+            vec![
+                vec!["cargo", "install", "--version", "0.12.2", "cargo-edit"],
+                vec![
+                    "cargo",
+                    "install",
+                    "--git",
+                    "https://github.com/me/tool",
+                    "tool"
+                ],
+            ],
+            runner.calls()
+        );
+        let installed: Vec<_> = changes
+            .packages_installed
+            .iter()
+            .map(|package| package.name.as_str())
+            .collect();
+        assert_eq!(vec!["cargo-edit", "tool"], installed);
+    }
+}
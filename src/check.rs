@@ -0,0 +1,175 @@
+//! Compares the machine against the configuration without changing anything,
+//! for `omiros check`. Each section reports what `omiros run` would do, and
+//! returns how many differences it found.
+
+use std::path::Path;
+
+use log::{info, warn};
+
+use crate::{
+    brew::{Brew, check_brew_installed, find_missing_packages, get_installed_brew_packages},
+    cargo_install::{
+        CargoInstall, check_cargo_installed, find_missing_crates, get_installed_crates,
+    },
+    doctor::check_dotfiles,
+    dotfiles::Dotfiles,
+    errors::SetupError,
+    macos::planned_writes,
+    mas::{Mas, check_mas_installed, find_missing_apps, get_installed_apps, resolve_app_ids},
+    npm::{Npm, check_npm_installed, find_missing_npm_packages, get_installed_npm_packages},
+    runner::CommandRunner,
+    system::MacOS,
+    vscode::Vscode,
+};
+
+/// Reports the declared formulae and casks that aren't installed.
+pub fn check_brew(runner: &dyn CommandRunner, brew: &Brew) -> Result<usize, SetupError> {
+    if check_brew_installed(runner).is_err() {
+        warn!("❌ brew is not installed");
+        return Ok(1);
+    }
+
+    let installed = get_installed_brew_packages(runner)?;
+    let missing = find_missing_packages(brew, &installed);
+    for formula in &missing.formulae {
+        warn!("❌ brew formula {} is not installed", formula.source());
+    }
+    for cask in &missing.casks {
+        warn!("❌ brew cask {cask} is not installed");
+    }
+
+    Ok(missing.formulae.len() + missing.casks.len())
+}
+
+/// Reports the declared App Store apps that aren't installed. Apps declared by
+/// name alone are looked up first, like `omiros run` does.
+pub fn check_mas(
+    runner: &dyn CommandRunner,
+    mut mas: Mas,
+    system_config_dir: &Path,
+) -> anyhow::Result<usize> {
+    if check_mas_installed(runner).is_err() {
+        warn!("❌ mas is not installed");
+        return Ok(1);
+    }
+
+    resolve_app_ids(runner, &mut mas, system_config_dir)?;
+    let installed = get_installed_apps(runner)?;
+    let missing = find_missing_apps(&mas, &installed);
+    for app in &missing.apps {
+        warn!("❌ App Store app {app} is not installed");
+    }
+
+    Ok(missing.apps.len())
+}
+
+/// Reports the declared VS Code extensions that aren't installed.
+pub fn check_vscode(runner: &dyn CommandRunner, vscode: &Vscode) -> Result<usize, SetupError> {
+    let missing = vscode.find_missing_extensions(runner)?;
+    for extension in &missing {
+        warn!("❌ VS Code extension {extension} is not installed");
+    }
+
+    Ok(missing.len())
+}
+
+/// Reports the declared global npm packages that aren't installed.
+pub fn check_npm(runner: &dyn CommandRunner, npm: &Npm) -> Result<usize, SetupError> {
+    if check_npm_installed(runner).is_err() {
+        warn!("❌ npm is not installed");
+        return Ok(1);
+    }
+
+    let installed = get_installed_npm_packages(runner)?;
+    let missing = find_missing_npm_packages(npm, &installed);
+    for package in &missing {
+        warn!("❌ npm package {package} is not installed");
+    }
+
+    Ok(missing.len())
+}
+
+/// Reports the declared crates that aren't installed with `cargo install`.
+pub fn check_cargo(runner: &dyn CommandRunner, cargo: &CargoInstall) -> Result<usize, SetupError> {
+    if check_cargo_installed(runner).is_err() {
+        warn!("❌ cargo is not installed");
+        return Ok(1);
+    }
+
+    let installed = get_installed_crates(runner)?;
+    let missing = find_missing_crates(cargo, &installed);
+    for krate in &missing {
+        match installed.get(krate.name()) {
+            Some(version) => warn!(
+                "❌ cargo crate {} is at {version}, not the pinned version",
+                krate.name()
+            ),
+            None => warn!("❌ cargo crate {} is not installed", krate.name()),
+        }
+    }
+
+    Ok(missing.len())
+}
+
+/// Reports the dotfiles that aren't linked, or copied, as configured.
+pub fn check_dotfiles_drift(
+    runner: &dyn CommandRunner,
+    dotfiles: &Dotfiles,
+    dotfiles_dir: &Path,
+) -> Result<usize, SetupError> {
+    check_dotfiles(runner, dotfiles, dotfiles_dir, false)
+}
+
+/// Reports the `[macos]` settings that `defaults` doesn't hold the configured
+/// value for. Only settings written with `defaults write` are checked.
+pub fn check_macos(runner: &dyn CommandRunner, macos: &MacOS) -> anyhow::Result<usize> {
+    let mut drifted = 0;
+    for write in planned_writes(macos) {
+        if !write.is_current(runner)? {
+            warn!("❌ {} is not set to {}", write.field, write.value);
+            drifted += 1;
+        }
+    }
+
+    Ok(drifted)
+}
+
+/// Prints the summary line for a check that found `drifted` differences.
+pub fn report(drifted: usize) {
+    if drifted == 0 {
+        info!("✅ Everything matches the configuration");
+    } else {
+        warn!("❌ {drifted} difference(s) from the configuration");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, MockRunner};
+
+    #[test]
+    fn check_only_reads_and_counts_each_difference() {
+        let brew: Brew = toml::from_str(
+            r#"
+            formulae = ["fish", "git"]
+            casks = ["slack"]
+            "#,
+        )
+        .unwrap();
+        let runner = MockRunner::new()
+            .stub(
+                &["command", "-v", "brew"],
+                CommandOutput::ok("/opt/homebrew/bin/brew"),
+            )
+            .stub(&["brew", "leaves"], CommandOutput::ok("git\n"));
+
+        assert_eq!(2, check_brew(&runner, &brew).unwrap());
+        assert!(
+            runner
+                .calls()
+                .iter()
+                .all(|call| !call.contains(&"install".to_string()))
+        );
+    }
+}
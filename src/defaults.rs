@@ -1,10 +1,20 @@
 use std::{
+    collections::HashMap,
     fmt::Display,
-    process::{Command, Stdio},
+    io,
+    path::Path,
     str,
+    sync::{Mutex, MutexGuard},
 };
 
-use serde::Deserialize;
+use plist::{Dictionary, Value};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    output::{event, unchanged},
+    report::Changes,
+    runner::{CommandOutput, CommandRunner},
+};
 
 pub(crate) trait DefaultsType: Sized {
     /// The type flag used when writing values to the `defaults` command. For
@@ -14,8 +24,21 @@ pub(crate) trait DefaultsType: Sized {
     /// Parses the output from the `defaults` command, and returns back a
     /// instance of Self.
     fn parse_output(s: &str) -> Result<Self, DefaultsError>;
+
+    /// Whether a value read back with `defaults read` is the same as `other`,
+    /// the value about to be written.
+    fn same_as(&self, other: &Self) -> bool
+    where
+        Self: PartialEq,
+    {
+        self == other
+    }
 }
 
+/// How far apart two floats can be and still count as the same setting, since
+/// `defaults` can store them at a lower precision than they were written.
+const FLOAT_TOLERANCE: f64 = 1e-6;
+
 impl DefaultsType for bool {
     const TYPE_FLAG: &'static str = "-bool";
 
@@ -40,7 +63,30 @@ impl DefaultsType for i32 {
     }
 }
 
-#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+impl DefaultsType for f64 {
+    const TYPE_FLAG: &'static str = "-float";
+
+    /// Floats are printed with a `,` for the decimal point in some locales.
+    fn parse_output(s: &str) -> Result<Self, DefaultsError> {
+        s.replace(',', ".")
+            .parse::<f64>()
+            .map_err(|_| DefaultsError::ParseError(format!("Could not parse: {s}")))
+    }
+
+    fn same_as(&self, other: &Self) -> bool {
+        (self - other).abs() < FLOAT_TOLERANCE
+    }
+}
+
+impl DefaultsType for String {
+    const TYPE_FLAG: &'static str = "-string";
+
+    fn parse_output(s: &str) -> Result<Self, DefaultsError> {
+        Ok(s.to_string())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum DockOrientation {
     Left,
@@ -73,7 +119,72 @@ impl DefaultsType for DockOrientation {
     }
 }
 
-#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+/// The animation used when minimizing a window into the Dock.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum MinimizeEffect {
+    Genie,
+    Scale,
+}
+
+impl std::fmt::Display for MinimizeEffect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MinimizeEffect::Genie => write!(f, "genie"),
+            MinimizeEffect::Scale => write!(f, "scale"),
+        }
+    }
+}
+
+impl DefaultsType for MinimizeEffect {
+    const TYPE_FLAG: &'static str = "-string";
+
+    fn parse_output(s: &str) -> Result<Self, DefaultsError> {
+        match s {
+            "genie" => Ok(MinimizeEffect::Genie),
+            "scale" => Ok(MinimizeEffect::Scale),
+            s => Err(DefaultsError::ParseError(format!(
+                "Could not parse output: {s}"
+            ))),
+        }
+    }
+}
+
+/// Where a Finder search looks by default.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum SearchScope {
+    ThisMac,
+    CurrentFolder,
+    PreviousScope,
+}
+
+impl std::fmt::Display for SearchScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SearchScope::ThisMac => write!(f, "SCev"),
+            SearchScope::CurrentFolder => write!(f, "SCcf"),
+            SearchScope::PreviousScope => write!(f, "SCsp"),
+        }
+    }
+}
+
+impl DefaultsType for SearchScope {
+    const TYPE_FLAG: &'static str = "-string";
+
+    fn parse_output(s: &str) -> Result<Self, DefaultsError> {
+        match s {
+            "SCev" => Ok(SearchScope::ThisMac),
+            "SCcf" => Ok(SearchScope::CurrentFolder),
+            "SCsp" => Ok(SearchScope::PreviousScope),
+            s => Err(DefaultsError::ParseError(format!(
+                "Could not parse output: {s}"
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
 #[serde(rename_all = "kebab-case")]
 pub enum MouseButtonMode {
     OneButton,
@@ -103,21 +214,83 @@ impl DefaultsType for MouseButtonMode {
     }
 }
 
+/// How Finder arranges the icons on the desktop.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum DesktopArrangement {
+    None,
+    Grid,
+    Name,
+    Kind,
+    DateModified,
+    DateCreated,
+    DateAdded,
+    DateLastOpened,
+    Size,
+    Label,
+}
+
+impl std::fmt::Display for DesktopArrangement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DesktopArrangement::None => write!(f, "none"),
+            DesktopArrangement::Grid => write!(f, "grid"),
+            DesktopArrangement::Name => write!(f, "name"),
+            DesktopArrangement::Kind => write!(f, "kind"),
+            DesktopArrangement::DateModified => write!(f, "dateModified"),
+            DesktopArrangement::DateCreated => write!(f, "dateCreated"),
+            DesktopArrangement::DateAdded => write!(f, "dateAdded"),
+            DesktopArrangement::DateLastOpened => write!(f, "dateLastOpened"),
+            DesktopArrangement::Size => write!(f, "size"),
+            DesktopArrangement::Label => write!(f, "label"),
+        }
+    }
+}
+
+impl DefaultsType for DesktopArrangement {
+    const TYPE_FLAG: &'static str = "-string";
+
+    fn parse_output(s: &str) -> Result<Self, DefaultsError> {
+        match s {
+            "none" => Ok(DesktopArrangement::None),
+            "grid" => Ok(DesktopArrangement::Grid),
+            "name" => Ok(DesktopArrangement::Name),
+            "kind" => Ok(DesktopArrangement::Kind),
+            "dateModified" => Ok(DesktopArrangement::DateModified),
+            "dateCreated" => Ok(DesktopArrangement::DateCreated),
+            "dateAdded" => Ok(DesktopArrangement::DateAdded),
+            "dateLastOpened" => Ok(DesktopArrangement::DateLastOpened),
+            "size" => Ok(DesktopArrangement::Size),
+            "label" => Ok(DesktopArrangement::Label),
+            s => Err(DefaultsError::ParseError(format!(
+                "Could not parse output: {s}"
+            ))),
+        }
+    }
+}
+
 /// Reads the configuration value stored by macOS by using the `defaults` CLI
 /// for particular `domain` and `key`.
-fn read_defaults<T>(domain: &str, key: &str) -> Result<T, DefaultsError>
+fn read_defaults<T>(runner: &dyn CommandRunner, domain: &str, key: &str) -> Result<T, DefaultsError>
 where
     T: DefaultsType,
 {
-    let output = Command::new("defaults")
-        .args(["read", domain, key])
-        .output()
+    let output = runner
+        .output("defaults", &["read", domain, key])
         .map_err(|e| {
-            DefaultsError::CommandFailed(format!("Failed to execute defaults write: {}", e))
+            DefaultsError::CommandFailed(format!("Failed to execute defaults read: {}", e))
         })?;
 
-    if !output.status.success() {
-        return Err(DefaultsError::CommandFailed("sadness".to_string()));
+    if !output.success() {
+        let stderr = str::from_utf8(output.stderr.trim_ascii())?;
+        // `defaults read` exits with 1 either way, so a key that's never been
+        // set can only be told apart by its message.
+        if stderr.contains("does not exist") {
+            return Err(DefaultsError::KeyNotFound(format!("{domain}.{key}")));
+        }
+        return Err(DefaultsError::CommandFailed(format!(
+            "defaults read failed for {domain}.{key}: {stderr}"
+        )));
     }
 
     let s = str::from_utf8(output.stdout.trim_ascii())?;
@@ -125,52 +298,573 @@ where
     T::parse_output(s)
 }
 
-/// returns a bool telling you if a change had to occur, or if the setting was
-/// already the same as the given `value`, this lets you do things like add a
-/// follow-on step such as restarting the application that this setting affects.
+/// Like [`read_defaults`], but `None` when the value isn't set or isn't of
+/// the expected type, for reporting what's currently configured.
+pub(crate) fn read_setting<T>(runner: &dyn CommandRunner, domain: &str, key: &str) -> Option<T>
+where
+    T: DefaultsType,
+{
+    read_defaults(runner, domain, key).ok()
+}
+
+/// returns the change that had to occur, if any, or nothing when the setting
+/// was already the same as the given `value`, this lets you do things like add
+/// a follow-on step such as restarting the application that this setting
+/// affects.
 pub(crate) fn write_defaults<T>(
+    runner: &dyn CommandRunner,
+    domain: &str,
+    key: &str,
+    new_value: T,
+) -> Result<Changes, DefaultsError>
+where
+    T: Display + DefaultsType + PartialEq,
+{
+    write_defaults_as(runner, false, domain, key, new_value)
+}
+
+/// Like [`write_defaults`], but writes with `sudo`, for system-wide domains
+/// under `/Library/Preferences`. `sudo` is run non-interactively, so this
+/// fails rather than prompting when there's no cached sudo ticket, see
+/// [`can_sudo`].
+pub(crate) fn write_system_defaults<T>(
+    runner: &dyn CommandRunner,
     domain: &str,
     key: &str,
     new_value: T,
-) -> Result<bool, DefaultsError>
+) -> Result<Changes, DefaultsError>
 where
     T: Display + DefaultsType + PartialEq,
 {
-    match read_defaults::<T>(domain, key) {
+    write_defaults_as(runner, true, domain, key, new_value)
+}
+
+/// Whether `sudo` can be run without prompting for a password, either because
+/// omiros is already running as root or a sudo ticket is cached.
+pub(crate) fn can_sudo(runner: &dyn CommandRunner) -> Result<bool, DefaultsError> {
+    let output = runner
+        .output("sudo", &["-n", "true"])
+        .map_err(|e| DefaultsError::CommandFailed(format!("failed to run sudo {e}")))?;
+
+    Ok(output.success())
+}
+
+fn write_defaults_as<T>(
+    runner: &dyn CommandRunner,
+    sudo: bool,
+    domain: &str,
+    key: &str,
+    new_value: T,
+) -> Result<Changes, DefaultsError>
+where
+    T: Display + DefaultsType + PartialEq,
+{
+    match read_defaults::<T>(runner, domain, key) {
         Ok(current_value) => {
-            if current_value == new_value {
-                println!("ℹ️  {}.{} already set to {}", domain, key, new_value);
-                return Ok(false);
+            if current_value.same_as(&new_value) {
+                unchanged!("ℹ️  {}.{} already set to {}", domain, key, new_value);
+                return Ok(Changes::default());
             }
         }
-        Err(_) => todo!(),
+        // Never set, which is usual on a fresh machine, so there's nothing
+        // to compare against.
+        Err(DefaultsError::KeyNotFound(_)) => {}
+        Err(e) => return Err(e),
     }
 
-    println!(
-        "🔧 Setting {}.{} = {} ({})",
-        domain,
-        key,
-        new_value,
+    let target = format!("{domain}.{key}");
+    let new_value = new_value.to_string();
+    let args = ["write", domain, key, T::TYPE_FLAG, &new_value];
+    let output = if sudo {
+        runner.output("sudo", &[&["-n", "defaults"], &args[..]].concat())
+    } else {
+        runner.output("defaults", &args)
+    };
+    let output = output.map_err(|e| {
+        DefaultsError::CommandFailed(format!("Failed to execute defaults write: {}", e))
+    })?;
+
+    if !output.success() {
+        event!("write", &target, failed, "❌ Failed to set {target}");
+        if sudo && !can_sudo(runner)? {
+            return Err(DefaultsError::SudoRequired(target));
+        }
+        return Err(DefaultsError::CommandFailed(format!(
+            "defaults write failed for {target}"
+        )));
+    }
+    event!(
+        "write",
+        &target,
+        ok,
+        "🔧 Set {target} = {new_value} ({})",
         T::TYPE_FLAG
     );
+    let mut changes = Changes::default();
+    changes.default_changed(domain, key, &new_value);
 
-    let status = Command::new("defaults")
-        .args(["write", domain, key, T::TYPE_FLAG, &new_value.to_string()])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .status()
+    Ok(changes)
+}
+
+/// A value a run will write with `defaults write`, described up front without
+/// running anything, so a whole configuration's writes can be checked before
+/// any are made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DefaultsWrite {
+    /// The configuration field the value comes from, like
+    /// `macos.dock.orientation`.
+    pub field: String,
+    pub domain: String,
+    pub key: String,
+    /// The value, as it's passed to `defaults write`.
+    pub value: String,
+    /// The type flag it's written with, like `-bool`.
+    pub type_flag: &'static str,
+}
+
+impl DefaultsWrite {
+    /// A write of `value` to `domain`/`key`, when `value` is configured.
+    pub(crate) fn new<T: Display + DefaultsType>(
+        field: &str,
+        domain: &str,
+        key: &str,
+        value: Option<T>,
+    ) -> Option<Self> {
+        value.map(|value| DefaultsWrite {
+            field: field.to_string(),
+            domain: domain.to_string(),
+            key: key.to_string(),
+            value: value.to_string(),
+            type_flag: T::TYPE_FLAG,
+        })
+    }
+
+    /// Whether `domain`/`key` already holds the value, read back with
+    /// `defaults read` without writing anything. A key that isn't set, or is
+    /// set to a value of another type, doesn't.
+    pub(crate) fn is_current(&self, runner: &dyn CommandRunner) -> Result<bool, DefaultsError> {
+        fn matches<T: DefaultsType + PartialEq>(
+            runner: &dyn CommandRunner,
+            write: &DefaultsWrite,
+        ) -> Result<bool, DefaultsError> {
+            let wanted = T::parse_output(&write.value)?;
+            match read_defaults::<T>(runner, &write.domain, &write.key) {
+                Ok(current) => Ok(current.same_as(&wanted)),
+                Err(DefaultsError::KeyNotFound(_) | DefaultsError::ParseError(_)) => Ok(false),
+                Err(e) => Err(e),
+            }
+        }
+
+        match self.type_flag {
+            "-bool" => matches::<bool>(runner, self),
+            "-int" => matches::<i32>(runner, self),
+            "-float" => matches::<f64>(runner, self),
+            _ => matches::<String>(runner, self),
+        }
+    }
+}
+
+const PLIST_BUDDY: &str = "/usr/libexec/PlistBuddy";
+
+/// The PlistBuddy type name matching a `defaults` type flag, for adding a key
+/// that doesn't exist yet.
+fn plist_buddy_type(type_flag: &str) -> &str {
+    match type_flag {
+        "-bool" => "bool",
+        "-int" => "integer",
+        "-float" => "real",
+        _ => "string",
+    }
+}
+
+/// Like [`write_defaults`], but for a value nested inside dictionaries, which
+/// `defaults` can't address. `key_path` is a PlistBuddy entry path like
+/// `:DesktopViewSettings:IconViewSettings:arrangeBy`, and `plist` is the
+/// preferences file itself. The enclosing dictionaries have to exist already.
+pub(crate) fn write_plist_value<T>(
+    runner: &dyn CommandRunner,
+    plist: &Path,
+    key_path: &str,
+    new_value: T,
+) -> Result<Changes, DefaultsError>
+where
+    T: Display + DefaultsType + PartialEq,
+{
+    let plist = plist.to_str().ok_or_else(|| {
+        DefaultsError::CommandFailed(format!("path is not valid UTF-8: {}", plist.display()))
+    })?;
+    let plist_buddy = |command: String| {
+        runner
+            .output(PLIST_BUDDY, &["-c", &command, plist])
+            .map_err(|e| DefaultsError::CommandFailed(format!("Failed to execute PlistBuddy: {e}")))
+    };
+
+    let output = plist_buddy(format!("Print {key_path}"))?;
+    let exists = output.success();
+    if exists && T::parse_output(str::from_utf8(output.stdout.trim_ascii())?)? == new_value {
+        unchanged!("ℹ️  {plist}{key_path} already set to {new_value}");
+        return Ok(Changes::default());
+    }
+
+    let target = format!("{plist}{key_path}");
+    let command = if exists {
+        format!("Set {key_path} {new_value}")
+    } else {
+        format!(
+            "Add {key_path} {} {new_value}",
+            plist_buddy_type(T::TYPE_FLAG)
+        )
+    };
+    let output = plist_buddy(command)?;
+    if !output.success() {
+        event!("write", &target, failed, "❌ Failed to set {target}");
+        return Err(DefaultsError::CommandFailed(format!(
+            "PlistBuddy failed to set {key_path} in {plist}: {}",
+            String::from_utf8_lossy(&output.stdout).trim()
+        )));
+    }
+    event!("write", &target, ok, "🔧 Set {target} = {new_value}");
+    let mut changes = Changes::default();
+    changes.default_changed(plist, key_path, &new_value.to_string());
+
+    Ok(changes)
+}
+
+/// The paths of the apps kept in the Dock, in order. The Dock stores each one
+/// as a dictionary under `persistent-apps`, which `defaults read` can only
+/// print in its own format, so the domain is exported as XML and the path is
+/// taken from each tile's `tile-data.file-data._CFURLString`. The Dock writes
+/// that as a `file://` URL, but entries added with
+/// [`write_dock_persistent_apps`] are plain paths until the Dock rewrites them,
+/// so both are read back as paths. Tiles that aren't a file, like the
+/// `spacer-tile` and `small-spacer-tile` spacers, have no path and are skipped.
+pub(crate) fn read_dock_persistent_apps(
+    runner: &dyn CommandRunner,
+) -> Result<Vec<String>, DefaultsError> {
+    let output = runner
+        .output("defaults", &["export", "com.apple.dock", "-"])
         .map_err(|e| {
-            DefaultsError::CommandFailed(format!("Failed to execute defaults write: {}", e))
+            DefaultsError::CommandFailed(format!("Failed to execute defaults export: {e}"))
         })?;
-
-    if !status.success() {
+    if !output.success() {
         return Err(DefaultsError::CommandFailed(format!(
-            "defaults write failed for {}.{}",
-            domain, key
+            "defaults export failed for com.apple.dock: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
         )));
     }
 
-    Ok(true)
+    let dock = Value::from_reader_xml(output.stdout.as_slice())
+        .map_err(|e| DefaultsError::ParseError(format!("com.apple.dock: {e}")))?;
+    let Some(tiles) = dock
+        .as_dictionary()
+        .and_then(|dock| dock.get("persistent-apps"))
+        .and_then(Value::as_array)
+    else {
+        return Err(DefaultsError::KeyNotFound(
+            "com.apple.dock.persistent-apps".to_string(),
+        ));
+    };
+
+    Ok(tiles
+        .iter()
+        .filter_map(|tile| {
+            tile.as_dictionary()
+                .and_then(|tile| tile.get("tile-data"))
+                .and_then(Value::as_dictionary)
+                .and_then(|data| data.get("file-data"))
+                .and_then(Value::as_dictionary)
+                .and_then(|file| file.get("_CFURLString"))
+                .and_then(Value::as_string)
+                .map(url_to_path)
+        })
+        .collect())
+}
+
+/// Turns a `file://` URL, like `file:///Applications/Visual%20Studio%20Code.app/`,
+/// into a path, like `/Applications/Visual Studio Code.app`. Anything else is
+/// assumed to be a path already.
+fn url_to_path(url: &str) -> String {
+    let Some(path) = url.strip_prefix("file://") else {
+        return url.trim_end_matches('/').to_string();
+    };
+
+    let bytes = path.trim_end_matches('/').as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Keeps `apps`, given as paths like `/Applications/Safari.app`, in the Dock,
+/// in order, followed by any other apps already there unless
+/// `clear_other_apps` is set. Like [`write_defaults`], returns what changed, so
+/// the Dock can be restarted.
+///
+/// `defaults write` can't replace one element of an array of dictionaries, so
+/// when the apps differ the array is cleared with `-array`, and each app is
+/// added back with `-array-add` as a tile dictionary in the XML plist syntax
+/// `defaults` accepts for values. The Dock fills in the rest of each tile the
+/// next time it starts. Spacers aren't apps, so they don't count as a
+/// difference, but they're gone once the apps are rewritten.
+pub(crate) fn write_dock_persistent_apps(
+    runner: &dyn CommandRunner,
+    apps: &[String],
+    clear_other_apps: bool,
+) -> Result<Changes, DefaultsError> {
+    let current = match read_dock_persistent_apps(runner) {
+        Ok(current) => current,
+        Err(DefaultsError::KeyNotFound(_)) => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    let apps = dock_apps_to_keep(&current, apps, clear_other_apps);
+    if current == apps {
+        unchanged!("ℹ️  com.apple.dock.persistent-apps already set to {apps:?}");
+        return Ok(Changes::default());
+    }
+
+    let target = "com.apple.dock.persistent-apps";
+    let write = |args: &[&str]| -> Result<(), DefaultsError> {
+        let output = runner
+            .output(
+                "defaults",
+                &[&["write", "com.apple.dock", "persistent-apps"], args].concat(),
+            )
+            .map_err(|e| {
+                DefaultsError::CommandFailed(format!("Failed to execute defaults write: {e}"))
+            })?;
+        if !output.success() {
+            return Err(DefaultsError::CommandFailed(format!(
+                "defaults write failed for com.apple.dock.persistent-apps: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    };
+
+    let written = write(&["-array"]).and_then(|()| {
+        apps.iter()
+            .try_for_each(|app| write(&["-array-add", &app_tile(app)]))
+    });
+    if let Err(e) = written {
+        event!("write", target, failed, "❌ Failed to set {target}");
+        return Err(e);
+    }
+    event!("write", target, ok, "🔧 Set {target} = {apps:?}");
+    let mut changes = Changes::default();
+    changes.default_changed("com.apple.dock", "persistent-apps", &apps.join(", "));
+
+    Ok(changes)
+}
+
+/// The apps that should be in the Dock: `apps`, in order, then unless
+/// `clear_other_apps` is set, the `current` ones that aren't among them.
+fn dock_apps_to_keep(current: &[String], apps: &[String], clear_other_apps: bool) -> Vec<String> {
+    let mut keep = apps.to_vec();
+    if !clear_other_apps {
+        keep.extend(current.iter().filter(|app| !apps.contains(app)).cloned());
+    }
+    keep
+}
+
+/// The `persistent-apps` tile dictionary for the app at `path`.
+fn app_tile(path: &str) -> String {
+    let path = path
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    format!(
+        "<dict><key>tile-data</key><dict><key>file-data</key><dict>\
+         <key>_CFURLString</key><string>{path}</string>\
+         <key>_CFURLStringType</key><integer>0</integer>\
+         </dict></dict></dict>"
+    )
+}
+
+/// A preferences domain as it was exported, with the batched writes applied,
+/// and the writes themselves.
+struct BatchedDomain {
+    values: Dictionary,
+    written: Dictionary,
+}
+
+/// A [`CommandRunner`] that batches `defaults` reads and writes, to cut the
+/// read and write per key down to a `defaults export` and `defaults import`
+/// per domain. Reads are answered from the exported domain, and writes are
+/// applied to it, until any other command runs or [`DefaultsBatch::flush`] is
+/// called, at which point every changed domain is imported. That keeps writes
+/// landing before the `killall` that follows them.
+///
+/// Importing replaces the whole domain, so each one is exported again just
+/// before it's imported, and only the batched writes are applied on top. That
+/// keeps what apps wrote to it in the meantime, but an app writing between
+/// that export and the import still loses its write, so apps that are running
+/// should be quit first.
+pub struct DefaultsBatch<'a> {
+    runner: &'a dyn CommandRunner,
+    domains: Mutex<HashMap<String, BatchedDomain>>,
+}
+
+impl<'a> DefaultsBatch<'a> {
+    pub fn new(runner: &'a dyn CommandRunner) -> Self {
+        DefaultsBatch {
+            runner,
+            domains: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn domains(&self) -> MutexGuard<'_, HashMap<String, BatchedDomain>> {
+        self.domains.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Imports every domain with batched writes.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut domains = self.domains();
+        for (domain, batched) in domains.iter_mut() {
+            if batched.written.is_empty() {
+                continue;
+            }
+            let mut values = self.export(domain)?;
+            for (key, value) in &batched.written {
+                values.insert(key.clone(), value.clone());
+            }
+
+            // The domain can be a path, so it's kept out of the file name.
+            let mut file = tempfile::Builder::new()
+                .prefix("omiros-")
+                .suffix(".plist")
+                .tempfile()?;
+            Value::Dictionary(values)
+                .to_writer_xml(file.as_file_mut())
+                .map_err(io::Error::other)?;
+            let path = file.path().to_string_lossy();
+            if !self
+                .runner
+                .output("defaults", &["import", domain, &path])?
+                .success()
+            {
+                return Err(io::Error::other(format!("defaults import {domain} failed")));
+            }
+            batched.written.clear();
+        }
+        domains.clear();
+
+        Ok(())
+    }
+
+    /// Exports `domain` with `defaults export`.
+    fn export(&self, domain: &str) -> io::Result<Dictionary> {
+        let output = self.runner.output("defaults", &["export", domain, "-"])?;
+        // A domain that has never been written to exports as nothing.
+        if !output.success() || output.stdout.trim_ascii().is_empty() {
+            return Ok(Dictionary::new());
+        }
+
+        Ok(Value::from_reader_xml(output.stdout.as_slice())
+            .map_err(io::Error::other)?
+            .into_dictionary()
+            .unwrap_or_default())
+    }
+
+    /// The exported `domain`, exporting it the first time it's used.
+    fn domain<'d>(
+        &self,
+        domains: &'d mut HashMap<String, BatchedDomain>,
+        domain: &str,
+    ) -> io::Result<&'d mut BatchedDomain> {
+        if !domains.contains_key(domain) {
+            let values = self.export(domain)?;
+            domains.insert(
+                domain.to_string(),
+                BatchedDomain {
+                    values,
+                    written: Dictionary::new(),
+                },
+            );
+        }
+
+        Ok(domains.get_mut(domain).unwrap())
+    }
+
+    /// Answers a `defaults read domain key` the way `defaults` prints it.
+    fn read(&self, domain: &str, key: &str) -> io::Result<CommandOutput> {
+        let mut domains = self.domains();
+        let printed = match self.domain(&mut domains, domain)?.values.get(key) {
+            Some(Value::Boolean(value)) => u8::from(*value).to_string(),
+            Some(Value::Integer(value)) => value.to_string(),
+            Some(Value::Real(value)) => value.to_string(),
+            Some(Value::String(value)) => value.clone(),
+            // Anything else isn't a type omiros writes, so it's read as not
+            // set.
+            _ => {
+                return Ok(CommandOutput::failed(
+                    1,
+                    &format!("The domain/default pair of ({domain}, {key}) does not exist"),
+                ));
+            }
+        };
+
+        Ok(CommandOutput::ok(&printed))
+    }
+
+    /// Applies a `defaults write domain key -type value` to the batch.
+    fn write(&self, domain: &str, key: &str, type_flag: &str, value: &str) -> io::Result<()> {
+        let invalid = || io::Error::other(format!("can't batch {type_flag} {value} for {key}"));
+        let value = match type_flag {
+            "-bool" => Value::Boolean(value == "true"),
+            "-int" => Value::Integer(value.parse::<i64>().map_err(|_| invalid())?.into()),
+            "-float" => Value::Real(value.parse().map_err(|_| invalid())?),
+            "-string" => Value::String(value.to_string()),
+            _ => return Err(invalid()),
+        };
+
+        let mut domains = self.domains();
+        let batched = self.domain(&mut domains, domain)?;
+        batched.values.insert(key.to_string(), value.clone());
+        batched.written.insert(key.to_string(), value);
+
+        Ok(())
+    }
+}
+
+impl CommandRunner for DefaultsBatch<'_> {
+    fn output(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        match (program, args) {
+            ("defaults", ["read", domain, key]) => self.read(domain, key),
+            // Arrays, and the dictionaries in them, aren't batched.
+            ("defaults", ["write", domain, key, type_flag, value])
+                if !type_flag.starts_with("-array") =>
+            {
+                self.write(domain, key, type_flag, value)?;
+                Ok(CommandOutput::ok(""))
+            }
+            _ => {
+                self.flush()?;
+                self.runner.output(program, args)
+            }
+        }
+    }
+
+    fn status(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        self.flush()?;
+        self.runner.status(program, args)
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -178,9 +872,382 @@ pub enum DefaultsError {
     /// `default` command failed.
     #[error("Defaults command failed {0}")]
     CommandFailed(String),
+    /// `defaults read` found no value for the key.
+    #[error("Defaults key not found: {0}")]
+    KeyNotFound(String),
     #[error("Defaults output parsing failed {0}")]
     ParseError(String),
+    /// A configured value is outside of the range macOS accepts.
+    #[error("Invalid configuration value: {0}")]
+    InvalidValue(String),
+    /// Writing a system-wide setting needs `sudo`, which would have prompted
+    /// for a password.
+    #[error("{0} needs sudo, run `sudo -v` before omiros to cache your password")]
+    SudoRequired(String),
     /// Error when converting a &[u8] to a utf-8 &str
     #[error("UTF-8 error: {0}")]
     Utf8Error(#[from] core::str::Utf8Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, MockRunner};
+    use rstest::rstest;
+
+    #[test]
+    fn defaults_batch_exports_and_imports_each_domain_once() {
+        let export = r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0"><dict>
+    <key>autohide</key><false/>
+    <key>tilesize</key><integer>48</integer>
+</dict></plist>"#;
+        let runner = MockRunner::new().stub(
+            &["defaults", "export", "com.apple.dock", "-"],
+            CommandOutput::ok(export),
+        );
+        let batch = DefaultsBatch::new(&runner);
+
+        assert!(
+            write_defaults(&batch, "com.apple.dock", "autohide", true)
+                .unwrap()
+                .has_changes()
+        );
+        assert!(
+            !write_defaults(&batch, "com.apple.dock", "tilesize", 48)
+                .unwrap()
+                .has_changes()
+        );
+        assert_eq!(1, runner.calls().len());
+
+        // Anything else flushes the batch first, so a restart sees the writes.
+        batch.output("killall", &["Dock"]).unwrap();
+        let calls = runner.calls();
+        assert_eq!(4, calls.len());
+        assert_eq!(["defaults", "export", "com.apple.dock", "-"], calls[1][..]);
+        assert_eq!(["defaults", "import", "com.apple.dock"], calls[2][..3]);
+        assert_eq!(["killall", "Dock"], calls[3][..]);
+    }
+
+    /// Answers each `defaults export` with the next of `exports`, and keeps
+    /// what each `defaults import` was given.
+    struct ImportingRunner {
+        exports: Mutex<Vec<&'static str>>,
+        imported: Mutex<Vec<(String, Dictionary)>>,
+    }
+
+    impl CommandRunner for ImportingRunner {
+        fn output(&self, _program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            match args {
+                ["export", ..] => Ok(CommandOutput::ok(self.exports.lock().unwrap().remove(0))),
+                ["import", domain, path] => {
+                    let values = Value::from_file(path).unwrap().into_dictionary().unwrap();
+                    self.imported
+                        .lock()
+                        .unwrap()
+                        .push((domain.to_string(), values));
+                    Ok(CommandOutput::ok(""))
+                }
+                _ => Ok(CommandOutput::ok("")),
+            }
+        }
+
+        fn status(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+            self.output(program, args)
+        }
+    }
+
+    #[test]
+    fn defaults_batch_keeps_what_was_written_to_a_domain_in_the_meantime() {
+        let runner = ImportingRunner {
+            exports: Mutex::new(vec![
+                r#"<plist version="1.0"><dict>
+                    <key>autohide</key><false/>
+                </dict></plist>"#,
+                r#"<plist version="1.0"><dict>
+                    <key>autohide</key><false/>
+                    <key>recent</key><string>Safari</string>
+                </dict></plist>"#,
+            ]),
+            imported: Mutex::new(Vec::new()),
+        };
+        // A domain can be a path, which has to stay out of the temp file name.
+        let domain = "~/Library/Preferences/com.apple.dock.plist";
+        let batch = DefaultsBatch::new(&runner);
+
+        assert!(
+            write_defaults(&batch, domain, "autohide", true)
+                .unwrap()
+                .has_changes()
+        );
+        batch.flush().unwrap();
+
+        let imported = runner.imported.into_inner().unwrap();
+        assert_eq!(1, imported.len());
+        assert_eq!(domain, imported[0].0);
+        assert_eq!(Some(&Value::Boolean(true)), imported[0].1.get("autohide"));
+        assert_eq!(
+            Some(&Value::String("Safari".to_string())),
+            imported[0].1.get("recent")
+        );
+    }
+
+    #[test]
+    fn defaults_write_is_current_compares_by_type() {
+        let runner = MockRunner::new()
+            .stub(
+                &["defaults", "read", "com.apple.dock", "autohide"],
+                CommandOutput::ok("1\n"),
+            )
+            .stub(
+                &["defaults", "read", "com.apple.dock", "orientation"],
+                CommandOutput::ok("bottom\n"),
+            )
+            .stub(
+                &["defaults", "read", "com.apple.dock", "tilesize"],
+                CommandOutput::failed(1, "does not exist"),
+            );
+
+        let autohide = DefaultsWrite::new("autohide", "com.apple.dock", "autohide", Some(true));
+        assert!(autohide.unwrap().is_current(&runner).unwrap());
+        let orientation = DefaultsWrite::new(
+            "orientation",
+            "com.apple.dock",
+            "orientation",
+            Some(DockOrientation::Left),
+        );
+        assert!(!orientation.unwrap().is_current(&runner).unwrap());
+        let icon_size = DefaultsWrite::new("icon-size", "com.apple.dock", "tilesize", Some(48));
+        assert!(!icon_size.unwrap().is_current(&runner).unwrap());
+    }
+
+    #[test]
+    fn f64_parses_defaults_output_and_compares_with_tolerance() {
+        assert_eq!(0.5, f64::parse_output("0.5").unwrap());
+        assert_eq!(0.5, f64::parse_output("0.50").unwrap());
+        assert_eq!(0.5, f64::parse_output("0,5").unwrap());
+        assert_eq!(1.0, f64::parse_output("1").unwrap());
+        assert!(f64::parse_output("fast").is_err());
+
+        // 0.15 as stored in single precision.
+        assert!(0.15.same_as(&0.15000000596046448));
+        assert!(!0.15.same_as(&0.2));
+    }
+
+    #[test]
+    fn write_defaults_writes_missing_keys_but_fails_on_read_errors() {
+        let read = ["defaults", "read", "com.apple.dock", "autohide"];
+
+        let missing = "The domain/default pair of (com.apple.dock, autohide) does not exist";
+        let runner = MockRunner::new().stub(&read, CommandOutput::failed(1, missing));
+        assert!(
+            write_defaults(&runner, "com.apple.dock", "autohide", true)
+                .unwrap()
+                .has_changes()
+        );
+        assert_eq!(
+            [
+                "defaults",
+                "write",
+                "com.apple.dock",
+                "autohide",
+                "-bool",
+                "true"
+            ],
+            runner.calls()[1][..]
+        );
+
+        let runner = MockRunner::new().stub(&read, CommandOutput::failed(1, "Killed: 9"));
+        assert!(matches!(
+            write_defaults(&runner, "com.apple.dock", "autohide", true),
+            Err(DefaultsError::CommandFailed(_))
+        ));
+        assert_eq!(1, runner.calls().len());
+    }
+
+    #[test]
+    fn write_plist_value_adds_missing_keys_and_sets_existing_ones() {
+        let plist = Path::new("/Users/me/Library/Preferences/com.apple.finder.plist");
+        let key_path = ":DesktopViewSettings:IconViewSettings:arrangeBy";
+        let print = [
+            "/usr/libexec/PlistBuddy",
+            "-c",
+            "Print :DesktopViewSettings:IconViewSettings:arrangeBy",
+            plist.to_str().unwrap(),
+        ];
+
+        let runner = MockRunner::new().stub(&print, CommandOutput::failed(1, "Does Not Exist"));
+        assert!(
+            write_plist_value(&runner, plist, key_path, DesktopArrangement::Kind)
+                .unwrap()
+                .has_changes()
+        );
+        assert_eq!(
+            "Add :DesktopViewSettings:IconViewSettings:arrangeBy string kind",
+            runner.calls()[1][2]
+        );
+
+        let runner = MockRunner::new().stub(&print, CommandOutput::ok("grid\n"));
+        assert!(
+            write_plist_value(&runner, plist, key_path, DesktopArrangement::Kind)
+                .unwrap()
+                .has_changes()
+        );
+        assert_eq!(
+            "Set :DesktopViewSettings:IconViewSettings:arrangeBy kind",
+            runner.calls()[1][2]
+        );
+
+        let runner = MockRunner::new().stub(&print, CommandOutput::ok("kind\n"));
+        assert!(
+            !write_plist_value(&runner, plist, key_path, DesktopArrangement::Kind)
+                .unwrap()
+                .has_changes()
+        );
+        assert_eq!(1, runner.calls().len());
+    }
+
+    #[test]
+    fn dock_persistent_apps_are_only_rewritten_when_they_differ() {
+        let export = r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0"><dict>
+    <key>persistent-apps</key><array>
+        <dict><key>tile-data</key><dict><key>file-data</key><dict>
+            <key>_CFURLString</key><string>file:///Applications/Visual%20Studio%20Code.app/</string>
+            <key>_CFURLStringType</key><integer>15</integer>
+        </dict></dict><key>tile-type</key><string>file-tile</string></dict>
+        <dict><key>tile-data</key><dict><key>file-data</key><dict>
+            <key>_CFURLString</key><string>/Applications/Safari.app</string>
+            <key>_CFURLStringType</key><integer>0</integer>
+        </dict></dict></dict>
+    </array>
+</dict></plist>"#;
+        let runner = MockRunner::new().stub(
+            &["defaults", "export", "com.apple.dock", "-"],
+            CommandOutput::ok(export),
+        );
+        let current = vec![
+            "/Applications/Visual Studio Code.app".to_string(),
+            "/Applications/Safari.app".to_string(),
+        ];
+
+        assert_eq!(current, read_dock_persistent_apps(&runner).unwrap());
+        assert!(
+            !write_dock_persistent_apps(&runner, &current, true)
+                .unwrap()
+                .has_changes()
+        );
+        assert_eq!(2, runner.calls().len());
+
+        let wanted = vec!["/Applications/Ghostty.app".to_string()];
+        assert!(
+            write_dock_persistent_apps(&runner, &wanted, true)
+                .unwrap()
+                .has_changes()
+        );
+        let calls = runner.calls();
+        assert_eq!(
+            [
+                "defaults",
+                "write",
+                "com.apple.dock",
+                "persistent-apps",
+                "-array"
+            ],
+            calls[3][..]
+        );
+        assert_eq!("-array-add", calls[4][4]);
+        assert!(calls[4][5].contains("<string>/Applications/Ghostty.app</string>"));
+    }
+
+    #[rstest]
+    #[case::same(&["/A.app", "/B.app"], &["/A.app", "/B.app"], true, &["/A.app", "/B.app"])]
+    #[case::reordered(&["/A.app", "/B.app"], &["/B.app", "/A.app"], true, &["/B.app", "/A.app"])]
+    #[case::clears_others(&["/A.app", "/C.app", "/B.app"], &["/A.app", "/B.app"], true, &["/A.app", "/B.app"])]
+    #[case::keeps_others_after(
+        &["/C.app", "/A.app", "/D.app"],
+        &["/A.app", "/B.app"],
+        false,
+        &["/A.app", "/B.app", "/C.app", "/D.app"]
+    )]
+    #[case::empty_dock(&[], &["/A.app"], false, &["/A.app"])]
+    fn dock_apps_to_keep_puts_declared_apps_first(
+        #[case] current: &[&str],
+        #[case] apps: &[&str],
+        #[case] clear_other_apps: bool,
+        #[case] expected: &[&str],
+    ) {
+        let strings =
+            |apps: &[&str]| -> Vec<String> { apps.iter().map(|a| a.to_string()).collect() };
+
+        let keep = dock_apps_to_keep(&strings(current), &strings(apps), clear_other_apps);
+
+        assert_eq!(strings(expected), keep);
+    }
+
+    #[test]
+    fn dock_apps_already_in_place_are_not_rewritten_when_keeping_others() {
+        let export = r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0"><dict>
+    <key>persistent-apps</key><array>
+        <dict><key>tile-data</key><dict><key>file-data</key><dict>
+            <key>_CFURLString</key><string>file:///Applications/Safari.app/</string>
+        </dict></dict></dict>
+        <dict><key>tile-data</key><dict><key>file-data</key><dict>
+            <key>_CFURLString</key><string>file:///System/Applications/Mail.app/</string>
+        </dict></dict></dict>
+    </array>
+</dict></plist>"#;
+        let runner = MockRunner::new().stub(
+            &["defaults", "export", "com.apple.dock", "-"],
+            CommandOutput::ok(export),
+        );
+        let safari = vec!["/Applications/Safari.app".to_string()];
+
+        assert!(
+            !write_dock_persistent_apps(&runner, &safari, false)
+                .unwrap()
+                .has_changes()
+        );
+        assert!(
+            write_dock_persistent_apps(&runner, &safari, true)
+                .unwrap()
+                .has_changes()
+        );
+    }
+
+    #[test]
+    fn dock_spacer_tiles_are_skipped_when_reading_the_apps() {
+        let export = r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0"><dict>
+    <key>persistent-apps</key><array>
+        <dict><key>tile-data</key><dict><key>file-data</key><dict>
+            <key>_CFURLString</key><string>file:///Applications/Safari.app/</string>
+        </dict></dict></dict>
+        <dict><key>tile-data</key><dict/><key>tile-type</key><string>spacer-tile</string></dict>
+        <dict><key>tile-data</key><dict/><key>tile-type</key><string>small-spacer-tile</string></dict>
+        <dict><key>tile-data</key><dict><key>file-data</key><dict>
+            <key>_CFURLString</key><string>file:///System/Applications/Mail.app/</string>
+        </dict></dict></dict>
+    </array>
+</dict></plist>"#;
+        let runner = MockRunner::new().stub(
+            &["defaults", "export", "com.apple.dock", "-"],
+            CommandOutput::ok(export),
+        );
+
+        assert_eq!(
+            vec!["/Applications/Safari.app", "/System/Applications/Mail.app"],
+            read_dock_persistent_apps(&runner).unwrap()
+        );
+        let apps = vec![
+            "/Applications/Safari.app".to_string(),
+            "/System/Applications/Mail.app".to_string(),
+        ];
+        assert!(
+            !write_dock_persistent_apps(&runner, &apps, true)
+                .unwrap()
+                .has_changes()
+        );
+    }
+}
@@ -0,0 +1,213 @@
+//! Checks the environment for common broken states, and optionally repairs the
+//! ones that can be repaired safely.
+
+use std::{collections::HashMap, path::Path};
+
+use log::{info, warn};
+
+use crate::{
+    brew::BREW_KNOWN_PATHS,
+    dotfiles::{
+        CopyState, DeployedCopies, DotfileLink, DotfileMethod, Dotfiles, LinkState, copy_contents,
+        copy_state, link_state, relink,
+    },
+    errors::SetupError,
+    mas::get_signed_in_account,
+    runner::CommandRunner,
+    system_utils::command,
+};
+
+/// Runs every check, attempting a conservative fix for each problem found when
+/// `fix` is set. Dotfiles are only checked when both the configuration and the
+/// dotfiles directory are given. Returns the number of problems that are still
+/// outstanding.
+pub fn run_doctor(
+    runner: &dyn CommandRunner,
+    dotfiles: Option<(&Dotfiles, &Path)>,
+    fix: bool,
+) -> Result<usize, SetupError> {
+    info!("🩺 Checking environment...");
+
+    let mut problems = 0;
+    problems += check_brew(runner, fix);
+    problems += check_mas(runner)?;
+    if let Some((dotfiles, dotfiles_dir)) = dotfiles {
+        problems += check_dotfiles(runner, dotfiles, dotfiles_dir, fix)?;
+    }
+
+    if problems == 0 {
+        info!("✅ No problems found");
+    } else {
+        warn!("❌ {problems} problem(s) found");
+    }
+
+    Ok(problems)
+}
+
+/// Checks that `brew` is on the PATH. If it's installed but not on the PATH
+/// the fix is to print the `shellenv` line the user needs to add to their
+/// shell profile, we can't safely edit that for them.
+fn check_brew(runner: &dyn CommandRunner, fix: bool) -> usize {
+    if command(runner, "brew").is_ok() {
+        return 0;
+    }
+
+    match BREW_KNOWN_PATHS
+        .iter()
+        .find(|path| Path::new(path).exists())
+    {
+        Some(brew) => {
+            warn!("❌ brew is installed at {brew} but is not on your PATH");
+            if fix {
+                info!("🔧 Add the following line to your shell profile (e.g. ~/.zprofile):");
+                info!("    eval \"$({brew} shellenv)\"");
+            }
+        }
+        None => warn!("❌ brew is not installed, see https://brew.sh"),
+    }
+
+    1
+}
+
+/// Reports who is signed in to the Mac App Store, since `mas install` fails
+/// without an account. Newer versions of macOS don't let `mas account` see who's
+/// signed in, so not finding anyone is only a warning, never a problem, the
+/// same as in [`crate::mas::check_apple_id`].
+fn check_mas(runner: &dyn CommandRunner) -> Result<usize, SetupError> {
+    if command(runner, "mas").is_err() {
+        info!("ℹ️  mas is not installed, skipping App Store checks");
+        return Ok(0);
+    }
+
+    match get_signed_in_account(runner)? {
+        Some(account) => info!("✅ Signed in to the App Store as {account}"),
+        None => warn!(
+            "⚠️  Could not determine the App Store account, make sure you're signed in before \
+             installing apps"
+        ),
+    }
+
+    Ok(0)
+}
+
+/// Checks every configured dotfile link. Broken symlinks are the only thing
+/// fixed automatically, everything else is left for `omiros run` or the user.
+pub(crate) fn check_dotfiles(
+    runner: &dyn CommandRunner,
+    dotfiles: &Dotfiles,
+    dotfiles_dir: &Path,
+    fix: bool,
+) -> Result<usize, SetupError> {
+    let mut problems = 0;
+
+    let links = dotfiles.links(dotfiles_dir)?;
+    let vars = if links.iter().any(|l| l.method == DotfileMethod::Template) {
+        dotfiles.template_vars(runner)?
+    } else {
+        HashMap::new()
+    };
+    let copies = if links.iter().any(|l| l.method.is_copy()) {
+        DeployedCopies::load(&DeployedCopies::path()?)?
+    } else {
+        DeployedCopies::default()
+    };
+
+    for link in links {
+        if !link.original.exists() {
+            warn!("❌ Original dotfile not found: {}", link.original.display());
+            problems += 1;
+            continue;
+        }
+
+        if link.method.is_copy() {
+            problems += check_copied_dotfile(runner, &link, &vars, &copies)?;
+            continue;
+        }
+
+        match link_state(&link.original, &link.link)? {
+            LinkState::Correct => (),
+            LinkState::Broken if fix => {
+                relink(&link.original, &link.link)?;
+                info!(
+                    "🔧 Re-linked broken symlink {} -> {}",
+                    link.link.display(),
+                    link.original.display()
+                );
+            }
+            LinkState::Broken => {
+                warn!("❌ Broken symlink: {}", link.link.display());
+                problems += 1;
+            }
+            LinkState::WrongTarget(target) => {
+                warn!(
+                    "❌ {} points to {} instead of {}",
+                    link.link.display(),
+                    target.display(),
+                    link.original.display()
+                );
+                problems += 1;
+            }
+            LinkState::Missing => {
+                warn!("❌ Not linked yet: {}", link.link.display());
+                problems += 1;
+            }
+            LinkState::Conflict => {
+                warn!(
+                    "❌ {} exists as a regular file/directory, back it up and remove it",
+                    link.link.display()
+                );
+                problems += 1;
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Checks a copied dotfile. Copies aren't fixed automatically, since that
+/// could throw away edits made to the copy.
+fn check_copied_dotfile(
+    runner: &dyn CommandRunner,
+    dotfile: &DotfileLink,
+    vars: &HashMap<String, String>,
+    copies: &DeployedCopies,
+) -> Result<usize, SetupError> {
+    let contents = copy_contents(runner, dotfile, vars)?;
+    let problem = match copy_state(&contents, &dotfile.link, copies.get(&dotfile.link))? {
+        CopyState::UpToDate => return Ok(0),
+        CopyState::Missing => "has not been copied yet",
+        CopyState::Outdated => "is out of date with the original",
+        CopyState::Drifted => "has drifted from the original",
+        CopyState::Symlink => "is a symlink instead of a copy",
+        CopyState::Conflict => "is a directory",
+    };
+    warn!("❌ {} {problem}", dotfile.link.display());
+
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, MockRunner};
+
+    #[test]
+    fn check_mas_only_warns_when_the_account_is_unknown() {
+        let runner = MockRunner::new().stub(
+            &["mas", "account"],
+            CommandOutput::failed(1, "Not supported on this version of macOS"),
+        );
+
+        assert_eq!(0, check_mas(&runner).unwrap());
+        assert!(!runner.calls().iter().any(|call| call[0] == "open"));
+    }
+
+    #[test]
+    fn check_mas_passes_when_signed_in() {
+        let runner =
+            MockRunner::new().stub(&["mas", "account"], CommandOutput::ok("me@example.com\n"));
+
+        assert_eq!(0, check_mas(&runner).unwrap());
+        assert!(!runner.calls().iter().any(|call| call[0] == "open"));
+    }
+}
@@ -1,30 +1,124 @@
 use std::{
-    env::home_dir,
-    fs,
+    collections::{BTreeMap, HashMap},
+    env::{self, home_dir},
+    fs, io,
+    os::unix::fs::PermissionsExt,
     path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use serde::Deserialize;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::errors::SetupError;
+use crate::{
+    errors::SetupError,
+    git::GitConfig,
+    output::{event, unchanged},
+    report::Changes,
+    runner::CommandRunner,
+    system::Section,
+    system_utils::{Retry, command, hostname},
+    template,
+    validate::{ConfigProblem, warn_duplicates},
+};
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
 pub struct Dotfiles {
     files: Vec<DotfileEntry>,
+    /// Overwrite copied dotfiles that have been edited in place since omiros
+    /// last copied them, rather than skipping them with a warning.
+    #[serde(default)]
+    force: bool,
+    /// What to do with symlinks that point to the wrong place, or nowhere.
+    #[serde(default)]
+    reconcile_mode: ReconcileMode,
+    /// Values for `{{ name }}` variables in templated dotfiles, on top of the
+    /// built-in `hostname`, `user`, and `home`.
+    vars: Option<BTreeMap<String, String>>,
+    /// The repository to clone the dotfiles directory from, and pull updates
+    /// from.
+    git: Option<GitConfig>,
+    when: Option<String>,
+}
+
+/// What to do with an existing symlink that's in the way of a dotfile.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReconcileMode {
+    /// Remove wrong and broken symlinks, and link the dotfile in their place.
+    #[default]
+    Replace,
+    /// Report wrong and broken symlinks, but leave them, and the dotfile
+    /// unlinked, for a human to sort out.
+    ReportOnly,
+}
+
+impl Section for Dotfiles {
+    fn when(&self) -> Option<&str> {
+        self.when.as_deref()
+    }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 #[serde(untagged)]
 enum DotfileEntry {
     Implicit(PathBuf),
-    Explicit { original: PathBuf, link: PathBuf },
+    Explicit {
+        original: PathBuf,
+        link: PathBuf,
+        #[serde(default)]
+        method: DotfileMethod,
+        /// Octal permissions for a copied dotfile, like `"0755"` for scripts.
+        mode: Option<String>,
+        /// Treat `original` as a directory, and put each file in it in place
+        /// under `link` individually, rather than linking the directory
+        /// itself. Files that are only in `link` are left alone, so the two
+        /// directories merge.
+        #[serde(default)]
+        recursive: bool,
+    },
+}
+
+/// Parses an octal permission string like `"0755"` or `"644"`.
+fn parse_mode(mode: &str) -> Result<u32, SetupError> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| {
+            SetupError::DotfileError(format!("`{mode}` is not an octal file mode, like \"0755\""))
+        })
+}
+
+/// How a dotfile is put in place.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DotfileMethod {
+    /// Symlink to the original, so edits on either side are shared.
+    #[default]
+    Symlink,
+    /// Copy the original, for tools that don't follow symlinks. Copies that
+    /// are edited in place are detected as drifted, see [`DeployedCopies`].
+    Copy,
+    /// Copy the original with its `{{ name }}` variables filled in, for
+    /// dotfiles that differ a little between machines.
+    Template,
+}
+
+impl DotfileMethod {
+    /// Whether the dotfile is put in place as a file of its own, rather than a
+    /// symlink.
+    pub fn is_copy(self) -> bool {
+        matches!(self, DotfileMethod::Copy | DotfileMethod::Template)
+    }
 }
 
 /// Takes a path, if it stats with `~/`, expand the home path by prepending the
 /// home path and removing the tilde. Effectively expanding the tilde path to
 /// home. This is usually done by the shell, but here we have to do it by hand
 /// because there is no shell to do the expansion.
-fn tilde_expand_path(path: &Path, home: &Path) -> Result<PathBuf, SetupError> {
+pub(crate) fn tilde_expand_path(path: &Path, home: &Path) -> Result<PathBuf, SetupError> {
     let expanded = if path.starts_with("~/") {
         path.components()
             .enumerate()
@@ -43,115 +137,1395 @@ fn tilde_expand_path(path: &Path, home: &Path) -> Result<PathBuf, SetupError> {
     Ok(expanded)
 }
 
-/// Sets up the dotfiles by creating symlinks from the specified dotfiles
-/// directory to the home directory.
-pub fn setup_dotfiles(dotfiles: &Dotfiles, dotfiles_dir: &Path) -> Result<(), SetupError> {
-    println!("🔗 Setting up dotfiles...");
-
-    if !dotfiles_dir.exists() {
-        return Err(SetupError::DotfileError(format!(
-            "Dotfiles directory not found: {}",
-            dotfiles_dir.display()
-        )));
+/// Resolves the `original` of an explicit dotfile entry: relative paths are in
+/// the dotfiles directory, while absolute and `~/` paths are used as they are,
+/// for dotfiles kept somewhere else.
+fn resolve_original(
+    original: &Path,
+    dotfiles_dir: &Path,
+    home: &Path,
+) -> Result<PathBuf, SetupError> {
+    let original = tilde_expand_path(original, home)?;
+    if original.is_absolute() {
+        Ok(original)
+    } else {
+        Ok(dotfiles_dir.join(original))
     }
+}
 
-    let home = home_dir().ok_or_else(|| {
-        SetupError::DotfileError("Could not determine home directory.".to_string())
-    })?;
+/// A single resolved dotfile: the original file, usually in the dotfiles
+/// directory, and where it should be linked to.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DotfileLink {
+    /// The file to link to, or copy.
+    pub original: PathBuf,
+    /// Where the symlink to, or copy of, `original` should live.
+    pub link: PathBuf,
+    pub method: DotfileMethod,
+    /// The permissions to give a copy, instead of the original's.
+    pub mode: Option<u32>,
+}
 
-    for entry in &dotfiles.files {
-        let (original, link) = match entry {
-            DotfileEntry::Implicit(path_buf) => {
-                let original = dotfiles_dir.join(path_buf);
-                let link = home.join(path_buf);
-                (original, link)
-            }
-            DotfileEntry::Explicit { original, link } => {
-                let original = dotfiles_dir.join(original);
-                let link = tilde_expand_path(link, &home)?;
-                (original, link)
-            }
-        };
+/// What is currently at a dotfile's link location.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LinkState {
+    /// Nothing exists at the link location.
+    Missing,
+    /// A symlink pointing at the original.
+    Correct,
+    /// A symlink pointing at some other existing file.
+    WrongTarget(PathBuf),
+    /// A symlink whose target doesn't exist, or can't be read.
+    Broken,
+    /// A regular file or directory.
+    Conflict,
+}
+
+impl Dotfiles {
+    /// The repository the dotfiles directory is cloned from, if any.
+    pub fn git(&self) -> Option<&GitConfig> {
+        self.git.as_ref()
+    }
 
-        // Verify original file exists
-        if !original.exists() {
+    /// Resolves every configured entry into the absolute paths of the original
+    /// file and its link location.
+    pub fn links(&self, dotfiles_dir: &Path) -> Result<Vec<DotfileLink>, SetupError> {
+        if !dotfiles_dir.exists() {
             return Err(SetupError::DotfileError(format!(
-                "Original dotfile not found: {}",
-                original.display()
+                "Dotfiles directory not found: {}",
+                dotfiles_dir.display()
             )));
         }
 
-        // Create parent directory if it doesn't exist
-        if let Some(link_parent) = link.parent()
-            && !link_parent.exists()
-        {
-            fs::create_dir_all(link_parent)?;
-            println!("📁 Created directory: {}", link_parent.display());
-        }
+        let home = home_dir().ok_or_else(|| {
+            SetupError::DotfileError("Could not determine home directory.".to_string())
+        })?;
 
-        // Check what exists at the link location.
-        match fs::symlink_metadata(&link) {
-            Ok(metadata) => {
-                if metadata.is_symlink() {
-                    // It's a symlink, check if it points to the correct location
-                    match fs::read_link(&link) {
-                        Ok(link_target) if link_target == original => {
-                            println!("✅ {} already correctly linked", link.display());
-                            continue;
-                        }
-                        Ok(_) => {
-                            // It's a symlink, but it points to the wrong place
-                            fs::remove_file(&link)?;
-                            println!("🔄 Removed incorrect symlink: {}", link.display());
-                        }
-                        Err(_) => {
-                            // It's a broken symlink
-                            fs::remove_file(&link)?;
-                            println!("🗑️  Removed broken symlink: {}", link.display());
+        let mut links = Vec::new();
+        for entry in &self.files {
+            match entry {
+                DotfileEntry::Implicit(path_buf) => links.push(DotfileLink {
+                    original: dotfiles_dir.join(path_buf),
+                    link: home.join(path_buf),
+                    method: DotfileMethod::Symlink,
+                    mode: None,
+                }),
+                DotfileEntry::Explicit {
+                    original,
+                    link,
+                    method,
+                    mode,
+                    recursive,
+                } => {
+                    // A symlink has the permissions of whatever it points to.
+                    if mode.is_some() && !method.is_copy() {
+                        return Err(SetupError::DotfileError(format!(
+                            "`mode` is set for {}, but only applies to copied dotfiles",
+                            original.display()
+                        )));
+                    }
+
+                    let original = resolve_original(original, dotfiles_dir, &home)?;
+                    let link = tilde_expand_path(link, &home)?;
+                    let mode = mode.as_deref().map(parse_mode).transpose()?;
+                    if *recursive {
+                        for (original, link) in mirrored_files(&original, &link)? {
+                            links.push(DotfileLink {
+                                original,
+                                link,
+                                method: *method,
+                                mode,
+                            });
                         }
+                    } else {
+                        links.push(DotfileLink {
+                            original,
+                            link,
+                            method: *method,
+                            mode,
+                        });
                     }
-                } else {
-                    // It's a regular file or directory - error out and have the user
-                    // manually remove it.
-                    return Err(SetupError::DotfileError(format!(
-                        "Link path already exists as a file/directory:{}\n\
-                            Please manually backup and remove this file before running omiros again.",
-                        link.display()
-                    )));
                 }
             }
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                // The link does not exist, which is what we want.
-            }
+        }
+
+        Ok(links)
+    }
+
+    /// The variables available to templated dotfiles: the configured `vars`,
+    /// and the built-in `hostname`, `user`, and `home`, which can't be
+    /// overridden.
+    pub fn template_vars(
+        &self,
+        runner: &dyn CommandRunner,
+    ) -> Result<HashMap<String, String>, SetupError> {
+        let mut vars: HashMap<_, _> = self.vars.clone().unwrap_or_default().into_iter().collect();
+
+        let hostname = hostname(runner)?
+            .ok_or_else(|| SetupError::DotfileError("Could not determine hostname.".to_string()))?;
+        vars.insert("hostname".to_string(), hostname);
+        let user = env::var("USER")
+            .map_err(|_| SetupError::DotfileError("Could not determine user.".to_string()))?;
+        vars.insert("user".to_string(), user);
+        let home = home_dir().ok_or_else(|| {
+            SetupError::DotfileError("Could not determine home directory.".to_string())
+        })?;
+        vars.insert("home".to_string(), home.to_string_lossy().into_owned());
+
+        Ok(vars)
+    }
+
+    /// Checks that every original file exists, and that no two entries link to
+    /// the same place. Nothing can be checked before `[dotfiles.git]` has
+    /// cloned the dotfiles directory.
+    pub(crate) fn validate(&self, dotfiles_dir: &Path, problems: &mut Vec<ConfigProblem>) {
+        if self.git.is_some() && !dotfiles_dir.exists() {
+            return;
+        }
+
+        let links = match self.links(dotfiles_dir) {
+            Ok(links) => links,
             Err(e) => {
-                // Some other error, bubble it up.
-                return Err(SetupError::IoError(e));
+                problems.push(ConfigProblem::error("dotfiles", e.to_string()));
+                return;
+            }
+        };
+
+        for link in &links {
+            if !link.original.exists() {
+                problems.push(ConfigProblem::error(
+                    "dotfiles.files",
+                    format!("original dotfile not found: {}", link.original.display()),
+                ));
             }
         }
 
-        // Create symlink
-        std::os::unix::fs::symlink(&original, &link)?;
-        println!("🔗 Linked {} -> {}", link.display(), original.display());
+        let link_paths: Vec<_> = links.iter().map(|l| l.link.to_string_lossy()).collect();
+        warn_duplicates(
+            "dotfiles.files",
+            link_paths.iter().map(|l| l.as_ref()),
+            problems,
+        );
+    }
+}
+
+/// Every file under the `original` directory, paired with the same relative
+/// path under `link`, sorted so dotfiles are set up in a stable order.
+/// Symlinks in `original` are treated as files, and not followed. A missing
+/// `original` is returned as is, to be reported as not found like any other.
+fn mirrored_files(original: &Path, link: &Path) -> Result<Vec<(PathBuf, PathBuf)>, SetupError> {
+    if !original.exists() {
+        return Ok(vec![(original.to_path_buf(), link.to_path_buf())]);
+    }
+    if !original.is_dir() {
+        return Err(SetupError::DotfileError(format!(
+            "{} is marked `recursive`, but isn't a directory",
+            original.display()
+        )));
+    }
+
+    let mut files = Vec::new();
+    let mut entries: Vec<_> = fs::read_dir(original)?.collect::<Result<_, _>>()?;
+    entries.sort_by_key(fs::DirEntry::file_name);
+    for entry in entries {
+        let nested_link = link.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            files.extend(mirrored_files(&entry.path(), &nested_link)?);
+        } else {
+            files.push((entry.path(), nested_link));
+        }
+    }
+
+    Ok(files)
+}
+
+/// The ancestor of `link` that's a symlink into the directory holding
+/// `original`, like `~/.config/nvim` linked to the whole `nvim` directory
+/// before it was marked `recursive`. Whatever is under it is in the dotfiles
+/// directory itself.
+fn symlinked_ancestor(original: &Path, link: &Path) -> Option<PathBuf> {
+    let original = original.canonicalize().ok()?;
+    link.ancestors()
+        .skip(1)
+        .find(|ancestor| {
+            fs::symlink_metadata(ancestor).is_ok_and(|metadata| metadata.is_symlink())
+                && ancestor
+                    .canonicalize()
+                    .is_ok_and(|target| original.starts_with(target))
+        })
+        .map(Path::to_path_buf)
+}
+
+/// Whether `link` is `original` itself, reached through a symlinked ancestor.
+fn is_original_through_ancestor(original: &Path, link: &Path) -> bool {
+    symlinked_ancestor(original, link).is_some()
+        && link.canonicalize().ok() == original.canonicalize().ok()
+}
+
+/// Inspects what is at `link` without following it, and compares it against
+/// where it should point.
+pub fn link_state(original: &Path, link: &Path) -> Result<LinkState, SetupError> {
+    match fs::symlink_metadata(link) {
+        Ok(metadata) if metadata.is_symlink() => match fs::read_link(link) {
+            Ok(link_target) if link_target == original => Ok(LinkState::Correct),
+            // `exists` follows the symlink, so this tells us if the target is
+            // still there.
+            Ok(link_target) if link.exists() => Ok(LinkState::WrongTarget(link_target)),
+            Ok(_) | Err(_) => Ok(LinkState::Broken),
+        },
+        Ok(_) if is_original_through_ancestor(original, link) => Ok(LinkState::Correct),
+        Ok(_) => Ok(LinkState::Conflict),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(LinkState::Missing),
+        Err(e) => Err(SetupError::IoError(e)),
+    }
+}
+
+/// What is currently at a copied dotfile's destination.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CopyState {
+    /// Nothing exists at the destination.
+    Missing,
+    /// A file with the same contents as the original.
+    UpToDate,
+    /// A file omiros copied that hasn't been touched since, but whose original
+    /// has changed, so it should be copied again.
+    Outdated,
+    /// A file that differs from both the original and what omiros last copied
+    /// there, because it was edited in place, or was never copied by omiros.
+    Drifted,
+    /// A symlink, probably left over from before the entry was copied.
+    Symlink,
+    /// A directory.
+    Conflict,
+}
+
+/// What a copied dotfile's destination should contain: the original as is,
+/// or rendered with `vars` if it's a template, reading any 1Password secret
+/// references with `op`.
+pub fn copy_contents(
+    runner: &dyn CommandRunner,
+    dotfile: &DotfileLink,
+    vars: &HashMap<String, String>,
+) -> Result<Vec<u8>, SetupError> {
+    let contents = fs::read(&dotfile.original)?;
+    if dotfile.method != DotfileMethod::Template {
+        return Ok(contents);
+    }
+
+    let mut signed_in = false;
+    let mut secret = |reference: &str| {
+        if !signed_in {
+            check_op_signed_in(runner)?;
+            signed_in = true;
+        }
+        read_secret(runner, reference)
+    };
+    template::render(&String::from_utf8(contents)?, vars, &mut secret)
+        .map(String::into_bytes)
+        .map_err(|e| SetupError::DotfileError(format!("{}: {e}", dotfile.original.display())))
+}
+
+/// Checks the 1Password CLI is installed and signed in, so secret references
+/// in templates can be read.
+fn check_op_signed_in(runner: &dyn CommandRunner) -> Result<(), SetupError> {
+    command(runner, "op").map_err(|_| {
+        SetupError::DotfileError(
+            "templates reference 1Password secrets, but the 1Password CLI isn't installed, \
+             install it with `brew install 1password-cli`"
+                .to_string(),
+        )
+    })?;
+
+    if !runner.output("op", &["whoami"])?.success() {
+        return Err(SetupError::DotfileError(
+            "templates reference 1Password secrets, but the 1Password CLI isn't signed in, \
+             run `eval $(op signin)` or turn on the desktop app integration, then run omiros \
+             again"
+                .to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Reads a secret with `op read`. The value is never logged, only the
+/// reference it was read from.
+fn read_secret(runner: &dyn CommandRunner, reference: &str) -> Result<String, SetupError> {
+    let output = runner.output("op", &["read", "--no-newline", reference])?;
+    if !output.success() {
+        return Err(SetupError::DotfileError(format!(
+            "could not read {reference} from 1Password: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    info!("🔑 Read {reference} from 1Password");
+
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// The hash of every copied dotfile's contents when omiros last put it in
+/// place, keyed by destination. Comparing a destination with its hash tells an
+/// edited copy, which is left alone, apart from a changed original, which is
+/// copied again.
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct DeployedCopies(BTreeMap<PathBuf, String>);
+
+impl DeployedCopies {
+    /// Where the hashes are kept between runs.
+    pub fn path() -> Result<PathBuf, SetupError> {
+        let home = home_dir().ok_or_else(|| {
+            SetupError::DotfileError("Could not determine home directory.".to_string())
+        })?;
+        Ok(home.join(".local/state/omiros/copied-dotfiles.toml"))
+    }
+
+    /// Reads the hashes at `path`, or none if nothing has been copied yet.
+    pub fn load(path: &Path) -> Result<Self, SetupError> {
+        match fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents).map_err(|e| {
+                SetupError::DotfileError(format!("could not parse {}: {e}", path.display()))
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(SetupError::IoError(e)),
+        }
+    }
+
+    /// Writes the hashes to `path`, creating its directory if needed.
+    pub fn save(&self, path: &Path) -> Result<(), SetupError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let contents = toml::to_string(self)
+            .map_err(|e| SetupError::DotfileError(format!("could not save copy hashes: {e}")))?;
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    /// The hash of what was last copied to `destination`, if anything.
+    pub fn get(&self, destination: &Path) -> Option<&str> {
+        self.0.get(destination).map(String::as_str)
+    }
+
+    fn record(&mut self, destination: &Path, contents: &[u8]) {
+        self.0
+            .insert(destination.to_path_buf(), content_hash(contents));
     }
+}
 
-    println!("✅ Dotfiles setup complete");
+/// The SHA-256 of `contents`, in hex.
+fn content_hash(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
 
+/// Inspects what is at `destination` without following it, and compares it
+/// against the `contents` it should have, and the hash of what was `deployed`
+/// there last, if anything.
+pub fn copy_state(
+    contents: &[u8],
+    destination: &Path,
+    deployed: Option<&str>,
+) -> Result<CopyState, SetupError> {
+    match fs::symlink_metadata(destination) {
+        Ok(metadata) if metadata.is_symlink() => Ok(CopyState::Symlink),
+        Ok(metadata) if metadata.is_file() => {
+            let current = fs::read(destination)?;
+            if current == contents {
+                Ok(CopyState::UpToDate)
+            } else if deployed == Some(content_hash(&current).as_str()) {
+                Ok(CopyState::Outdated)
+            } else {
+                Ok(CopyState::Drifted)
+            }
+        }
+        Ok(_) => Ok(CopyState::Conflict),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(CopyState::Missing),
+        Err(e) => Err(SetupError::IoError(e)),
+    }
+}
+
+/// Replaces whatever symlink is at `link` with one pointing at `original`.
+pub fn relink(original: &Path, link: &Path) -> Result<(), SetupError> {
+    fs::remove_file(link)?;
+    std::os::unix::fs::symlink(original, link)?;
     Ok(())
 }
 
+/// How many times linking a dotfile is retried when it fails because the
+/// filesystem is (hopefully temporarily) read-only or not writable.
+pub const TRANSIENT_FS_RETRIES: u32 = 2;
+
+/// On some managed machines the home directory is briefly read-only during
+/// login, which shows up as these errors. They're worth retrying, and if they
+/// persist they shouldn't stop the rest of the dotfiles from being linked.
+fn is_transient_fs_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ReadOnlyFilesystem | io::ErrorKind::PermissionDenied
+    )
+}
+
+/// Sets up the dotfiles by creating symlinks from the specified dotfiles
+/// directory to the home directory. With `backup_existing`, regular files in
+/// the way of a symlink are moved aside, see [`backup_path`], rather than
+/// failing. Transient filesystem errors are retried with `retry`.
+pub fn setup_dotfiles(
+    runner: &dyn CommandRunner,
+    dotfiles: &Dotfiles,
+    dotfiles_dir: &Path,
+    backup_existing: bool,
+    retry: Retry,
+) -> Result<Changes, SetupError> {
+    info!("🔗 Setting up dotfiles...");
+
+    let links = dotfiles.links(dotfiles_dir)?;
+    let vars = if links.iter().any(|l| l.method == DotfileMethod::Template) {
+        dotfiles.template_vars(runner)?
+    } else {
+        HashMap::new()
+    };
+    let copies_path = if links.iter().any(|l| l.method.is_copy()) {
+        Some(DeployedCopies::path()?)
+    } else {
+        None
+    };
+    let mut copies = match &copies_path {
+        Some(path) => DeployedCopies::load(path)?,
+        None => DeployedCopies::default(),
+    };
+    let mut changes = Changes::default();
+    let mut unlinked = Vec::new();
+
+    for dotfile in links {
+        let result = retry.until(
+            &format!("Linking {}", dotfile.link.display()),
+            || {
+                setup_dotfile(
+                    runner,
+                    &dotfile,
+                    &vars,
+                    &mut copies,
+                    dotfiles.force,
+                    dotfiles.reconcile_mode,
+                    backup_existing,
+                )
+            },
+            |result| !matches!(result, Err(SetupError::IoError(e)) if is_transient_fs_error(e)),
+        );
+        match result {
+            Ok(linked) => {
+                changes.merge(linked);
+            }
+            Err(SetupError::IoError(e)) if is_transient_fs_error(&e) => {
+                event!(
+                    "link",
+                    dotfile.link.to_string_lossy().as_ref(),
+                    failed,
+                    "❌ Could not link {} ({e})",
+                    dotfile.link.display()
+                );
+                unlinked.push(format!("{}: {e}", dotfile.link.display()));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    if let Some(path) = copies_path {
+        copies.save(&path)?;
+    }
+
+    if !unlinked.is_empty() {
+        return Err(SetupError::DotfileError(format!(
+            "Could not link the following dotfiles, the filesystem may be read-only:\n{}",
+            unlinked.join("\n")
+        )));
+    }
+
+    info!("✅ Dotfiles setup complete");
+
+    Ok(changes)
+}
+
+/// Renders what [`setup_dotfiles`] would do with each symlinked dotfile as a
+/// tree, grouped by action and sorted by link path so plans can be diffed
+/// across runs. Copied and templated dotfiles aren't included.
+pub fn plan_dotfiles(dotfiles: &Dotfiles, dotfiles_dir: &Path) -> Result<String, SetupError> {
+    let mut planned = Vec::new();
+    for dotfile in dotfiles.links(dotfiles_dir)? {
+        if dotfile.method == DotfileMethod::Symlink {
+            let state = link_state(&dotfile.original, &dotfile.link)?;
+            planned.push((dotfile, state));
+        }
+    }
+    Ok(render_plan(planned, dotfiles.reconcile_mode))
+}
+
+/// Renders [`plan_dotfiles`]'s tree from each dotfile's [`LinkState`].
+fn render_plan(
+    mut planned: Vec<(DotfileLink, LinkState)>,
+    reconcile_mode: ReconcileMode,
+) -> String {
+    let replace = reconcile_mode == ReconcileMode::Replace;
+    let mut groups: [(&str, Vec<String>); 6] = [
+        ("create", Vec::new()),
+        ("already correct", Vec::new()),
+        ("replace wrong target", Vec::new()),
+        ("replace broken symlink", Vec::new()),
+        ("leave wrong or broken symlink", Vec::new()),
+        ("conflict (real file present)", Vec::new()),
+    ];
+
+    planned.sort_by(|(a, _), (b, _)| a.link.cmp(&b.link));
+    for (DotfileLink { original, link, .. }, state) in planned {
+        let (group, line) = match state {
+            LinkState::Missing => (0, format!("{} -> {}", link.display(), original.display())),
+            LinkState::Correct => (1, link.display().to_string()),
+            LinkState::WrongTarget(target) => (
+                if replace { 2 } else { 4 },
+                format!(
+                    "{} ({} -> {})",
+                    link.display(),
+                    target.display(),
+                    original.display()
+                ),
+            ),
+            LinkState::Broken => (
+                if replace { 3 } else { 4 },
+                format!("{} -> {}", link.display(), original.display()),
+            ),
+            LinkState::Conflict => (5, link.display().to_string()),
+        };
+        groups[group].1.push(line);
+    }
+
+    let groups: Vec<_> = groups
+        .into_iter()
+        .filter(|(_, lines)| !lines.is_empty())
+        .collect();
+    let total: usize = groups.iter().map(|(_, lines)| lines.len()).sum();
+    let mut plan = format!("Dotfiles plan ({total} symlinked)\n");
+    for (i, (name, lines)) in groups.iter().enumerate() {
+        let last_group = i + 1 == groups.len();
+        let (branch, indent) = if last_group {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        plan.push_str(&format!("{branch}{name} ({})\n", lines.len()));
+        for (j, line) in lines.iter().enumerate() {
+            let leaf = if j + 1 == lines.len() {
+                "└── "
+            } else {
+                "├── "
+            };
+            plan.push_str(&format!("{indent}{leaf}{line}\n"));
+        }
+    }
+    plan
+}
+
+/// Puts a single dotfile in place, either by linking or copying it.
+fn setup_dotfile(
+    runner: &dyn CommandRunner,
+    dotfile: &DotfileLink,
+    vars: &HashMap<String, String>,
+    copies: &mut DeployedCopies,
+    force: bool,
+    reconcile_mode: ReconcileMode,
+    backup_existing: bool,
+) -> Result<Changes, SetupError> {
+    let DotfileLink {
+        original,
+        link,
+        method,
+        mode,
+    } = dotfile;
+
+    // Verify original file exists
+    if !original.exists() {
+        return Err(SetupError::DotfileError(format!(
+            "Original dotfile not found: {}",
+            original.display()
+        )));
+    }
+
+    // Create parent directory if it doesn't exist
+    if let Some(link_parent) = link.parent()
+        && !link_parent.exists()
+    {
+        fs::create_dir_all(link_parent)?;
+        info!("📁 Created directory: {}", link_parent.display());
+    }
+
+    match method {
+        DotfileMethod::Symlink => link_dotfile(original, link, reconcile_mode, backup_existing),
+        DotfileMethod::Copy | DotfileMethod::Template => copy_dotfile(
+            original,
+            link,
+            &copy_contents(runner, dotfile, vars)?,
+            *mode,
+            copies,
+            force,
+        ),
+    }
+}
+
+/// Links a single dotfile, replacing an incorrect or broken symlink if one is
+/// in the way, unless `reconcile_mode` says to only report it. A regular file
+/// in the way is only moved aside with `backup_existing`.
+fn link_dotfile(
+    original: &Path,
+    link: &Path,
+    reconcile_mode: ReconcileMode,
+    backup_existing: bool,
+) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    // Anything moved or linked under a symlink into the dotfiles directory
+    // would land in the dotfiles directory itself.
+    if let Some(ancestor) = symlinked_ancestor(original, link) {
+        if is_original_through_ancestor(original, link) {
+            unchanged!(
+                "✅ {} already linked through {}",
+                link.display(),
+                ancestor.display()
+            );
+            return Ok(changes);
+        }
+        return Err(SetupError::DotfileError(format!(
+            "{} is inside {}, which links into the dotfiles directory. \
+                Remove that symlink to have omiros link each file instead.",
+            link.display(),
+            ancestor.display()
+        )));
+    }
+
+    // Check what exists at the link location.
+    match link_state(original, link)? {
+        LinkState::Correct => {
+            unchanged!("✅ {} already correctly linked", link.display());
+            return Ok(changes);
+        }
+        LinkState::WrongTarget(target) if reconcile_mode == ReconcileMode::ReportOnly => {
+            warn!(
+                "⚠️  {} links to {} instead of {}, leaving it",
+                link.display(),
+                target.display(),
+                original.display()
+            );
+            return Ok(changes);
+        }
+        LinkState::Broken if reconcile_mode == ReconcileMode::ReportOnly => {
+            warn!(
+                "⚠️  {} is a broken symlink, leaving it instead of linking {}",
+                link.display(),
+                original.display()
+            );
+            return Ok(changes);
+        }
+        LinkState::WrongTarget(_) => {
+            fs::remove_file(link)?;
+            info!("🔄 Removed incorrect symlink: {}", link.display());
+        }
+        LinkState::Broken => {
+            fs::remove_file(link)?;
+            info!("🗑️  Removed broken symlink: {}", link.display());
+            changes.other_change(format!("Removed broken symlink {}", link.display()));
+        }
+        LinkState::Conflict if backup_existing && link.is_file() => {
+            let backup = backup_path(link);
+            fs::rename(link, &backup)?;
+            info!("📦 Backed up {} to {}", link.display(), backup.display());
+        }
+        LinkState::Conflict => {
+            // It's a regular file or directory - error out and have the user
+            // manually remove it.
+            return Err(SetupError::DotfileError(format!(
+                "Link path already exists as a file/directory:{}\n\
+                    Please manually backup and remove this file before running omiros again, \
+                    or pass --backup to have omiros move it aside.",
+                link.display()
+            )));
+        }
+        LinkState::Missing => {
+            // The link does not exist, which is what we want.
+        }
+    }
+
+    // Create symlink
+    std::os::unix::fs::symlink(original, link)?;
+    event!(
+        "link",
+        link.to_string_lossy().as_ref(),
+        ok,
+        "🔗 Linked {} -> {}",
+        link.display(),
+        original.display()
+    );
+    changes.symlink_created(link, original);
+
+    Ok(changes)
+}
+
+/// Where to move a file that's in the way of a dotfile: `<link>.omiros.bak`,
+/// or if that's taken too, a variant with the current time in it.
+pub(crate) fn backup_path(link: &Path) -> PathBuf {
+    let mut backup = link.as_os_str().to_owned();
+    backup.push(".omiros.bak");
+    let backup = PathBuf::from(backup);
+    if !backup.exists() {
+        return backup;
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut backup = link.as_os_str().to_owned();
+    backup.push(format!(".omiros.{now}.bak"));
+    PathBuf::from(backup)
+}
+
+/// Gives an up to date copy the configured `mode`, if it doesn't have it.
+fn apply_mode(destination: &Path, mode: Option<u32>) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    let Some(mode) = mode else {
+        return Ok(changes);
+    };
+
+    if fs::metadata(destination)?.permissions().mode() & 0o7777 != mode {
+        fs::set_permissions(destination, fs::Permissions::from_mode(mode))?;
+        event!(
+            "chmod",
+            destination.to_string_lossy().as_ref(),
+            ok,
+            "🔧 Set mode of {} to {mode:04o}",
+            destination.display()
+        );
+        changes.other_change(format!(
+            "Set mode of {} to {mode:04o}",
+            destination.display()
+        ));
+    }
+
+    Ok(changes)
+}
+
+/// Copies a single dotfile, writing `contents` with `mode`, or the original's
+/// permissions, and recording their hash in `copies`. A destination that's
+/// unchanged since the last copy is updated when the original changes. One
+/// that's been edited in place is only overwritten when `force` is set,
+/// otherwise it's skipped with a warning so local edits aren't lost.
+fn copy_dotfile(
+    original: &Path,
+    destination: &Path,
+    contents: &[u8],
+    mode: Option<u32>,
+    copies: &mut DeployedCopies,
+    force: bool,
+) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    match copy_state(contents, destination, copies.get(destination))? {
+        CopyState::UpToDate => {
+            unchanged!("✅ {} already up to date", destination.display());
+            copies.record(destination, contents);
+            return apply_mode(destination, mode);
+        }
+        CopyState::Outdated => {
+            info!(
+                "🔄 {} has changed, updating {}",
+                original.display(),
+                destination.display()
+            );
+        }
+        CopyState::Drifted if force => {
+            info!(
+                "🔄 {} has drifted from {}, overwriting",
+                destination.display(),
+                original.display()
+            );
+        }
+        CopyState::Drifted => {
+            warn!(
+                "⚠️  {} has drifted from {}, skipping (set `force = true` to overwrite)",
+                destination.display(),
+                original.display()
+            );
+            return Ok(changes);
+        }
+        CopyState::Symlink => {
+            fs::remove_file(destination)?;
+            info!(
+                "🔄 Removed symlink in place of copy: {}",
+                destination.display()
+            );
+        }
+        CopyState::Conflict => {
+            return Err(SetupError::DotfileError(format!(
+                "Copy destination already exists as a directory: {}",
+                destination.display()
+            )));
+        }
+        CopyState::Missing => {}
+    }
+
+    fs::write(destination, contents)?;
+    copies.record(destination, contents);
+    let permissions = match mode {
+        Some(mode) => fs::Permissions::from_mode(mode),
+        None => fs::metadata(original)?.permissions(),
+    };
+    fs::set_permissions(destination, permissions)?;
+    event!(
+        "copy",
+        destination.to_string_lossy().as_ref(),
+        ok,
+        "📄 Copied {} -> {}",
+        original.display(),
+        destination.display()
+    );
+    changes.other_change(format!(
+        "Copied {} to {}",
+        original.display(),
+        destination.display()
+    ));
+
+    Ok(changes)
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
 
+    use rstest::rstest;
+    use tempfile::tempdir;
+
     use super::*;
+    use crate::runner::{CommandOutput, MockRunner};
+
+    #[rstest]
+    #[case(ReconcileMode::Replace, true)]
+    #[case(ReconcileMode::ReportOnly, false)]
+    fn link_dotfile_only_replaces_wrong_and_broken_symlinks_in_replace_mode(
+        #[case] reconcile_mode: ReconcileMode,
+        #[case] replaced: bool,
+    ) {
+        let dir = tempdir().unwrap();
+        let original = dir.path().join("original");
+        let elsewhere = dir.path().join("elsewhere");
+        let wrong = dir.path().join("wrong");
+        let broken = dir.path().join("broken");
+        fs::write(&original, "set -g mouse on").unwrap();
+        fs::write(&elsewhere, "").unwrap();
+        std::os::unix::fs::symlink(&elsewhere, &wrong).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("gone"), &broken).unwrap();
+
+        link_dotfile(&original, &wrong, reconcile_mode, false).unwrap();
+        link_dotfile(&original, &broken, reconcile_mode, false).unwrap();
+
+        let expected = |link: &Path| {
+            if replaced {
+                LinkState::Correct
+            } else if link == wrong {
+                LinkState::WrongTarget(elsewhere.clone())
+            } else {
+                LinkState::Broken
+            }
+        };
+        assert_eq!(expected(&wrong), link_state(&original, &wrong).unwrap());
+        assert_eq!(expected(&broken), link_state(&original, &broken).unwrap());
+    }
+
+    #[test]
+    fn link_dotfile_only_backs_up_conflicting_files_when_asked() {
+        let dir = tempdir().unwrap();
+        let original = dir.path().join("original");
+        let link = dir.path().join(".zshrc");
+        fs::write(&original, "set -o vi").unwrap();
+        fs::write(&link, "set -o emacs").unwrap();
+
+        assert!(link_dotfile(&original, &link, ReconcileMode::Replace, false).is_err());
+        assert_eq!(LinkState::Conflict, link_state(&original, &link).unwrap());
+
+        link_dotfile(&original, &link, ReconcileMode::Replace, true).unwrap();
+        assert_eq!(LinkState::Correct, link_state(&original, &link).unwrap());
+        let backup = dir.path().join(".zshrc.omiros.bak");
+        assert_eq!("set -o emacs", fs::read_to_string(backup).unwrap());
+    }
+
+    #[test]
+    fn recursive_entries_link_each_file_into_existing_directories() {
+        let dir = tempdir().unwrap();
+        let dotfiles_dir = dir.path().join("dotfiles");
+        let nvim = dir.path().join("home/.config/nvim");
+        fs::create_dir_all(dotfiles_dir.join("nvim/lua/plugins")).unwrap();
+        fs::write(dotfiles_dir.join("nvim/init.lua"), "require('options')").unwrap();
+        fs::write(dotfiles_dir.join("nvim/lua/options.lua"), "").unwrap();
+        fs::write(dotfiles_dir.join("nvim/lua/plugins/lsp.lua"), "").unwrap();
+        // A real directory with a machine-local file already in the way.
+        fs::create_dir_all(nvim.join("lua")).unwrap();
+        fs::write(nvim.join("lua/local.lua"), "").unwrap();
+        let dotfiles: Dotfiles = toml::from_str(&format!(
+            r#"files = [{{ original = "nvim", link = "{}", recursive = true }}]"#,
+            nvim.display()
+        ))
+        .unwrap();
+
+        let links: Vec<_> = dotfiles
+            .links(&dotfiles_dir)
+            .unwrap()
+            .into_iter()
+            .map(|l| l.link)
+            .collect();
+        assert_eq!(
+            vec![
+                nvim.join("init.lua"),
+                nvim.join("lua/options.lua"),
+                nvim.join("lua/plugins/lsp.lua"),
+            ],
+            links
+        );
+
+        let runner = MockRunner::new();
+        let changes =
+            setup_dotfiles(&runner, &dotfiles, &dotfiles_dir, false, Retry::new(0)).unwrap();
+        assert_eq!(3, changes.symlinks_created.len());
+        // Running again finds everything already linked.
+        let changes =
+            setup_dotfiles(&runner, &dotfiles, &dotfiles_dir, false, Retry::new(0)).unwrap();
+        assert!(!changes.has_changes());
+
+        for file in ["init.lua", "lua/options.lua", "lua/plugins/lsp.lua"] {
+            assert_eq!(
+                LinkState::Correct,
+                link_state(&dotfiles_dir.join("nvim").join(file), &nvim.join(file)).unwrap()
+            );
+        }
+        assert!(!fs::symlink_metadata(nvim.join("lua")).unwrap().is_symlink());
+        assert!(nvim.join("lua/local.lua").is_file());
+    }
 
     #[test]
-    fn tilde_expand_path_works() {
+    fn recursive_entries_must_be_directories() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join(".zshrc"), "").unwrap();
+        let dotfiles: Dotfiles = toml::from_str(
+            r#"files = [{ original = ".zshrc", link = "~/.zshrc", recursive = true }]"#,
+        )
+        .unwrap();
+
+        assert!(dotfiles.links(dir.path()).is_err());
+    }
+
+    #[test]
+    fn missing_recursive_originals_are_reported_as_not_found() {
+        let dir = tempdir().unwrap();
+        let dotfiles: Dotfiles = toml::from_str(
+            r#"files = [{ original = "nvim", link = "~/.config/nvim", recursive = true }]"#,
+        )
+        .unwrap();
+
+        let mut problems = Vec::new();
+        dotfiles.validate(dir.path(), &mut problems);
+
+        assert_eq!(1, problems.len());
+        assert!(problems[0].message.contains("original dotfile not found"));
+    }
+
+    #[test]
+    fn recursive_entries_leave_a_directory_symlinked_into_the_dotfiles_alone() {
+        let dir = tempdir().unwrap();
+        let dotfiles_dir = dir.path().join("dotfiles");
+        let nvim = dir.path().join("home/.config/nvim");
+        fs::create_dir_all(dotfiles_dir.join("nvim")).unwrap();
+        fs::write(dotfiles_dir.join("nvim/init.lua"), "require('options')").unwrap();
+        fs::create_dir_all(nvim.parent().unwrap()).unwrap();
+        // How the directory was linked before it was marked `recursive`.
+        std::os::unix::fs::symlink(dotfiles_dir.join("nvim"), &nvim).unwrap();
+        let dotfiles: Dotfiles = toml::from_str(&format!(
+            r#"files = [{{ original = "nvim", link = "{}", recursive = true }}]"#,
+            nvim.display()
+        ))
+        .unwrap();
+
+        let runner = MockRunner::new();
+        setup_dotfiles(&runner, &dotfiles, &dotfiles_dir, true, Retry::new(0)).unwrap();
+
+        let original = dotfiles_dir.join("nvim/init.lua");
+        assert!(!fs::symlink_metadata(&original).unwrap().is_symlink());
+        assert_eq!("require('options')", fs::read_to_string(&original).unwrap());
+        assert!(!dotfiles_dir.join("nvim/init.lua.omiros.bak").exists());
+        assert_eq!(
+            LinkState::Correct,
+            link_state(&original, &nvim.join("init.lua")).unwrap()
+        );
+    }
+
+    #[test]
+    fn render_plan_groups_and_sorts_entries() {
+        let planned = |link: &str, state| {
+            let link = DotfileLink {
+                original: PathBuf::from("/dotfiles").join(link),
+                link: PathBuf::from("/home").join(link),
+                method: DotfileMethod::Symlink,
+                mode: None,
+            };
+            (link, state)
+        };
+        let plan = vec![
+            planned(".zshrc", LinkState::Missing),
+            planned(".vimrc", LinkState::Conflict),
+            planned(".gitconfig", LinkState::Correct),
+            planned(".bashrc", LinkState::Missing),
+            planned(
+                ".tmux.conf",
+                LinkState::WrongTarget("/old/tmux.conf".into()),
+            ),
+        ];
+
+        assert_eq!(
+            "Dotfiles plan (5 symlinked)
+├── create (2)
+│   ├── /home/.bashrc -> /dotfiles/.bashrc
+│   └── /home/.zshrc -> /dotfiles/.zshrc
+├── already correct (1)
+│   └── /home/.gitconfig
+├── replace wrong target (1)
+│   └── /home/.tmux.conf (/old/tmux.conf -> /dotfiles/.tmux.conf)
+└── conflict (real file present) (1)
+    └── /home/.vimrc
+",
+            render_plan(plan, ReconcileMode::Replace)
+        );
+    }
+
+    #[test]
+    fn copy_dotfile_copies_missing_destination() {
+        let dir = tempdir().unwrap();
+        let original = dir.path().join("original");
+        let destination = dir.path().join("destination");
+        fs::write(&original, "set -g mouse on").unwrap();
+
+        copy_dotfile(
+            &original,
+            &destination,
+            b"set -g mouse on",
+            None,
+            &mut DeployedCopies::default(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!("set -g mouse on", fs::read_to_string(&destination).unwrap());
+        assert_eq!(
+            CopyState::UpToDate,
+            copy_state(b"set -g mouse on", &destination, None).unwrap()
+        );
+    }
+
+    #[test]
+    fn copy_dotfile_leaves_identical_destination_alone() {
+        let dir = tempdir().unwrap();
+        let original = dir.path().join("original");
+        let destination = dir.path().join("destination");
+        fs::write(&original, "set -g mouse on").unwrap();
+        fs::write(&destination, "set -g mouse on").unwrap();
+
+        assert_eq!(
+            CopyState::UpToDate,
+            copy_state(b"set -g mouse on", &destination, None).unwrap()
+        );
+        copy_dotfile(
+            &original,
+            &destination,
+            b"set -g mouse on",
+            None,
+            &mut DeployedCopies::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!("set -g mouse on", fs::read_to_string(&destination).unwrap());
+    }
+
+    #[test]
+    fn copy_dotfile_only_overwrites_drifted_destination_when_forced() {
+        let dir = tempdir().unwrap();
+        let original = dir.path().join("original");
+        let destination = dir.path().join("destination");
+        fs::write(&original, "set -g mouse on").unwrap();
+        fs::write(&destination, "set -g mouse off").unwrap();
+
+        assert_eq!(
+            CopyState::Drifted,
+            copy_state(b"set -g mouse on", &destination, None).unwrap()
+        );
+
+        copy_dotfile(
+            &original,
+            &destination,
+            b"set -g mouse on",
+            None,
+            &mut DeployedCopies::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            "set -g mouse off",
+            fs::read_to_string(&destination).unwrap()
+        );
+
+        copy_dotfile(
+            &original,
+            &destination,
+            b"set -g mouse on",
+            None,
+            &mut DeployedCopies::default(),
+            true,
+        )
+        .unwrap();
+        assert_eq!("set -g mouse on", fs::read_to_string(&destination).unwrap());
+    }
+
+    #[test]
+    fn copy_dotfile_updates_copies_whose_original_changed() {
+        let dir = tempdir().unwrap();
+        let original = dir.path().join("original");
+        let destination = dir.path().join("destination");
+        let mut copies = DeployedCopies::default();
+        fs::write(&original, "set -g mouse on").unwrap();
+        copy_dotfile(
+            &original,
+            &destination,
+            b"set -g mouse on",
+            None,
+            &mut copies,
+            false,
+        )
+        .unwrap();
+
+        fs::write(&original, "set -g mouse off").unwrap();
+        assert_eq!(
+            CopyState::Outdated,
+            copy_state(b"set -g mouse off", &destination, copies.get(&destination)).unwrap()
+        );
+        copy_dotfile(
+            &original,
+            &destination,
+            b"set -g mouse off",
+            None,
+            &mut copies,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "set -g mouse off",
+            fs::read_to_string(&destination).unwrap()
+        );
+        assert_eq!(
+            Some(content_hash(b"set -g mouse off").as_str()),
+            copies.get(&destination)
+        );
+    }
+
+    #[test]
+    fn copy_dotfile_skips_copies_edited_since_they_were_copied() {
+        let dir = tempdir().unwrap();
+        let original = dir.path().join("original");
+        let destination = dir.path().join("destination");
+        let mut copies = DeployedCopies::default();
+        fs::write(&original, "set -g mouse on").unwrap();
+        copy_dotfile(
+            &original,
+            &destination,
+            b"set -g mouse on",
+            None,
+            &mut copies,
+            false,
+        )
+        .unwrap();
+
+        // Edited in place, and then the original changed too.
+        fs::write(&destination, "set -g mouse on\nset -g status off").unwrap();
+        fs::write(&original, "set -g mouse off").unwrap();
+        assert_eq!(
+            CopyState::Drifted,
+            copy_state(b"set -g mouse off", &destination, copies.get(&destination)).unwrap()
+        );
+        copy_dotfile(
+            &original,
+            &destination,
+            b"set -g mouse off",
+            None,
+            &mut copies,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "set -g mouse on\nset -g status off",
+            fs::read_to_string(&destination).unwrap()
+        );
+    }
+
+    #[test]
+    fn deployed_copies_round_trip_and_start_empty() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state/copied-dotfiles.toml");
+        assert_eq!(
+            DeployedCopies::default(),
+            DeployedCopies::load(&path).unwrap()
+        );
+
+        let mut copies = DeployedCopies::default();
+        copies.record(Path::new("/home/me/.ssh/config"), b"Host *");
+        copies.save(&path).unwrap();
+
+        assert_eq!(copies, DeployedCopies::load(&path).unwrap());
+    }
+
+    #[test]
+    fn copy_dotfile_applies_mode_to_new_and_existing_copies() {
+        let dir = tempdir().unwrap();
+        let original = dir.path().join("original");
+        let destination = dir.path().join("destination");
+        fs::write(&original, "#!/bin/sh").unwrap();
+        fs::set_permissions(&original, fs::Permissions::from_mode(0o644)).unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+
+        copy_dotfile(
+            &original,
+            &destination,
+            b"#!/bin/sh",
+            Some(0o755),
+            &mut DeployedCopies::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(0o755, mode(&destination));
+
+        fs::set_permissions(&destination, fs::Permissions::from_mode(0o600)).unwrap();
+        copy_dotfile(
+            &original,
+            &destination,
+            b"#!/bin/sh",
+            Some(0o755),
+            &mut DeployedCopies::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(0o755, mode(&destination));
+        assert_eq!(0o644, mode(&original));
+    }
+
+    #[test]
+    fn parse_mode_only_accepts_octal_permissions() {
+        assert_eq!(0o755, parse_mode("0755").unwrap());
+        assert_eq!(0o644, parse_mode("644").unwrap());
+        assert!(parse_mode("0855").is_err());
+        assert!(parse_mode("rwxr-xr-x").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
+
+    #[test]
+    fn copy_contents_only_renders_templates() {
+        let dir = tempdir().unwrap();
+        let original = dir.path().join("original");
+        fs::write(&original, "Host {{ hostname }}").unwrap();
+        let runner = MockRunner::new();
+        let vars = HashMap::from([("hostname".to_string(), "work-mbp".to_string())]);
+        let dotfile = |method| DotfileLink {
+            original: original.clone(),
+            link: dir.path().join("destination"),
+            method,
+            mode: None,
+        };
+
+        assert_eq!(
+            b"Host work-mbp".to_vec(),
+            copy_contents(&runner, &dotfile(DotfileMethod::Template), &vars).unwrap()
+        );
+        assert_eq!(
+            b"Host {{ hostname }}".to_vec(),
+            copy_contents(&runner, &dotfile(DotfileMethod::Copy), &vars).unwrap()
+        );
+    }
+
+    #[test]
+    fn copy_contents_reads_secrets_only_when_signed_in_to_op() {
+        let dir = tempdir().unwrap();
+        let original = dir.path().join("original");
+        fs::write(&original, "token = {{ op://Personal/GitHub/token }}").unwrap();
+        let dotfile = DotfileLink {
+            original,
+            link: dir.path().join("destination"),
+            method: DotfileMethod::Template,
+            mode: None,
+        };
+        let read = ["op", "read", "--no-newline", "op://Personal/GitHub/token"];
+
+        let runner = MockRunner::new()
+            .stub(
+                &["command", "-v", "op"],
+                CommandOutput::ok("/opt/homebrew/bin/op"),
+            )
+            .stub(&read, CommandOutput::ok("hunter2"));
+        assert_eq!(
+            b"token = hunter2".to_vec(),
+            copy_contents(&runner, &dotfile, &HashMap::new()).unwrap()
+        );
+
+        let runner = MockRunner::new()
+            .stub(
+                &["command", "-v", "op"],
+                CommandOutput::ok("/opt/homebrew/bin/op"),
+            )
+            .stub(&["op", "whoami"], CommandOutput::failed(1, "not signed in"));
+        assert!(copy_contents(&runner, &dotfile, &HashMap::new()).is_err());
+        assert!(!runner.calls().contains(&read.map(String::from).to_vec()));
+    }
+
+    #[rstest]
+    #[case::home_relative("~/.config/thing", "/User/me/.config/thing")]
+    #[case::absolute("/etc/thing", "/etc/thing")]
+    #[case::relative(".config/thing", ".config/thing")]
+    fn tilde_expand_path_works(#[case] path: &str, #[case] expected: &str) {
         let home = Path::new("/User/me/");
-        let path = Path::new("~/.config/thing");
+        let path = Path::new(path);
 
         let x = tilde_expand_path(path, home).unwrap();
 
-        assert_eq!(PathBuf::from_str("/User/me/.config/thing").unwrap(), x)
+        assert_eq!(PathBuf::from_str(expected).unwrap(), x)
+    }
+
+    #[rstest]
+    #[case::relative(
+        ".config/fish/config.fish",
+        "/User/me/dotfiles/.config/fish/config.fish"
+    )]
+    #[case::home_relative("~/Sync/.zshrc", "/User/me/Sync/.zshrc")]
+    #[case::absolute("/opt/shared/.gitconfig", "/opt/shared/.gitconfig")]
+    fn originals_are_relative_to_the_dotfiles_dir_unless_absolute(
+        #[case] original: &str,
+        #[case] expected: &str,
+    ) {
+        let home = Path::new("/User/me");
+        let dotfiles_dir = Path::new("/User/me/dotfiles");
+
+        let resolved = resolve_original(Path::new(original), dotfiles_dir, home).unwrap();
+
+        assert_eq!(Path::new(expected), resolved);
+    }
+
+    #[test]
+    fn is_transient_fs_error_only_matches_read_only_and_permission_errors() {
+        assert!(is_transient_fs_error(&io::Error::from(
+            io::ErrorKind::ReadOnlyFilesystem
+        )));
+        assert!(is_transient_fs_error(&io::Error::from(
+            io::ErrorKind::PermissionDenied
+        )));
+        assert!(!is_transient_fs_error(&io::Error::from(
+            io::ErrorKind::AlreadyExists
+        )));
     }
 }
@@ -7,18 +7,24 @@ pub enum SetupError {
     /// Indicates that a required program is not installed or not found in the system's PATH.
     #[error("Program not found: {0}")]
     ProgramFileNotFound(String),
-    /// Indicates that a Homebrew package installation failed.
-    #[error("Failed to install brew package")]
-    BrewInstallFailed,
-    /// Indicates that a Mac App Store package installation failed.
-    #[error("Failed to install mas package")]
-    MasInstallFailed,
     /// Generic installation failed.
     #[error("Installation failed: {0}")]
     InstallFailed(String),
+    /// A Homebrew command, like `brew tap`, failed.
+    #[error("Brew install failed: {0}")]
+    BrewInstallFailed(String),
     /// Generic error setting up Dotfiles.
     #[error("Error setting up dotfiles:\n{0}")]
     DotfileError(String),
+    /// The configuration couldn't be resolved for this machine.
+    #[error("Configuration error: {0}")]
+    ConfigError(String),
+    /// VS Code's `settings.json` couldn't be read or written.
+    #[error("VS Code settings error: {0}")]
+    VscodeSettingsError(String),
+    /// Cloning or pulling the dotfiles repository failed.
+    #[error("Git error: {0}")]
+    GitError(String),
     /// IO error.
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
@@ -35,3 +41,42 @@ pub enum SetupError {
     #[error("Infallible error: {0}")]
     Infallible(#[from] std::convert::Infallible),
 }
+
+/// What to do when one of several installs fails.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OnFailure {
+    /// Stop at the first failure.
+    FailFast,
+    /// Attempt everything else, then report every failure together.
+    #[default]
+    KeepGoing,
+}
+
+impl OnFailure {
+    /// Records that installing `item` failed. Failing fast, that's an error
+    /// straight away, otherwise it's added to `failed` for [`failures`].
+    pub(crate) fn record(self, item: String, failed: &mut Vec<String>) -> Result<(), SetupError> {
+        match self {
+            OnFailure::FailFast => Err(SetupError::InstallFailed(format!(
+                "failed to install {item}, stopped early due to --fail-fast"
+            ))),
+            OnFailure::KeepGoing => {
+                failed.push(item);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Reports the installs that failed while keeping going, if there were any.
+pub(crate) fn failures(failed: &[String]) -> Result<(), SetupError> {
+    if failed.is_empty() {
+        return Ok(());
+    }
+
+    Err(SetupError::InstallFailed(format!(
+        "{} failure(s), continued: {}",
+        failed.len(),
+        failed.join(", ")
+    )))
+}
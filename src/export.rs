@@ -0,0 +1,61 @@
+//! Builds a configuration from the live state of the machine, the inverse of
+//! `omiros run`, to bootstrap a `system.toml` from an already set up machine.
+
+use log::{info, warn};
+
+use crate::{
+    brew::{Brew, check_brew_installed, get_installed_brew_packages},
+    macos::read_current_settings,
+    mas::{Mas, check_mas_installed, get_installed_apps},
+    migrate::CURRENT_VERSION,
+    runner::CommandRunner,
+    system::System,
+    system_utils::command,
+    vscode::Vscode,
+};
+
+/// Reads everything installed with the package managers omiros knows about.
+/// Tools that aren't installed are skipped with a warning. The `[macos]`
+/// settings are only read when `macos` is set, since the defaults on a fresh
+/// machine are rarely worth declaring.
+pub fn export_system(runner: &dyn CommandRunner, macos: bool) -> anyhow::Result<System> {
+    info!("📄 Exporting the current state of this machine...");
+
+    let brew = match check_brew_installed(runner) {
+        Ok(()) => Some(Brew::from_installed(&get_installed_brew_packages(runner)?)),
+        Err(e) => {
+            warn!("⚠️  Skipping `[brew]`: {e}");
+            None
+        }
+    };
+
+    let mas = match check_mas_installed(runner) {
+        Ok(()) => Some(Mas::from_installed(&get_installed_apps(runner)?)),
+        Err(e) => {
+            warn!("⚠️  Skipping `[mas]`: {e}");
+            None
+        }
+    };
+
+    let vscode = match command(runner, "code") {
+        Ok(_) => Some(Vscode::from_installed(runner)?),
+        Err(e) => {
+            warn!("⚠️  Skipping `[vscode]`: {e}");
+            None
+        }
+    };
+
+    Ok(System {
+        version: Some(CURRENT_VERSION),
+        order: None,
+        brew,
+        mas,
+        dotfiles: None,
+        vscode,
+        npm: None,
+        cargo: None,
+        macos: macos.then(|| read_current_settings(runner)),
+        shell_installers: None,
+        hosts: None,
+    })
+}
@@ -0,0 +1,225 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::SetupError,
+    output::{event, unchanged},
+    report::Changes,
+    runner::CommandRunner,
+    system_utils::command,
+};
+
+const GIT_PROGRAM_NAME: &str = "git";
+
+/// The git repository the dotfiles directory is a clone of, so omiros can
+/// clone it on a fresh machine and keep it up to date.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GitConfig {
+    /// The URL to clone, like `https://github.com/me/dotfiles.git`.
+    pub repo: String,
+    /// The branch to check out when cloning, otherwise the repository's
+    /// default branch.
+    pub branch: Option<String>,
+}
+
+/// What to do with the dotfiles repository before linking dotfiles.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RepoAction {
+    /// The directory doesn't exist yet, so clone the repository into it.
+    Clone,
+    /// The directory exists, so pull the latest changes into it.
+    Pull,
+    /// The directory exists, and pulling is turned off.
+    Skip,
+}
+
+impl RepoAction {
+    /// Decides what to do given whether the dotfiles directory `exists`, and
+    /// whether to `pull` when it does.
+    pub fn new(exists: bool, pull: bool) -> Self {
+        match (exists, pull) {
+            (false, _) => RepoAction::Clone,
+            (true, true) => RepoAction::Pull,
+            (true, false) => RepoAction::Skip,
+        }
+    }
+}
+
+/// Clones the dotfiles repository into `dir` if it doesn't exist yet, or
+/// otherwise pulls it with `--ff-only`, unless `pull` is false, so that local
+/// changes are never merged over.
+pub fn sync_repo(
+    runner: &dyn CommandRunner,
+    git: &GitConfig,
+    dir: &Path,
+    pull: bool,
+) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    let action = RepoAction::new(dir.exists(), pull);
+    if action == RepoAction::Skip {
+        unchanged!("ℹ️  Not pulling {}", dir.display());
+        return Ok(changes);
+    }
+
+    command(runner, GIT_PROGRAM_NAME)?;
+    let dir_str = dir.to_str().ok_or_else(|| {
+        SetupError::GitError(format!("path is not valid UTF-8: {}", dir.display()))
+    })?;
+    let kind = match action {
+        RepoAction::Clone => "clone",
+        _ => "pull",
+    };
+    let args = match action {
+        RepoAction::Clone => {
+            event!(
+                kind,
+                dir_str,
+                started,
+                "🔧 Cloning {} into {}",
+                git.repo,
+                dir.display()
+            );
+            let mut args = vec!["clone"];
+            if let Some(branch) = &git.branch {
+                args.extend(["--branch", branch]);
+            }
+            args.extend([git.repo.as_str(), dir_str]);
+            args
+        }
+        _ => {
+            event!(kind, dir_str, started, "🔄 Pulling {}", dir.display());
+            vec!["-C", dir_str, "pull", "--ff-only"]
+        }
+    };
+
+    let before = match action {
+        RepoAction::Pull => head(runner, dir_str)?,
+        _ => None,
+    };
+    if !runner.status(GIT_PROGRAM_NAME, &args)?.success() {
+        event!(kind, dir_str, failed, "❌ `git {}` failed", args.join(" "));
+        return Err(SetupError::GitError(format!(
+            "`git {}` failed",
+            args.join(" ")
+        )));
+    }
+    match action {
+        RepoAction::Clone => {
+            event!(
+                kind,
+                dir_str,
+                ok,
+                "✅ Cloned {} into {}",
+                git.repo,
+                dir.display()
+            );
+            changes.other_change(format!("Cloned {} into {}", git.repo, dir.display()));
+        }
+        _ => {
+            let after = head(runner, dir_str)?;
+            if after != before {
+                event!(
+                    kind,
+                    dir_str,
+                    ok,
+                    "✅ Pulled new commits into {}",
+                    dir.display()
+                );
+                changes.other_change(format!("Pulled new commits into {}", dir.display()));
+            } else {
+                unchanged!("✅ {} is up to date", dir.display());
+            }
+        }
+    }
+
+    Ok(changes)
+}
+
+/// The commit checked out in the repository at `dir`, if it has one.
+fn head(runner: &dyn CommandRunner, dir: &str) -> Result<Option<String>, SetupError> {
+    let output = runner.output(GIT_PROGRAM_NAME, &["-C", dir, "rev-parse", "HEAD"])?;
+    if !output.success() {
+        return Ok(None);
+    }
+
+    Ok(Some(String::from_utf8(output.stdout)?.trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, MockRunner};
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(false, true, RepoAction::Clone)]
+    #[case(false, false, RepoAction::Clone)]
+    #[case(true, true, RepoAction::Pull)]
+    #[case(true, false, RepoAction::Skip)]
+    fn repo_action_depends_on_whether_the_directory_exists(
+        #[case] exists: bool,
+        #[case] pull: bool,
+        #[case] expected: RepoAction,
+    ) {
+        assert_eq!(expected, RepoAction::new(exists, pull));
+    }
+
+    #[test]
+    fn sync_repo_clones_missing_directory_on_its_branch() {
+        let git: GitConfig = toml::from_str(
+            r#"
+            repo = "https://github.com/me/dotfiles.git"
+            branch = "main"
+            "#,
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let clone_into = dir.path().join("dotfiles");
+        let runner =
+            MockRunner::new().stub(&["command", "-v", "git"], CommandOutput::ok("/usr/bin/git"));
+
+        let changes = sync_repo(&runner, &git, &clone_into, true).unwrap();
+
+        assert_eq!(
+            vec![format!(
+                "Cloned https://github.com/me/dotfiles.git into {}",
+                clone_into.display()
+            )],
+            changes.other_changes
+        );
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            &[
+                "git",
+                "clone",
+                "--branch",
+                "main",
+                "https://github.com/me/dotfiles.git",
+                clone_into.to_str().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn sync_repo_reports_a_pull_only_when_it_moves_head() {
+        let git: GitConfig =
+            toml::from_str(r#"repo = "https://github.com/me/dotfiles.git""#).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let dir_str = dir.path().to_str().unwrap();
+        let rev_parse = ["git", "-C", dir_str, "rev-parse", "HEAD"];
+        let runner = MockRunner::new()
+            .stub(&["command", "-v", "git"], CommandOutput::ok("/usr/bin/git"))
+            .stub(&rev_parse, CommandOutput::ok("1111111\n"))
+            .stub(&rev_parse, CommandOutput::ok("1111111\n"))
+            .stub(&rev_parse, CommandOutput::ok("1111111\n"))
+            .stub(&rev_parse, CommandOutput::ok("2222222\n"));
+        let pulled = vec![format!("Pulled new commits into {dir_str}")];
+
+        let unchanged = sync_repo(&runner, &git, dir.path(), true).unwrap();
+        let changed = sync_repo(&runner, &git, dir.path(), true).unwrap();
+
+        assert!(!unchanged.has_changes());
+        assert_eq!(pulled, changed.other_changes);
+    }
+}
@@ -3,24 +3,50 @@
 //! This crate contains the core logic for checking and installing packages from
 //! various package managers.
 
+/// Appends items installed by hand to the configuration file.
+pub mod add;
 /// Contains the logic for interacting with Homebrew.
 pub mod brew;
+/// Contains the logic for installing binaries with `cargo install`.
+pub mod cargo_install;
+/// Reports how the machine has drifted from the configuration.
+pub mod check;
 /// Contains the logic for interacting with the `defaults` commandline tool.
 mod defaults;
+/// Checks for, and repairs, common broken states of the environment.
+pub mod doctor;
 /// Contains the logic for working with dotfiles.
 pub mod dotfiles;
 /// Defines the custom error types for the application.
 pub mod errors;
+/// Builds a configuration from the current state of the machine.
+pub mod export;
+/// Clones and updates the dotfiles repository with git.
+pub mod git;
 /// Contains the logic for configuring macOS settings.
 pub mod macos;
 /// Contains the logic for interacting with the Mac App Store commandline tool.
 pub mod mas;
+/// Upgrades configuration files written for older versions of the schema.
+pub mod migrate;
+/// Contains the logic for installing global packages with `npm`.
+pub mod npm;
+/// Controls how much is reported about what omiros is doing.
+pub mod output;
+/// Collects what a run changed, for a machine-readable summary.
+pub mod report;
+/// Abstraction over running external commands, so they can be faked in tests.
+pub mod runner;
 /// Logic for setting packages through shell installers.
 pub mod shell_installers;
 /// Defines the data structures for the system configuration file.
 pub mod system;
 /// Contains utility functions for interacting with the system.
-mod system_utils;
+pub mod system_utils;
+/// Renders the variables in templated dotfiles.
+mod template;
+/// Semantic checks of the configuration file.
+pub mod validate;
 /// Contains logic for interacting with vscode extensions through the `code`
 /// commandline tool.
 pub mod vscode;
@@ -1,21 +1,58 @@
-use serde::Deserialize;
-use std::process::Command;
+use std::{
+    collections::BTreeSet,
+    env::home_dir,
+    path::{Path, PathBuf},
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::defaults::{DefaultsError, DockOrientation, MouseButtonMode, write_defaults};
+pub use crate::defaults::DefaultsBatch;
+use crate::{
+    defaults::{
+        DefaultsError, DefaultsWrite, DesktopArrangement, DockOrientation, MinimizeEffect,
+        MouseButtonMode, SearchScope, can_sudo, read_dock_persistent_apps, read_setting,
+        write_defaults, write_dock_persistent_apps, write_plist_value, write_system_defaults,
+    },
+    dotfiles::tilde_expand_path,
+    output::{event, unchanged},
+    report::Changes,
+    runner::CommandRunner,
+    system::MacOS,
+};
 
 /// Represents the Dock configuration.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Dock {
     pub orientation: Option<DockOrientation>,
     pub autohide: Option<bool>,
+    /// How long the Dock takes to slide in and out when hidden, as a multiple
+    /// of the default, so `0` is instant.
+    pub autohide_time_modifier: Option<f64>,
     pub icon_size: Option<i32>,
     pub transparent_hidden_app_icons: Option<bool>,
+    /// Stop apps from being added to, removed from, or rearranged in the Dock.
+    pub contents_immutable: Option<bool>,
+    /// Stop the Dock from being resized.
+    pub size_immutable: Option<bool>,
+    /// Show recently used apps that aren't kept in the Dock.
+    pub show_recents: Option<bool>,
+    pub minimize_effect: Option<MinimizeEffect>,
+    /// Bounce app icons while they're opening.
+    pub animate_opening: Option<bool>,
+    /// The apps kept in the Dock, in order, as paths like
+    /// `/Applications/Safari.app`. Apps that aren't listed are removed,
+    /// unless `clear_other_apps` is false.
+    pub persistent_apps: Option<Vec<String>>,
+    /// Whether to remove apps that aren't in `persistent_apps` from the Dock,
+    /// rather than keeping them after the listed ones. Defaults to true.
+    pub clear_other_apps: Option<bool>,
 }
 
 /// Represents the Mission Control configuration.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct MissionControl {
     pub automatically_rearrange_spaces: Option<bool>,
@@ -23,17 +60,16 @@ pub struct MissionControl {
 }
 
 /// Represents the Safari configuration.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Safari {
     pub show_full_url: Option<bool>,
 }
 
 /// System-wide configuration.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct SystemSettings {
-    pub show_file_extensions: Option<bool>,
     /// Never have I experienced a more unnatural scrolling direction as Apple's
     /// "natural" scrolling direction.
     pub natural_scrolling: Option<bool>,
@@ -41,6 +77,9 @@ pub struct SystemSettings {
     /// presses. I've never used this feature, and it causes issues with vim
     /// navigation.
     pub key_press_and_hold: Option<bool>,
+    /// Bundle IDs of apps, like `com.microsoft.VSCode`, to turn Press&Hold off
+    /// for, leaving the global `key-press-and-hold` setting alone elsewhere.
+    pub key_press_and_hold_per_app: Option<Vec<String>>,
     /// Delay before repetition starts. Lower value means shorter wait time
     /// before repeat starts.
     pub initial_key_repeat_wait: Option<i32>,
@@ -50,22 +89,937 @@ pub struct SystemSettings {
     /// Automatically capitalizes the first letter of a new sentence and proper
     /// nouns as you type. How annoying.
     pub automatic_capitalization: Option<bool>,
+    /// Stop window backgrounds from being tinted with the colors of the
+    /// desktop wallpaper.
+    pub reduce_desktop_tinting: Option<bool>,
+    /// Move a window by holding Ctrl+Cmd and dragging anywhere inside it,
+    /// rather than only by its title bar.
+    pub drag_window_with_gesture: Option<bool>,
 }
 
 /// Magic Mouse configuration.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct MagicMouse {
     pub mouse_button_mode: Option<MouseButtonMode>,
 }
 
+/// Trackpad configuration. Natural scrolling is shared with the mouse, and
+/// set with [`SystemSettings::natural_scrolling`].
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Trackpad {
+    /// Tap with one finger to click, rather than pressing down. Written to
+    /// both trackpad domains.
+    pub tap_to_click: Option<bool>,
+    /// How far the pointer moves for the same movement on the trackpad, from
+    /// 0.0 to 3.0. Written to `NSGlobalDomain`.
+    pub tracking_speed: Option<f64>,
+    /// Swipe left or right with two fingers to go back and forward a page.
+    /// Written to `NSGlobalDomain`.
+    pub swipe_between_pages: Option<bool>,
+    /// Swipe left or right with four fingers to move between full-screen apps
+    /// and Spaces. Written to both trackpad domains.
+    pub swipe_between_apps: Option<bool>,
+    /// Pinch with thumb and three fingers to open Launchpad. Written to both
+    /// trackpad domains and `com.apple.dock`.
+    pub launchpad_pinch: Option<bool>,
+}
+
+/// Keyboard configuration. Key repeat is set with [`SystemSettings`].
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Keyboard {
+    /// Move focus between all controls with Tab, rather than only text fields
+    /// and lists.
+    pub full_keyboard_access: Option<bool>,
+    /// Use F1, F2, etc. as standard function keys, holding Fn for the special
+    /// features printed on them, like brightness and volume.
+    pub use_f_keys_as_standard: Option<bool>,
+}
+
+/// The `AppleKeyboardUIMode` for full keyboard access, which is 0 when it's
+/// off.
+const FULL_KEYBOARD_ACCESS_ON: i32 = 2;
+
+fn full_keyboard_access(enabled: bool) -> i32 {
+    if enabled { FULL_KEYBOARD_ACCESS_ON } else { 0 }
+}
+
+/// The built-in and Bluetooth trackpads keep their gesture settings in separate
+/// domains, and a gesture is only reliably on or off when both agree.
+const TRACKPAD_DOMAINS: [&str; 2] = [
+    "com.apple.AppleMultitouchTrackpad",
+    "com.apple.driver.AppleBluetoothMultitouch.trackpad",
+];
+
+/// The value the trackpad domains use for a gesture that's turned on. Turned
+/// off is 0, 1 means a three-finger variant of the gesture instead.
+const TRACKPAD_GESTURE_ON: i32 = 2;
+
+fn trackpad_gesture(enabled: bool) -> i32 {
+    if enabled { TRACKPAD_GESTURE_ON } else { 0 }
+}
+
 /// Finder configuration.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Finder {
+    /// Show the extension of every file. This is a global setting, but it's
+    /// mostly Finder that it affects.
+    pub show_file_extensions: Option<bool>,
     /// Display directory breadcrumbs at the bottom of the finder window.
     pub show_pathbar: Option<bool>,
     pub show_full_posix_path_in_title_bar: Option<bool>,
+    /// Set to false for a clean desktop, with no icons on it at all.
+    pub show_desktop_icons: Option<bool>,
+    /// How the icons on the desktop are sorted and snapped.
+    pub arrange_by: Option<DesktopArrangement>,
+    /// Ask for confirmation before emptying the Trash.
+    pub warn_before_emptying_trash: Option<bool>,
+    /// Delete items that have been in the Trash for 30 days.
+    pub remove_old_trash_items: Option<bool>,
+    /// Sort folders before files in windows and on the desktop.
+    pub keep_folders_on_top: Option<bool>,
+    pub default_search_scope: Option<SearchScope>,
+}
+
+/// Pointer accessibility configuration.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Pointer {
+    /// Size of the mouse cursor, from 1.0 (normal) to 4.0 (largest).
+    pub cursor_size: Option<f64>,
+    /// Zoom the screen by holding a modifier key (^ by default) and scrolling.
+    pub scroll_to_zoom: Option<bool>,
+}
+
+/// Menu bar configuration.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MenuBar {
+    pub show_battery_percentage: Option<bool>,
+}
+
+/// Which items Control Center shows in the menu bar. macOS renames these keys
+/// between versions (Focus was `DoNotDisturb` before Monterey, for one), so
+/// these are the names current versions use.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ControlCenter {
+    pub bluetooth: Option<bool>,
+    pub sound: Option<bool>,
+    pub now_playing: Option<bool>,
+    pub battery: Option<bool>,
+    pub focus: Option<bool>,
+}
+
+impl ControlCenter {
+    /// Each configured item, paired with its `com.apple.controlcenter` key.
+    fn items(&self) -> [(&'static str, &'static str, Option<bool>); 5] {
+        [
+            (
+                "bluetooth",
+                "NSStatusItem Visible Bluetooth",
+                self.bluetooth,
+            ),
+            ("sound", "NSStatusItem Visible Sound", self.sound),
+            (
+                "now-playing",
+                "NSStatusItem Visible NowPlaying",
+                self.now_playing,
+            ),
+            ("battery", "NSStatusItem Visible Battery", self.battery),
+            ("focus", "NSStatusItem Visible FocusModes", self.focus),
+        ]
+    }
+}
+
+/// Stage Manager configuration.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct StageManager {
+    pub enabled: Option<bool>,
+    /// Hide desktop items while Stage Manager is on.
+    pub auto_hide_desktop: Option<bool>,
+    /// Show the strip of recent apps on the left of the screen.
+    pub show_recent_apps: Option<bool>,
+}
+
+/// Window tiling configuration, for the tiling added in macOS Sequoia.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WindowTiling {
+    /// Tile windows by dragging them to the left or right edge of the screen.
+    pub drag_to_edges: Option<bool>,
+    /// Fill the screen by dragging a window to the menu bar.
+    pub drag_to_menu_bar: Option<bool>,
+    /// Tile windows by holding Option while dragging them.
+    pub option_key_tiling: Option<bool>,
+    /// Leave gaps between tiled windows.
+    pub margins: Option<bool>,
+}
+
+impl WindowTiling {
+    /// The `com.apple.WindowManager` key for each setting, with its value.
+    fn keys(&self) -> [(&'static str, &'static str, Option<bool>); 4] {
+        [
+            (
+                "drag-to-edges",
+                "EnableTilingByEdgeDrag",
+                self.drag_to_edges,
+            ),
+            (
+                "drag-to-menu-bar",
+                "EnableTopTilingByEdgeDrag",
+                self.drag_to_menu_bar,
+            ),
+            (
+                "option-key-tiling",
+                "EnableTilingOptionAccelerator",
+                self.option_key_tiling,
+            ),
+            ("margins", "EnableTiledWindowMargins", self.margins),
+        ]
+    }
+}
+
+/// The first version of macOS with window tiling.
+const WINDOW_TILING_MIN_VERSION: MacOSVersion = MacOSVersion {
+    major: 15,
+    minor: 0,
+};
+
+/// How an app is restarted to pick up changed settings.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartStrategy {
+    /// `killall` the app, which macOS relaunches for the Dock and Finder. Fast,
+    /// but the app loses its open windows and tabs.
+    #[default]
+    Killall,
+    /// Ask the app to quit with AppleScript, then open it again, so it can
+    /// save and restore its window state. Apps that aren't running are left
+    /// alone.
+    Relaunch,
+}
+
+/// Sound configuration.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Sound {
+    /// Play the chime when the Mac starts up. This is stored in NVRAM rather
+    /// than preferences, so changing it needs `sudo`.
+    pub startup_chime: Option<bool>,
+}
+
+/// Software Update configuration. These are system-wide settings, so changing
+/// them needs `sudo`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SoftwareUpdate {
+    /// Check for updates automatically.
+    pub auto_check: Option<bool>,
+    /// Download updates in the background once they're found.
+    pub auto_download: Option<bool>,
+    /// Install system data files and security updates, like XProtect
+    /// definitions.
+    pub install_system_data_files: Option<bool>,
+}
+
+/// A whole preferences domain imported from a `.plist` file, as exported with
+/// `defaults export <domain> <file>`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DefaultsImport {
+    /// The domain to import into, like `com.googlecode.iterm2`.
+    pub domain: String,
+    /// The `.plist` file to import. Relative paths are relative to the
+    /// directory containing `system.toml`, and `~/` is expanded.
+    pub file: PathBuf,
+    /// The app to restart when the domain changes.
+    pub restart: Option<String>,
+}
+
+/// A `defaults` key omiros has no typed setting for, written as is with
+/// `defaults write <domain> <key> -<type> <value>`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CustomDefault {
+    pub domain: String,
+    pub key: String,
+    /// The type to write the value as.
+    #[serde(rename = "type")]
+    pub value_type: CustomDefaultType,
+    /// The value, either as a TOML value of the matching type, or a string
+    /// that parses as one.
+    pub value: toml::Value,
+    /// The app to restart when the value changes. The Dock and Finder are
+    /// restarted for `com.apple.dock` and `com.apple.finder` without it.
+    pub restart: Option<String>,
+}
+
+/// The types a [`CustomDefault`] can be written as.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CustomDefaultType {
+    Bool,
+    Int,
+    Float,
+    String,
+}
+
+/// A [`CustomDefault`]'s value, checked against its type.
+#[derive(Debug, PartialEq)]
+pub(crate) enum CustomValue {
+    Bool(bool),
+    Int(i32),
+    Float(f64),
+    String(String),
+}
+
+impl CustomDefault {
+    /// The value as its declared type, or a message saying why it isn't one.
+    pub(crate) fn typed_value(&self) -> Result<CustomValue, String> {
+        use toml::Value;
+
+        let value = match (self.value_type, &self.value) {
+            (CustomDefaultType::Bool, Value::Boolean(b)) => Some(CustomValue::Bool(*b)),
+            (CustomDefaultType::Bool, Value::String(s)) => s.parse().ok().map(CustomValue::Bool),
+            (CustomDefaultType::Int, Value::Integer(i)) => {
+                i32::try_from(*i).ok().map(CustomValue::Int)
+            }
+            (CustomDefaultType::Int, Value::String(s)) => s.parse().ok().map(CustomValue::Int),
+            (CustomDefaultType::Float, Value::Float(f)) => Some(CustomValue::Float(*f)),
+            (CustomDefaultType::Float, Value::Integer(i)) => Some(CustomValue::Float(*i as f64)),
+            (CustomDefaultType::Float, Value::String(s)) => s.parse().ok().map(CustomValue::Float),
+            (CustomDefaultType::String, Value::String(s)) => Some(CustomValue::String(s.clone())),
+            _ => None,
+        };
+        value.ok_or_else(|| {
+            format!(
+                "{}.{}: `{}` isn't a valid {}",
+                self.domain, self.key, self.value, self.value_type
+            )
+        })
+    }
+
+    /// The write this entry makes, if its value is valid.
+    fn planned_write(&self) -> Option<DefaultsWrite> {
+        let field = "macos.custom";
+        let (domain, key) = (self.domain.as_str(), self.key.as_str());
+        match self.typed_value().ok()? {
+            CustomValue::Bool(b) => DefaultsWrite::new(field, domain, key, Some(b)),
+            CustomValue::Int(i) => DefaultsWrite::new(field, domain, key, Some(i)),
+            CustomValue::Float(f) => DefaultsWrite::new(field, domain, key, Some(f)),
+            CustomValue::String(s) => DefaultsWrite::new(field, domain, key, Some(s)),
+        }
+    }
+
+    /// The app to restart when the value changes, if any.
+    fn app_to_restart(&self) -> Option<&str> {
+        match (&self.restart, self.domain.as_str()) {
+            (Some(app), _) => Some(app),
+            (None, "com.apple.dock") => Some("Dock"),
+            (None, "com.apple.finder") => Some("Finder"),
+            (None, _) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for CustomDefaultType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CustomDefaultType::Bool => "bool",
+            CustomDefaultType::Int => "int",
+            CustomDefaultType::Float => "float",
+            CustomDefaultType::String => "string",
+        })
+    }
+}
+
+/// A macOS version, as reported by `sw_vers -productVersion`.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct MacOSVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl MacOSVersion {
+    /// Parses versions like `14.5` or `10.15.7`, ignoring the patch version.
+    fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.trim().split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next().map_or(Some(0), |minor| minor.parse().ok())?;
+        Some(MacOSVersion { major, minor })
+    }
+}
+
+impl std::fmt::Display for MacOSVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Returns the version of macOS that is running.
+pub fn macos_version(runner: &dyn CommandRunner) -> Result<MacOSVersion, DefaultsError> {
+    let output = runner
+        .output("sw_vers", &["-productVersion"])
+        .map_err(|e| DefaultsError::CommandFailed(format!("failed to run sw_vers {e}")))?;
+    let version = std::str::from_utf8(&output.stdout)?;
+
+    MacOSVersion::parse(version).ok_or_else(|| {
+        DefaultsError::ParseError(format!("Could not parse macOS version: {version}"))
+    })
+}
+
+/// Checks whether this process has Full Disk Access, by listing a directory
+/// macOS only lets through with it. Without access, listing fails with
+/// "Operation not permitted" rather than a missing directory error.
+pub fn has_full_disk_access(runner: &dyn CommandRunner) -> Result<bool, DefaultsError> {
+    let home = home_dir().ok_or_else(|| {
+        DefaultsError::CommandFailed("Could not determine home directory.".to_string())
+    })?;
+    let protected = home.join("Library/Safari");
+    let output = runner
+        .output("ls", &[&protected.to_string_lossy()])
+        .map_err(|e| DefaultsError::CommandFailed(format!("failed to run ls {e}")))?;
+
+    Ok(output.success() || !String::from_utf8_lossy(&output.stderr).contains("not permitted"))
+}
+
+/// Warns up front when `sections` need Full Disk Access and this process
+/// doesn't have it, so the permission errors they fail with later make sense.
+pub fn warn_if_missing_full_disk_access(
+    runner: &dyn CommandRunner,
+    sections: &[&str],
+) -> Result<(), DefaultsError> {
+    if sections.is_empty() || has_full_disk_access(runner)? {
+        return Ok(());
+    }
+
+    warn!(
+        "⚠️  This terminal doesn't have Full Disk Access, so these settings will fail with permission errors: {}",
+        sections.join(", ")
+    );
+    info!(
+        "💡 Grant it in System Settings > Privacy & Security > Full Disk Access, then restart the terminal"
+    );
+
+    Ok(())
+}
+
+/// Every `defaults` value the `[macos]` section writes, as described by the
+/// configuration alone. Settings whose key depends on the version of macOS,
+/// like the battery percentage, are listed under the key current versions use.
+/// Nested values written with PlistBuddy and imported domains aren't included.
+pub(crate) fn planned_writes(macos: &MacOS) -> Vec<DefaultsWrite> {
+    let mut writes = Vec::new();
+
+    if let Some(dock) = &macos.dock {
+        writes.extend([
+            DefaultsWrite::new(
+                "macos.dock.orientation",
+                "com.apple.dock",
+                "orientation",
+                dock.orientation,
+            ),
+            DefaultsWrite::new(
+                "macos.dock.autohide",
+                "com.apple.dock",
+                "autohide",
+                dock.autohide,
+            ),
+            DefaultsWrite::new(
+                "macos.dock.autohide-time-modifier",
+                "com.apple.dock",
+                "autohide-time-modifier",
+                dock.autohide_time_modifier,
+            ),
+            DefaultsWrite::new(
+                "macos.dock.icon-size",
+                "com.apple.dock",
+                "tilesize",
+                dock.icon_size,
+            ),
+            DefaultsWrite::new(
+                "macos.dock.transparent-hidden-app-icons",
+                "com.apple.dock",
+                "showhidden",
+                dock.transparent_hidden_app_icons,
+            ),
+            DefaultsWrite::new(
+                "macos.dock.contents-immutable",
+                "com.apple.dock",
+                "contents-immutable",
+                dock.contents_immutable,
+            ),
+            DefaultsWrite::new(
+                "macos.dock.size-immutable",
+                "com.apple.dock",
+                "size-immutable",
+                dock.size_immutable,
+            ),
+            DefaultsWrite::new(
+                "macos.dock.show-recents",
+                "com.apple.dock",
+                "show-recents",
+                dock.show_recents,
+            ),
+            DefaultsWrite::new(
+                "macos.dock.minimize-effect",
+                "com.apple.dock",
+                "mineffect",
+                dock.minimize_effect,
+            ),
+            DefaultsWrite::new(
+                "macos.dock.animate-opening",
+                "com.apple.dock",
+                "launchanim",
+                dock.animate_opening,
+            ),
+        ]);
+    }
+    if let Some(mission_control) = &macos.mission_control {
+        writes.extend([
+            DefaultsWrite::new(
+                "macos.mission-control.automatically-rearrange-spaces",
+                "com.apple.dock",
+                "mru-spaces",
+                mission_control.automatically_rearrange_spaces,
+            ),
+            DefaultsWrite::new(
+                "macos.mission-control.group-apps",
+                "com.apple.dock",
+                "expose-group-apps",
+                mission_control.group_apps,
+            ),
+        ]);
+    }
+    if let Some(safari) = &macos.safari {
+        writes.push(DefaultsWrite::new(
+            "macos.safari.show-full-url",
+            "com.apple.Safari",
+            "ShowFullURLInSmartSearchField",
+            safari.show_full_url,
+        ));
+    }
+    if let Some(system) = &macos.system {
+        let global = "NSGlobalDomain";
+        writes.extend([
+            DefaultsWrite::new(
+                "macos.system.natural-scrolling",
+                global,
+                "com.apple.swipescrolldirection",
+                system.natural_scrolling,
+            ),
+            DefaultsWrite::new(
+                "macos.system.key-press-and-hold",
+                global,
+                "ApplePressAndHoldEnabled",
+                system.key_press_and_hold,
+            ),
+            DefaultsWrite::new(
+                "macos.system.initial-key-repeat-wait",
+                global,
+                "InitialKeyRepeat",
+                system.initial_key_repeat_wait,
+            ),
+            DefaultsWrite::new(
+                "macos.system.key-repeat-rate",
+                global,
+                "KeyRepeat",
+                system.key_repeat_rate,
+            ),
+            DefaultsWrite::new(
+                "macos.system.automatic-capitalization",
+                global,
+                "NSAutomaticCapitalizationEnabled",
+                system.automatic_capitalization,
+            ),
+            DefaultsWrite::new(
+                "macos.system.reduce-desktop-tinting",
+                global,
+                "AppleReduceDesktopTinting",
+                system.reduce_desktop_tinting,
+            ),
+            DefaultsWrite::new(
+                "macos.system.drag-window-with-gesture",
+                global,
+                "NSWindowShouldDragOnGesture",
+                system.drag_window_with_gesture,
+            ),
+        ]);
+        for bundle_id in system.key_press_and_hold_per_app.iter().flatten() {
+            writes.push(DefaultsWrite::new(
+                "macos.system.key-press-and-hold-per-app",
+                bundle_id,
+                "ApplePressAndHoldEnabled",
+                Some(false),
+            ));
+        }
+    }
+    if let Some(magic_mouse) = &macos.magic_mouse {
+        writes.push(DefaultsWrite::new(
+            "macos.magic-mouse.mouse-button-mode",
+            "com.apple.AppleMultitouchMouse",
+            "MouseButtonMode",
+            magic_mouse.mouse_button_mode,
+        ));
+    }
+    if let Some(trackpad) = &macos.trackpad {
+        writes.extend([
+            DefaultsWrite::new(
+                "macos.trackpad.tracking-speed",
+                "NSGlobalDomain",
+                "com.apple.trackpad.scaling",
+                trackpad.tracking_speed,
+            ),
+            DefaultsWrite::new(
+                "macos.trackpad.swipe-between-pages",
+                "NSGlobalDomain",
+                "AppleEnableSwipeNavigateWithScrolls",
+                trackpad.swipe_between_pages,
+            ),
+        ]);
+        for domain in TRACKPAD_DOMAINS {
+            writes.extend([
+                DefaultsWrite::new(
+                    "macos.trackpad.tap-to-click",
+                    domain,
+                    "Clicking",
+                    trackpad.tap_to_click,
+                ),
+                DefaultsWrite::new(
+                    "macos.trackpad.swipe-between-apps",
+                    domain,
+                    "TrackpadFourFingerHorizSwipeGesture",
+                    trackpad.swipe_between_apps.map(trackpad_gesture),
+                ),
+                DefaultsWrite::new(
+                    "macos.trackpad.launchpad-pinch",
+                    domain,
+                    "TrackpadFiveFingerPinchGesture",
+                    trackpad.launchpad_pinch.map(trackpad_gesture),
+                ),
+            ]);
+        }
+        writes.push(DefaultsWrite::new(
+            "macos.trackpad.launchpad-pinch",
+            "com.apple.dock",
+            "showLaunchpadGestureEnabled",
+            trackpad.launchpad_pinch,
+        ));
+    }
+    if let Some(keyboard) = &macos.keyboard {
+        writes.extend([
+            DefaultsWrite::new(
+                "macos.keyboard.full-keyboard-access",
+                "NSGlobalDomain",
+                "AppleKeyboardUIMode",
+                keyboard.full_keyboard_access.map(full_keyboard_access),
+            ),
+            DefaultsWrite::new(
+                "macos.keyboard.use-f-keys-as-standard",
+                "NSGlobalDomain",
+                "com.apple.keyboard.fnState",
+                keyboard.use_f_keys_as_standard,
+            ),
+        ]);
+    }
+    if let Some(finder) = &macos.finder {
+        writes.extend([
+            DefaultsWrite::new(
+                "macos.finder.show-file-extensions",
+                "NSGlobalDomain",
+                "AppleShowAllExtensions",
+                finder.show_file_extensions,
+            ),
+            DefaultsWrite::new(
+                "macos.finder.show-pathbar",
+                "com.apple.finder",
+                "ShowPathbar",
+                finder.show_pathbar,
+            ),
+            DefaultsWrite::new(
+                "macos.finder.show-full-posix-path-in-title-bar",
+                "com.apple.finder",
+                "_FXShowPosixPathInTitle",
+                finder.show_full_posix_path_in_title_bar,
+            ),
+            DefaultsWrite::new(
+                "macos.finder.show-desktop-icons",
+                "com.apple.finder",
+                "CreateDesktop",
+                finder.show_desktop_icons,
+            ),
+            DefaultsWrite::new(
+                "macos.finder.warn-before-emptying-trash",
+                "com.apple.finder",
+                "WarnOnEmptyTrash",
+                finder.warn_before_emptying_trash,
+            ),
+            DefaultsWrite::new(
+                "macos.finder.remove-old-trash-items",
+                "com.apple.finder",
+                "FXRemoveOldTrashItems",
+                finder.remove_old_trash_items,
+            ),
+            DefaultsWrite::new(
+                "macos.finder.keep-folders-on-top",
+                "com.apple.finder",
+                "_FXSortFoldersFirst",
+                finder.keep_folders_on_top,
+            ),
+            DefaultsWrite::new(
+                "macos.finder.default-search-scope",
+                "com.apple.finder",
+                "FXDefaultSearchScope",
+                finder.default_search_scope,
+            ),
+        ]);
+    }
+    if let Some(pointer) = &macos.pointer {
+        writes.extend([
+            DefaultsWrite::new(
+                "macos.pointer.cursor-size",
+                "com.apple.universalaccess",
+                "mouseDriverCursorSize",
+                pointer.cursor_size,
+            ),
+            DefaultsWrite::new(
+                "macos.pointer.scroll-to-zoom",
+                "com.apple.universalaccess",
+                "closeViewScrollWheelToggle",
+                pointer.scroll_to_zoom,
+            ),
+        ]);
+    }
+    if let Some(menu_bar) = &macos.menu_bar {
+        writes.push(DefaultsWrite::new(
+            "macos.menu-bar.show-battery-percentage",
+            "com.apple.controlcenter",
+            "BatteryShowPercentage",
+            menu_bar.show_battery_percentage,
+        ));
+    }
+    if let Some(control_center) = &macos.control_center {
+        for (field, key, visible) in control_center.items() {
+            writes.push(DefaultsWrite::new(
+                &format!("macos.control-center.{field}"),
+                "com.apple.controlcenter",
+                key,
+                visible,
+            ));
+        }
+    }
+    if let Some(window_tiling) = &macos.window_tiling {
+        for (field, key, value) in window_tiling.keys() {
+            writes.push(DefaultsWrite::new(
+                &format!("macos.window-tiling.{field}"),
+                "com.apple.WindowManager",
+                key,
+                value,
+            ));
+        }
+    }
+    if let Some(stage_manager) = &macos.stage_manager {
+        writes.extend([
+            DefaultsWrite::new(
+                "macos.stage-manager.enabled",
+                "com.apple.WindowManager",
+                "GloballyEnabled",
+                stage_manager.enabled,
+            ),
+            DefaultsWrite::new(
+                "macos.stage-manager.auto-hide-desktop",
+                "com.apple.WindowManager",
+                "HideDesktop",
+                stage_manager.auto_hide_desktop,
+            ),
+            DefaultsWrite::new(
+                "macos.stage-manager.show-recent-apps",
+                "com.apple.WindowManager",
+                "AutoHide",
+                stage_manager.show_recent_apps.map(|show| !show),
+            ),
+        ]);
+    }
+    if let Some(software_update) = &macos.software_update {
+        writes.extend([
+            DefaultsWrite::new(
+                "macos.software-update.auto-check",
+                SOFTWARE_UPDATE_DOMAIN,
+                "AutomaticCheckEnabled",
+                software_update.auto_check,
+            ),
+            DefaultsWrite::new(
+                "macos.software-update.auto-download",
+                SOFTWARE_UPDATE_DOMAIN,
+                "AutomaticDownload",
+                software_update.auto_download,
+            ),
+            DefaultsWrite::new(
+                "macos.software-update.install-system-data-files",
+                SOFTWARE_UPDATE_DOMAIN,
+                "ConfigDataInstall",
+                software_update.install_system_data_files,
+            ),
+        ]);
+    }
+
+    for custom in macos.custom.iter().flatten() {
+        writes.push(custom.planned_write());
+    }
+
+    writes.into_iter().flatten().collect()
+}
+
+/// `section`, or `None` when none of its settings are set, so it's left out of
+/// an exported configuration entirely.
+fn non_empty<T: Serialize>(section: T) -> Option<T> {
+    match toml::Table::try_from(&section) {
+        Ok(table) if table.is_empty() => None,
+        _ => Some(section),
+    }
+}
+
+/// The current value of every setting the `[macos]` section manages, read
+/// back with `defaults`. Like [`planned_writes`], settings whose key depends on
+/// the version of macOS are read from the key current versions use, and nested
+/// values and per-app settings are left out.
+pub fn read_current_settings(runner: &dyn CommandRunner) -> MacOS {
+    let read_bool = |domain, key| read_setting::<bool>(runner, domain, key);
+    let global = "NSGlobalDomain";
+
+    let control_center = ControlCenter {
+        bluetooth: None,
+        sound: None,
+        now_playing: None,
+        battery: None,
+        focus: None,
+    };
+    let [bluetooth, sound, now_playing, battery, focus] = control_center
+        .items()
+        .map(|(_, key, _)| read_bool("com.apple.controlcenter", key));
+
+    MacOS {
+        dock: non_empty(Dock {
+            orientation: read_setting(runner, "com.apple.dock", "orientation"),
+            autohide: read_bool("com.apple.dock", "autohide"),
+            autohide_time_modifier: read_setting(
+                runner,
+                "com.apple.dock",
+                "autohide-time-modifier",
+            ),
+            icon_size: read_setting(runner, "com.apple.dock", "tilesize"),
+            transparent_hidden_app_icons: read_bool("com.apple.dock", "showhidden"),
+            contents_immutable: read_bool("com.apple.dock", "contents-immutable"),
+            size_immutable: read_bool("com.apple.dock", "size-immutable"),
+            show_recents: read_bool("com.apple.dock", "show-recents"),
+            minimize_effect: read_setting(runner, "com.apple.dock", "mineffect"),
+            animate_opening: read_bool("com.apple.dock", "launchanim"),
+            persistent_apps: read_dock_persistent_apps(runner)
+                .ok()
+                .filter(|apps| !apps.is_empty()),
+            clear_other_apps: None,
+        }),
+        safari: non_empty(Safari {
+            show_full_url: read_bool("com.apple.Safari", "ShowFullURLInSmartSearchField"),
+        }),
+        system: non_empty(SystemSettings {
+            natural_scrolling: read_bool(global, "com.apple.swipescrolldirection"),
+            key_press_and_hold: read_bool(global, "ApplePressAndHoldEnabled"),
+            key_press_and_hold_per_app: None,
+            initial_key_repeat_wait: read_setting(runner, global, "InitialKeyRepeat"),
+            key_repeat_rate: read_setting(runner, global, "KeyRepeat"),
+            automatic_capitalization: read_bool(global, "NSAutomaticCapitalizationEnabled"),
+            reduce_desktop_tinting: read_bool(global, "AppleReduceDesktopTinting"),
+            drag_window_with_gesture: read_bool(global, "NSWindowShouldDragOnGesture"),
+        }),
+        mission_control: non_empty(MissionControl {
+            automatically_rearrange_spaces: read_bool("com.apple.dock", "mru-spaces"),
+            group_apps: read_bool("com.apple.dock", "expose-group-apps"),
+        }),
+        magic_mouse: non_empty(MagicMouse {
+            mouse_button_mode: read_setting(
+                runner,
+                "com.apple.AppleMultitouchMouse",
+                "MouseButtonMode",
+            ),
+        }),
+        trackpad: non_empty(Trackpad {
+            tap_to_click: read_bool(TRACKPAD_DOMAINS[0], "Clicking"),
+            tracking_speed: read_setting(runner, global, "com.apple.trackpad.scaling"),
+            swipe_between_pages: read_bool(global, "AppleEnableSwipeNavigateWithScrolls"),
+            swipe_between_apps: read_setting::<i32>(
+                runner,
+                TRACKPAD_DOMAINS[0],
+                "TrackpadFourFingerHorizSwipeGesture",
+            )
+            .map(|gesture| gesture == TRACKPAD_GESTURE_ON),
+            launchpad_pinch: read_bool("com.apple.dock", "showLaunchpadGestureEnabled"),
+        }),
+        keyboard: non_empty(Keyboard {
+            full_keyboard_access: read_setting::<i32>(runner, global, "AppleKeyboardUIMode")
+                .map(|mode| mode != 0),
+            use_f_keys_as_standard: read_bool(global, "com.apple.keyboard.fnState"),
+        }),
+        finder: non_empty(Finder {
+            show_file_extensions: read_bool(global, "AppleShowAllExtensions"),
+            show_pathbar: read_bool("com.apple.finder", "ShowPathbar"),
+            show_full_posix_path_in_title_bar: read_bool(
+                "com.apple.finder",
+                "_FXShowPosixPathInTitle",
+            ),
+            show_desktop_icons: read_bool("com.apple.finder", "CreateDesktop"),
+            arrange_by: None,
+            warn_before_emptying_trash: read_bool("com.apple.finder", "WarnOnEmptyTrash"),
+            remove_old_trash_items: read_bool("com.apple.finder", "FXRemoveOldTrashItems"),
+            keep_folders_on_top: read_bool("com.apple.finder", "_FXSortFoldersFirst"),
+            default_search_scope: read_setting(runner, "com.apple.finder", "FXDefaultSearchScope"),
+        }),
+        pointer: non_empty(Pointer {
+            cursor_size: read_setting(runner, "com.apple.universalaccess", "mouseDriverCursorSize"),
+            scroll_to_zoom: read_bool("com.apple.universalaccess", "closeViewScrollWheelToggle"),
+        }),
+        menu_bar: non_empty(MenuBar {
+            show_battery_percentage: read_bool("com.apple.controlcenter", "BatteryShowPercentage"),
+        }),
+        window_tiling: non_empty(WindowTiling {
+            drag_to_edges: read_bool("com.apple.WindowManager", "EnableTilingByEdgeDrag"),
+            drag_to_menu_bar: read_bool("com.apple.WindowManager", "EnableTopTilingByEdgeDrag"),
+            option_key_tiling: read_bool(
+                "com.apple.WindowManager",
+                "EnableTilingOptionAccelerator",
+            ),
+            margins: read_bool("com.apple.WindowManager", "EnableTiledWindowMargins"),
+        }),
+        stage_manager: non_empty(StageManager {
+            enabled: read_bool("com.apple.WindowManager", "GloballyEnabled"),
+            auto_hide_desktop: read_bool("com.apple.WindowManager", "HideDesktop"),
+            show_recent_apps: read_bool("com.apple.WindowManager", "AutoHide").map(|hide| !hide),
+        }),
+        control_center: non_empty(ControlCenter {
+            bluetooth,
+            sound,
+            now_playing,
+            battery,
+            focus,
+        }),
+        sound: non_empty(Sound {
+            startup_chime: startup_muted(runner).ok().map(|muted| !muted),
+        }),
+        software_update: non_empty(SoftwareUpdate {
+            auto_check: read_bool(SOFTWARE_UPDATE_DOMAIN, "AutomaticCheckEnabled"),
+            auto_download: read_bool(SOFTWARE_UPDATE_DOMAIN, "AutomaticDownload"),
+            install_system_data_files: read_bool(SOFTWARE_UPDATE_DOMAIN, "ConfigDataInstall"),
+        }),
+        import: None,
+        custom: None,
+        restart_strategy: RestartStrategy::default(),
+        when: None,
+    }
 }
 
 /// Represents the possible errors that can occur when applying macOS settings.
@@ -80,178 +1034,1671 @@ pub enum MacOSError {
 }
 
 /// Applies the Dock settings.
-pub fn apply_dock_settings(dock: &Dock) -> Result<bool, DefaultsError> {
-    let mut changed = false;
+pub fn apply_dock_settings(
+    runner: &dyn CommandRunner,
+    dock: &Dock,
+) -> Result<Changes, DefaultsError> {
+    let mut changes = Changes::default();
 
     if let Some(orientation) = dock.orientation {
-        changed |= write_defaults("com.apple.dock", "orientation", orientation)?;
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.dock",
+            "orientation",
+            orientation,
+        )?);
     }
 
     if let Some(autohide) = dock.autohide {
-        changed |= write_defaults("com.apple.dock", "autohide", autohide)?;
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.dock",
+            "autohide",
+            autohide,
+        )?);
+    }
+
+    if let Some(modifier) = dock.autohide_time_modifier {
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.dock",
+            "autohide-time-modifier",
+            modifier,
+        )?);
     }
 
     if let Some(icon_size) = dock.icon_size {
-        changed |= write_defaults("com.apple.dock", "tilesize", icon_size)?;
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.dock",
+            "tilesize",
+            icon_size,
+        )?);
     }
 
     if let Some(showhidden) = dock.transparent_hidden_app_icons {
-        changed |= write_defaults("com.apple.dock", "showhidden", showhidden)?;
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.dock",
+            "showhidden",
+            showhidden,
+        )?);
+    }
+
+    if let Some(contents_immutable) = dock.contents_immutable {
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.dock",
+            "contents-immutable",
+            contents_immutable,
+        )?);
+    }
+
+    if let Some(size_immutable) = dock.size_immutable {
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.dock",
+            "size-immutable",
+            size_immutable,
+        )?);
+    }
+
+    if let Some(show_recents) = dock.show_recents {
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.dock",
+            "show-recents",
+            show_recents,
+        )?);
     }
 
-    Ok(changed)
+    if let Some(minimize_effect) = dock.minimize_effect {
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.dock",
+            "mineffect",
+            minimize_effect,
+        )?);
+    }
+
+    if let Some(animate_opening) = dock.animate_opening {
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.dock",
+            "launchanim",
+            animate_opening,
+        )?);
+    }
+
+    if let Some(persistent_apps) = &dock.persistent_apps {
+        changes.merge(write_dock_persistent_apps(
+            runner,
+            persistent_apps,
+            dock.clear_other_apps.unwrap_or(true),
+        )?);
+    }
+
+    Ok(changes)
 }
 
 /// Applies the Mission Control settings.
 pub fn apply_mission_control_settings(
+    runner: &dyn CommandRunner,
     mission_control: &MissionControl,
-) -> Result<bool, DefaultsError> {
-    let mut changed = false;
+) -> Result<Changes, DefaultsError> {
+    let mut changes = Changes::default();
 
     if let Some(rearrange) = mission_control.automatically_rearrange_spaces {
-        changed |= write_defaults("com.apple.dock", "mru-spaces", rearrange)?;
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.dock",
+            "mru-spaces",
+            rearrange,
+        )?);
     }
 
     if let Some(group_apps) = mission_control.group_apps {
-        changed |= write_defaults("com.apple.dock", "expose-group-apps", group_apps)?;
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.dock",
+            "expose-group-apps",
+            group_apps,
+        )?);
     }
 
-    Ok(changed)
+    Ok(changes)
 }
 
 /// Restarts the Dock.
-pub fn restart_dock() -> Result<(), DefaultsError> {
-    println!("Restarting Dock to apply changes...");
-    Command::new("killall")
-        .arg("Dock")
-        .status()
-        .map_err(|e| DefaultsError::CommandFailed(format!("failed to kill Dock {e}")))?;
+pub fn restart_dock(
+    runner: &dyn CommandRunner,
+    strategy: RestartStrategy,
+) -> Result<(), DefaultsError> {
+    restart_app(runner, "Dock", strategy)
+}
+
+/// Restarts `app` so it picks up changed settings, the same way for every
+/// section, using `strategy`.
+pub fn restart_app(
+    runner: &dyn CommandRunner,
+    app: &str,
+    strategy: RestartStrategy,
+) -> Result<(), DefaultsError> {
+    info!("Restarting {app} to apply changes...");
+    match strategy {
+        RestartStrategy::Killall => {
+            runner
+                .status("killall", &[app])
+                .map_err(|e| DefaultsError::CommandFailed(format!("failed to kill {app} {e}")))?;
+        }
+        RestartStrategy::Relaunch => {
+            let running = runner
+                .output("pgrep", &["-x", app])
+                .map_err(|e| DefaultsError::CommandFailed(format!("failed to run pgrep {e}")))?;
+            if !running.success() {
+                info!("ℹ️  {app} isn't running, it'll pick up the changes when it's next opened");
+                return Ok(());
+            }
+
+            plist_output(runner, "osascript", &["-e", &format!("quit app \"{app}\"")])?;
+            plist_output(runner, "open", &["-a", app])?;
+        }
+    }
+
     Ok(())
 }
 
 /// Applies the Safari settings.
-pub fn apply_safari_settings(safari: &Safari) -> Result<(), DefaultsError> {
-    let mut changed = false;
-
+pub fn apply_safari_settings(
+    runner: &dyn CommandRunner,
+    safari: &Safari,
+    restart: RestartStrategy,
+) -> Result<Changes, DefaultsError> {
+    let mut changes = Changes::default();
     if let Some(show_full_url) = safari.show_full_url {
-        changed |= write_defaults(
+        changes.merge(write_defaults(
+            runner,
             "com.apple.Safari",
             "ShowFullURLInSmartSearchField",
             show_full_url,
-        )?;
+        )?);
     }
 
-    if changed {
-        println!("Restarting Safari to apply changes...");
-        Command::new("killall")
-            .arg("Safari")
-            .status()
-            .map_err(|e| DefaultsError::CommandFailed(format!("failed to kill Safari {e}")))?;
+    if changes.has_changes() {
+        restart_app(runner, "Safari", restart)?;
     }
 
-    Ok(())
+    Ok(changes)
 }
 
-/// Applies the system-wide settings.
-pub fn apply_system_settings(system: &SystemSettings) -> Result<(), DefaultsError> {
-    let mut changed = false;
-
-    // TODO: we might want to move this over to the finder section, even though
-    // this is a global configuration, because it mainly affects Finder.
-    if let Some(show_file_extensions) = system.show_file_extensions {
-        changed |= write_defaults(
-            "NSGlobalDomain",
-            "AppleShowAllExtensions",
-            show_file_extensions,
-        )?;
+/// Applies the system-wide settings, then says which of the changed ones only
+/// take effect after logging out and back in, and which apps need restarting.
+pub fn apply_system_settings(
+    runner: &dyn CommandRunner,
+    system: &SystemSettings,
+) -> Result<Changes, DefaultsError> {
+    let mut logout_required = Vec::new();
+    let mut restart_required = Vec::new();
+    let written =
+        write_system_settings(runner, system, &mut logout_required, &mut restart_required);
+    notify_logout_required(&logout_required);
+    if !restart_required.is_empty() {
+        warn!(
+            "⚠️  Restart these apps for the Press&Hold setting to take effect: {}",
+            restart_required.join(", ")
+        );
     }
+    written
+}
 
-    if let Some(natural_scrolling) = system.natural_scrolling {
-        write_defaults(
+/// Writes the system-wide settings, adding each changed one that needs a
+/// logout to take effect to `logout_required`, by its configuration name, and
+/// the bundle ID of each app whose own setting changed to `restart_required`,
+/// since only the app itself needs restarting for those.
+fn write_system_settings<'a>(
+    runner: &dyn CommandRunner,
+    system: &'a SystemSettings,
+    logout_required: &mut Vec<&'a str>,
+    restart_required: &mut Vec<&'a str>,
+) -> Result<Changes, DefaultsError> {
+    let mut changes = Changes::default();
+    if let Some(natural_scrolling) = system.natural_scrolling
+        && changes.merge(write_defaults(
+            runner,
             "NSGlobalDomain",
             "com.apple.swipescrolldirection",
             natural_scrolling,
-        )?;
-        // Logout, login, or System restart required. TODO: somehow signify that this needs to happen in the output.
+        )?)
+    {
+        logout_required.push("natural-scrolling");
     }
 
-    if let Some(key_press_and_hold) = system.key_press_and_hold {
-        write_defaults(
+    if let Some(key_press_and_hold) = system.key_press_and_hold
+        && changes.merge(write_defaults(
+            runner,
             "NSGlobalDomain",
             "ApplePressAndHoldEnabled",
             key_press_and_hold,
-        )?;
-        // Logout, login, or System restart required. TODO: somehow signify that this needs to happen in the output.
+        )?)
+    {
+        logout_required.push("key-press-and-hold");
+    }
+
+    let mut failed_apps = Vec::new();
+    for bundle_id in system.key_press_and_hold_per_app.iter().flatten() {
+        match write_defaults(runner, bundle_id, "ApplePressAndHoldEnabled", false) {
+            Ok(written) => {
+                if changes.merge(written) {
+                    restart_required.push(bundle_id.as_str());
+                }
+            }
+            Err(e) => {
+                error!("❌ Failed to turn off Press&Hold for {bundle_id}: {e}");
+                failed_apps.push(bundle_id.as_str());
+            }
+        }
     }
 
-    if let Some(initial_key_repeat_wait) = system.initial_key_repeat_wait {
-        write_defaults(
+    if let Some(initial_key_repeat_wait) = system.initial_key_repeat_wait
+        && changes.merge(write_defaults(
+            runner,
             "NSGlobalDomain",
             "InitialKeyRepeat",
             initial_key_repeat_wait,
-        )?;
-        // Logout, login, or System restart required. TODO: somehow signify that this needs to happen in the output.
+        )?)
+    {
+        logout_required.push("initial-key-repeat-wait");
     }
 
-    if let Some(key_repeat_rate) = system.key_repeat_rate {
-        write_defaults("NSGlobalDomain", "KeyRepeat", key_repeat_rate)?;
-        // Logout, login, or System restart required. TODO: somehow signify that this needs to happen in the output.
+    if let Some(key_repeat_rate) = system.key_repeat_rate
+        && changes.merge(write_defaults(
+            runner,
+            "NSGlobalDomain",
+            "KeyRepeat",
+            key_repeat_rate,
+        )?)
+    {
+        logout_required.push("key-repeat-rate");
     }
 
     if let Some(automatic_capitalization) = system.automatic_capitalization {
-        write_defaults(
+        changes.merge(write_defaults(
+            runner,
             "NSGlobalDomain",
             "NSAutomaticCapitalizationEnabled",
             automatic_capitalization,
-        )?;
+        )?);
         // No logout or restart needed, update happens immediately.
     }
 
-    if changed {
-        println!("Restarting Finder to apply changes...");
-        Command::new("killall")
-            .arg("Finder")
-            .status()
-            .map_err(|e| DefaultsError::CommandFailed(format!("failed to kill Finder {e}")))?;
+    // Both of these are only read when an app launches.
+    if let Some(reduce_desktop_tinting) = system.reduce_desktop_tinting
+        && changes.merge(write_defaults(
+            runner,
+            "NSGlobalDomain",
+            "AppleReduceDesktopTinting",
+            reduce_desktop_tinting,
+        )?)
+    {
+        logout_required.push("reduce-desktop-tinting");
     }
 
-    Ok(())
+    if let Some(drag_window_with_gesture) = system.drag_window_with_gesture
+        && changes.merge(write_defaults(
+            runner,
+            "NSGlobalDomain",
+            "NSWindowShouldDragOnGesture",
+            drag_window_with_gesture,
+        )?)
+    {
+        logout_required.push("drag-window-with-gesture");
+    }
+
+    if failed_apps.is_empty() {
+        Ok(changes)
+    } else {
+        Err(DefaultsError::CommandFailed(format!(
+            "could not turn off Press&Hold for {}",
+            failed_apps.join(", ")
+        )))
+    }
 }
 
-pub fn apply_magic_mouse_settings(magic_mouse: &MagicMouse) -> Result<(), DefaultsError> {
+/// Tells the user which settings only take effect after logging out and back in.
+fn notify_logout_required(settings: &[&str]) {
+    if !settings.is_empty() {
+        warn!(
+            "⚠️  Log out and back in for these settings to take effect: {}",
+            settings.join(", ")
+        );
+    }
+}
+
+/// Applies the pointer accessibility settings.
+pub fn apply_pointer_settings(
+    runner: &dyn CommandRunner,
+    pointer: &Pointer,
+) -> Result<Changes, DefaultsError> {
+    let mut changes = Changes::default();
+    let mut logout_required = Vec::new();
+
+    if let Some(cursor_size) = pointer.cursor_size {
+        if !(1.0..=4.0).contains(&cursor_size) {
+            return Err(DefaultsError::InvalidValue(format!(
+                "macos.pointer.cursor-size must be between 1.0 and 4.0, got {cursor_size}"
+            )));
+        }
+        if changes.merge(write_defaults(
+            runner,
+            "com.apple.universalaccess",
+            "mouseDriverCursorSize",
+            cursor_size,
+        )?) {
+            logout_required.push("cursor-size");
+        }
+    }
+
+    if let Some(scroll_to_zoom) = pointer.scroll_to_zoom
+        && changes.merge(write_defaults(
+            runner,
+            "com.apple.universalaccess",
+            "closeViewScrollWheelToggle",
+            scroll_to_zoom,
+        )?)
+    {
+        logout_required.push("scroll-to-zoom");
+    }
+
+    notify_logout_required(&logout_required);
+
+    Ok(changes)
+}
+
+/// Applies the menu bar settings.
+pub fn apply_menu_bar_settings(
+    runner: &dyn CommandRunner,
+    menu_bar: &MenuBar,
+) -> Result<Changes, DefaultsError> {
+    let mut changes = Changes::default();
+    if let Some(show_battery_percentage) = menu_bar.show_battery_percentage {
+        // Big Sur moved the battery menu extra into Control Center, which
+        // stores this setting as a bool under a different domain and key. The
+        // old menu extra stored it as a "YES"/"NO" string.
+        let (changed, app) = if macos_version(runner)?.major >= 11 {
+            let changed = changes.merge(write_defaults(
+                runner,
+                "com.apple.controlcenter",
+                "BatteryShowPercentage",
+                show_battery_percentage,
+            )?);
+            (changed, "ControlCenter")
+        } else {
+            let show_percent = if show_battery_percentage { "YES" } else { "NO" };
+            let changed = changes.merge(write_defaults(
+                runner,
+                "com.apple.menuextra.battery",
+                "ShowPercent",
+                show_percent.to_string(),
+            )?);
+            (changed, "SystemUIServer")
+        };
+
+        // Menu bar agents aren't apps AppleScript can quit, and launchd
+        // relaunches them as soon as they exit anyway.
+        if changed {
+            restart_app(runner, app, RestartStrategy::Killall)?;
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Applies the Control Center menu bar settings.
+pub fn apply_control_center_settings(
+    runner: &dyn CommandRunner,
+    control_center: &ControlCenter,
+) -> Result<Changes, DefaultsError> {
+    let mut changes = Changes::default();
+    for (_, key, visible) in control_center.items() {
+        if let Some(visible) = visible {
+            changes.merge(write_defaults(
+                runner,
+                "com.apple.controlcenter",
+                key,
+                visible,
+            )?);
+        }
+    }
+
+    // ControlCenter is a menu bar agent, launchd relaunches it.
+    if changes.has_changes() {
+        restart_app(runner, "ControlCenter", RestartStrategy::Killall)?;
+    }
+
+    Ok(changes)
+}
+
+/// Applies the window tiling settings. Older versions of macOS don't have
+/// window tiling, so there they're skipped with a warning.
+pub fn apply_window_tiling_settings(
+    runner: &dyn CommandRunner,
+    window_tiling: &WindowTiling,
+) -> Result<Changes, DefaultsError> {
+    let mut changes = Changes::default();
+    let version = macos_version(runner)?;
+    if version < WINDOW_TILING_MIN_VERSION {
+        warn!(
+            "⚠️  Window tiling needs macOS {WINDOW_TILING_MIN_VERSION} or later, this is \
+             {version}, skipping `[macos.window-tiling]`"
+        );
+        return Ok(changes);
+    }
+
+    for (_, key, value) in window_tiling.keys() {
+        if let Some(value) = value {
+            changes.merge(write_defaults(
+                runner,
+                "com.apple.WindowManager",
+                key,
+                value,
+            )?);
+        }
+    }
+
+    // WindowManager is a background agent, so there's nothing to relaunch.
+    if changes.has_changes() {
+        restart_app(runner, "WindowManager", RestartStrategy::Killall)?;
+    }
+
+    Ok(changes)
+}
+
+/// Applies the Stage Manager settings.
+pub fn apply_stage_manager_settings(
+    runner: &dyn CommandRunner,
+    stage_manager: &StageManager,
+) -> Result<Changes, DefaultsError> {
+    let mut changes = Changes::default();
+    if let Some(enabled) = stage_manager.enabled {
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.WindowManager",
+            "GloballyEnabled",
+            enabled,
+        )?);
+    }
+
+    if let Some(auto_hide_desktop) = stage_manager.auto_hide_desktop {
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.WindowManager",
+            "HideDesktop",
+            auto_hide_desktop,
+        )?);
+    }
+
+    // macOS stores whether to *hide* the recent apps strip.
+    if let Some(show_recent_apps) = stage_manager.show_recent_apps {
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.WindowManager",
+            "AutoHide",
+            !show_recent_apps,
+        )?);
+    }
+
+    // WindowManager is a background agent, so there's nothing to relaunch.
+    if changes.has_changes() {
+        restart_app(runner, "WindowManager", RestartStrategy::Killall)?;
+    }
+
+    Ok(changes)
+}
+
+/// The system-wide Software Update preferences, addressed by path since they
+/// live under `/Library/Preferences` rather than the user's own preferences.
+const SOFTWARE_UPDATE_DOMAIN: &str = "/Library/Preferences/com.apple.SoftwareUpdate";
+
+/// Applies the Software Update settings. Values that are already set are left
+/// alone, so `sudo` is only needed when something actually changes.
+pub fn apply_software_update_settings(
+    runner: &dyn CommandRunner,
+    software_update: &SoftwareUpdate,
+) -> Result<Changes, DefaultsError> {
+    let mut changes = Changes::default();
+    if let Some(auto_check) = software_update.auto_check {
+        changes.merge(write_system_defaults(
+            runner,
+            SOFTWARE_UPDATE_DOMAIN,
+            "AutomaticCheckEnabled",
+            auto_check,
+        )?);
+    }
+
+    if let Some(auto_download) = software_update.auto_download {
+        changes.merge(write_system_defaults(
+            runner,
+            SOFTWARE_UPDATE_DOMAIN,
+            "AutomaticDownload",
+            auto_download,
+        )?);
+    }
+
+    if let Some(install_system_data_files) = software_update.install_system_data_files {
+        changes.merge(write_system_defaults(
+            runner,
+            SOFTWARE_UPDATE_DOMAIN,
+            "ConfigDataInstall",
+            install_system_data_files,
+        )?);
+    }
+
+    Ok(changes)
+}
+
+/// Whether the startup chime is muted, going by the `StartupMute` NVRAM
+/// variable, which isn't set at all until it's been muted once.
+fn startup_muted(runner: &dyn CommandRunner) -> Result<bool, DefaultsError> {
+    let output = runner
+        .output("nvram", &["StartupMute"])
+        .map_err(|e| DefaultsError::CommandFailed(format!("failed to run nvram {e}")))?;
+
+    // Prints the name and value separated by a tab, like `StartupMute\t%01`.
+    Ok(output.success()
+        && String::from_utf8_lossy(&output.stdout)
+            .trim_end()
+            .ends_with("%01"))
+}
+
+/// Applies the sound settings.
+pub fn apply_sound_settings(
+    runner: &dyn CommandRunner,
+    sound: &Sound,
+) -> Result<Changes, DefaultsError> {
+    let mut changes = Changes::default();
+    if let Some(startup_chime) = sound.startup_chime {
+        if startup_muted(runner)? != startup_chime {
+            unchanged!(
+                "ℹ️  startup chime already {}",
+                if startup_chime { "on" } else { "off" }
+            );
+            return Ok(changes);
+        }
+
+        let assignment = format!("StartupMute=%0{}", u8::from(!startup_chime));
+        if !can_sudo(runner)? {
+            info!("💡 Run this to change the startup chime: sudo nvram {assignment}");
+            return Err(DefaultsError::SudoRequired(
+                "macos.sound.startup-chime".to_string(),
+            ));
+        }
+
+        if let Err(e) = plist_output(runner, "sudo", &["-n", "nvram", &assignment]) {
+            event!(
+                "write",
+                "nvram StartupMute",
+                failed,
+                "❌ Failed to set nvram {assignment}"
+            );
+            return Err(e);
+        }
+        event!(
+            "write",
+            "nvram StartupMute",
+            ok,
+            "🔧 Set nvram {assignment}"
+        );
+        changes.other_change(format!("Set nvram {assignment}"));
+    }
+
+    Ok(changes)
+}
+
+pub fn apply_magic_mouse_settings(
+    runner: &dyn CommandRunner,
+    magic_mouse: &MagicMouse,
+) -> Result<Changes, DefaultsError> {
+    let mut changes = Changes::default();
     if let Some(mouse_button_mode) = magic_mouse.mouse_button_mode {
-        write_defaults(
+        changes.merge(write_defaults(
+            runner,
             "com.apple.AppleMultitouchMouse",
             "MouseButtonMode",
             mouse_button_mode,
-        )?;
+        )?);
     }
 
-    Ok(())
+    Ok(changes)
+}
+
+/// Applies the trackpad settings. Returns what changed, and whether the Dock,
+/// which handles the app switching and Launchpad gestures, needs restarting.
+pub fn apply_trackpad_settings(
+    runner: &dyn CommandRunner,
+    trackpad: &Trackpad,
+) -> Result<(Changes, bool), DefaultsError> {
+    let mut changes = Changes::default();
+    let mut dock_changed = false;
+    let mut logout_required = Vec::new();
+
+    if let Some(tap_to_click) = trackpad.tap_to_click {
+        let mut changed = false;
+        for domain in TRACKPAD_DOMAINS {
+            changed |= changes.merge(write_defaults(runner, domain, "Clicking", tap_to_click)?);
+        }
+        if changed {
+            logout_required.push("tap-to-click");
+        }
+    }
+
+    if let Some(tracking_speed) = trackpad.tracking_speed {
+        if !(0.0..=3.0).contains(&tracking_speed) {
+            return Err(DefaultsError::InvalidValue(format!(
+                "macos.trackpad.tracking-speed must be between 0.0 and 3.0, got {tracking_speed}"
+            )));
+        }
+        if changes.merge(write_defaults(
+            runner,
+            "NSGlobalDomain",
+            "com.apple.trackpad.scaling",
+            tracking_speed,
+        )?) {
+            logout_required.push("tracking-speed");
+        }
+    }
+
+    if let Some(swipe_between_pages) = trackpad.swipe_between_pages {
+        changes.merge(write_defaults(
+            runner,
+            "NSGlobalDomain",
+            "AppleEnableSwipeNavigateWithScrolls",
+            swipe_between_pages,
+        )?);
+        // Apps pick this up when they're next launched.
+    }
+
+    if let Some(swipe_between_apps) = trackpad.swipe_between_apps {
+        for domain in TRACKPAD_DOMAINS {
+            dock_changed |= changes.merge(write_defaults(
+                runner,
+                domain,
+                "TrackpadFourFingerHorizSwipeGesture",
+                trackpad_gesture(swipe_between_apps),
+            )?);
+        }
+    }
+
+    if let Some(launchpad_pinch) = trackpad.launchpad_pinch {
+        for domain in TRACKPAD_DOMAINS {
+            dock_changed |= changes.merge(write_defaults(
+                runner,
+                domain,
+                "TrackpadFiveFingerPinchGesture",
+                trackpad_gesture(launchpad_pinch),
+            )?);
+        }
+        dock_changed |= changes.merge(write_defaults(
+            runner,
+            "com.apple.dock",
+            "showLaunchpadGestureEnabled",
+            launchpad_pinch,
+        )?);
+    }
+
+    notify_logout_required(&logout_required);
+
+    Ok((changes, dock_changed))
 }
 
-pub fn apply_finder_settings(finder: &Finder) -> Result<(), DefaultsError> {
-    let mut changed = false;
+/// Applies the keyboard settings.
+pub fn apply_keyboard_settings(
+    runner: &dyn CommandRunner,
+    keyboard: &Keyboard,
+) -> Result<Changes, DefaultsError> {
+    let mut changes = Changes::default();
+    let mut logout_required = Vec::new();
+
+    if let Some(enabled) = keyboard.full_keyboard_access
+        && changes.merge(write_defaults(
+            runner,
+            "NSGlobalDomain",
+            "AppleKeyboardUIMode",
+            full_keyboard_access(enabled),
+        )?)
+    {
+        logout_required.push("full-keyboard-access");
+    }
+
+    if let Some(use_f_keys_as_standard) = keyboard.use_f_keys_as_standard
+        && changes.merge(write_defaults(
+            runner,
+            "NSGlobalDomain",
+            "com.apple.keyboard.fnState",
+            use_f_keys_as_standard,
+        )?)
+    {
+        logout_required.push("use-f-keys-as-standard");
+    }
+
+    notify_logout_required(&logout_required);
+
+    Ok(changes)
+}
+
+pub fn apply_finder_settings(
+    runner: &dyn CommandRunner,
+    finder: &Finder,
+    restart: RestartStrategy,
+) -> Result<Changes, DefaultsError> {
+    let mut changes = Changes::default();
+    if let Some(show_file_extensions) = finder.show_file_extensions {
+        changes.merge(write_defaults(
+            runner,
+            "NSGlobalDomain",
+            "AppleShowAllExtensions",
+            show_file_extensions,
+        )?);
+    }
 
     if let Some(show_pathbar) = finder.show_pathbar {
-        changed |= write_defaults("com.apple.finder", "ShowPathbar", show_pathbar)?;
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.finder",
+            "ShowPathbar",
+            show_pathbar,
+        )?);
     }
 
     if let Some(show_full_posix_path_in_title_bar) = finder.show_full_posix_path_in_title_bar {
-        changed |= write_defaults(
+        changes.merge(write_defaults(
+            runner,
             "com.apple.finder",
             "_FXShowPosixPathInTitle",
             show_full_posix_path_in_title_bar,
-        )?;
+        )?);
     }
 
-    if changed {
-        println!("Restarting Finder to apply changes...");
-        Command::new("killall")
-            .arg("Finder")
-            .status()
-            .map_err(|e| DefaultsError::CommandFailed(format!("failed to kill Finder {e}")))?;
+    if let Some(show_desktop_icons) = finder.show_desktop_icons {
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.finder",
+            "CreateDesktop",
+            show_desktop_icons,
+        )?);
     }
 
-    Ok(())
+    if let Some(warn_before_emptying_trash) = finder.warn_before_emptying_trash {
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.finder",
+            "WarnOnEmptyTrash",
+            warn_before_emptying_trash,
+        )?);
+    }
+
+    if let Some(remove_old_trash_items) = finder.remove_old_trash_items {
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.finder",
+            "FXRemoveOldTrashItems",
+            remove_old_trash_items,
+        )?);
+    }
+
+    if let Some(keep_folders_on_top) = finder.keep_folders_on_top {
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.finder",
+            "_FXSortFoldersFirst",
+            keep_folders_on_top,
+        )?);
+    }
+
+    if let Some(default_search_scope) = finder.default_search_scope {
+        changes.merge(write_defaults(
+            runner,
+            "com.apple.finder",
+            "FXDefaultSearchScope",
+            default_search_scope,
+        )?);
+    }
+
+    if let Some(arrange_by) = finder.arrange_by {
+        // The desktop's icon view settings are a dictionary nested in the
+        // Finder preferences, which `defaults write` can only replace whole.
+        let plist = home_dir()
+            .ok_or_else(|| {
+                DefaultsError::CommandFailed("Could not determine home directory.".to_string())
+            })?
+            .join("Library/Preferences/com.apple.finder.plist");
+        changes.merge(write_plist_value(
+            runner,
+            &plist,
+            ":DesktopViewSettings:IconViewSettings:arrangeBy",
+            arrange_by,
+        )?);
+    }
+
+    if changes.has_changes() {
+        restart_app(runner, "Finder", restart)?;
+    }
+
+    Ok(changes)
+}
+
+/// Runs `program` with `args`, returning its stdout.
+fn plist_output(
+    runner: &dyn CommandRunner,
+    program: &str,
+    args: &[&str],
+) -> Result<Vec<u8>, DefaultsError> {
+    let output = runner
+        .output(program, args)
+        .map_err(|e| DefaultsError::CommandFailed(format!("failed to run {program} {e}")))?;
+    if !output.success() {
+        return Err(DefaultsError::CommandFailed(format!(
+            "{program} {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(output.stdout)
+}
+
+/// Imports whole preferences domains from `.plist` files. Each file is only
+/// imported when it differs from the domain's current contents, which are
+/// compared as XML so binary and XML plists compare equal.
+pub fn apply_defaults_imports(
+    runner: &dyn CommandRunner,
+    imports: &[DefaultsImport],
+    system_config_dir: &Path,
+    restart: RestartStrategy,
+) -> Result<Changes, DefaultsError> {
+    let mut changes = Changes::default();
+    let home = home_dir().ok_or_else(|| {
+        DefaultsError::CommandFailed("Could not determine home directory.".to_string())
+    })?;
+
+    for import in imports {
+        let file = system_config_dir.join(
+            tilde_expand_path(&import.file, &home)
+                .map_err(|e| DefaultsError::CommandFailed(e.to_string()))?,
+        );
+        let file_str = file.to_str().ok_or_else(|| {
+            DefaultsError::CommandFailed(format!("path is not valid UTF-8: {}", file.display()))
+        })?;
+        let domain = import.domain.as_str();
+
+        let desired = plist_output(runner, "plutil", &["-convert", "xml1", "-o", "-", file_str])?;
+        let current = plist_output(runner, "defaults", &["export", domain, "-"])?;
+        if desired.trim_ascii() == current.trim_ascii() {
+            unchanged!("ℹ️  {domain} already matches {}", file.display());
+            continue;
+        }
+
+        if let Err(e) = plist_output(runner, "defaults", &["import", domain, file_str]) {
+            event!("import", domain, failed, "❌ Failed to import {domain}");
+            return Err(e);
+        }
+        event!(
+            "import",
+            domain,
+            ok,
+            "🔧 Imported {domain} from {}",
+            file.display()
+        );
+        changes.other_change(format!("Imported {domain} from {}", file.display()));
+
+        if let Some(app) = &import.restart {
+            restart_app(runner, app, restart)?;
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Writes the custom `defaults` entries, restarting the app each changed
+/// domain belongs to once they're all written. Returns what changed, and
+/// whether the Dock needs restarting, so it can be restarted along with the
+/// other Dock settings.
+pub fn apply_custom_defaults(
+    runner: &dyn CommandRunner,
+    custom: &[CustomDefault],
+    restart: RestartStrategy,
+) -> Result<(Changes, bool), DefaultsError> {
+    let mut changes = Changes::default();
+    let mut restart_apps = BTreeSet::new();
+
+    for entry in custom {
+        let (domain, key) = (entry.domain.as_str(), entry.key.as_str());
+        let written = match entry.typed_value().map_err(DefaultsError::InvalidValue)? {
+            CustomValue::Bool(b) => write_defaults(runner, domain, key, b)?,
+            CustomValue::Int(i) => write_defaults(runner, domain, key, i)?,
+            CustomValue::Float(f) => write_defaults(runner, domain, key, f)?,
+            CustomValue::String(s) => write_defaults(runner, domain, key, s)?,
+        };
+        if changes.merge(written)
+            && let Some(app) = entry.app_to_restart()
+        {
+            restart_apps.insert(app);
+        }
+    }
+
+    let dock_changed = restart_apps.remove("Dock");
+    for app in restart_apps {
+        restart_app(runner, app, restart)?;
+    }
+
+    Ok((changes, dock_changed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, MockRunner};
+
+    #[test]
+    fn has_full_disk_access_only_fails_on_permission_errors() {
+        let protected = home_dir().unwrap().join("Library/Safari");
+        let ls = ["ls", protected.to_str().unwrap()];
+
+        let runner = MockRunner::new().stub(
+            &ls,
+            CommandOutput::failed(1, "ls: Safari: Operation not permitted"),
+        );
+        assert!(!has_full_disk_access(&runner).unwrap());
+
+        let runner = MockRunner::new().stub(
+            &ls,
+            CommandOutput::failed(1, "ls: Safari: No such file or directory"),
+        );
+        assert!(has_full_disk_access(&runner).unwrap());
+    }
+
+    #[test]
+    fn only_changed_settings_that_need_a_logout_are_reported() {
+        let system: SystemSettings = toml::from_str(
+            r#"
+            natural-scrolling = false
+            key-repeat-rate = 2
+            automatic-capitalization = false
+            "#,
+        )
+        .unwrap();
+        let read = |key| ["defaults", "read", "NSGlobalDomain", key];
+        let runner = MockRunner::new()
+            .stub(
+                &read("com.apple.swipescrolldirection"),
+                CommandOutput::ok("0"),
+            )
+            .stub(&read("KeyRepeat"), CommandOutput::ok("6"))
+            .stub(
+                &read("NSAutomaticCapitalizationEnabled"),
+                CommandOutput::ok("1"),
+            );
+
+        let mut logout_required = Vec::new();
+        let mut restart_required = Vec::new();
+        write_system_settings(
+            &runner,
+            &system,
+            &mut logout_required,
+            &mut restart_required,
+        )
+        .unwrap();
+
+        assert_eq!(vec!["key-repeat-rate"], logout_required);
+        assert!(restart_required.is_empty());
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            &[
+                "defaults",
+                "write",
+                "NSGlobalDomain",
+                "NSAutomaticCapitalizationEnabled",
+                "-bool",
+                "false"
+            ]
+        );
+    }
+
+    #[test]
+    fn apps_with_changed_press_and_hold_need_a_restart_not_a_logout() {
+        let system: SystemSettings = toml::from_str(
+            r#"
+            key-press-and-hold-per-app = ["com.microsoft.VSCode", "com.apple.TextEdit"]
+            "#,
+        )
+        .unwrap();
+        let read = |domain| ["defaults", "read", domain, "ApplePressAndHoldEnabled"];
+        let runner = MockRunner::new()
+            .stub(&read("com.microsoft.VSCode"), CommandOutput::ok("1"))
+            .stub(&read("com.apple.TextEdit"), CommandOutput::ok("0"));
+
+        let mut logout_required = Vec::new();
+        let mut restart_required = Vec::new();
+        write_system_settings(
+            &runner,
+            &system,
+            &mut logout_required,
+            &mut restart_required,
+        )
+        .unwrap();
+
+        assert!(logout_required.is_empty());
+        assert_eq!(vec!["com.microsoft.VSCode"], restart_required);
+    }
+
+    #[test]
+    fn apply_defaults_imports_only_imports_changed_domains() {
+        let plist = "<plist><dict><key>A</key><true/></dict></plist>\n";
+        let runner = MockRunner::new()
+            .stub(
+                &[
+                    "plutil",
+                    "-convert",
+                    "xml1",
+                    "-o",
+                    "-",
+                    "/config/iterm.plist",
+                ],
+                CommandOutput::ok(plist),
+            )
+            .stub(
+                &[
+                    "plutil",
+                    "-convert",
+                    "xml1",
+                    "-o",
+                    "-",
+                    "/config/rectangle.plist",
+                ],
+                CommandOutput::ok(plist),
+            )
+            .stub(
+                &["defaults", "export", "com.googlecode.iterm2", "-"],
+                CommandOutput::ok(plist),
+            );
+        let imports = [
+            DefaultsImport {
+                domain: "com.googlecode.iterm2".to_string(),
+                file: PathBuf::from("iterm.plist"),
+                restart: Some("iTerm2".to_string()),
+            },
+            DefaultsImport {
+                domain: "com.knollsoft.Rectangle".to_string(),
+                file: PathBuf::from("rectangle.plist"),
+                restart: Some("Rectangle".to_string()),
+            },
+        ];
+
+        apply_defaults_imports(
+            &runner,
+            &imports,
+            Path::new("/config"),
+            RestartStrategy::Killall,
+        )
+        .unwrap();
+
+        let calls = runner.calls();
+        assert!(
+            !calls
+                .iter()
+                .any(|call| call.contains(&"iTerm2".to_string()))
+        );
+        assert_eq!(
+            &calls[calls.len() - 2..],
+            [
+                vec![
+                    "defaults",
+                    "import",
+                    "com.knollsoft.Rectangle",
+                    "/config/rectangle.plist"
+                ],
+                vec!["killall", "Rectangle"],
+            ]
+        );
+    }
+
+    #[test]
+    fn window_tiling_is_skipped_before_sequoia() {
+        let window_tiling = WindowTiling {
+            drag_to_edges: None,
+            drag_to_menu_bar: None,
+            option_key_tiling: None,
+            margins: Some(false),
+        };
+        let read = [
+            "defaults",
+            "read",
+            "com.apple.WindowManager",
+            "EnableTiledWindowMargins",
+        ];
+
+        let runner = MockRunner::new().stub(
+            &["sw_vers", "-productVersion"],
+            CommandOutput::ok("14.6.1\n"),
+        );
+        apply_window_tiling_settings(&runner, &window_tiling).unwrap();
+        assert_eq!(1, runner.calls().len());
+
+        let runner = MockRunner::new()
+            .stub(&["sw_vers", "-productVersion"], CommandOutput::ok("15.1\n"))
+            .stub(&read, CommandOutput::ok("1"));
+        apply_window_tiling_settings(&runner, &window_tiling).unwrap();
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            &["killall", "WindowManager"]
+        );
+    }
+
+    #[test]
+    fn macos_version_parses_two_and_three_part_versions() {
+        assert_eq!(
+            Some(MacOSVersion {
+                major: 14,
+                minor: 5
+            }),
+            MacOSVersion::parse("14.5\n")
+        );
+        assert_eq!(
+            Some(MacOSVersion {
+                major: 10,
+                minor: 15
+            }),
+            MacOSVersion::parse("10.15.7")
+        );
+        assert_eq!(
+            Some(MacOSVersion {
+                major: 15,
+                minor: 0
+            }),
+            MacOSVersion::parse("15")
+        );
+        assert_eq!(None, MacOSVersion::parse("not a version"));
+    }
+
+    #[test]
+    fn battery_percentage_falls_back_to_menu_extra_before_big_sur() {
+        let runner = MockRunner::new()
+            .stub(
+                &["sw_vers", "-productVersion"],
+                CommandOutput::ok("10.15.7"),
+            )
+            .stub(
+                &[
+                    "defaults",
+                    "read",
+                    "com.apple.menuextra.battery",
+                    "ShowPercent",
+                ],
+                CommandOutput::ok("NO"),
+            );
+        let menu_bar = MenuBar {
+            show_battery_percentage: Some(true),
+        };
+
+        apply_menu_bar_settings(&runner, &menu_bar).unwrap();
+
+        let calls = runner.calls();
+        assert!(
+            calls.contains(
+                &[
+                    "defaults",
+                    "write",
+                    "com.apple.menuextra.battery",
+                    "ShowPercent",
+                    "-string",
+                    "YES"
+                ]
+                .map(String::from)
+                .to_vec()
+            )
+        );
+        assert!(calls.contains(&["killall", "SystemUIServer"].map(String::from).to_vec()));
+    }
+
+    #[test]
+    fn read_current_settings_leaves_out_unset_sections() {
+        // Unstubbed reads print nothing, which doesn't parse as any setting.
+        let runner = MockRunner::new().stub(
+            &["defaults", "read", "com.apple.WindowManager", "AutoHide"],
+            CommandOutput::ok("1\n"),
+        );
+
+        let macos = read_current_settings(&runner);
+
+        assert!(macos.dock.is_none());
+        let stage_manager = macos.stage_manager.unwrap();
+        assert_eq!(Some(false), stage_manager.show_recent_apps);
+        assert_eq!(None, stage_manager.enabled);
+    }
+
+    #[test]
+    fn trackpad_gestures_are_written_to_both_trackpad_domains() {
+        let [built_in, bluetooth] = TRACKPAD_DOMAINS;
+        let key = "TrackpadFourFingerHorizSwipeGesture";
+        let runner = MockRunner::new()
+            .stub(&["defaults", "read", built_in, key], CommandOutput::ok("0"))
+            .stub(
+                &["defaults", "read", bluetooth, key],
+                CommandOutput::ok("2"),
+            );
+        let trackpad = Trackpad {
+            tap_to_click: None,
+            tracking_speed: None,
+            swipe_between_pages: None,
+            swipe_between_apps: Some(false),
+            launchpad_pinch: None,
+        };
+
+        assert!(apply_trackpad_settings(&runner, &trackpad).unwrap().1);
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            &["defaults", "write", bluetooth, key, "-int", "0"]
+        );
+    }
+
+    #[test]
+    fn tap_to_click_and_tracking_speed_are_written() {
+        let [built_in, bluetooth] = TRACKPAD_DOMAINS;
+        let unset = || CommandOutput::failed(1, "does not exist");
+        let runner = MockRunner::new()
+            .stub(&["defaults", "read", built_in, "Clicking"], unset())
+            .stub(&["defaults", "read", bluetooth, "Clicking"], unset())
+            .stub(
+                &[
+                    "defaults",
+                    "read",
+                    "NSGlobalDomain",
+                    "com.apple.trackpad.scaling",
+                ],
+                unset(),
+            );
+        let trackpad = Trackpad {
+            tap_to_click: Some(true),
+            tracking_speed: Some(1.5),
+            swipe_between_pages: None,
+            swipe_between_apps: None,
+            launchpad_pinch: None,
+        };
+
+        assert!(!apply_trackpad_settings(&runner, &trackpad).unwrap().1);
+        let writes: Vec<_> = runner
+            .calls()
+            .into_iter()
+            .filter(|call| call[1] == "write")
+            .collect();
+        assert_eq!(
+            vec![
+                vec!["defaults", "write", built_in, "Clicking", "-bool", "true"],
+                vec!["defaults", "write", bluetooth, "Clicking", "-bool", "true"],
+                vec![
+                    "defaults",
+                    "write",
+                    "NSGlobalDomain",
+                    "com.apple.trackpad.scaling",
+                    "-float",
+                    "1.5"
+                ],
+            ],
+            writes
+        );
+    }
+
+    #[test]
+    fn keyboard_settings_are_only_written_when_they_differ() {
+        let read = |key| vec!["defaults", "read", "NSGlobalDomain", key];
+        let runner = MockRunner::new()
+            .stub(&read("AppleKeyboardUIMode"), CommandOutput::ok("2"))
+            .stub(
+                &read("com.apple.keyboard.fnState"),
+                CommandOutput::failed(1, "does not exist"),
+            );
+        let keyboard = Keyboard {
+            full_keyboard_access: Some(true),
+            use_f_keys_as_standard: Some(true),
+        };
+
+        apply_keyboard_settings(&runner, &keyboard).unwrap();
+
+        let writes: Vec<_> = runner
+            .calls()
+            .into_iter()
+            .filter(|call| call[1] == "write")
+            .collect();
+        assert_eq!(
+            vec![vec![
+                "defaults",
+                "write",
+                "NSGlobalDomain",
+                "com.apple.keyboard.fnState",
+                "-bool",
+                "true"
+            ]],
+            writes
+        );
+    }
+
+    #[test]
+    fn full_keyboard_access_off_writes_zero() {
+        let runner = MockRunner::new().stub(
+            &["defaults", "read", "NSGlobalDomain", "AppleKeyboardUIMode"],
+            CommandOutput::ok("2"),
+        );
+        let keyboard = Keyboard {
+            full_keyboard_access: Some(false),
+            use_f_keys_as_standard: None,
+        };
+
+        apply_keyboard_settings(&runner, &keyboard).unwrap();
+
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            &[
+                "defaults",
+                "write",
+                "NSGlobalDomain",
+                "AppleKeyboardUIMode",
+                "-int",
+                "0"
+            ]
+        );
+    }
+
+    fn custom_default(toml: &str) -> CustomDefault {
+        toml::from_str(&format!(
+            "domain = \"com.example.app\"\nkey = \"Key\"\n{toml}"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn custom_default_values_are_checked_against_their_type() {
+        let valid = [
+            ("type = \"bool\"\nvalue = true", CustomValue::Bool(true)),
+            (
+                "type = \"bool\"\nvalue = \"false\"",
+                CustomValue::Bool(false),
+            ),
+            ("type = \"int\"\nvalue = 42", CustomValue::Int(42)),
+            ("type = \"int\"\nvalue = \"-1\"", CustomValue::Int(-1)),
+            ("type = \"float\"\nvalue = 1", CustomValue::Float(1.0)),
+            ("type = \"float\"\nvalue = 0.5", CustomValue::Float(0.5)),
+            (
+                "type = \"string\"\nvalue = \"Nlsv\"",
+                CustomValue::String("Nlsv".into()),
+            ),
+        ];
+        for (toml, expected) in valid {
+            assert_eq!(Ok(expected), custom_default(toml).typed_value(), "{toml}");
+        }
+
+        let invalid = [
+            "type = \"bool\"\nvalue = \"yes\"",
+            "type = \"int\"\nvalue = 1.5",
+            "type = \"int\"\nvalue = 4294967296",
+            "type = \"string\"\nvalue = 5",
+        ];
+        for toml in invalid {
+            assert!(custom_default(toml).typed_value().is_err(), "{toml}");
+        }
+    }
+
+    #[test]
+    fn invalid_custom_defaults_are_configuration_errors() {
+        let macos: MacOS = toml::from_str(
+            r#"
+            [[custom]]
+            domain = "com.apple.finder"
+            key = "QLEnableTextSelection"
+            type = "bool"
+            value = "sure"
+            "#,
+        )
+        .unwrap();
+        let mut problems = Vec::new();
+
+        macos.validate(&mut problems);
+
+        assert_eq!(1, problems.len());
+        assert_eq!("macos.custom", problems[0].field);
+    }
+
+    #[test]
+    fn custom_defaults_restart_the_app_whose_domain_changed() {
+        let custom: Vec<CustomDefault> = [
+            "domain = \"com.apple.finder\"\nkey = \"ShowStatusBar\"\ntype = \"bool\"\nvalue = true",
+            "domain = \"com.apple.finder\"\nkey = \"QLEnableTextSelection\"\ntype = \"bool\"\nvalue = true",
+            "domain = \"com.apple.dock\"\nkey = \"static-only\"\ntype = \"bool\"\nvalue = true",
+            "domain = \"com.example.app\"\nkey = \"Theme\"\ntype = \"string\"\nvalue = \"dark\"",
+        ]
+        .iter()
+        .map(|entry| toml::from_str(entry).unwrap())
+        .collect();
+        let unset = || CommandOutput::failed(1, "does not exist");
+        let runner = MockRunner::new()
+            .stub(
+                &["defaults", "read", "com.apple.finder", "ShowStatusBar"],
+                unset(),
+            )
+            .stub(
+                &[
+                    "defaults",
+                    "read",
+                    "com.apple.finder",
+                    "QLEnableTextSelection",
+                ],
+                unset(),
+            )
+            .stub(
+                &["defaults", "read", "com.apple.dock", "static-only"],
+                unset(),
+            )
+            .stub(&["defaults", "read", "com.example.app", "Theme"], unset());
+
+        let (changes, dock_changed) =
+            apply_custom_defaults(&runner, &custom, RestartStrategy::Killall).unwrap();
+
+        assert!(dock_changed);
+        let written: Vec<_> = changes
+            .defaults_changed
+            .iter()
+            .map(|default| (default.domain.as_str(), default.key.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("com.apple.finder", "ShowStatusBar"),
+                ("com.apple.finder", "QLEnableTextSelection"),
+                ("com.apple.dock", "static-only"),
+                ("com.example.app", "Theme"),
+            ],
+            written
+        );
+        let restarts: Vec<_> = runner
+            .calls()
+            .into_iter()
+            .filter(|call| call[0] == "killall")
+            .collect();
+        assert_eq!(vec![vec!["killall", "Finder"]], restarts);
+    }
+
+    #[test]
+    fn control_center_writes_keys_with_spaces_verbatim() {
+        let runner = MockRunner::new().stub(
+            &[
+                "defaults",
+                "read",
+                "com.apple.controlcenter",
+                "NSStatusItem Visible Sound",
+            ],
+            CommandOutput::ok("0"),
+        );
+        let control_center = ControlCenter {
+            bluetooth: None,
+            sound: Some(true),
+            now_playing: None,
+            battery: None,
+            focus: None,
+        };
+
+        apply_control_center_settings(&runner, &control_center).unwrap();
+
+        let calls = runner.calls();
+        assert!(
+            calls.contains(
+                &[
+                    "defaults",
+                    "write",
+                    "com.apple.controlcenter",
+                    "NSStatusItem Visible Sound",
+                    "-bool",
+                    "true"
+                ]
+                .map(String::from)
+                .to_vec()
+            )
+        );
+        assert_eq!(calls.last().unwrap(), &["killall", "ControlCenter"]);
+    }
+
+    #[test]
+    fn restart_app_relaunches_only_running_apps() {
+        let runner = MockRunner::new();
+        restart_app(&runner, "Safari", RestartStrategy::Relaunch).unwrap();
+        assert_eq!(
+            runner.calls(),
+            [
+                vec!["pgrep", "-x", "Safari"],
+                vec!["osascript", "-e", "quit app \"Safari\""],
+                vec!["open", "-a", "Safari"],
+            ]
+        );
+
+        let runner =
+            MockRunner::new().stub(&["pgrep", "-x", "Safari"], CommandOutput::failed(1, ""));
+        restart_app(&runner, "Safari", RestartStrategy::Relaunch).unwrap();
+        assert_eq!(runner.calls(), [vec!["pgrep", "-x", "Safari"]]);
+
+        let runner = MockRunner::new();
+        restart_app(&runner, "Finder", RestartStrategy::Killall).unwrap();
+        assert_eq!(runner.calls(), [vec!["killall", "Finder"]]);
+    }
+
+    #[test]
+    fn startup_chime_is_only_changed_when_it_differs() {
+        let sound = Sound {
+            startup_chime: Some(false),
+        };
+
+        let runner = MockRunner::new().stub(
+            &["nvram", "StartupMute"],
+            CommandOutput::ok("StartupMute\t%01\n"),
+        );
+        apply_sound_settings(&runner, &sound).unwrap();
+        assert_eq!(runner.calls(), [vec!["nvram", "StartupMute"]]);
+
+        // Never muted, so the variable doesn't exist yet.
+        let runner = MockRunner::new().stub(
+            &["nvram", "StartupMute"],
+            CommandOutput::failed(1, "nvram: Error getting variable - 'StartupMute'"),
+        );
+        apply_sound_settings(&runner, &sound).unwrap();
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            &["sudo", "-n", "nvram", "StartupMute=%01"]
+        );
+
+        let runner = MockRunner::new()
+            .stub(
+                &["nvram", "StartupMute"],
+                CommandOutput::ok("StartupMute\t%00\n"),
+            )
+            .stub(&["sudo", "-n", "true"], CommandOutput::failed(1, ""));
+        let err = apply_sound_settings(&runner, &sound).unwrap_err();
+        assert!(matches!(err, DefaultsError::SudoRequired(_)));
+    }
+
+    #[test]
+    fn software_update_writes_with_sudo_only_when_changed() {
+        let read = |key| {
+            [
+                "defaults",
+                "read",
+                "/Library/Preferences/com.apple.SoftwareUpdate",
+                key,
+            ]
+        };
+        let runner = MockRunner::new()
+            .stub(&read("AutomaticCheckEnabled"), CommandOutput::ok("1"))
+            .stub(&read("AutomaticDownload"), CommandOutput::ok("1"));
+        let software_update = SoftwareUpdate {
+            auto_check: Some(true),
+            auto_download: Some(false),
+            install_system_data_files: None,
+        };
+
+        apply_software_update_settings(&runner, &software_update).unwrap();
+
+        let writes: Vec<_> = runner
+            .calls()
+            .into_iter()
+            .filter(|call| call[0] == "sudo")
+            .collect();
+        assert_eq!(
+            writes,
+            [[
+                "sudo",
+                "-n",
+                "defaults",
+                "write",
+                "/Library/Preferences/com.apple.SoftwareUpdate",
+                "AutomaticDownload",
+                "-bool",
+                "false"
+            ]
+            .map(String::from)
+            .to_vec()]
+        );
+    }
+
+    #[test]
+    fn software_update_reports_when_sudo_would_prompt() {
+        let runner = MockRunner::new()
+            .stub(
+                &[
+                    "defaults",
+                    "read",
+                    "/Library/Preferences/com.apple.SoftwareUpdate",
+                    "AutomaticDownload",
+                ],
+                CommandOutput::ok("1"),
+            )
+            .stub(
+                &[
+                    "sudo",
+                    "-n",
+                    "defaults",
+                    "write",
+                    "/Library/Preferences/com.apple.SoftwareUpdate",
+                    "AutomaticDownload",
+                    "-bool",
+                    "false",
+                ],
+                CommandOutput::failed(1, "sudo: a password is required"),
+            )
+            .stub(
+                &["sudo", "-n", "true"],
+                CommandOutput::failed(1, "sudo: a password is required"),
+            );
+        let software_update = SoftwareUpdate {
+            auto_check: None,
+            auto_download: Some(false),
+            install_system_data_files: None,
+        };
+
+        let err = apply_software_update_settings(&runner, &software_update).unwrap_err();
+        assert!(matches!(err, DefaultsError::SudoRequired(_)));
+    }
 }
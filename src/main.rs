@@ -1,17 +1,53 @@
-use std::{fs, io, path::PathBuf};
+use std::{
+    fs,
+    io::{self, Write},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    thread,
+    time::Duration,
+};
 
 use clap::{CommandFactory, Parser};
 use clap_complete::{Shell, generate};
+use clap_mangen::Man;
+
+use log::{LevelFilter, error, info};
 
 use omiros::{
+    add,
     brew::{
-        check_brew_installed, find_missing_packages, get_installed_brew_packages,
-        install_missing_packages,
+        check_brew_installed, check_command_line_tools, find_missing_packages,
+        find_stopped_services, get_installed_brew_packages, get_installed_taps,
+        get_outdated_packages, get_pinned_formulae, get_service_statuses, handle_keg_only_formulae,
+        install_missing_packages, prune_packages, reconcile_pins, start_services, tap_missing_taps,
+        upgrade_packages,
+    },
+    cargo_install::{
+        check_cargo_installed, find_missing_crates, get_installed_crates, install_missing_crates,
+    },
+    check,
+    doctor::run_doctor,
+    dotfiles::{self, TRANSIENT_FS_RETRIES, setup_dotfiles},
+    errors::OnFailure,
+    export::export_system,
+    git, macos,
+    mas::{
+        check_apple_id, check_mas_installed, find_missing_apps, find_outdated_apps,
+        get_installed_apps, install_missing_apps, resolve_app_ids, upgrade_outdated_apps,
+        verify_installed_apps,
     },
-    dotfiles::setup_dotfiles,
-    macos,
-    mas::{check_mas_installed, find_missing_apps, get_installed_apps, install_missing_apps},
-    system::System,
+    migrate::{self, CURRENT_VERSION},
+    npm::{
+        check_npm_installed, find_missing_npm_packages, get_installed_npm_packages,
+        install_missing_npm_packages,
+    },
+    output::{self, ColorChoice},
+    report::{Changes, OutputFormat, RunReport},
+    runner::{CommandRunner, SystemRunner},
+    system::{MacOS, Module, Section, System},
+    system_utils::Retry,
+    validate, vscode,
 };
 
 /// A home manager for normies.
@@ -26,105 +62,1060 @@ enum Cli {
         /// Path to the dotfiles directory.
         #[arg(short, long)]
         dotfiles_dir: PathBuf,
+        /// Print extra diagnostic output, such as the desired and installed
+        /// sets each package manager was compared against.
+        #[arg(short, long, conflicts_with = "quiet")]
+        verbose: bool,
+        /// Only print warnings and errors.
+        #[arg(short, long)]
+        quiet: bool,
+        /// Only print what changed, and errors, leaving out everything that
+        /// was already as configured.
+        #[arg(long)]
+        quiet_success: bool,
+        /// Start the Xcode Command Line Tools installer if they're missing,
+        /// rather than just explaining how to install them.
+        #[arg(long)]
+        install_xcode_tools: bool,
+        /// Only parse and validate the configuration, then exit without
+        /// touching the system.
+        #[arg(long)]
+        config_check_only: bool,
+        /// Print the configuration as omiros understands it, then exit
+        /// without touching the system.
+        #[arg(long)]
+        print_config: bool,
+        /// Print what would be done with each symlinked dotfile, as a tree
+        /// grouped by action, then exit without touching the system.
+        #[arg(long)]
+        plan_dotfiles: bool,
+        /// Also write all output to this file, with a timestamp on each line.
+        /// The file is appended to unless --truncate-output-file is given.
+        #[arg(long)]
+        output_file: Option<PathBuf>,
+        /// Truncate the --output-file instead of appending to it.
+        #[arg(long, requires = "output_file")]
+        truncate_output_file: bool,
+        /// Also stream every message to this file as a JSON object per line,
+        /// or to stdout with `-`, which moves the regular output to stderr.
+        #[arg(long, value_name = "PATH")]
+        log_json_lines: Option<PathBuf>,
+        /// Whether to print emoji and color, or plain ASCII tags like `[OK]`.
+        /// `auto` also turns them off when `NO_COLOR` or `CI` is set.
+        #[arg(long, value_enum, default_value_t)]
+        color: ColorChoice,
+        /// Print the usual output as the run goes, or a JSON summary of what
+        /// changed to stdout at the end, with the usual output on stderr.
+        #[arg(
+            long,
+            value_enum,
+            default_value_t,
+            conflicts_with_all = ["print_config", "plan_dotfiles", "config_check_only"]
+        )]
+        format: OutputFormat,
+        /// Kill any external command, like `brew install`, that runs for
+        /// longer than this many seconds, and treat it as having failed.
+        #[arg(long, value_name = "SECONDS")]
+        timeout: Option<u64>,
+        /// Stop at the first failed install, rather than attempting everything
+        /// else and reporting all the failures at the end.
+        #[arg(long, conflicts_with = "keep_going")]
+        fail_fast: bool,
+        /// Attempt every install and section even after one fails, reporting
+        /// all the failures at the end. This is the default.
+        #[arg(long)]
+        keep_going: bool,
+        /// Fail the run when a configured section is skipped because a tool
+        /// it needs is missing or it can't apply, rather than just saying so.
+        #[arg(long)]
+        strict: bool,
+        /// Read and write each `[macos]` preferences domain in one go, with
+        /// `defaults export` and `defaults import`, rather than a `defaults`
+        /// call per key. Importing replaces the whole domain, so quit the apps
+        /// being configured first.
+        #[arg(long, alias = "batch-defaults")]
+        concurrency_safe: bool,
+        /// Uninstall Homebrew formulae and casks that are installed but not
+        /// declared in `[brew]`. Only top-level formulae are considered, never
+        /// ones installed as dependencies.
+        #[arg(long)]
+        prune: bool,
+        /// Upgrade declared Homebrew packages and Mac App Store apps that have
+        /// a newer version available, rather than only installing missing
+        /// ones.
+        #[arg(long)]
+        upgrade: bool,
+        /// Move regular files that are in the way of a dotfile's symlink to
+        /// `<file>.omiros.bak`, rather than failing.
+        #[arg(long)]
+        backup: bool,
+        /// Don't `git pull` the dotfiles directory when `[dotfiles.git]` is
+        /// set, such as when offline. It's still cloned if it doesn't exist.
+        #[arg(long)]
+        no_pull: bool,
+        /// How many Homebrew formulae to install at once. Defaults to the
+        /// number of CPUs.
+        #[arg(long, value_name = "N")]
+        jobs: Option<NonZeroUsize>,
+        /// How many times to retry a failed `brew install` or `mas install`,
+        /// in case it was a network blip, waiting 1s, then 2s, and so on. Off
+        /// by default, since most failures, like a misspelled formula, fail
+        /// again.
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        retries: u32,
+        /// Only apply these sections, like `--only macos`. Can be repeated.
+        #[arg(long, value_enum, value_name = "SECTION")]
+        only: Vec<Module>,
+        /// Don't apply these sections, like `--skip brew`. Can be repeated.
+        #[arg(long, value_enum, value_name = "SECTION")]
+        skip: Vec<Module>,
+        /// Exit with 2, rather than 0, when the run had to change anything, to
+        /// check that a machine was already fully configured. Errors still
+        /// exit with 1.
+        #[arg(long)]
+        detect_changes: bool,
+    },
+    /// Report how this machine has drifted from the configuration, without
+    /// changing anything. Exits with 1 if anything differs
+    Check {
+        /// Path to the directory containing the system.toml file.
+        #[arg(short, long)]
+        system_config_dir: PathBuf,
+        /// Path to the dotfiles directory.
+        #[arg(short, long)]
+        dotfiles_dir: PathBuf,
+        /// Exit with 2 when anything differs, keeping 1 for errors, like
+        /// `terraform plan -detailed-exitcode`.
+        #[arg(long)]
+        detailed_exitcode: bool,
+        /// Whether to print emoji and color, or plain ASCII tags like `[OK]`.
+        #[arg(long, value_enum, default_value_t)]
+        color: ColorChoice,
+    },
+    /// Check for common problems with the environment
+    Doctor {
+        /// Path to the directory containing the system.toml file.
+        #[arg(short, long)]
+        system_config_dir: Option<PathBuf>,
+        /// Path to the dotfiles directory.
+        #[arg(short, long)]
+        dotfiles_dir: Option<PathBuf>,
+        /// Attempt to automatically fix the problems that can be fixed safely.
+        #[arg(long)]
+        fix: bool,
+        /// Whether to print emoji and color, or plain ASCII tags like `[OK]`.
+        #[arg(long, value_enum, default_value_t)]
+        color: ColorChoice,
+    },
+    /// Write a system.toml declaring what's installed on this machine
+    Export {
+        /// Where to write the configuration.
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Also export the current value of every `[macos]` setting omiros
+        /// manages.
+        #[arg(long)]
+        macos: bool,
+        /// Overwrite the output file if it already exists.
+        #[arg(long)]
+        force: bool,
+        /// Whether to print emoji and color, or plain ASCII tags like `[OK]`.
+        #[arg(long, value_enum, default_value_t)]
+        color: ColorChoice,
+    },
+    /// Add something installed by hand to the system.toml file, keeping its
+    /// comments and formatting
+    Add {
+        /// Path to the directory containing the system.toml file.
+        #[arg(short, long)]
+        system_config_dir: PathBuf,
+        #[command(subcommand)]
+        item: AddItem,
+    },
+    /// Work with the system.toml file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
     },
     /// Generate shell completions
     Completions {
         /// The shell to generate completions for
         #[arg(value_enum)]
         shell: Shell,
+        /// Write the completions to this file, rather than to stdout.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Generate a man page
+    Manpage {
+        /// Write the man page to this file, rather than to stdout.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum AddItem {
+    /// A Homebrew formula, or cask
+    Brew {
+        name: String,
+        /// Add it to the casks instead of the formulae.
+        #[arg(long)]
+        cask: bool,
+    },
+    /// A Mac App Store app, by its ID, which has to be installed already so
+    /// its name can be looked up
+    Mas { id: String },
+    /// A VS Code extension, by its identifier
+    Vscode { id: String },
+}
+
+#[derive(clap::Subcommand)]
+enum ConfigCommand {
+    /// Upgrade a system.toml written for an older version of omiros, and the
+    /// files it includes, to the current schema, in place
+    Migrate {
+        /// Path to the directory containing the system.toml file.
+        #[arg(short, long)]
+        system_config_dir: PathBuf,
+        /// Print the upgraded configuration instead of writing it back.
+        #[arg(long)]
+        dry_run: bool,
     },
+    /// Check that system.toml parses, and look for mistakes like duplicate
+    /// packages, without touching the system. Exits with 1 if there are any
+    /// problems, warnings included
+    Validate {
+        /// Path to the directory containing the system.toml file.
+        #[arg(short, long)]
+        system_config_dir: PathBuf,
+        /// Path to the dotfiles directory, to check that every dotfile exists.
+        /// Dotfiles aren't checked without it.
+        #[arg(short, long)]
+        dotfiles_dir: Option<PathBuf>,
+    },
+}
+
+/// Writes what `generate` outputs to `output`, creating its parent directories
+/// if needed, or to stdout without one.
+fn write_generated(
+    output: Option<&Path>,
+    generate: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> anyhow::Result<()> {
+    let Some(output) = output else {
+        return Ok(generate(&mut io::stdout())?);
+    };
+
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::File::create(output)?;
+    generate(&mut file)?;
+    info!("✅ Wrote {}", output.display());
+    Ok(())
+}
+
+/// Loads the `system.toml` in `system_config_dir`, resolved for this machine.
+fn load_system(runner: &dyn CommandRunner, system_config_dir: &Path) -> anyhow::Result<System> {
+    Ok(System::load(system_config_dir)?.resolve_for_current_host(runner)?)
+}
+
+/// Decides whether a configuration section should be applied, printing why
+/// when it isn't: either it's absent from the configuration file, or its `when`
+/// guard command didn't exit zero.
+fn enabled<T: Section>(
+    runner: &dyn CommandRunner,
+    module: Module,
+    section: Option<T>,
+) -> anyhow::Result<Option<T>> {
+    let Some(section) = section else {
+        info!("ℹ️  No `[{module}]` block in configuration file");
+        return Ok(None);
+    };
+
+    if let Some(guard) = section.when()
+        && !runner.output("sh", &["-c", guard])?.success()
+    {
+        info!("ℹ️  `[{module}]` skipped (guard failed: `{guard}`)");
+        return Ok(None);
+    }
+
+    Ok(Some(section))
 }
 
-fn main() -> anyhow::Result<()> {
+/// Clones or pulls the dotfiles repository ahead of `[brew]`, see
+/// [`System::sync_dotfiles_before_brew`], unless the `[dotfiles]` guard fails.
+/// Returns what syncing it changed, or `None` when it wasn't synced.
+fn sync_dotfiles_early(
+    runner: &dyn CommandRunner,
+    system: &System,
+    dotfiles_dir: &Path,
+    pull: bool,
+) -> anyhow::Result<Option<Changes>> {
+    let Some(dotfiles) = &system.dotfiles else {
+        return Ok(None);
+    };
+    let Some(git) = dotfiles.git() else {
+        return Ok(None);
+    };
+    if let Some(guard) = dotfiles.when()
+        && !runner.output("sh", &["-c", guard])?.success()
+    {
+        return Ok(None);
+    }
+
+    output::set_module(Some(Module::Dotfiles.name()));
+    info!("ℹ️  `[brew]` installs formulae from the dotfiles repository, syncing it first");
+    let changes = git::sync_repo(runner, git, dotfiles_dir, pull)?;
+    output::set_module(None);
+
+    Ok(Some(changes))
+}
+
+/// Reports how far a single module's section of the configuration has drifted
+/// from the machine, if it's enabled, without changing anything. Returns the
+/// number of differences.
+fn check_module(
+    runner: &dyn CommandRunner,
+    module: Module,
+    system: &mut System,
+    system_config_dir: &Path,
+    dotfiles_dir: &Path,
+) -> anyhow::Result<usize> {
+    let drifted = match module {
+        // Shell installers are only run, there's nothing to compare them to.
+        Module::ShellInstallers => 0,
+        Module::Brew => match enabled(runner, module, system.brew.take())? {
+            Some(brew) => check::check_brew(runner, &brew)?,
+            None => 0,
+        },
+        Module::Mas => match enabled(runner, module, system.mas.take())? {
+            Some(mas) => check::check_mas(runner, mas, system_config_dir)?,
+            None => 0,
+        },
+        Module::Dotfiles => match enabled(runner, module, system.dotfiles.take())? {
+            Some(dotfiles) => {
+                check::check_dotfiles_drift(runner, &dotfiles, &dotfiles_dir.canonicalize()?)?
+            }
+            None => 0,
+        },
+        Module::Vscode => match enabled(runner, module, system.vscode.take())? {
+            Some(vscode) => check::check_vscode(runner, &vscode)?,
+            None => 0,
+        },
+        Module::Npm => match enabled(runner, module, system.npm.take())? {
+            Some(npm) => check::check_npm(runner, &npm)?,
+            None => 0,
+        },
+        Module::Cargo => match enabled(runner, module, system.cargo.take())? {
+            Some(cargo) => check::check_cargo(runner, &cargo)?,
+            None => 0,
+        },
+        Module::Macos => match enabled(runner, module, system.macos.take())? {
+            Some(macos) => check::check_macos(runner, &macos)?,
+            None => 0,
+        },
+    };
+
+    Ok(drifted)
+}
+
+/// The settings for a run that apply across modules.
+struct RunOptions<'a> {
+    system_config_dir: &'a Path,
+    dotfiles_dir: &'a Path,
+    install_xcode_tools: bool,
+    on_failure: OnFailure,
+    concurrency_safe: bool,
+    prune: bool,
+    upgrade: bool,
+    backup: bool,
+    pull: bool,
+    /// Whether the dotfiles repository was already synced, ahead of `[brew]`.
+    dotfiles_synced: bool,
+    jobs: usize,
+    retry: Retry,
+}
+
+/// Applies a single module's section of the configuration, if it's enabled,
+/// and returns what that changed. Configured sections that are skipped because
+/// they can't be applied, rather than because of their `when` guard, are added
+/// to `skipped`.
+fn run_module(
+    runner: &dyn CommandRunner,
+    module: Module,
+    system: &mut System,
+    options: &RunOptions,
+    skipped: &mut Vec<String>,
+) -> anyhow::Result<Changes> {
+    let &RunOptions {
+        system_config_dir,
+        dotfiles_dir,
+        install_xcode_tools,
+        on_failure,
+        concurrency_safe,
+        prune,
+        upgrade,
+        backup,
+        pull,
+        dotfiles_synced,
+        jobs,
+        retry,
+    } = options;
+
+    let mut changes = Changes::default();
+    match module {
+        Module::ShellInstallers => {
+            // TODO: There's a chicken and egg problem here, some shell installers
+            // require curl or wget, or some other tooling, but at least for brew, we'll
+            // need to install that first before we have a macOS package manager. We
+            // might have to special-case the installation of brew first if requested
+            // for install.
+            if let Some(shell_installers) = enabled(runner, module, system.shell_installers.take())?
+            {
+                for installer in shell_installers.install {
+                    changes.merge(installer.install(runner)?);
+                }
+            }
+        }
+        Module::Brew => {
+            if let Some(brew) = enabled(runner, module, system.brew.take())? {
+                if !check_command_line_tools(runner, install_xcode_tools)? {
+                    skipped.push("`[brew]`: Xcode Command Line Tools are not installed".into());
+                    return Ok(changes);
+                }
+
+                check_brew_installed(runner)?;
+                if !brew.taps().is_empty() {
+                    let installed_taps = get_installed_taps(runner)?;
+                    changes.merge(tap_missing_taps(runner, &brew, &installed_taps)?);
+                }
+                let installed_packages = get_installed_brew_packages(runner)?;
+                let missing_packages = find_missing_packages(&brew, &installed_packages);
+                changes.merge(install_missing_packages(
+                    runner,
+                    &missing_packages,
+                    on_failure,
+                    jobs,
+                    retry,
+                )?);
+                changes.merge(handle_keg_only_formulae(runner, &brew, &missing_packages)?);
+
+                if upgrade {
+                    let outdated_packages = get_outdated_packages(runner, &brew)?;
+                    changes.merge(upgrade_packages(runner, &outdated_packages, on_failure)?);
+                }
+
+                if prune {
+                    changes.merge(prune_packages(
+                        runner,
+                        &brew,
+                        &installed_packages,
+                        on_failure,
+                    )?);
+                }
+
+                if !brew.services().is_empty() {
+                    let service_statuses = get_service_statuses(runner)?;
+                    let stopped_services = find_stopped_services(&brew, &service_statuses);
+                    changes.merge(start_services(runner, &stopped_services)?);
+                }
+
+                if brew.pinned().is_some() {
+                    let pinned = get_pinned_formulae(runner)?;
+                    changes.merge(reconcile_pins(runner, &brew, &pinned)?);
+                }
+            }
+        }
+        Module::Mas => {
+            if let Some(mut mas) = enabled(runner, module, system.mas.take())? {
+                check_mas_installed(runner)?;
+                if check_apple_id(runner, &mas)? {
+                    resolve_app_ids(runner, &mut mas, system_config_dir)?;
+                    let installed_apps = get_installed_apps(runner)?;
+                    let missing_apps = find_missing_apps(&mas, &installed_apps);
+                    changes.merge(install_missing_apps(
+                        runner,
+                        &missing_apps,
+                        on_failure,
+                        retry,
+                    )?);
+                    verify_installed_apps(runner, &missing_apps)?;
+
+                    if upgrade {
+                        let outdated_apps = find_outdated_apps(runner, &mas, &installed_apps)?;
+                        changes.merge(upgrade_outdated_apps(runner, &outdated_apps, on_failure)?);
+                    }
+                } else {
+                    skipped.push("`[mas]`: signed in to the wrong Apple ID".into());
+                }
+            }
+        }
+        Module::Dotfiles => {
+            if let Some(dotfiles) = enabled(runner, module, system.dotfiles.take())? {
+                if let Some(git) = dotfiles.git()
+                    && !dotfiles_synced
+                {
+                    changes.merge(git::sync_repo(runner, git, dotfiles_dir, pull)?);
+                }
+                changes.merge(setup_dotfiles(
+                    runner,
+                    &dotfiles,
+                    &dotfiles_dir.canonicalize()?,
+                    backup,
+                    Retry::new(TRANSIENT_FS_RETRIES),
+                )?);
+            }
+        }
+        Module::Vscode => {
+            if let Some(vscode) = enabled(runner, module, system.vscode.take())? {
+                changes.merge(vscode.install_missing_extensions(runner, on_failure)?);
+                if vscode.settings.is_some() {
+                    changes.merge(vscode.sync_settings(&vscode::settings_path()?)?);
+                }
+            }
+        }
+        Module::Npm => {
+            if let Some(npm) = enabled(runner, module, system.npm.take())? {
+                check_npm_installed(runner)?;
+                let installed_packages = get_installed_npm_packages(runner)?;
+                let missing_packages = find_missing_npm_packages(&npm, &installed_packages);
+                changes.merge(install_missing_npm_packages(
+                    runner,
+                    &missing_packages,
+                    on_failure,
+                )?);
+            }
+        }
+        Module::Cargo => {
+            if let Some(cargo) = enabled(runner, module, system.cargo.take())? {
+                check_cargo_installed(runner)?;
+                let installed_crates = get_installed_crates(runner)?;
+                let missing_crates = find_missing_crates(&cargo, &installed_crates);
+                changes.merge(install_missing_crates(runner, &missing_crates, on_failure)?);
+            }
+        }
+        Module::Macos => {
+            if let Some(macos) = enabled(runner, module, system.macos.take())? {
+                if concurrency_safe {
+                    let batch = macos::DefaultsBatch::new(runner);
+                    let applied = apply_macos(&batch, macos, system_config_dir);
+                    batch.flush()?;
+                    changes.merge(applied?);
+                } else {
+                    changes.merge(apply_macos(runner, macos, system_config_dir)?);
+                }
+            }
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Applies every configured `[macos]` setting, returning what changed.
+fn apply_macos(
+    runner: &dyn CommandRunner,
+    macos: MacOS,
+    system_config_dir: &Path,
+) -> anyhow::Result<Changes> {
+    let mut changes = Changes::default();
+    let restart = macos.restart_strategy;
+    // Imported first, so the typed settings below win over anything the
+    // imported domains also set.
+    if let Some(imports) = &macos.import {
+        changes.merge(macos::apply_defaults_imports(
+            runner,
+            imports,
+            system_config_dir,
+            restart,
+        )?);
+    }
+
+    let mut dock_changed = false;
+    if let Some(dock) = &macos.dock {
+        dock_changed |= changes.merge(macos::apply_dock_settings(runner, dock)?);
+    }
+    if let Some(mission_control) = &macos.mission_control {
+        dock_changed |= changes.merge(macos::apply_mission_control_settings(
+            runner,
+            mission_control,
+        )?);
+    }
+    if let Some(trackpad) = &macos.trackpad {
+        let (trackpad_changes, trackpad_dock_changed) =
+            macos::apply_trackpad_settings(runner, trackpad)?;
+        changes.merge(trackpad_changes);
+        dock_changed |= trackpad_dock_changed;
+    }
+    if let Some(custom) = &macos.custom {
+        let (custom_changes, custom_dock_changed) =
+            macos::apply_custom_defaults(runner, custom, restart)?;
+        changes.merge(custom_changes);
+        dock_changed |= custom_dock_changed;
+    }
+
+    if dock_changed {
+        macos::restart_dock(runner, restart)?;
+    }
+
+    if let Some(safari) = macos.safari {
+        changes.merge(macos::apply_safari_settings(runner, &safari, restart)?);
+    }
+    if let Some(system) = macos.system {
+        changes.merge(macos::apply_system_settings(runner, &system)?);
+    }
+    if let Some(magic_mouse) = macos.magic_mouse {
+        changes.merge(macos::apply_magic_mouse_settings(runner, &magic_mouse)?);
+    }
+    if let Some(keyboard) = macos.keyboard {
+        changes.merge(macos::apply_keyboard_settings(runner, &keyboard)?);
+    }
+    if let Some(finder) = macos.finder {
+        changes.merge(macos::apply_finder_settings(runner, &finder, restart)?);
+    }
+    if let Some(pointer) = macos.pointer {
+        changes.merge(macos::apply_pointer_settings(runner, &pointer)?);
+    }
+    if let Some(menu_bar) = macos.menu_bar {
+        changes.merge(macos::apply_menu_bar_settings(runner, &menu_bar)?);
+    }
+    if let Some(window_tiling) = macos.window_tiling {
+        changes.merge(macos::apply_window_tiling_settings(runner, &window_tiling)?);
+    }
+    if let Some(stage_manager) = macos.stage_manager {
+        changes.merge(macos::apply_stage_manager_settings(runner, &stage_manager)?);
+    }
+    if let Some(control_center) = macos.control_center {
+        changes.merge(macos::apply_control_center_settings(
+            runner,
+            &control_center,
+        )?);
+    }
+    if let Some(sound) = macos.sound {
+        changes.merge(macos::apply_sound_settings(runner, &sound)?);
+    }
+    if let Some(software_update) = macos.software_update {
+        changes.merge(macos::apply_software_update_settings(
+            runner,
+            &software_update,
+        )?);
+    }
+
+    Ok(changes)
+}
+
+fn main() -> ExitCode {
     let cli = Cli::parse();
 
+    let (json_report, detect_changes) = match &cli {
+        Cli::Run {
+            format,
+            detect_changes,
+            ..
+        } => (*format == OutputFormat::Json, *detect_changes),
+        _ => (false, false),
+    };
+    let logged = match &cli {
+        Cli::Run {
+            verbose,
+            quiet,
+            quiet_success,
+            output_file,
+            truncate_output_file,
+            color,
+            log_json_lines,
+            ..
+        } => {
+            if json_report && log_json_lines.as_deref() == Some(Path::new("-")) {
+                eprintln!("❌ --format json and --log-json-lines - can't both write to stdout");
+                return ExitCode::FAILURE;
+            }
+            output::init(
+                output::level(*verbose, *quiet),
+                *quiet_success,
+                *color,
+                output_file.as_deref(),
+                *truncate_output_file,
+                log_json_lines.as_deref(),
+                json_report,
+            )
+        }
+        Cli::Check { color, .. } | Cli::Doctor { color, .. } | Cli::Export { color, .. } => {
+            output::init(LevelFilter::Info, false, *color, None, false, None, false)
+        }
+        Cli::Add { .. } | Cli::Config { .. } | Cli::Completions { .. } | Cli::Manpage { .. } => {
+            output::init(
+                LevelFilter::Info,
+                false,
+                ColorChoice::Auto,
+                None,
+                false,
+                None,
+                false,
+            )
+        }
+    };
+    if let Err(e) = logged {
+        eprintln!("❌ Could not set up output: {e}");
+        return ExitCode::FAILURE;
+    }
+
+    let mut report = RunReport::default();
+    let exit_code = match run(cli, &mut report) {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            error!("Error: {e:#}");
+            report.errors.push(format!("{e:#}"));
+            ExitCode::FAILURE
+        }
+    };
+
+    if json_report {
+        match serde_json::to_string_pretty(&report) {
+            Ok(report) => println!("{report}"),
+            Err(e) => {
+                eprintln!("❌ Could not write the report: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    if detect_changes && report.changes.has_changes() && exit_code == ExitCode::SUCCESS {
+        info!("🔧 The run changed this machine, exiting with 2");
+        return ExitCode::from(2);
+    }
+
+    exit_code
+}
+
+/// Runs the command, adding what `omiros run` changed, and the errors it ran
+/// into, to `report`.
+fn run(cli: Cli, report: &mut RunReport) -> anyhow::Result<ExitCode> {
     match cli {
-        Cli::Completions { shell } => {
+        Cli::Completions { shell, output } => {
             // This is needed by the generator below to walk over the CLI spec
             // so that it can emit completions.
             let mut cmd = Cli::command();
-            generate(shell, &mut cmd, "omiros", &mut io::stdout());
+            write_generated(output.as_deref(), |out| {
+                generate(shell, &mut cmd, "omiros", out);
+                Ok(())
+            })?;
         }
-        Cli::Run {
+        Cli::Manpage { output } => {
+            write_generated(output.as_deref(), |out| {
+                Man::new(Cli::command()).render(out)
+            })?;
+        }
+        Cli::Check {
             system_config_dir,
             dotfiles_dir,
+            detailed_exitcode,
+            ..
+        } => {
+            let runner = SystemRunner::default();
+            let mut system = load_system(&runner, &system_config_dir)?;
+
+            let mut drifted = 0;
+            for module in system.order().to_vec() {
+                output::set_module(Some(module.name()));
+                drifted += check_module(
+                    &runner,
+                    module,
+                    &mut system,
+                    &system_config_dir,
+                    &dotfiles_dir,
+                )?;
+            }
+            output::set_module(None);
+
+            check::report(drifted);
+            if drifted > 0 && detailed_exitcode {
+                return Ok(ExitCode::from(2));
+            } else if drifted > 0 {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Cli::Doctor {
+            system_config_dir,
+            dotfiles_dir,
+            fix,
+            ..
+        } => {
+            let runner = SystemRunner::default();
+            let system = system_config_dir
+                .as_deref()
+                .map(|dir| load_system(&runner, dir))
+                .transpose()?;
+            let dotfiles_dir = dotfiles_dir.map(|dir| dir.canonicalize()).transpose()?;
+            let dotfiles = system
+                .as_ref()
+                .and_then(|system| system.dotfiles.as_ref())
+                .zip(dotfiles_dir.as_deref());
+
+            let problems = run_doctor(&runner, dotfiles, fix)?;
+            if problems > 0 {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Cli::Export {
+            output,
+            macos,
+            force,
+            ..
+        } => {
+            if output.exists() && !force {
+                error!(
+                    "❌ {} already exists, pass --force to overwrite it",
+                    output.display()
+                );
+                return Ok(ExitCode::FAILURE);
+            }
+
+            let system = export_system(&SystemRunner::default(), macos)?;
+            fs::write(&output, toml::to_string_pretty(&system)?)?;
+            info!("✅ Wrote {}", output.display());
+        }
+        Cli::Add {
+            system_config_dir,
+            item,
         } => {
             let system_config_path = system_config_dir.join("system.toml");
-            let system_config = fs::read_to_string(system_config_path)?;
-            let system: System = toml::from_str(&system_config)?;
+            let mut system_config: toml_edit::DocumentMut =
+                fs::read_to_string(&system_config_path)?.parse()?;
 
-            // TODO: There's a chicken and egg problem here, some shell installers
-            // require curl or wget, or some other tooling, but at least for brew, we'll
-            // need to install that first before we have a macOS package manager. We
-            // might have to special-case the installation of brew first if requested
-            // for install.
-            if let Some(shell_installers) = system.shell_installers {
-                for installer in shell_installers.install {
-                    installer.install()?;
+            let runner = SystemRunner::default();
+            let (added, description) = match &item {
+                AddItem::Brew { name, cask } => (
+                    add::add_brew(&mut system_config, name, *cask)?,
+                    format!("brew {} {name}", if *cask { "cask" } else { "formula" }),
+                ),
+                AddItem::Mas { id } => {
+                    check_mas_installed(&runner)?;
+                    let app = get_installed_apps(&runner)?
+                        .apps
+                        .into_iter()
+                        .find(|app| app.id == *id)
+                        .ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "no installed app has id {id}, install it with `mas install {id}` first"
+                            )
+                        })?;
+                    (
+                        add::add_mas(&mut system_config, &app)?,
+                        format!("mas app {app}"),
+                    )
                 }
-            } else {
-                println!("ℹ️  No `[shell-installers]` block in configuration file");
+                AddItem::Vscode { id } => (
+                    add::add_vscode(&mut system_config, id)?,
+                    format!("VS Code extension {id}"),
+                ),
+            };
+
+            if !added {
+                info!("ℹ️  The {description} is already in system.toml");
+                return Ok(ExitCode::SUCCESS);
             }
+            // Make sure the result is something `omiros run` will accept.
+            let mut parsed: toml::Table = system_config.to_string().parse()?;
+            migrate::migrate(&mut parsed)?;
+            let _: System = parsed.try_into()?;
 
-            if let Some(brew) = system.brew {
-                check_brew_installed()?;
-                let installed_packages = get_installed_brew_packages()?;
-                let missing_packages = find_missing_packages(&brew, &installed_packages);
-                install_missing_packages(&missing_packages)?;
+            fs::write(&system_config_path, system_config.to_string())?;
+            info!("✅ Added the {description} to system.toml");
+        }
+        Cli::Config {
+            command:
+                ConfigCommand::Migrate {
+                    system_config_dir,
+                    dry_run,
+                },
+        } => {
+            let system_config_path = system_config_dir.join("system.toml");
+            let (from, files) = migrate::migrate_files(&system_config_path)?;
+
+            if dry_run {
+                for file in &files {
+                    if files.len() > 1 {
+                        println!("# {}", file.path.display());
+                    }
+                    print!("{}", file.migrated);
+                }
+            } else if from == CURRENT_VERSION {
+                info!("ℹ️  system.toml is already config version {CURRENT_VERSION}");
             } else {
-                println!("ℹ️  No `[brew]` block in configuration file");
+                for file in files.iter().filter(|file| file.changed()) {
+                    let backup = file.write()?;
+                    info!(
+                        "📦 Backed up {} to {}",
+                        file.path.display(),
+                        backup.display()
+                    );
+                }
+                info!("✅ Migrated system.toml from config version {from} to {CURRENT_VERSION}");
             }
-
-            if let Some(mas) = system.mas {
-                check_mas_installed()?;
-                let installed_apps = get_installed_apps()?;
-                let missing_apps = find_missing_apps(&mas, &installed_apps);
-                install_missing_apps(&missing_apps)?;
+        }
+        Cli::Config {
+            command:
+                ConfigCommand::Validate {
+                    system_config_dir,
+                    dotfiles_dir,
+                },
+        } => {
+            let system = load_system(&SystemRunner::default(), &system_config_dir)?;
+            let problems = validate::validate(&system, dotfiles_dir.as_deref());
+            validate::report(&problems);
+            // Unlike `run`, which only refuses to apply a configuration with
+            // errors, any problem at all fails validation.
+            if !problems.is_empty() {
+                return Ok(ExitCode::FAILURE);
+            }
+            info!("✅ config OK");
+        }
+        Cli::Run {
+            system_config_dir,
+            dotfiles_dir,
+            install_xcode_tools,
+            config_check_only,
+            print_config,
+            plan_dotfiles,
+            timeout,
+            strict,
+            concurrency_safe,
+            prune,
+            upgrade,
+            backup,
+            no_pull,
+            jobs,
+            retries,
+            only,
+            skip,
+            fail_fast,
+            ..
+        } => {
+            let on_failure = if fail_fast {
+                OnFailure::FailFast
             } else {
-                println!("ℹ️  No `[mas]` block in configuration file");
+                OnFailure::KeepGoing
+            };
+
+            let runner = SystemRunner::new(timeout.map(Duration::from_secs));
+
+            let mut system = load_system(&runner, &system_config_dir)?;
+            if print_config {
+                print!("{}", toml::to_string_pretty(&system)?);
+                return Ok(ExitCode::SUCCESS);
             }
 
-            if let Some(dotfiles) = system.dotfiles {
-                setup_dotfiles(&dotfiles, &dotfiles_dir.canonicalize()?)?;
-            } else {
-                println!("ℹ️  No `[dotfiles]` block in configuration file");
+            let problems = validate::for_modules(
+                validate::validate(&system, Some(&dotfiles_dir)),
+                &only,
+                &skip,
+            );
+            if validate::report(&problems) {
+                error!("❌ Configuration has errors, not applying it");
+                report
+                    .errors
+                    .push("configuration has errors, not applying it".to_string());
+                return Ok(ExitCode::FAILURE);
+            }
+            if config_check_only {
+                info!("✅ config OK");
+                return Ok(ExitCode::SUCCESS);
+            }
+            if plan_dotfiles {
+                match &system.dotfiles {
+                    Some(dotfiles) => print!(
+                        "{}",
+                        dotfiles::plan_dotfiles(dotfiles, &dotfiles_dir.canonicalize()?)?
+                    ),
+                    None => info!("ℹ️  No `[dotfiles]` block in system.toml"),
+                }
+                return Ok(ExitCode::SUCCESS);
             }
 
-            if let Some(vscode) = system.vscode {
-                vscode.install_missing_extensions()?;
-            } else {
-                println!("ℹ️  No `[vscode]` block in configuration file");
+            if let Some(macos) = &system.macos
+                && Module::Macos.selected(&only, &skip)
+            {
+                macos::warn_if_missing_full_disk_access(
+                    &runner,
+                    &macos.full_disk_access_sections(),
+                )?;
+            }
+
+            if let Some(brew) = &mut system.brew {
+                brew.resolve_formula_paths(&system_config_dir)?;
+            }
+            let mut dotfiles_synced = false;
+            if Module::Brew.selected(&only, &skip)
+                && Module::Dotfiles.selected(&only, &skip)
+                && system.sync_dotfiles_before_brew(&dotfiles_dir)
+                && let Some(changes) =
+                    sync_dotfiles_early(&runner, &system, &dotfiles_dir, !no_pull)?
+            {
+                report.changes.merge(changes);
+                dotfiles_synced = true;
             }
 
-            if let Some(macos) = system.macos {
-                let mut dock_changed = false;
-                if let Some(dock) = &macos.dock {
-                    dock_changed |= macos::apply_dock_settings(dock)?;
+            let options = RunOptions {
+                system_config_dir: &system_config_dir,
+                dotfiles_dir: &dotfiles_dir,
+                install_xcode_tools,
+                on_failure,
+                concurrency_safe,
+                prune,
+                upgrade,
+                backup,
+                pull: !no_pull,
+                dotfiles_synced,
+                jobs: jobs
+                    .or_else(|| thread::available_parallelism().ok())
+                    .map_or(1, NonZeroUsize::get),
+                retry: Retry::new(retries),
+            };
+            let mut skipped = Vec::new();
+            let mut failed = Vec::new();
+            for module in system.order().to_vec() {
+                if !module.selected(&only, &skip) {
+                    info!("ℹ️  `[{module}]` skipped (not selected)");
+                    continue;
                 }
-                if let Some(mission_control) = &macos.mission_control {
-                    dock_changed |= macos::apply_mission_control_settings(mission_control)?;
+                output::set_module(Some(module.name()));
+                let result = run_module(&runner, module, &mut system, &options, &mut skipped);
+                match result {
+                    Ok(changes) => {
+                        report.changes.merge(changes);
+                    }
+                    Err(e) if on_failure == OnFailure::KeepGoing => {
+                        error!("❌ `[{module}]` failed: {e:#}");
+                        report.errors.push(format!("[{module}] failed: {e:#}"));
+                        failed.push(module);
+                    }
+                    Err(e) => return Err(e),
                 }
+                output::set_module(None);
+            }
 
-                if dock_changed {
-                    macos::restart_dock()?;
-                }
+            if !failed.is_empty() {
+                let failed: Vec<_> = failed.into_iter().map(Module::name).collect();
+                error!(
+                    "❌ {} section(s) failed, continued with the rest: {}",
+                    failed.len(),
+                    failed.join(", ")
+                );
+                return Ok(ExitCode::FAILURE);
+            }
 
-                if let Some(safari) = macos.safari {
-                    macos::apply_safari_settings(&safari)?;
-                }
-                if let Some(system) = macos.system {
-                    macos::apply_system_settings(&system)?;
-                }
-                if let Some(magic_mouse) = macos.magic_mouse {
-                    macos::apply_magic_mouse_settings(&magic_mouse)?;
-                }
-                if let Some(finder) = macos.finder {
-                    macos::apply_finder_settings(&finder)?;
-                }
-            } else {
-                println!("ℹ️  No `[macos]` block in configuration file");
+            if strict && !skipped.is_empty() {
+                error!(
+                    "❌ --strict: these configured sections were skipped:\n{}",
+                    skipped.join("\n")
+                );
+                report.errors.push(format!(
+                    "--strict: these configured sections were skipped: {}",
+                    skipped.join(", ")
+                ));
+                return Ok(ExitCode::FAILURE);
             }
         }
     }
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
@@ -1,34 +1,160 @@
+use log::{error, info, warn};
 use pest::Parser;
 use pest_derive::Parser;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use std::{collections::HashSet, process::Command, str::from_utf8};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::from_utf8,
+};
 
-use crate::{errors::SetupError, system_utils::command};
+use crate::{
+    errors::{OnFailure, SetupError, failures},
+    output::{event, print_compared_sets, unchanged},
+    report::Changes,
+    runner::CommandRunner,
+    system::Section,
+    system_utils::{Retry, Seen, command},
+    validate::{ConfigProblem, warn_duplicates},
+};
 
 const MAS_PROGRAM_NAME: &str = "mas";
 
+/// Popular apps, so they can be declared by name alone without needing a
+/// network `mas search` to find their ID.
+const KNOWN_APPS: &[(&str, &str)] = &[
+    ("1Password 7", "1333542190"),
+    ("Amphetamine", "937984704"),
+    ("Bear", "1091189122"),
+    ("Day One", "1055511498"),
+    ("Final Cut Pro", "424389933"),
+    ("GarageBand", "682658836"),
+    ("Hidden Bar", "1452453066"),
+    ("iMovie", "408981434"),
+    ("Keynote", "409183694"),
+    ("Logic Pro", "634148309"),
+    ("Magnet", "441258766"),
+    ("Microsoft Excel", "462058435"),
+    ("Microsoft Outlook", "985367838"),
+    ("Microsoft PowerPoint", "462062816"),
+    ("Microsoft Word", "462054704"),
+    ("Numbers", "409203825"),
+    ("Pages", "409201541"),
+    ("Pixelmator Pro", "1289583905"),
+    ("Slack", "803453959"),
+    ("Tailscale", "1475387142"),
+    ("Telegram", "747648890"),
+    ("TestFlight", "899247664"),
+    ("The Unarchiver", "425424353"),
+    ("Things 3", "904280696"),
+    ("Todoist", "585829637"),
+    ("WireGuard", "1451685025"),
+    ("Xcode", "497799835"),
+];
+
 /// Represents the Mac App Store configuration, specifying which apps to install.
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
 pub struct Mas {
     /// The list of apps to install.
     pub apps: Vec<App>,
+    /// The Apple ID the apps were bought with. `mas` can't switch accounts, so
+    /// this is only checked against the one signed in to the App Store.
+    pub apple_id: Option<String>,
+    /// A TOML file mapping app names to IDs, relative to the directory
+    /// containing `system.toml`. Consulted before the built-in catalog when
+    /// resolving apps declared without an `id`.
+    pub catalog: Option<PathBuf>,
+    /// Shell command that must exit zero for this section to be applied.
+    pub when: Option<String>,
+}
+
+impl Mas {
+    /// Checks for duplicate and malformed app IDs.
+    pub(crate) fn validate(&self, problems: &mut Vec<ConfigProblem>) {
+        warn_duplicates(
+            "mas.apps",
+            self.apps
+                .iter()
+                .map(|app| app.id.as_str())
+                .filter(|id| !id.is_empty()),
+            problems,
+        );
+
+        // Apps without an `id` are looked up by name when they're installed.
+        for app in self.apps.iter().filter(|app| !app.id.is_empty()) {
+            if !app.id.chars().all(|c| c.is_ascii_digit()) {
+                problems.push(ConfigProblem::error(
+                    "mas.apps",
+                    format!("`{}` has a non-numeric id `{}`", app.name, app.id),
+                ));
+            }
+        }
+    }
+}
+
+impl Mas {
+    /// A configuration declaring exactly the `installed` apps, sorted by name.
+    pub fn from_installed(installed: &InstalledMasApps) -> Self {
+        let mut apps: Vec<_> = installed.apps.iter().cloned().collect();
+        apps.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+        Mas {
+            apps,
+            apple_id: None,
+            catalog: None,
+            when: None,
+        }
+    }
+}
+
+impl Section for Mas {
+    fn when(&self) -> Option<&str> {
+        self.when.as_deref()
+    }
 }
 
 /// Represents a single Mac App Store application.
-#[derive(Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Hash, Clone)]
 pub struct App {
     /// The name of the app.
     pub name: String,
-    /// The ID of the app in the Mac App Store.
+    /// The ID of the app in the Mac App Store. When left out, it's looked up
+    /// by name, see [`resolve_app_ids`].
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub id: String,
 }
 
+impl fmt::Display for App {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.id, self.name)
+    }
+}
+
+/// A Mac App Store app as listed by `mas list`, with its installed version.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct InstalledApp {
+    pub app: App,
+    pub version: String,
+}
+
 /// Represents the set of currently installed Mac App Store apps.
 #[derive(Debug)]
 pub struct InstalledMasApps {
     /// The set of installed apps.
     pub apps: HashSet<App>,
+    /// The installed version of each app, keyed by ID.
+    pub versions: HashMap<String, String>,
+}
+
+/// An installed app that has a newer version in the Mac App Store.
+#[derive(Debug, PartialEq, Eq)]
+pub struct OutdatedApp {
+    pub app: App,
+    pub installed_version: String,
+    pub available_version: String,
 }
 
 /// Represents the set of missing Mac App Store apps that need to be installed.
@@ -39,25 +165,77 @@ pub struct MissingMasApps<'a> {
 }
 
 /// Checks if `mas` is installed and available in the system's PATH.
-pub fn check_mas_installed() -> Result<(), SetupError> {
-    let _ = command(MAS_PROGRAM_NAME)?;
+pub fn check_mas_installed(runner: &dyn CommandRunner) -> Result<(), SetupError> {
+    let _ = command(runner, MAS_PROGRAM_NAME)?;
 
     Ok(())
 }
 
+/// Returns the Apple ID signed in to the Mac App Store, or `None` if nobody is
+/// signed in.
+pub fn get_signed_in_account(runner: &dyn CommandRunner) -> Result<Option<String>, SetupError> {
+    let output = runner.output(MAS_PROGRAM_NAME, &["account"])?;
+    let success = output.success();
+    let account = String::from_utf8(output.stdout)?.trim().to_string();
+
+    if success && !account.is_empty() {
+        Ok(Some(account))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Checks that the Apple ID signed in to the App Store is the one the
+/// configuration expects, if it names one. Returns true if the apps can be
+/// installed, otherwise the `[mas]` section should be skipped, since every
+/// install would fail anyway.
+pub fn check_apple_id(runner: &dyn CommandRunner, mas: &Mas) -> Result<bool, SetupError> {
+    let Some(expected) = &mas.apple_id else {
+        return Ok(true);
+    };
+
+    match get_signed_in_account(runner)? {
+        Some(account) if account.eq_ignore_ascii_case(expected) => {
+            unchanged!("✅ Signed in to the App Store as {account}");
+            Ok(true)
+        }
+        Some(account) => {
+            error!(
+                "❌ Config expects Apple ID {expected} but {account} is signed in, skipping `[mas]`"
+            );
+            info!("💡 Sign in to the App Store as {expected} and run omiros again");
+            Ok(false)
+        }
+        // Newer versions of macOS don't let `mas account` see who's signed in,
+        // so this can't tell a signed out App Store from an unsupported one.
+        None => {
+            warn!("⚠️  Could not determine the signed-in Apple ID, expected {expected}");
+            Ok(true)
+        }
+    }
+}
+
 /// Retrieves the list of currently installed Mac App Store apps.
-pub fn get_installed_apps() -> anyhow::Result<InstalledMasApps> {
-    let mas_output = Command::new("mas").args(["list"]).output()?;
+pub fn get_installed_apps(runner: &dyn CommandRunner) -> anyhow::Result<InstalledMasApps> {
+    let mas_output = runner.output(MAS_PROGRAM_NAME, &["list"])?;
 
-    let apps = from_utf8(&mas_output.stdout)?
+    let installed: Vec<_> = from_utf8(&mas_output.stdout)?
         .lines()
         .map(parse_mas_list_record)
         .collect();
+    let versions = installed
+        .iter()
+        .map(|installed| (installed.app.id.clone(), installed.version.clone()))
+        .collect();
+    let apps = installed
+        .into_iter()
+        .map(|installed| installed.app)
+        .collect();
 
-    Ok(InstalledMasApps { apps })
+    Ok(InstalledMasApps { apps, versions })
 }
 
-fn parse_mas_list_record(record: &str) -> App {
+fn parse_mas_list_record(record: &str) -> InstalledApp {
     let record = record.trim();
     let record = MasListParser::parse(Rule::record, record)
         .expect("unsuccessful mas list parse")
@@ -66,31 +244,109 @@ fn parse_mas_list_record(record: &str) -> App {
 
     let mut id: String = Default::default();
     let mut name: String = Default::default();
+    let mut version: String = Default::default();
 
     for field in record.into_inner() {
         match field.as_rule() {
             Rule::app_id => id = field.as_str().to_string(),
             Rule::app_name => name = field.as_str().trim().to_string(),
-            // We're ignoring the app_version for now, even though we parse it.
-            Rule::app_version => (),
+            Rule::app_version => {
+                version = field.as_str().trim_matches(['(', ')']).to_string();
+            }
             Rule::EOI => (),
             _ => unreachable!(),
         }
     }
 
-    App { id, name }
+    InstalledApp {
+        app: App { id, name },
+        version,
+    }
 }
 
 #[derive(Parser)]
 #[grammar = "grammars/mas_list.pest"]
 pub struct MasListParser;
 
+/// Fills in the ID of every app declared by name alone. Names are matched
+/// case-insensitively against the `catalog` file, then [`KNOWN_APPS`], and only
+/// then with a `mas search`, which needs the network.
+pub fn resolve_app_ids(
+    runner: &dyn CommandRunner,
+    mas: &mut Mas,
+    system_config_dir: &Path,
+) -> anyhow::Result<()> {
+    if mas.apps.iter().all(|app| !app.id.is_empty()) {
+        return Ok(());
+    }
+
+    let mut catalog: HashMap<String, String> = KNOWN_APPS
+        .iter()
+        .map(|(name, id)| (name.to_lowercase(), id.to_string()))
+        .collect();
+    if let Some(file) = &mas.catalog {
+        let file = system_config_dir.join(file);
+        let contents = fs::read_to_string(&file)
+            .map_err(|e| anyhow::anyhow!("could not read {}: {e}", file.display()))?;
+        let entries: HashMap<String, String> = toml::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("could not parse {}: {e}", file.display()))?;
+        catalog.extend(
+            entries
+                .into_iter()
+                .map(|(name, id)| (name.to_lowercase(), id)),
+        );
+    }
+
+    for app in mas.apps.iter_mut().filter(|app| app.id.is_empty()) {
+        app.id = match catalog.get(&app.name.to_lowercase()) {
+            Some(id) => id.clone(),
+            None => search_app_id(runner, &app.name)?,
+        };
+        info!("🔎 Resolved {app}");
+    }
+
+    Ok(())
+}
+
+/// Looks up the ID of the app named exactly `name` with `mas search`, which
+/// also lists apps whose names merely contain it.
+fn search_app_id(runner: &dyn CommandRunner, name: &str) -> anyhow::Result<String> {
+    let output = runner.output(MAS_PROGRAM_NAME, &["search", name])?;
+
+    // The results are formatted the same as `mas list`, but a failed search
+    // prints a message instead.
+    from_utf8(&output.stdout)?
+        .lines()
+        .filter(|line| MasListParser::parse(Rule::record, line.trim()).is_ok())
+        .map(parse_mas_list_record)
+        .find(|installed| installed.app.name.eq_ignore_ascii_case(name))
+        .map(|installed| installed.app.id)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "`{name}` isn't in the mas catalog and `mas search` found no app by \
+                 exactly that name, give its `id` instead"
+            )
+        })
+}
+
 /// Compares the desired Mac App Store apps with the installed apps to determine which ones are missing.
+/// Apps are matched by ID alone, since a name declared by hand rarely matches
+/// the one `mas list` shows exactly.
 pub fn find_missing_apps<'a>(desired: &'a Mas, installed: &InstalledMasApps) -> MissingMasApps<'a> {
     let mut missing = MissingMasApps { apps: Vec::new() };
 
+    let desired_apps: Vec<_> = desired.apps.iter().map(App::to_string).collect();
+    let installed_apps: Vec<_> = installed.apps.iter().map(App::to_string).collect();
+    print_compared_sets(
+        "mas apps",
+        desired_apps.iter().map(String::as_str),
+        installed_apps.iter().map(String::as_str),
+    );
+
+    let installed_ids: HashSet<_> = installed.apps.iter().map(|app| &app.id).collect();
+    let mut seen = Seen::new("mas app");
     for app in &desired.apps {
-        if !installed.apps.contains(app) {
+        if !installed_ids.contains(&app.id) && (app.id.is_empty() || seen.first(&app.id)) {
             missing.apps.push(app);
         }
     }
@@ -98,17 +354,191 @@ pub fn find_missing_apps<'a>(desired: &'a Mas, installed: &InstalledMasApps) ->
     missing
 }
 
-/// Installs the missing Mac App Store apps.
-pub fn install_missing_apps(missing: &MissingMasApps) -> Result<(), SetupError> {
+/// Installs the missing Mac App Store apps, stopping at the first failure or
+/// attempting every app depending on `on_failure`. Each failed install is
+/// retried as `retry` allows first.
+pub fn install_missing_apps(
+    runner: &dyn CommandRunner,
+    missing: &MissingMasApps,
+    on_failure: OnFailure,
+    retry: Retry,
+) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    let mut failed = Vec::new();
+
     for app in &missing.apps {
-        println!("Installing app: {}", app.name);
-        let status = Command::new("mas").args(["install", &app.id]).status()?;
+        event!(
+            "install",
+            app.id.as_str(),
+            started,
+            "Installing app: {}",
+            app.name
+        );
+        let status = retry.run(&format!("Installing app {app}"), || {
+            runner.status(MAS_PROGRAM_NAME, &["install", &app.id])
+        })?;
         if !status.success() {
-            return Err(SetupError::MasInstallFailed);
+            event!(
+                "install",
+                app.id.as_str(),
+                failed,
+                "❌ Failed to install app: {app}"
+            );
+            on_failure.record(format!("mas app {app}"), &mut failed)?;
+        } else {
+            changes.app_installed(&app.id, &app.name);
         }
     }
 
-    Ok(())
+    failures(&failed)?;
+
+    Ok(changes)
+}
+
+/// Returns the declared apps that have an upgrade available, according to
+/// `mas outdated`, with the version installed as listed by `mas list`.
+pub fn find_outdated_apps(
+    runner: &dyn CommandRunner,
+    desired: &Mas,
+    installed: &InstalledMasApps,
+) -> anyhow::Result<Vec<OutdatedApp>> {
+    let output = runner.output(MAS_PROGRAM_NAME, &["outdated"])?;
+    if !output.success() {
+        return Err(SetupError::InstallFailed(format!(
+            "mas outdated failed: {}",
+            from_utf8(&output.stderr)?.trim()
+        ))
+        .into());
+    }
+
+    let declared: HashSet<_> = desired.apps.iter().map(|app| app.id.as_str()).collect();
+    let mut outdated = Vec::new();
+    for line in from_utf8(&output.stdout)?.lines() {
+        let Some(app) = parse_mas_outdated_record(line) else {
+            continue;
+        };
+        if !declared.contains(app.app.id.as_str()) {
+            continue;
+        }
+        let installed_version = installed
+            .versions
+            .get(&app.app.id)
+            .cloned()
+            .unwrap_or(app.installed_version);
+        outdated.push(OutdatedApp {
+            installed_version,
+            ..app
+        });
+    }
+
+    Ok(outdated)
+}
+
+/// Parses a line of `mas outdated`, which looks like:
+///
+/// ```text
+/// 497799835 Xcode (15.0 -> 15.1)
+/// ```
+fn parse_mas_outdated_record(record: &str) -> Option<OutdatedApp> {
+    let (id, rest) = record.trim().split_once(char::is_whitespace)?;
+    let (name, versions) = rest.trim().rsplit_once(" (")?;
+    let (installed, available) = versions.strip_suffix(')')?.split_once(" -> ")?;
+    if !id.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(OutdatedApp {
+        app: App {
+            id: id.to_string(),
+            name: name.trim().to_string(),
+        },
+        installed_version: installed.to_string(),
+        available_version: available.to_string(),
+    })
+}
+
+/// Upgrades each of the outdated apps with `mas upgrade`, stopping at the first
+/// failure or attempting every app depending on `on_failure`.
+pub fn upgrade_outdated_apps(
+    runner: &dyn CommandRunner,
+    outdated: &[OutdatedApp],
+    on_failure: OnFailure,
+) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    let mut failed = Vec::new();
+
+    for OutdatedApp {
+        app,
+        installed_version,
+        available_version,
+    } in outdated
+    {
+        event!(
+            "upgrade",
+            app.id.as_str(),
+            started,
+            "Upgrading app: {} ({installed_version} -> {available_version})",
+            app.name
+        );
+        let status = runner.status(MAS_PROGRAM_NAME, &["upgrade", &app.id])?;
+        if !status.success() {
+            event!(
+                "upgrade",
+                app.id.as_str(),
+                failed,
+                "❌ Failed to upgrade app: {app}"
+            );
+            on_failure.record(format!("mas app {app} upgrade"), &mut failed)?;
+        } else {
+            event!("upgrade", app.id.as_str(), ok, "✅ Upgraded app: {app}");
+            changes.other_change(format!("Upgraded app {} to {available_version}", app.name));
+        }
+    }
+
+    failures(&failed)?;
+
+    Ok(changes)
+}
+
+/// Re-lists the installed apps to confirm every app that was just installed
+/// actually shows up, since `mas install` sometimes exits zero while the
+/// install is still pending or has silently failed. Apps are matched on ID
+/// alone, as the name in the App Store can differ from the one configured.
+pub fn verify_installed_apps(
+    runner: &dyn CommandRunner,
+    attempted: &MissingMasApps,
+) -> anyhow::Result<()> {
+    if attempted.apps.is_empty() {
+        return Ok(());
+    }
+
+    let installed = get_installed_apps(runner)?;
+    let installed_ids: HashSet<_> = installed.apps.iter().map(|app| &app.id).collect();
+
+    let mut unconfirmed = Vec::new();
+    for app in &attempted.apps {
+        if installed_ids.contains(&app.id) {
+            event!("install", app.id.as_str(), ok, "✅ {app} installed");
+        } else {
+            event!(
+                "install",
+                app.id.as_str(),
+                failed,
+                "❌ {app} reported as installed but isn't listed by `mas list`"
+            );
+            unconfirmed.push(app.to_string());
+        }
+    }
+
+    if unconfirmed.is_empty() {
+        Ok(())
+    } else {
+        Err(SetupError::InstallFailed(format!(
+            "mas apps not found after install: {}",
+            unconfirmed.join(", ")
+        ))
+        .into())
+    }
 }
 
 #[cfg(test)]
@@ -116,6 +546,119 @@ mod tests {
     use rstest::rstest;
 
     use super::*;
+    use crate::{
+        output::capture_warnings,
+        runner::{CommandOutput, MockRunner},
+    };
+
+    fn app(id: &str, name: &str) -> App {
+        App {
+            name: name.to_string(),
+            id: id.to_string(),
+        }
+    }
+
+    #[test]
+    fn duplicate_app_ids_are_only_missing_once() {
+        let desired = Mas {
+            apps: vec![
+                app("937984704", "Amphetamine"),
+                app("497799835", "Xcode"),
+                app("937984704", "Amphetamine again"),
+            ],
+            ..toml::from_str("apps = []").unwrap()
+        };
+        let installed = InstalledMasApps {
+            apps: HashSet::new(),
+            versions: HashMap::new(),
+        };
+
+        let mut missing = None;
+        let warnings = capture_warnings(|| missing = Some(find_missing_apps(&desired, &installed)));
+
+        let ids: Vec<_> = missing
+            .unwrap()
+            .apps
+            .iter()
+            .map(|app| app.id.as_str())
+            .collect();
+        assert_eq!(vec!["937984704", "497799835"], ids);
+        assert_eq!(
+            vec!["⚠️  mas app `937984704` is listed more than once, only installing it once"],
+            warnings
+        );
+    }
+
+    #[test]
+    fn installed_apps_are_matched_by_id_whatever_their_name() {
+        let desired = Mas {
+            apps: vec![app("904280696", "things"), app("497799835", "Xcode")],
+            ..toml::from_str("apps = []").unwrap()
+        };
+        let installed = InstalledMasApps {
+            apps: HashSet::from([app("904280696", "Things 3")]),
+            versions: HashMap::new(),
+        };
+
+        let missing = find_missing_apps(&desired, &installed);
+
+        assert_eq!(vec![&app("497799835", "Xcode")], missing.apps);
+    }
+
+    #[test]
+    fn install_missing_apps_returns_the_installed_apps() {
+        let apps = [app("497799835", "Xcode"), app("904280696", "Things 3")];
+        let runner = MockRunner::new();
+        let missing = MissingMasApps {
+            apps: apps.iter().collect(),
+        };
+
+        let changes =
+            install_missing_apps(&runner, &missing, OnFailure::KeepGoing, Retry::new(0)).unwrap();
+
+        let installed: Vec<_> = changes
+            .apps_installed
+            .iter()
+            .map(|app| (app.id.as_str(), app.name.as_str()))
+            .collect();
+        assert_eq!(
+            vec![("497799835", "Xcode"), ("904280696", "Things 3")],
+            installed
+        );
+    }
+
+    #[rstest]
+    #[case::keep_going(OnFailure::KeepGoing, 3)]
+    #[case::fail_fast(OnFailure::FailFast, 1)]
+    fn install_missing_apps_reports_failures(
+        #[case] on_failure: OnFailure,
+        #[case] attempted: usize,
+    ) {
+        let apps = [
+            app("1", "Broken"),
+            app("2", "Fine"),
+            app("3", "Also Broken"),
+        ];
+        let runner = MockRunner::new()
+            .stub(&["mas", "install", "1"], CommandOutput::failed(1, ""))
+            .stub(&["mas", "install", "3"], CommandOutput::failed(1, ""));
+        let missing = MissingMasApps {
+            apps: apps.iter().collect(),
+        };
+
+        let err = install_missing_apps(&runner, &missing, on_failure, Retry::new(0))
+            .unwrap_err()
+            .to_string();
+
+        assert_eq!(attempted, runner.calls().len());
+        assert!(err.contains("1 (Broken)"), "unexpected error: {err}");
+        if on_failure == OnFailure::KeepGoing {
+            assert!(
+                err.contains("2 failure(s)") && err.contains("3 (Also Broken)"),
+                "unexpected error: {err}"
+            );
+        }
+    }
 
     #[test]
     fn parse_mas_list_record_parses_single_word_app_name_correctly() {
@@ -126,7 +669,8 @@ mod tests {
         };
         let actual = parse_mas_list_record(input);
 
-        assert_eq!(expected, actual);
+        assert_eq!(expected, actual.app);
+        assert_eq!("5.3.2", actual.version);
     }
 
     #[rstest]
@@ -135,39 +679,183 @@ mod tests {
         App {
             name: "Sleep Control Centre".to_string(),
             id: "946798523".to_string(),
-        }
+        },
+        "2.27"
     )]
     #[case(
         "1352211125  Tide Alert (NOAA) - Tide Chart  (3.2)",
         App {
             name: "Tide Alert (NOAA) - Tide Chart".to_string(),
             id: "1352211125".to_string(),
-        }
+        },
+        "3.2"
     )]
     #[case(
         "  1491074310  Tetris®                         (7.3.3)  ",
         App {
             name: "Tetris®".to_string(),
             id: "1491074310".to_string(),
-        }
+        },
+        "7.3.3"
     )]
     #[case(
         "   381471023  Flashlight Ⓞ                    (2.3.5) ",
         App {
             name: "Flashlight Ⓞ".to_string(),
             id: "381471023".to_string(),
-        }
+        },
+        "2.3.5"
     )]
     #[case(
         "   890378044  Toy Blast                       (21004) ",
         App {
             name: "Toy Blast".to_string(),
             id: "890378044".to_string(),
-        }
+        },
+        "21004"
     )]
-    fn parse_mas_list_record_parses_app_name_correctly(#[case] input: &str, #[case] expected: App) {
+    fn parse_mas_list_record_parses_app_name_correctly(
+        #[case] input: &str,
+        #[case] expected: App,
+        #[case] version: &str,
+    ) {
         let actual = parse_mas_list_record(input);
 
-        assert_eq!(expected, actual);
+        assert_eq!(expected, actual.app);
+        assert_eq!(version, actual.version);
+    }
+
+    #[test]
+    fn check_apple_id_skips_when_another_account_is_signed_in() {
+        let mas: Mas = toml::from_str(
+            r#"
+            apple-id = "me@example.com"
+            apps = []
+            "#,
+        )
+        .unwrap();
+
+        let runner =
+            MockRunner::new().stub(&["mas", "account"], CommandOutput::ok("Me@Example.com\n"));
+        assert!(check_apple_id(&runner, &mas).unwrap());
+
+        let runner =
+            MockRunner::new().stub(&["mas", "account"], CommandOutput::ok("work@example.com\n"));
+        assert!(!check_apple_id(&runner, &mas).unwrap());
+
+        let runner = MockRunner::new().stub(
+            &["mas", "account"],
+            CommandOutput::failed(1, "Error: Not supported"),
+        );
+        assert!(check_apple_id(&runner, &mas).unwrap());
+    }
+
+    #[test]
+    fn resolve_app_ids_prefers_catalogs_over_searching() {
+        let config_dir = tempfile::tempdir().unwrap();
+        fs::write(config_dir.path().join("apps.toml"), "\"Bear\" = \"1\"\n").unwrap();
+        let mut mas: Mas = toml::from_str(
+            r#"
+            catalog = "apps.toml"
+            apps = [
+                { name = "xcode" },
+                { name = "Bear" },
+                { name = "Tot" },
+                { name = "Amphetamine", id = "937984704" },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        let runner = MockRunner::new().stub(
+            &["mas", "search", "Tot"],
+            CommandOutput::ok(
+                "  1491071483  Tot                 (1.3.3)\n  1551531632  Totals Tracker  (1.0)\n",
+            ),
+        );
+        resolve_app_ids(&runner, &mut mas, config_dir.path()).unwrap();
+
+        let ids: Vec<_> = mas.apps.iter().map(|app| app.id.as_str()).collect();
+        assert_eq!(
+            ["497799835", "1", "1491071483", "937984704"],
+            ids.as_slice()
+        );
+        assert_eq!(runner.calls(), [vec!["mas", "search", "Tot"]]);
+
+        let mut mas: Mas = toml::from_str(r#"apps = [{ name = "Tota" }]"#).unwrap();
+        assert!(resolve_app_ids(&runner, &mut mas, config_dir.path()).is_err());
+    }
+
+    #[test]
+    fn verify_installed_apps_reports_apps_missing_after_install() {
+        let runner = MockRunner::new().stub(
+            &["mas", "list"],
+            CommandOutput::ok("937984704   Amphetamine  (5.3.2)\n"),
+        );
+        let amphetamine = App {
+            name: "Amphetamine".to_string(),
+            id: "937984704".to_string(),
+        };
+        let xcode = App {
+            name: "Xcode".to_string(),
+            id: "497799835".to_string(),
+        };
+
+        let installed = MissingMasApps {
+            apps: vec![&amphetamine],
+        };
+        assert!(verify_installed_apps(&runner, &installed).is_ok());
+
+        let pending = MissingMasApps {
+            apps: vec![&amphetamine, &xcode],
+        };
+        let error = verify_installed_apps(&runner, &pending).unwrap_err();
+        assert!(error.to_string().contains("497799835 (Xcode)"));
+    }
+
+    #[test]
+    fn find_outdated_apps_only_upgrades_declared_apps() {
+        let desired: Mas = toml::from_str(
+            r#"
+            [[apps]]
+            name = "Xcode"
+            id = "497799835"
+            [[apps]]
+            name = "Amphetamine"
+            id = "937984704"
+            "#,
+        )
+        .unwrap();
+        let runner = MockRunner::new()
+            .stub(
+                &["mas", "list"],
+                CommandOutput::ok("497799835  Xcode  (15.0)\n937984704  Amphetamine  (5.3.2)\n"),
+            )
+            .stub(
+                &["mas", "outdated"],
+                CommandOutput::ok(
+                    "497799835 Xcode (15.0 -> 15.1)\n409183694 Keynote (13.1 -> 14.0)\n",
+                ),
+            );
+        let installed = get_installed_apps(&runner).unwrap();
+
+        let outdated = find_outdated_apps(&runner, &desired, &installed).unwrap();
+        upgrade_outdated_apps(&runner, &outdated, OnFailure::KeepGoing).unwrap();
+
+        assert_eq!(
+            vec![OutdatedApp {
+                app: App {
+                    name: "Xcode".to_string(),
+                    id: "497799835".to_string(),
+                },
+                installed_version: "15.0".to_string(),
+                available_version: "15.1".to_string(),
+            }],
+            outdated
+        );
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            &["mas", "upgrade", "497799835"]
+        );
     }
 }
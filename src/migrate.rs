@@ -0,0 +1,314 @@
+//! Upgrades configuration files written for older versions of the schema, so
+//! renamed and moved keys don't silently stop being applied.
+//!
+//! Migrations edit the files through `toml_edit`, like [`crate::add`], so
+//! `omiros config migrate` keeps their comments and formatting.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail};
+use toml_edit::{DocumentMut, Item};
+
+use crate::{dotfiles::backup_path, system};
+
+/// The schema version this build of omiros reads. Configurations without a
+/// `version` are from before it was introduced, and are version 1.
+pub const CURRENT_VERSION: u32 = 2;
+
+/// Rewrites a configuration from one version of the schema to the next.
+type Migration = fn(&mut toml_edit::Table) -> anyhow::Result<()>;
+
+/// The known migrations, keyed by the version they upgrade from.
+const MIGRATIONS: &[(u32, Migration)] = &[(1, move_show_file_extensions_to_finder)];
+
+/// The schema version `config` was written for.
+fn version(config: &toml_edit::Table) -> anyhow::Result<u32> {
+    let Some(version) = config.get("version") else {
+        return Ok(1);
+    };
+    match version.as_integer() {
+        Some(number) => u32::try_from(number)
+            .ok()
+            .filter(|&number| number >= 1)
+            .ok_or_else(|| anyhow!("`version = {number}` isn't a valid config version")),
+        None => bail!(
+            "`version` should be a number, not `{}`",
+            version.to_string().trim()
+        ),
+    }
+}
+
+/// Upgrades `config` to [`CURRENT_VERSION`] in place, applying each migration
+/// from its version onwards. Returns the version it started at.
+pub fn migrate(config: &mut toml::Table) -> anyhow::Result<u32> {
+    let mut document: DocumentMut = toml::to_string(config)?.parse()?;
+    let from = migrate_table(document.as_table_mut())?;
+    *config = document.to_string().parse()?;
+    Ok(from)
+}
+
+/// Upgrades a whole configuration file, setting its `version`. Returns the
+/// version it started at.
+fn migrate_table(config: &mut toml_edit::Table) -> anyhow::Result<u32> {
+    let from = version(config)?;
+    if from > CURRENT_VERSION {
+        bail!(
+            "config is version {from}, but this omiros only understands up to \
+             version {CURRENT_VERSION}, try updating omiros"
+        );
+    }
+
+    upgrade(config, from)?;
+    config.insert("version", toml_edit::value(i64::from(CURRENT_VERSION)));
+    Ok(from)
+}
+
+/// Applies each migration from version `from` onwards to `config`, which can
+/// also be a file included by the configuration, without a `version` of its
+/// own.
+fn upgrade(config: &mut toml_edit::Table, from: u32) -> anyhow::Result<()> {
+    for current in from..CURRENT_VERSION {
+        let (_, migration) = MIGRATIONS
+            .iter()
+            .find(|(version, _)| *version == current)
+            .ok_or_else(|| anyhow!("no migration from config version {current}"))?;
+        migration(config)?;
+    }
+    Ok(())
+}
+
+/// A configuration file, as it was read and as it is once migrated.
+pub struct MigratedFile {
+    pub path: PathBuf,
+    original: String,
+    pub migrated: DocumentMut,
+}
+
+impl MigratedFile {
+    /// Whether migrating changed anything in the file.
+    pub fn changed(&self) -> bool {
+        self.migrated.to_string() != self.original
+    }
+
+    /// Writes the migrated file back, copying the original to a backup next
+    /// to it first. Returns where the backup is.
+    pub fn write(&self) -> anyhow::Result<PathBuf> {
+        let backup = backup_path(&self.path);
+        fs::write(&backup, &self.original)?;
+        fs::write(&self.path, self.migrated.to_string())?;
+        Ok(backup)
+    }
+}
+
+/// Migrates the configuration file at `path`, and every file it includes,
+/// from the version `path` declares. Only `path` itself gets the new
+/// `version`. The result is checked to be a configuration `omiros run`
+/// accepts before anything is returned. Returns the version it started at.
+pub fn migrate_files(path: &Path) -> anyhow::Result<(u32, Vec<MigratedFile>)> {
+    let mut files = Vec::new();
+    read_files(path, &mut files)?;
+
+    let from = migrate_table(files[0].migrated.as_table_mut())?;
+    for file in &mut files[1..] {
+        upgrade(file.migrated.as_table_mut(), from)?;
+    }
+
+    let migrated = |path: &Path| {
+        files
+            .iter()
+            .find(|file| file.path == path)
+            .map(|file| file.migrated.to_string())
+            .ok_or_else(|| std::io::Error::other("not read while migrating"))
+    };
+    let _: system::System = system::read_config_from(path, &migrated)?.try_into()?;
+
+    Ok((from, files))
+}
+
+/// Reads the file at `path` into `files`, followed by the files it includes,
+/// each only once.
+fn read_files(path: &Path, files: &mut Vec<MigratedFile>) -> anyhow::Result<()> {
+    let path = path
+        .canonicalize()
+        .map_err(|e| anyhow!("couldn't read {}: {e}", path.display()))?;
+    if files.iter().any(|file| file.path == path) {
+        return Ok(());
+    }
+
+    let original =
+        fs::read_to_string(&path).map_err(|e| anyhow!("couldn't read {}: {e}", path.display()))?;
+    let migrated: DocumentMut = original
+        .parse()
+        .map_err(|e| anyhow!("in {}: {e}", path.display()))?;
+    let includes: Vec<PathBuf> = migrated
+        .get("include")
+        .and_then(Item::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|include| include.as_str())
+        .map(PathBuf::from)
+        .collect();
+    let dir = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+    files.push(MigratedFile {
+        path,
+        original,
+        migrated,
+    });
+
+    for include in includes {
+        read_files(&dir.join(include), files)?;
+    }
+    Ok(())
+}
+
+/// Whether two values are the same, ignoring how they're formatted.
+fn same_value(a: &Item, b: &Item) -> bool {
+    let plain = |item: &Item| {
+        item.as_value().map(|value| {
+            let mut value = value.clone();
+            value.decor_mut().clear();
+            value.to_string()
+        })
+    };
+    plain(a) == plain(b)
+}
+
+/// Version 2 moved `show-file-extensions` from `[macos.system]` to
+/// `[macos.finder]`, since it's mostly Finder that it affects.
+fn move_show_file_extensions_to_finder(config: &mut toml_edit::Table) -> anyhow::Result<()> {
+    let Some(macos) = config.get_mut("macos").and_then(Item::as_table_like_mut) else {
+        return Ok(());
+    };
+    let Some(system) = macos.get_mut("system").and_then(Item::as_table_like_mut) else {
+        return Ok(());
+    };
+    let Some(key) = system.key("show-file-extensions").cloned() else {
+        return Ok(());
+    };
+    let Some(show) = system.remove("show-file-extensions") else {
+        return Ok(());
+    };
+
+    let finder = macos
+        .entry("finder")
+        .or_insert_with(toml_edit::table)
+        .as_table_like_mut()
+        .ok_or_else(|| anyhow!("`[macos.finder]` should be a table"))?;
+    match finder.get("show-file-extensions") {
+        Some(existing) if !same_value(existing, &show) => bail!(
+            "`show-file-extensions` is set differently in `[macos.system]` and \
+             `[macos.finder]`, remove one of them"
+        ),
+        Some(_) => {}
+        None => {
+            finder.entry_format(&key).or_insert(show);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml::Table;
+
+    #[test]
+    fn unversioned_config_is_upgraded_to_current() {
+        let mut config: Table = r#"
+            [macos.system]
+            show-file-extensions = true
+            natural-scrolling = false
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(1, migrate(&mut config).unwrap());
+
+        let expected: Table = r#"
+            version = 2
+
+            [macos.system]
+            natural-scrolling = false
+
+            [macos.finder]
+            show-file-extensions = true
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(expected, config);
+    }
+
+    #[test]
+    fn current_config_is_left_alone() {
+        let source = "version = 2\n\n[macos.finder]\nshow-file-extensions = true\n";
+        let mut config: Table = source.parse().unwrap();
+
+        assert_eq!(CURRENT_VERSION, migrate(&mut config).unwrap());
+        assert_eq!(source.parse::<Table>().unwrap(), config);
+    }
+
+    #[test]
+    fn migrate_files_keeps_comments_and_migrates_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("system.toml"),
+            "# My laptop\ninclude = [\"macos.toml\"]\n\n[brew]\n# Editors\nformulae = [\"neovim\"]\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("macos.toml"),
+            "[macos.system]\n# Show them all\nshow-file-extensions = true\nnatural-scrolling = false\n",
+        )
+        .unwrap();
+
+        let (from, files) = migrate_files(&dir.path().join("system.toml")).unwrap();
+
+        assert_eq!(1, from);
+        assert_eq!(
+            "# My laptop\ninclude = [\"macos.toml\"]\nversion = 2\n\n[brew]\n# Editors\nformulae = [\"neovim\"]\n",
+            files[0].migrated.to_string()
+        );
+        assert_eq!(
+            "[macos.system]\nnatural-scrolling = false\n\n[macos.finder]\n# Show them all\nshow-file-extensions = true\n",
+            files[1].migrated.to_string()
+        );
+    }
+
+    #[test]
+    fn writing_a_migrated_file_backs_up_the_original() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("system.toml");
+        let original = "# Work laptop\n[macos.system]\nshow-file-extensions = true\n";
+        fs::write(&path, original).unwrap();
+
+        let (_, files) = migrate_files(&path).unwrap();
+        assert!(files[0].changed());
+        let backup = files[0].write().unwrap();
+
+        assert_eq!(original, fs::read_to_string(backup).unwrap());
+        assert_eq!(
+            files[0].migrated.to_string(),
+            fs::read_to_string(&path).unwrap()
+        );
+    }
+
+    #[test]
+    fn newer_and_conflicting_configs_are_rejected() {
+        let mut newer: Table = "version = 99".parse().unwrap();
+        assert!(migrate(&mut newer).is_err());
+
+        let mut conflicting: Table = r#"
+            [macos.system]
+            show-file-extensions = true
+            [macos.finder]
+            show-file-extensions = false
+        "#
+        .parse()
+        .unwrap();
+        assert!(migrate(&mut conflicting).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::{OnFailure, SetupError, failures},
+    output::{event, print_compared_sets, unchanged},
+    report::Changes,
+    runner::CommandRunner,
+    system::Section,
+    system_utils::{Seen, command},
+    validate::{ConfigProblem, warn_duplicates},
+};
+
+const NPM_PROGRAM_NAME: &str = "npm";
+
+/// Represents the npm configuration, specifying which packages to install
+/// globally.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Npm {
+    /// Package names, optionally with a version, like `typescript@5`.
+    pub packages: Vec<String>,
+    /// Shell command that must exit zero for this section to be applied.
+    pub when: Option<String>,
+}
+
+impl Npm {
+    /// Checks for duplicate packages, ignoring their versions.
+    pub(crate) fn validate(&self, problems: &mut Vec<ConfigProblem>) {
+        warn_duplicates(
+            "npm.packages",
+            self.packages.iter().map(|package| package_name(package)),
+            problems,
+        );
+    }
+}
+
+impl Section for Npm {
+    fn when(&self) -> Option<&str> {
+        self.when.as_deref()
+    }
+}
+
+/// The name a package is installed under, without any version, so
+/// `typescript@5` is `typescript` and `@angular/cli@17` is `@angular/cli`.
+fn package_name(package: &str) -> &str {
+    match package.rfind('@') {
+        Some(0) | None => package,
+        Some(at) => &package[..at],
+    }
+}
+
+/// The parts of `npm ls -g --depth=0 --json` needed to list the installed
+/// packages.
+#[derive(Deserialize)]
+struct NpmList {
+    #[serde(default)]
+    dependencies: HashMap<String, serde::de::IgnoredAny>,
+}
+
+/// Checks if `npm` is installed and available in the system's PATH.
+pub fn check_npm_installed(runner: &dyn CommandRunner) -> Result<(), SetupError> {
+    let _ = command(runner, NPM_PROGRAM_NAME)?;
+
+    Ok(())
+}
+
+/// Retrieves the names of the globally installed npm packages.
+pub fn get_installed_npm_packages(
+    runner: &dyn CommandRunner,
+) -> Result<HashSet<String>, SetupError> {
+    let output = runner.output(NPM_PROGRAM_NAME, &["ls", "-g", "--depth=0", "--json"])?;
+
+    // `npm ls` also exits non-zero when it finds problems with the installed
+    // packages, like extraneous ones, but still lists them.
+    let list: NpmList = serde_json::from_slice(&output.stdout).map_err(|e| {
+        SetupError::InstallFailed(format!(
+            "could not parse npm ls output: {e}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    })?;
+
+    Ok(list.dependencies.into_keys().collect())
+}
+
+/// Compares the desired npm packages with the installed packages to determine
+/// which ones are missing.
+pub fn find_missing_npm_packages<'a>(
+    desired: &'a Npm,
+    installed: &HashSet<String>,
+) -> Vec<&'a str> {
+    print_compared_sets(
+        "npm packages",
+        desired.packages.iter().map(|package| package_name(package)),
+        installed.iter().map(String::as_str),
+    );
+
+    let mut seen = Seen::new("npm package");
+    desired
+        .packages
+        .iter()
+        .filter(|package| {
+            let name = package_name(package);
+            !installed.contains(name) && seen.first(name)
+        })
+        .map(String::as_str)
+        .collect()
+}
+
+/// Installs the missing npm packages globally, stopping at the first failure
+/// or attempting every package depending on `on_failure`.
+pub fn install_missing_npm_packages(
+    runner: &dyn CommandRunner,
+    missing: &[&str],
+    on_failure: OnFailure,
+) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    if missing.is_empty() {
+        unchanged!("All npm packages are installed.");
+        return Ok(changes);
+    }
+
+    let mut failed = Vec::new();
+    for package in missing {
+        event!(
+            "install",
+            package,
+            started,
+            "Installing npm package: {package}"
+        );
+        let status = runner.status(NPM_PROGRAM_NAME, &["install", "-g", package])?;
+        if !status.success() {
+            event!(
+                "install",
+                package,
+                failed,
+                "❌ Failed to install npm package: {package}"
+            );
+            on_failure.record(format!("npm package {package}"), &mut failed)?;
+        } else {
+            event!(
+                "install",
+                package,
+                ok,
+                "✅ Installed npm package: {package}"
+            );
+            changes.package_installed("npm", package);
+        }
+    }
+
+    failures(&failed)?;
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, MockRunner};
+
+    #[test]
+    fn installs_only_missing_packages_from_npm_ls_json() {
+        let list = r#"{
+            "name": "lib",
+            "dependencies": {
+                "npm": {"version": "10.5.0", "overridden": false},
+                "typescript": {"version": "5.4.5", "overridden": false},
+                "@angular/cli": {"version": "17.3.0", "overridden": false}
+            }
+        }"#;
+        let runner = MockRunner::new().stub(
+            &["npm", "ls", "-g", "--depth=0", "--json"],
+            CommandOutput {
+                code: Some(1),
+                stdout: list.as_bytes().to_vec(),
+                stderr: b"npm ERR! extraneous: left-pad".to_vec(),
+            },
+        );
+        let desired: Npm =
+            toml::from_str(r#"packages = ["typescript@5", "@angular/cli@17", "prettier"]"#)
+                .unwrap();
+
+        let installed = get_installed_npm_packages(&runner).unwrap();
+        let missing = find_missing_npm_packages(&desired, &installed);
+        let changes =
+            install_missing_npm_packages(&runner, &missing, OnFailure::KeepGoing).unwrap();
+
+        assert_eq!(vec!["prettier"], missing);
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            &["npm", "install", "-g", "prettier"]
+        );
+        assert_eq!(1, changes.packages_installed.len());
+        assert_eq!("npm", changes.packages_installed[0].manager);
+        assert_eq!("prettier", changes.packages_installed[0].name);
+    }
+
+    #[test]
+    fn empty_global_install_has_no_dependencies() {
+        let runner = MockRunner::new().stub(
+            &["npm", "ls", "-g", "--depth=0", "--json"],
+            CommandOutput::ok(r#"{"name": "lib"}"#),
+        );
+
+        assert!(get_installed_npm_packages(&runner).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,467 @@
+//! Controls how much omiros reports about what it's doing, and where it goes.
+//!
+//! All output goes through the [`log`] facade. [`init`] installs a [`Logger`]
+//! that prints to the terminal, and optionally tees every line to a log file
+//! with a timestamp so unattended runs leave a record behind.
+//!
+//! Messages are written with an emoji prefix, like `✅ fish installed`. When
+//! color is turned off the [`Logger`] swaps those for plain ASCII tags, like
+//! `[OK] fish installed`, for terminals and log collectors that mangle them.
+//!
+//! The [`Logger`] can also stream every message as a JSON line, see
+//! [`Event`], for log aggregators to follow a run as it happens. Messages
+//! about an action, like installing a formula, are logged with [`event!`], so
+//! the line also says what kind of action it was, what it acted on, and how it
+//! went.
+
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    env,
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::{self, IsTerminal, Write},
+    path::Path,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record, debug, kv::Key, log_enabled};
+use serde::Serialize;
+
+/// Whether to emit emoji and ANSI color.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Only when stdout is a terminal, `NO_COLOR` isn't set, and omiros isn't
+    /// running in CI.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Resolves `Auto` against the environment and whether stdout is a
+    /// terminal.
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => auto_color(
+                env::var_os("NO_COLOR"),
+                env::var_os("CI"),
+                io::stdout().is_terminal(),
+            ),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+/// Whether `CI` says this is a CI pipeline, which GitHub Actions and most
+/// other providers set to `true`.
+fn ci_value(ci: Option<OsString>) -> bool {
+    ci.is_some_and(|ci| !ci.is_empty() && ci != "0" && !ci.eq_ignore_ascii_case("false"))
+}
+
+/// Whether omiros is running in CI, or some other pipeline without anybody
+/// watching, so shouldn't open apps for the user to interact with.
+pub fn is_ci() -> bool {
+    ci_value(env::var_os("CI"))
+}
+
+/// Whether `--color auto` turns color on. Any non-empty `NO_COLOR` turns it
+/// off, following <https://no-color.org>, as does running in CI.
+fn auto_color(no_color: Option<OsString>, ci: Option<OsString>, terminal: bool) -> bool {
+    let no_color = no_color.is_some_and(|no_color| !no_color.is_empty());
+    terminal && !no_color && !ci_value(ci)
+}
+
+/// The ASCII tag each emoji prefix is replaced with when color is off.
+const ASCII_TAGS: [(&str, &str); 19] = [
+    ("✅", "[OK]"),
+    ("❌", "[ERROR]"),
+    ("⚠️", "[WARN]"),
+    ("ℹ️", "[SKIP]"),
+    ("💡", "[HINT]"),
+    ("🔧", "[CHANGE]"),
+    ("🦀", "[INSTALL]"),
+    ("🔗", "[LINK]"),
+    ("📌", "[PIN]"),
+    ("📄", "[COPY]"),
+    ("📁", "[MKDIR]"),
+    ("🔄", "[REPLACE]"),
+    ("🗑️", "[REMOVE]"),
+    ("⏳", "[RETRY]"),
+    ("🩺", "[CHECK]"),
+    ("🔍", "[DEBUG]"),
+    ("🔎", "[RESOLVE]"),
+    ("🔑", "[SECRET]"),
+    ("📦", "[BACKUP]"),
+];
+
+/// Splits a known emoji prefix off `message`, returning its ASCII tag and the
+/// rest of the message.
+fn split_tag(message: &str) -> Option<(&'static str, &str)> {
+    ASCII_TAGS.iter().find_map(|(emoji, tag)| {
+        message
+            .strip_prefix(emoji)
+            .map(|rest| (*tag, rest.trim_start()))
+    })
+}
+
+/// Replaces a leading emoji in `message` with its ASCII tag. Messages without
+/// a known emoji prefix are left as they are.
+fn ascii_tags(message: &str) -> Cow<'_, str> {
+    match split_tag(message) {
+        Some((tag, rest)) => Cow::Owned(format!("{tag} {rest}")),
+        None => Cow::Borrowed(message),
+    }
+}
+
+/// The version of the [`Event`] schema. Bumped whenever a field is removed or
+/// changes meaning, but not when fields are added.
+pub const EVENT_SCHEMA_VERSION: u32 = 2;
+
+/// One line of the `--log-json-lines` stream.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Event<'a> {
+    /// Always [`EVENT_SCHEMA_VERSION`].
+    pub version: u32,
+    /// RFC 3339, in UTC.
+    pub timestamp: String,
+    /// `error`, `warn`, `info`, or `debug`.
+    pub level: String,
+    /// The kind of action the message is about, like `install`, `link`, or
+    /// `write`, or `null` for messages that aren't about one.
+    pub kind: Option<String>,
+    /// What the action acted on, like a formula or a dotfile, or `null`.
+    pub target: Option<String>,
+    /// How the action went: `started`, `ok`, or `failed`, or `null`.
+    pub result: Option<String>,
+    /// The configuration section being applied, like `brew`, or `null`
+    /// outside of one.
+    pub module: Option<&'a str>,
+    /// The message, without its emoji or tag.
+    pub message: &'a str,
+}
+
+impl<'a> Event<'a> {
+    /// The event for `record`, taking its kind, target, and result from the
+    /// key-values [`event!`] logs it with.
+    fn new(record: &Record, module: Option<&'a str>, message: &'a str) -> Self {
+        let field = |key: &str| {
+            record
+                .key_values()
+                .get(Key::from_str(key))
+                .map(|value| value.to_string())
+        };
+
+        Event {
+            version: EVENT_SCHEMA_VERSION,
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            level: record.level().as_str().to_lowercase(),
+            kind: field("kind"),
+            target: field("target"),
+            result: field("result"),
+            module,
+            message: split_tag(message).map_or(message, |(_, rest)| rest),
+        }
+    }
+}
+
+/// The configuration section currently being applied, for [`Event::module`].
+static MODULE: Mutex<Option<&'static str>> = Mutex::new(None);
+
+/// Attributes everything logged from here on to `module`, until it's changed
+/// again.
+pub fn set_module(module: Option<&'static str>) {
+    *MODULE.lock().unwrap_or_else(|e| e.into_inner()) = module;
+}
+
+/// The log target for messages saying something was already as configured,
+/// which `--quiet-success` keeps off the terminal.
+pub(crate) const UNCHANGED: &str = "omiros::unchanged";
+
+/// Logs, at info level, that something was already as configured and so
+/// nothing was done.
+macro_rules! unchanged {
+    ($($arg:tt)+) => {
+        log::info!(target: $crate::output::UNCHANGED, $($arg)+)
+    };
+}
+pub(crate) use unchanged;
+
+/// Logs a message about an action, along with the `kind` of action, like
+/// `install`, and its `target`, like the formula, which the `--log-json-lines`
+/// stream has as fields of the [`Event`]. The result is `started` or `ok`,
+/// logged at info level, or `failed`, logged as an error.
+macro_rules! event {
+    ($kind:expr, $target:expr, started, $($arg:tt)+) => {
+        log::info!(kind = $kind, target = $target, result = "started"; $($arg)+)
+    };
+    ($kind:expr, $target:expr, ok, $($arg:tt)+) => {
+        log::info!(kind = $kind, target = $target, result = "ok"; $($arg)+)
+    };
+    ($kind:expr, $target:expr, failed, $($arg:tt)+) => {
+        log::error!(kind = $kind, target = $target, result = "failed"; $($arg)+)
+    };
+}
+pub(crate) use event;
+
+/// Where the output of a run goes.
+pub struct Logger {
+    level: LevelFilter,
+    /// Leave messages about things that were already as configured out of the
+    /// terminal output. They're still written to the log file.
+    quiet_success: bool,
+    color: bool,
+    file: Option<Mutex<File>>,
+    json_lines: Option<Mutex<Box<dyn Write + Send>>>,
+    /// Print messages to stderr, since stdout is taken by the JSON lines, or
+    /// the JSON report.
+    stderr: bool,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let message = record.args().to_string();
+        let message = if self.color {
+            Cow::Borrowed(message.as_str())
+        } else {
+            ascii_tags(&message)
+        };
+
+        let quiet = self.quiet_success && record.target() == UNCHANGED;
+        match (record.level(), self.color) {
+            _ if quiet => {}
+            (Level::Error, true) => eprintln!("\x1b[31m{message}\x1b[0m"),
+            (Level::Error, false) => eprintln!("{message}"),
+            (Level::Warn, true) if self.stderr => eprintln!("\x1b[33m{message}\x1b[0m"),
+            (Level::Warn, true) => println!("\x1b[33m{message}\x1b[0m"),
+            _ if self.stderr => eprintln!("{message}"),
+            _ => println!("{message}"),
+        }
+
+        if let Some(json_lines) = &self.json_lines {
+            let module = *MODULE.lock().unwrap_or_else(|e| e.into_inner());
+            let original = record.args().to_string();
+            let event = Event::new(record, module, &original);
+            let mut json_lines = json_lines.lock().unwrap();
+            if let Ok(line) = serde_json::to_string(&event) {
+                let _ = writeln!(json_lines, "{line}");
+            }
+        }
+
+        if let Some(file) = &self.file {
+            let timestamp = humantime::format_rfc3339_seconds(SystemTime::now());
+            let mut file = file.lock().unwrap();
+            for line in message.lines() {
+                // There's nowhere left to report a failure to write the log.
+                let _ = writeln!(file, "{timestamp} {:<5} {line}", record.level());
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+        if let Some(json_lines) = &self.json_lines {
+            let _ = json_lines.lock().unwrap().flush();
+        }
+    }
+}
+
+/// The level to log at: debug output with `verbose`, only warnings and errors
+/// with `quiet`, and everything else in between otherwise.
+pub fn level(verbose: bool, quiet: bool) -> LevelFilter {
+    if verbose {
+        LevelFilter::Debug
+    } else if quiet {
+        LevelFilter::Warn
+    } else {
+        LevelFilter::Info
+    }
+}
+
+/// Installs the logger for the rest of the run. When `output_file` is given
+/// every line is also written to it, appended to whatever is already there
+/// unless `truncate` is set. When `json_lines` is given every message is also
+/// written to it as an [`Event`], where `-` means stdout, which moves the
+/// regular output to stderr. So does `json_report`, which leaves stdout for
+/// the [`crate::report::RunReport`].
+pub fn init(
+    level: LevelFilter,
+    quiet_success: bool,
+    color: ColorChoice,
+    output_file: Option<&Path>,
+    truncate: bool,
+    json_lines: Option<&Path>,
+    json_report: bool,
+) -> io::Result<()> {
+    let file = output_file
+        .map(|path| {
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(!truncate)
+                .truncate(truncate)
+                .open(path)
+        })
+        .transpose()?;
+
+    let json_lines_stdout = json_lines.is_some_and(|path| path == Path::new("-"));
+    let stderr = json_report || json_lines_stdout;
+    let json_lines = json_lines
+        .map(|path| -> io::Result<Box<dyn Write + Send>> {
+            if json_lines_stdout {
+                Ok(Box::new(io::stdout()))
+            } else {
+                Ok(Box::new(File::create(path)?))
+            }
+        })
+        .transpose()?;
+
+    log::set_max_level(level);
+    log::set_boxed_logger(Box::new(Logger {
+        level,
+        quiet_success,
+        color: color.enabled(),
+        file: file.map(Mutex::new),
+        json_lines: json_lines.map(Mutex::new),
+        stderr,
+    }))
+    .map_err(io::Error::other)
+}
+
+/// In verbose mode, prints the desired and installed sets that a diff compared.
+/// Both are de-duplicated, sorted, and quoted, so stray whitespace and case
+/// mismatches stand out when working out why something is considered missing.
+pub(crate) fn print_compared_sets<'a>(
+    what: &str,
+    desired: impl IntoIterator<Item = &'a str>,
+    installed: impl IntoIterator<Item = &'a str>,
+) {
+    if !log_enabled!(Level::Debug) {
+        return;
+    }
+
+    let desired: BTreeSet<_> = desired.into_iter().collect();
+    let installed: BTreeSet<_> = installed.into_iter().collect();
+    debug!("🔍 {what} desired:   {desired:?}");
+    debug!("🔍 {what} installed: {installed:?}");
+}
+
+/// A logger for tests that records the warnings logged on the calling thread
+/// while [`capture_warnings`] runs.
+#[cfg(test)]
+struct CaptureLogger;
+
+#[cfg(test)]
+thread_local! {
+    static CAPTURED: std::cell::RefCell<Option<Vec<String>>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+#[cfg(test)]
+impl Log for CaptureLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() == Level::Warn && CAPTURED.with_borrow(Option::is_some)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        CAPTURED.with_borrow_mut(|captured| {
+            if let Some(captured) = captured {
+                captured.push(record.args().to_string());
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+/// Runs `f`, and returns the warnings it logged.
+#[cfg(test)]
+pub(crate) fn capture_warnings(f: impl FnOnce()) -> Vec<String> {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&CaptureLogger).unwrap();
+        log::set_max_level(LevelFilter::Trace);
+    });
+
+    CAPTURED.set(Some(Vec::new()));
+    f();
+    CAPTURED.take().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_color_honors_no_color_and_ci() {
+        let set = |value: &str| Some(OsString::from(value));
+
+        assert!(auto_color(None, None, true));
+        assert!(!auto_color(None, None, false));
+        assert!(!auto_color(set("1"), None, true));
+        assert!(auto_color(set(""), None, true));
+        assert!(!auto_color(None, set("true"), true));
+        assert!(auto_color(None, set("false"), true));
+    }
+
+    #[test]
+    fn event_takes_its_fields_from_the_record_key_values() {
+        let fields = [
+            ("kind", "write"),
+            ("target", "com.apple.dock.autohide"),
+            ("result", "ok"),
+        ];
+        let message = "🔧 Set com.apple.dock.autohide = true (-bool)";
+        let record = Record::builder()
+            .level(Level::Info)
+            .key_values(&fields)
+            .build();
+        let event = Event::new(&record, Some("macos"), message);
+        assert_eq!(Some("write"), event.kind.as_deref());
+        assert_eq!(Some("com.apple.dock.autohide"), event.target.as_deref());
+        assert_eq!(Some("ok"), event.result.as_deref());
+        assert_eq!("Set com.apple.dock.autohide = true (-bool)", event.message);
+        assert_eq!("info", event.level);
+
+        // The emoji alone doesn't make a message an action.
+        let message = "✅ fish found";
+        let record = Record::builder().level(Level::Info).build();
+        let event = Event::new(&record, None, message);
+        assert_eq!(None, event.kind);
+        assert_eq!(None, event.result);
+        assert_eq!(None, event.module);
+
+        let json = serde_json::to_string(&event).unwrap();
+        assert!(json.starts_with(r#"{"version":2,"timestamp":""#));
+        assert!(json.contains(r#""kind":null,"target":null,"result":null"#));
+    }
+
+    #[test]
+    fn ascii_tags_replaces_known_emoji_prefixes() {
+        assert_eq!("[OK] fish found", ascii_tags("✅ fish found"));
+        assert_eq!(
+            "[SKIP] No `[mas]` block",
+            ascii_tags("ℹ️  No `[mas]` block")
+        );
+        assert_eq!(
+            "Installing formula: fish",
+            ascii_tags("Installing formula: fish")
+        );
+    }
+}
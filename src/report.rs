@@ -0,0 +1,199 @@
+//! What a run changed, for `omiros run --format json` to print as one JSON
+//! document at the end, instead of the usual prose.
+//!
+//! Each install and apply function returns the [`Changes`] it made, which
+//! `main.rs` adds up, along with the error each failed section ran into, into
+//! the [`RunReport`].
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// How `omiros run` reports what it did.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Print what's happening as it happens.
+    #[default]
+    Human,
+    /// Print a [`RunReport`] as JSON to stdout at the end, moving the usual
+    /// output to stderr.
+    Json,
+}
+
+/// Everything a run changed, and the errors it ran into.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct RunReport {
+    #[serde(flatten)]
+    pub changes: Changes,
+    /// The error each failed section, or the run as a whole, stopped at.
+    pub errors: Vec<String>,
+}
+
+/// What applying some part of the configuration changed.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    /// Packages installed by a package manager, like brew or npm.
+    pub packages_installed: Vec<InstalledPackage>,
+    /// Apps installed from the Mac App Store.
+    pub apps_installed: Vec<InstalledApp>,
+    /// Dotfiles symlinked into place.
+    pub symlinks_created: Vec<CreatedSymlink>,
+    /// Preferences written with `defaults`, or PlistBuddy.
+    pub defaults_changed: Vec<ChangedDefault>,
+    /// Anything else the run changed, like a tap added or a dotfile copied,
+    /// described in words.
+    pub other_changes: Vec<String>,
+}
+
+/// A package installed by a package manager.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct InstalledPackage {
+    /// The package manager that installed it, like `brew` or `cargo`.
+    pub manager: String,
+    pub name: String,
+}
+
+/// An app installed from the Mac App Store.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct InstalledApp {
+    pub id: String,
+    pub name: String,
+}
+
+/// A dotfile symlinked into place.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct CreatedSymlink {
+    pub link: String,
+    pub original: String,
+}
+
+/// A preference that was written.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ChangedDefault {
+    /// The preferences domain, or for nested values the `.plist` file.
+    pub domain: String,
+    pub key: String,
+    /// The value written, as it was passed to `defaults`.
+    pub value: String,
+}
+
+impl Changes {
+    /// Whether anything changed, rather than everything already being as
+    /// configured.
+    pub fn has_changes(&self) -> bool {
+        !(self.packages_installed.is_empty()
+            && self.apps_installed.is_empty()
+            && self.symlinks_created.is_empty()
+            && self.defaults_changed.is_empty()
+            && self.other_changes.is_empty())
+    }
+
+    /// Adds `other` to these changes, and returns whether it had any.
+    pub fn merge(&mut self, other: Changes) -> bool {
+        let changed = other.has_changes();
+        self.packages_installed.extend(other.packages_installed);
+        self.apps_installed.extend(other.apps_installed);
+        self.symlinks_created.extend(other.symlinks_created);
+        self.defaults_changed.extend(other.defaults_changed);
+        self.other_changes.extend(other.other_changes);
+        changed
+    }
+
+    pub(crate) fn package_installed(&mut self, manager: &str, name: &str) {
+        self.packages_installed.push(InstalledPackage {
+            manager: manager.to_string(),
+            name: name.to_string(),
+        });
+    }
+
+    pub(crate) fn app_installed(&mut self, id: &str, name: &str) {
+        self.apps_installed.push(InstalledApp {
+            id: id.to_string(),
+            name: name.to_string(),
+        });
+    }
+
+    pub(crate) fn symlink_created(&mut self, link: &Path, original: &Path) {
+        self.symlinks_created.push(CreatedSymlink {
+            link: link.display().to_string(),
+            original: original.display().to_string(),
+        });
+    }
+
+    pub(crate) fn default_changed(&mut self, domain: &str, key: &str, value: &str) {
+        self.defaults_changed.push(ChangedDefault {
+            domain: domain.to_string(),
+            key: key.to_string(),
+            value: value.to_string(),
+        });
+    }
+
+    pub(crate) fn other_change(&mut self, description: impl Into<String>) {
+        self.other_changes.push(description.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_report_round_trips_through_json() {
+        let changes = Changes {
+            packages_installed: vec![InstalledPackage {
+                manager: "brew".to_string(),
+                name: "fish".to_string(),
+            }],
+            apps_installed: vec![InstalledApp {
+                id: "937984704".to_string(),
+                name: "Amphetamine".to_string(),
+            }],
+            symlinks_created: vec![CreatedSymlink {
+                link: "/Users/me/.zshrc".to_string(),
+                original: "/Users/me/dotfiles/.zshrc".to_string(),
+            }],
+            defaults_changed: vec![ChangedDefault {
+                domain: "com.apple.dock".to_string(),
+                key: "autohide".to_string(),
+                value: "true".to_string(),
+            }],
+            other_changes: vec!["Tapped homebrew/cask-fonts".to_string()],
+        };
+        let report = RunReport {
+            changes,
+            errors: vec!["Failed to install formula: git".to_string()],
+        };
+
+        let json = serde_json::to_string(&report).unwrap();
+
+        assert_eq!(report, serde_json::from_str(&json).unwrap());
+    }
+
+    #[test]
+    fn merging_says_whether_anything_changed() {
+        let mut changes = Changes::default();
+        assert!(!changes.merge(Changes::default()));
+        assert!(!changes.has_changes());
+
+        let mut started = Changes::default();
+        started.other_change("Started service redis");
+        assert!(changes.merge(started));
+        assert_eq!(vec!["Started service redis"], changes.other_changes);
+    }
+
+    #[test]
+    fn report_keeps_its_flat_json_shape() {
+        let json = serde_json::to_value(RunReport::default()).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "packages_installed": [],
+                "apps_installed": [],
+                "symlinks_created": [],
+                "defaults_changed": [],
+                "other_changes": [],
+                "errors": [],
+            }),
+            json
+        );
+    }
+}
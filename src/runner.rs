@@ -0,0 +1,266 @@
+//! An abstraction over spawning external commands.
+//!
+//! Almost everything omiros does is shelling out to some other tool (`brew`,
+//! `mas`, `defaults`, `code`, ...). Routing every invocation through the
+//! [`CommandRunner`] trait lets the real implementation ([`SystemRunner`]) be
+//! swapped out for a fake ([`MockRunner`]) that records the exact argv of every
+//! call and returns canned output, so the install/diff/apply flows can be
+//! tested without a real macOS machine.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error};
+
+/// How often a command with a timeout is checked for having exited.
+const TIMEOUT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The result of running an external command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    /// The exit code of the process, `None` if it was terminated by a signal.
+    pub code: Option<i32>,
+    /// Everything the process wrote to stdout. Empty if stdout was inherited.
+    pub stdout: Vec<u8>,
+    /// Everything the process wrote to stderr. Empty if stderr was inherited.
+    pub stderr: Vec<u8>,
+}
+
+impl CommandOutput {
+    /// A successful run that printed `stdout`.
+    pub fn ok(stdout: &str) -> Self {
+        CommandOutput {
+            code: Some(0),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: Vec::new(),
+        }
+    }
+
+    /// A failed run that exited with `code` and printed `stderr`.
+    pub fn failed(code: i32, stderr: &str) -> Self {
+        CommandOutput {
+            code: Some(code),
+            stdout: Vec::new(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    /// Returns true if the process exited with a zero exit code.
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+/// Runs external commands on behalf of the rest of the crate.
+pub trait CommandRunner: Sync {
+    /// Runs `program` with `args`, capturing its stdout and stderr.
+    fn output(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput>;
+
+    /// Runs `program` with `args`, inheriting stdout and stderr so the user can
+    /// follow along with long-running commands like `brew install`.
+    fn status(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput>;
+}
+
+/// A [`CommandRunner`] that actually spawns processes.
+///
+/// With a timeout, any command still running once it's elapsed is killed and
+/// reported as terminated by a signal, so it counts as a failure for whatever
+/// it was doing rather than stalling the whole run.
+#[derive(Debug, Default)]
+pub struct SystemRunner {
+    timeout: Option<Duration>,
+}
+
+impl SystemRunner {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        SystemRunner { timeout }
+    }
+
+    /// Waits for `child` to exit, killing it if it outlives the timeout.
+    /// Returns `None` if it was killed.
+    fn wait(&self, child: &mut Child, program: &str) -> io::Result<Option<ExitStatus>> {
+        let Some(timeout) = self.timeout else {
+            return child.wait().map(Some);
+        };
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(Some(status));
+            }
+            if Instant::now() >= deadline {
+                child.kill()?;
+                child.wait()?;
+                error!(
+                    "❌ `{program}` timed out after {}, killed it",
+                    humantime::format_duration(timeout)
+                );
+                return Ok(None);
+            }
+            thread::sleep(TIMEOUT_POLL_INTERVAL);
+        }
+    }
+}
+
+/// Reads all of `pipe` on a separate thread, so a child filling one pipe can't
+/// block while we wait on it to exit.
+fn read_in_background(
+    mut pipe: impl Read + Send + 'static,
+) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        pipe.read_to_end(&mut buf)?;
+        Ok(buf)
+    })
+}
+
+impl CommandRunner for SystemRunner {
+    fn output(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        debug!("Running: {program} {}", args.join(" "));
+        if self.timeout.is_none() {
+            let output = Command::new(program).args(args).output()?;
+
+            return Ok(CommandOutput {
+                code: output.status.code(),
+                stdout: output.stdout,
+                stderr: output.stderr,
+            });
+        }
+
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().map(read_in_background);
+        let stderr = child.stderr.take().map(read_in_background);
+
+        let Some(status) = self.wait(&mut child, program)? else {
+            // Anything the child started may still be holding the pipes open,
+            // so don't wait around for the rest of its output.
+            return Ok(CommandOutput::default());
+        };
+
+        let collect = |reader: Option<thread::JoinHandle<io::Result<Vec<u8>>>>| {
+            reader
+                .map(|reader| reader.join().expect("pipe reader panicked"))
+                .transpose()
+                .map(Option::unwrap_or_default)
+        };
+
+        Ok(CommandOutput {
+            code: status.code(),
+            stdout: collect(stdout)?,
+            stderr: collect(stderr)?,
+        })
+    }
+
+    fn status(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        debug!("Running: {program} {}", args.join(" "));
+        let mut child = Command::new(program).args(args).spawn()?;
+        let status = self.wait(&mut child, program)?;
+
+        Ok(CommandOutput {
+            code: status.and_then(|status| status.code()),
+            ..Default::default()
+        })
+    }
+}
+
+/// A fake [`CommandRunner`] for tests.
+///
+/// Every invocation is recorded and can be inspected with
+/// [`MockRunner::calls`]. Responses are stubbed per exact argv (program
+/// followed by its arguments); commands without a stub succeed with empty
+/// output.
+#[derive(Debug, Default)]
+pub struct MockRunner {
+    stubs: Mutex<HashMap<Vec<String>, VecDeque<CommandOutput>>>,
+    calls: Mutex<Vec<Vec<String>>>,
+}
+
+impl MockRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues `output` as the response to `argv`. When several responses are
+    /// queued for the same argv they are returned in order, and the last one
+    /// keeps being returned once the queue is drained.
+    pub fn stub(self, argv: &[&str], output: CommandOutput) -> Self {
+        self.stubs
+            .lock()
+            .unwrap()
+            .entry(argv.iter().map(|s| s.to_string()).collect())
+            .or_default()
+            .push_back(output);
+        self
+    }
+
+    /// Returns the argv of every command run so far, in order.
+    pub fn calls(&self) -> Vec<Vec<String>> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn respond(&self, program: &str, args: &[&str]) -> CommandOutput {
+        let argv: Vec<String> = std::iter::once(program)
+            .chain(args.iter().copied())
+            .map(String::from)
+            .collect();
+        self.calls.lock().unwrap().push(argv.clone());
+
+        let mut stubs = self.stubs.lock().unwrap();
+        match stubs.get_mut(&argv) {
+            Some(queue) if queue.len() > 1 => queue.pop_front().unwrap(),
+            Some(queue) => queue.front().cloned().unwrap_or_default(),
+            None => CommandOutput::ok(""),
+        }
+    }
+}
+
+impl CommandRunner for MockRunner {
+    fn output(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        Ok(self.respond(program, args))
+    }
+
+    fn status(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        Ok(self.respond(program, args))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system_runner_captures_output_with_a_timeout() {
+        let runner = SystemRunner::new(Some(Duration::from_secs(10)));
+
+        let output = runner
+            .output("sh", &["-c", "echo out; echo err >&2"])
+            .unwrap();
+
+        assert!(output.success());
+        assert_eq!(b"out\n".to_vec(), output.stdout);
+        assert_eq!(b"err\n".to_vec(), output.stderr);
+    }
+
+    #[test]
+    fn system_runner_kills_commands_that_time_out() {
+        let runner = SystemRunner::new(Some(Duration::from_millis(100)));
+
+        let started = Instant::now();
+        let output = runner.status("sleep", &["10"]).unwrap();
+
+        assert!(!output.success());
+        assert_eq!(None, output.code);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
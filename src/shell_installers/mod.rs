@@ -1,17 +1,89 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+
+use crate::{report::Changes, runner::CommandRunner};
 
 pub mod rustup;
 
-#[derive(Deserialize, Debug, PartialEq, Eq)]
-#[serde(rename_all = "kebab-case")]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", from = "ShellInstallerConfig")]
 pub enum ShellInstaller {
+    Rustup(rustup::Rustup),
+}
+
+/// How a shell installer can be written in the configuration file: either
+/// just its name, `"rustup"`, or a table keyed by its name with its settings,
+/// `{ rustup = { components = ["clippy"] } }`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ShellInstallerConfig {
+    Name(ShellInstallerName),
+    Table(ConfiguredShellInstaller),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ShellInstallerName {
     Rustup,
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ConfiguredShellInstaller {
+    Rustup(rustup::Rustup),
+}
+
+impl From<ShellInstallerConfig> for ShellInstaller {
+    fn from(config: ShellInstallerConfig) -> Self {
+        match config {
+            ShellInstallerConfig::Name(ShellInstallerName::Rustup) => {
+                ShellInstaller::Rustup(rustup::Rustup::default())
+            }
+            ShellInstallerConfig::Table(ConfiguredShellInstaller::Rustup(rustup)) => {
+                ShellInstaller::Rustup(rustup)
+            }
+        }
+    }
+}
+
 impl ShellInstaller {
-    pub fn install(&self) -> anyhow::Result<()> {
+    pub fn install(&self, runner: &dyn CommandRunner) -> anyhow::Result<Changes> {
         match self {
-            ShellInstaller::Rustup => Ok(rustup::install_rustup()?),
+            ShellInstaller::Rustup(rustup) => {
+                let mut changes = rustup::install_rustup(runner, rustup)?;
+                changes.merge(rustup::install_rustup_items(runner, rustup)?);
+                Ok(changes)
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Installers {
+        install: Vec<ShellInstaller>,
+    }
+
+    #[test]
+    fn shell_installer_parses_bare_name_and_table() {
+        let installers: Installers = toml::from_str(
+            r#"install = ["rustup", { rustup = { toolchains = ["nightly"], targets = ["wasm32-unknown-unknown"] } }]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                ShellInstaller::Rustup(rustup::Rustup::default()),
+                ShellInstaller::Rustup(rustup::Rustup {
+                    toolchains: vec!["nightly".to_string()],
+                    components: Vec::new(),
+                    targets: vec!["wasm32-unknown-unknown".to_string()],
+                    ..Default::default()
+                }),
+            ],
+            installers.install
+        );
+    }
+}
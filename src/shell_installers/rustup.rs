@@ -2,47 +2,410 @@
 //! ```sh
 //! curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | sh
 //! ```
-use std::process::Command;
+use std::str::from_utf8;
 
-use crate::{errors::SetupError, system_utils::command};
+use log::info;
+use serde::{Deserialize, Serialize};
 
-/// Installs `rustup`, the Rust toolchain installer.
-pub fn install_rustup() -> Result<(), SetupError> {
-    println!("🦀 Installing rustup...");
-    let rustup_path = command("rustup")?;
+use crate::{
+    errors::SetupError,
+    output::{event, unchanged},
+    report::Changes,
+    runner::CommandRunner,
+    system_utils::command,
+};
 
-    if rustup_path.exists() {
-        println!(
+/// How much of each toolchain `rustup` installs.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum RustupProfile {
+    Minimal,
+    Default,
+    Complete,
+}
+
+impl std::fmt::Display for RustupProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RustupProfile::Minimal => write!(f, "minimal"),
+            RustupProfile::Default => write!(f, "default"),
+            RustupProfile::Complete => write!(f, "complete"),
+        }
+    }
+}
+
+/// The toolchains, components, and targets that should be installed with
+/// `rustup`, once it's been installed itself.
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Rustup {
+    /// Toolchains like `stable`, `nightly`, or `1.80.0`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub toolchains: Vec<String>,
+    /// Components like `clippy` and `rustfmt`, added to the default toolchain.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub components: Vec<String>,
+    /// Compilation targets like `wasm32-unknown-unknown`, added to the default
+    /// toolchain.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<String>,
+    /// The toolchain used outside of any project with a toolchain file of its
+    /// own. Switched to with `rustup default` if rustup is already installed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_toolchain: Option<String>,
+    /// The profile rustup is installed with, which only matters on install.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<RustupProfile>,
+}
+
+/// Installs `rustup`, the Rust toolchain installer, with the declared default
+/// toolchain and profile. If it's already installed, the default toolchain is
+/// switched to the declared one instead.
+pub fn install_rustup(runner: &dyn CommandRunner, rustup: &Rustup) -> Result<Changes, SetupError> {
+    event!("install", "rustup", started, "🦀 Installing rustup...");
+
+    if let Ok(rustup_path) = command(runner, "rustup") {
+        unchanged!(
             "ℹ️  rustup is already installed at: {}",
             rustup_path.display()
         );
-        return Ok(());
+        return match &rustup.default_toolchain {
+            Some(toolchain) => set_default_toolchain(runner, toolchain),
+            None => Ok(Changes::default()),
+        };
     }
 
     // Download and execute the rustup installer.
     // curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | sh -s -- -y
-    let status = Command::new("curl")
-        .args([
-            "--proto",
-            "'=https'",
-            "--tlsv1.2",
-            "-sSf",
-            "https://sh.rustup.rs",
-            "|",
-            "sh",
-            "-s",
-            "--",
-            "-y",
-        ])
-        .status()?;
+    // The options are passed to `sh` as positional arguments rather than
+    // pasted into the script, so a toolchain name can't inject shell syntax.
+    let installer =
+        r#"curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | sh -s -- -y "$@""#;
+    let profile = rustup.profile.map(|profile| profile.to_string());
+    let mut args = vec!["-c", installer, "sh"];
+    if let Some(toolchain) = &rustup.default_toolchain {
+        args.extend(["--default-toolchain", toolchain]);
+    }
+    if let Some(profile) = &profile {
+        args.extend(["--profile", profile]);
+    }
+    let status = runner.status("sh", &args)?;
 
     if status.success() {
-        println!("✅ rustup installed successfully");
-        println!("💡 You may need to restart your shell or run: source ~/.cargo/env");
-        Ok(())
+        event!("install", "rustup", ok, "✅ rustup installed successfully");
+        info!("💡 You may need to restart your shell or run: source ~/.cargo/env");
+        let mut changes = Changes::default();
+        changes.package_installed("rustup", "rustup");
+        Ok(changes)
     } else {
+        event!("install", "rustup", failed, "❌ rustup installation failed");
         Err(SetupError::InstallFailed(
             "rustup installation failed".to_string(),
         ))
     }
 }
+
+/// Makes `toolchain` the default, if it isn't already.
+fn set_default_toolchain(
+    runner: &dyn CommandRunner,
+    toolchain: &str,
+) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    let host = host_triple(runner)?;
+    let output = runner.output("rustup", &["default"])?;
+    if output.success() && is_listed(from_utf8(&output.stdout)?, toolchain, host.as_deref()) {
+        unchanged!("ℹ️  rustup default toolchain already {toolchain}");
+        return Ok(changes);
+    }
+
+    event!(
+        "set-default-toolchain",
+        toolchain,
+        started,
+        "🦀 Setting rustup default toolchain: {toolchain}"
+    );
+    if !runner.status("rustup", &["default", toolchain])?.success() {
+        event!(
+            "set-default-toolchain",
+            toolchain,
+            failed,
+            "❌ Failed to set rustup default toolchain: {toolchain}"
+        );
+        return Err(SetupError::InstallFailed(format!(
+            "failed to set rustup default toolchain to {toolchain}"
+        )));
+    }
+    event!(
+        "set-default-toolchain",
+        toolchain,
+        ok,
+        "✅ Set rustup default toolchain: {toolchain}"
+    );
+    changes.other_change(format!("Set rustup default toolchain to {toolchain}"));
+
+    Ok(changes)
+}
+
+/// Returns the triple rustup appends to toolchain and component names, like
+/// `aarch64-apple-darwin`, from the `Default host:` line of `rustup show`.
+fn host_triple(runner: &dyn CommandRunner) -> Result<Option<String>, SetupError> {
+    let output = runner.output("rustup", &["show"])?;
+    Ok(from_utf8(&output.stdout)?
+        .lines()
+        .find_map(|line| line.strip_prefix("Default host:"))
+        .map(|host| host.trim().to_string()))
+}
+
+/// Returns true if `name` is in the output of one of the `rustup ... list`
+/// commands. Toolchains and components are listed with the host triple
+/// appended, like `stable-aarch64-apple-darwin (default)`, so `name` followed
+/// by the host triple also counts, but not a longer name like a dated
+/// `nightly-2024-05-01`.
+fn is_listed(list: &str, name: &str, host: Option<&str>) -> bool {
+    let with_host = host.map(|host| format!("{name}-{host}"));
+    list.lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .any(|installed| installed == name || with_host.as_deref() == Some(installed))
+}
+
+/// Installs each declared toolchain, component, and target that isn't already
+/// installed. A failure to install one item is reported and the rest are still
+/// attempted.
+pub fn install_rustup_items(
+    runner: &dyn CommandRunner,
+    rustup: &Rustup,
+) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    let mut failed = Vec::new();
+    if rustup.toolchains.is_empty() && rustup.components.is_empty() && rustup.targets.is_empty() {
+        return Ok(changes);
+    }
+
+    let host = host_triple(runner)?;
+    changes.merge(install_missing(
+        runner,
+        host.as_deref(),
+        "toolchain",
+        &["toolchain", "list"],
+        &["toolchain", "install"],
+        &rustup.toolchains,
+        &mut failed,
+    )?);
+    changes.merge(install_missing(
+        runner,
+        host.as_deref(),
+        "component",
+        &["component", "list", "--installed"],
+        &["component", "add"],
+        &rustup.components,
+        &mut failed,
+    )?);
+    changes.merge(install_missing(
+        runner,
+        host.as_deref(),
+        "target",
+        &["target", "list", "--installed"],
+        &["target", "add"],
+        &rustup.targets,
+        &mut failed,
+    )?);
+
+    if failed.is_empty() {
+        Ok(changes)
+    } else {
+        Err(SetupError::InstallFailed(format!(
+            "failed to install rustup {}",
+            failed.join(", ")
+        )))
+    }
+}
+
+/// Runs `rustup <add_args> <name>` for each `desired` name that isn't in the
+/// output of `rustup <list_args>`, recording the ones that failed in `failed`.
+fn install_missing(
+    runner: &dyn CommandRunner,
+    host: Option<&str>,
+    kind: &str,
+    list_args: &[&str],
+    add_args: &[&str],
+    desired: &[String],
+    failed: &mut Vec<String>,
+) -> Result<Changes, SetupError> {
+    let mut changes = Changes::default();
+    if desired.is_empty() {
+        return Ok(changes);
+    }
+
+    let output = runner.output("rustup", list_args)?;
+    if !output.success() {
+        return Err(SetupError::InstallFailed(format!(
+            "rustup {} failed: {}",
+            list_args.join(" "),
+            from_utf8(&output.stderr)?.trim()
+        )));
+    }
+    let installed = from_utf8(&output.stdout)?;
+
+    for name in desired {
+        if is_listed(installed, name, host) {
+            unchanged!("ℹ️  rustup {kind} {name} already installed");
+            continue;
+        }
+
+        event!(
+            "install",
+            name,
+            started,
+            "🦀 Installing rustup {kind}: {name}"
+        );
+        let mut args = add_args.to_vec();
+        args.push(name);
+        if !runner.status("rustup", &args)?.success() {
+            event!(
+                "install",
+                name,
+                failed,
+                "❌ Failed to install rustup {kind}: {name}"
+            );
+            failed.push(format!("{kind} {name}"));
+        } else {
+            event!("install", name, ok, "✅ Installed rustup {kind}: {name}");
+            changes.package_installed("rustup", name);
+        }
+    }
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, MockRunner};
+
+    const RUSTUP_SHOW: &str = "Default host: aarch64-apple-darwin\n\
+                               rustup home:  /Users/me/.rustup\n";
+
+    #[test]
+    fn is_listed_matches_names_with_host_triple() {
+        let toolchains =
+            "stable-aarch64-apple-darwin (default)\nnightly-2024-05-01-aarch64-apple-darwin\n";
+
+        let host = Some("aarch64-apple-darwin");
+
+        assert!(is_listed(toolchains, "stable", host));
+        assert!(is_listed(toolchains, "nightly-2024-05-01", host));
+        assert!(!is_listed(toolchains, "nightly", host));
+        assert!(!is_listed(toolchains, "beta", host));
+        assert!(!is_listed(toolchains, "stable", None));
+        assert!(is_listed(
+            "wasm32-unknown-unknown\n",
+            "wasm32-unknown-unknown",
+            host
+        ));
+        assert!(!is_listed(
+            "rust-std-wasm32-unknown-unknown\n",
+            "rust-std",
+            host
+        ));
+    }
+
+    #[test]
+    fn install_rustup_passes_default_toolchain_and_profile_to_installer() {
+        let runner =
+            MockRunner::new().stub(&["command", "-v", "rustup"], CommandOutput::failed(1, ""));
+        let rustup = Rustup {
+            default_toolchain: Some("nightly".to_string()),
+            profile: Some(RustupProfile::Minimal),
+            ..Default::default()
+        };
+
+        let changes = install_rustup(&runner, &rustup).unwrap();
+
+        assert_eq!("rustup", changes.packages_installed[0].name);
+        assert_eq!(
+            runner.calls()[1],
+            [
+                "sh",
+                "-c",
+                r#"curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | sh -s -- -y "$@""#,
+                "sh",
+                "--default-toolchain",
+                "nightly",
+                "--profile",
+                "minimal"
+            ]
+        );
+    }
+
+    #[test]
+    fn install_rustup_switches_default_toolchain_when_installed() {
+        let runner = MockRunner::new()
+            .stub(
+                &["command", "-v", "rustup"],
+                CommandOutput::ok("/Users/me/.cargo/bin/rustup\n"),
+            )
+            .stub(&["rustup", "show"], CommandOutput::ok(RUSTUP_SHOW))
+            .stub(
+                &["rustup", "default"],
+                CommandOutput::ok("stable-aarch64-apple-darwin (default)\n"),
+            );
+        let rustup = |toolchain: &str| Rustup {
+            default_toolchain: Some(toolchain.to_string()),
+            ..Default::default()
+        };
+
+        let changes = install_rustup(&runner, &rustup("stable")).unwrap();
+        assert_eq!(runner.calls().last().unwrap(), &["rustup", "default"]);
+        assert!(!changes.has_changes());
+
+        let changes = install_rustup(&runner, &rustup("nightly")).unwrap();
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            &["rustup", "default", "nightly"]
+        );
+        assert_eq!(
+            vec!["Set rustup default toolchain to nightly"],
+            changes.other_changes
+        );
+    }
+
+    #[test]
+    fn install_rustup_items_only_adds_missing_and_keeps_going() {
+        let runner = MockRunner::new()
+            .stub(&["rustup", "show"], CommandOutput::ok(RUSTUP_SHOW))
+            .stub(
+                &["rustup", "component", "list", "--installed"],
+                CommandOutput::ok("clippy-aarch64-apple-darwin\nrust-std-aarch64-apple-darwin\n"),
+            )
+            .stub(
+                &["rustup", "component", "add", "rust-src"],
+                CommandOutput::failed(1, "error"),
+            );
+        let rustup = Rustup {
+            components: vec![
+                "clippy".to_string(),
+                "rust-src".to_string(),
+                "rustfmt".to_string(),
+            ],
+            ..Default::default()
+        };
+
+        let result = install_rustup_items(&runner, &rustup);
+
+        assert!(result.is_err());
+        let calls = runner.calls();
+        assert!(!calls.contains(&vec![
+            "rustup".to_string(),
+            "component".to_string(),
+            "add".to_string(),
+            "clippy".to_string()
+        ]));
+        assert!(calls.contains(&vec![
+            "rustup".to_string(),
+            "component".to_string(),
+            "add".to_string(),
+            "rustfmt".to_string()
+        ]));
+        assert!(!calls.iter().any(|call| call[1] == "toolchain"));
+    }
+}
@@ -1,19 +1,112 @@
-use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     brew::Brew,
+    cargo_install::CargoInstall,
     dotfiles::Dotfiles,
-    macos::{Dock, Finder, MagicMouse, MissionControl, Safari, SystemSettings},
+    errors::SetupError,
+    macos::{
+        ControlCenter, CustomDefault, DefaultsImport, Dock, Finder, Keyboard, MagicMouse, MenuBar,
+        MissionControl, Pointer, RestartStrategy, Safari, SoftwareUpdate, Sound, StageManager,
+        SystemSettings, Trackpad, WindowTiling, planned_writes,
+    },
     mas::Mas,
+    migrate,
+    npm::Npm,
+    runner::CommandRunner,
     shell_installers::ShellInstaller,
+    system_utils::hostname,
+    validate::{ConfigProblem, report_conflicting_writes},
     vscode::Vscode,
 };
 
+/// A top-level section of the configuration file.
+pub trait Section {
+    /// The guard command that must exit zero for this section to be applied,
+    /// for example `test -f ~/.vpn/work.pem` to only apply a section on work
+    /// machines.
+    fn when(&self) -> Option<&str>;
+}
+
+/// A top-level section of the configuration, applied as one step of a run.
+#[derive(Deserialize, Serialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Module {
+    ShellInstallers,
+    Brew,
+    Mas,
+    Dotfiles,
+    Vscode,
+    Npm,
+    Cargo,
+    Macos,
+}
+
+impl Module {
+    /// The order modules run in unless the configuration says otherwise.
+    pub const DEFAULT_ORDER: [Module; 8] = [
+        Module::ShellInstallers,
+        Module::Brew,
+        Module::Mas,
+        Module::Dotfiles,
+        Module::Vscode,
+        Module::Npm,
+        Module::Cargo,
+        Module::Macos,
+    ];
+
+    /// The name of the module's section in the configuration file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Module::ShellInstallers => "shell-installers",
+            Module::Brew => "brew",
+            Module::Mas => "mas",
+            Module::Dotfiles => "dotfiles",
+            Module::Vscode => "vscode",
+            Module::Npm => "npm",
+            Module::Cargo => "cargo",
+            Module::Macos => "macos",
+        }
+    }
+
+    /// Whether the module should run, given the modules passed to `--only`,
+    /// which are all of them when it's empty, and to `--skip`.
+    pub fn selected(self, only: &[Module], skip: &[Module]) -> bool {
+        (only.is_empty() || only.contains(&self)) && !skip.contains(&self)
+    }
+
+    /// Whether the tool this module drives is normally installed by brew:
+    /// `mas` and `node`, which `npm` comes with, are formulae, and `code` comes
+    /// with the `visual-studio-code` cask.
+    fn needs_brew(self) -> bool {
+        matches!(self, Module::Mas | Module::Vscode | Module::Npm)
+    }
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 /// Represents the entire system configuration, including all package managers,
 /// and dotfiles.
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct System {
+    /// The schema version the configuration was written for, see
+    /// [`crate::migrate`].
+    pub version: Option<u32>,
+    /// The order to apply the modules in, defaults to
+    /// [`Module::DEFAULT_ORDER`].
+    pub order: Option<Vec<Module>>,
     /// The Homebrew configuration.
     pub brew: Option<Brew>,
     /// The Mac App Store configuration.
@@ -22,14 +115,272 @@ pub struct System {
     pub dotfiles: Option<Dotfiles>,
     /// The VS Code configuration.
     pub vscode: Option<Vscode>,
+    /// The global npm packages configuration.
+    pub npm: Option<Npm>,
+    /// The `cargo install` configuration.
+    pub cargo: Option<CargoInstall>,
     /// The macOS configuration.
     pub macos: Option<MacOS>,
     /// The shell installers configuration.
     pub shell_installers: Option<ShellInstallers>,
+    /// Sections merged over the rest of the configuration on the machine with
+    /// that short hostname, see [`System::resolve_for_host`].
+    pub hosts: Option<BTreeMap<String, HostOverrides>>,
+}
+
+/// The sections a `[hosts.<hostname>]` table can add to, or override, for one
+/// machine. They're kept as plain TOML, rather than parsed, so that only the
+/// keys they set are merged over the base configuration.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HostOverrides {
+    pub brew: Option<toml::Table>,
+    pub mas: Option<toml::Table>,
+    pub macos: Option<toml::Table>,
+}
+
+impl System {
+    /// Reads and parses the `system.toml` in `config_dir`, with its includes.
+    /// Configurations for an older schema are upgraded in memory, with a nudge
+    /// to migrate them. `[hosts]` are left for
+    /// [`System::resolve_for_current_host`].
+    pub fn load(config_dir: &Path) -> Result<System, SetupError> {
+        let mut config = read_config(&config_dir.join("system.toml"))?;
+        let from =
+            migrate::migrate(&mut config).map_err(|e| SetupError::ConfigError(format!("{e:#}")))?;
+        if from < migrate::CURRENT_VERSION {
+            warn!(
+                "⚠️  system.toml is config version {from}, run `omiros config migrate` to \
+                 upgrade it to version {}",
+                migrate::CURRENT_VERSION
+            );
+        }
+        Ok(config.try_into()?)
+    }
+
+    /// Resolves the configuration for this machine, by its hostname, if any
+    /// `[hosts]` are configured.
+    pub fn resolve_for_current_host(
+        self,
+        runner: &dyn CommandRunner,
+    ) -> Result<System, SetupError> {
+        if self.hosts.is_none() {
+            return Ok(self);
+        }
+
+        let hostname = hostname(runner)?.ok_or_else(|| {
+            SetupError::ConfigError("could not determine hostname for `[hosts]`".to_string())
+        })?;
+        self.resolve_for_host(&hostname)
+    }
+
+    /// Merges the `[hosts.<hostname>]` sections over the rest of the
+    /// configuration: lists are unioned, and any other values, like scalar
+    /// macOS settings, are overridden. The result has no `[hosts]`.
+    pub fn resolve_for_host(mut self, hostname: &str) -> Result<System, SetupError> {
+        let Some(overrides) = self
+            .hosts
+            .take()
+            .and_then(|mut hosts| hosts.remove(hostname))
+        else {
+            return Ok(self);
+        };
+
+        let mut base =
+            toml::Table::try_from(&self).map_err(|e| SetupError::ConfigError(e.to_string()))?;
+        let sections = [
+            ("brew", overrides.brew),
+            ("mas", overrides.mas),
+            ("macos", overrides.macos),
+        ];
+        for (name, section) in sections {
+            if let Some(section) = section {
+                merge_into(&mut base, "", name, toml::Value::Table(section));
+            }
+        }
+
+        Ok(base.try_into()?)
+    }
+
+    /// The modules to run, in order.
+    pub fn order(&self) -> &[Module] {
+        self.order.as_deref().unwrap_or(&Module::DEFAULT_ORDER)
+    }
+
+    /// Whether the dotfiles repository has to be cloned or pulled before
+    /// `[brew]` runs, rather than when `[dotfiles]` comes up in the order,
+    /// because brew installs formulae from files in it, which won't exist yet
+    /// on a fresh machine.
+    pub fn sync_dotfiles_before_brew(&self, dotfiles_dir: &Path) -> bool {
+        let (Some(brew), Some(dotfiles)) = (&self.brew, &self.dotfiles) else {
+            return false;
+        };
+        let position = |module| self.order().iter().position(|m| *m == module);
+
+        dotfiles.git().is_some()
+            && matches!(
+                (position(Module::Brew), position(Module::Dotfiles)),
+                (Some(brew), Some(dotfiles)) if brew < dotfiles
+            )
+            && brew.has_formula_files_in(dotfiles_dir)
+    }
+
+    /// Whether the configuration has a section for `module`.
+    fn has_section(&self, module: Module) -> bool {
+        match module {
+            Module::ShellInstallers => self.shell_installers.is_some(),
+            Module::Brew => self.brew.is_some(),
+            Module::Mas => self.mas.is_some(),
+            Module::Dotfiles => self.dotfiles.is_some(),
+            Module::Vscode => self.vscode.is_some(),
+            Module::Npm => self.npm.is_some(),
+            Module::Cargo => self.cargo.is_some(),
+            Module::Macos => self.macos.is_some(),
+        }
+    }
+
+    /// Checks that a custom order doesn't repeat modules, leave out configured
+    /// ones, or run a module before brew has had a chance to install its tool.
+    pub(crate) fn validate_order(&self, problems: &mut Vec<ConfigProblem>) {
+        let Some(order) = &self.order else {
+            return;
+        };
+
+        let mut seen = HashSet::new();
+        for module in order {
+            if !seen.insert(module) {
+                problems.push(ConfigProblem::error(
+                    "order",
+                    format!("`{module}` is listed more than once"),
+                ));
+            }
+        }
+
+        for module in Module::DEFAULT_ORDER {
+            if self.has_section(module) && !seen.contains(&module) {
+                problems.push(ConfigProblem::warning(
+                    "order",
+                    format!("`[{module}]` is configured but not in the order, so it won't run"),
+                ));
+            }
+        }
+
+        if let Some(brew) = order.iter().position(|module| *module == Module::Brew) {
+            for module in &order[..brew] {
+                if module.needs_brew() {
+                    problems.push(ConfigProblem::error(
+                        "order",
+                        format!("`{module}` must come after `brew`, which installs it"),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Reads the configuration file at `path`, merged over the files listed in its
+/// top-level `include`, which are relative to the file including them, and
+/// can include files of their own. Later includes are merged over earlier
+/// ones, and the including file over all of them, with the same rules as
+/// [`System::resolve_for_host`].
+pub fn read_config(path: &Path) -> Result<toml::Table, SetupError> {
+    read_config_from(path, &|path| fs::read_to_string(path))
+}
+
+/// Like [`read_config`], but reading each file's contents with `read`, given
+/// its canonical path, like contents that haven't been written back yet.
+pub fn read_config_from(
+    path: &Path,
+    read: &dyn Fn(&Path) -> std::io::Result<String>,
+) -> Result<toml::Table, SetupError> {
+    read_with_includes(path, read, &mut Vec::new())
+}
+
+/// Reads the configuration file at `path` with its includes, where `chain` is
+/// the files that included it, to catch a file that ends up including itself.
+fn read_with_includes(
+    path: &Path,
+    read: &dyn Fn(&Path) -> std::io::Result<String>,
+    chain: &mut Vec<PathBuf>,
+) -> Result<toml::Table, SetupError> {
+    let unreadable = |e: std::io::Error| {
+        SetupError::ConfigError(format!("couldn't read {}: {e}", path.display()))
+    };
+    let path = path.canonicalize().map_err(unreadable)?;
+    if chain.contains(&path) {
+        let cycle: Vec<_> = chain
+            .iter()
+            .chain([&path])
+            .map(|path| path.display().to_string())
+            .collect();
+        return Err(SetupError::ConfigError(format!(
+            "circular include: {}",
+            cycle.join(" -> ")
+        )));
+    }
+
+    let mut table: toml::Table = read(&path)
+        .map_err(unreadable)?
+        .parse()
+        .map_err(|e| SetupError::ConfigError(format!("in {}: {e}", path.display())))?;
+    let includes: Vec<PathBuf> = match table.remove("include") {
+        Some(include) => include.try_into()?,
+        None => Vec::new(),
+    };
+
+    let dir = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+    chain.push(path);
+    let mut merged = toml::Table::new();
+    for include in includes {
+        for (key, value) in read_with_includes(&dir.join(include), read, chain)? {
+            merge_into(&mut merged, "", &key, value);
+        }
+    }
+    chain.pop();
+
+    for (key, value) in table {
+        merge_into(&mut merged, "", &key, value);
+    }
+    Ok(merged)
+}
+
+/// The dotted paths of the arrays whose order matters, which replace the array
+/// they're merged over rather than adding to it.
+const ORDERED_ARRAYS: [&str; 2] = ["order", "macos.dock.persistent-apps"];
+
+/// Merges `value` into `table`, found at the dotted `path`, under `key`:
+/// tables are merged key by key, arrays gain the items they don't already
+/// have, except for [`ORDERED_ARRAYS`], and anything else replaces what was
+/// there.
+fn merge_into(table: &mut toml::Table, path: &str, key: &str, value: toml::Value) {
+    let path = if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{path}.{key}")
+    };
+    match (table.get_mut(key), value) {
+        (Some(toml::Value::Table(base)), toml::Value::Table(over)) => {
+            for (key, value) in over {
+                merge_into(base, &path, &key, value);
+            }
+        }
+        (Some(toml::Value::Array(base)), toml::Value::Array(over))
+            if !ORDERED_ARRAYS.contains(&path.as_str()) =>
+        {
+            for item in over {
+                if !base.contains(&item) {
+                    base.push(item);
+                }
+            }
+        }
+        (_, value) => {
+            table.insert(key.to_string(), value);
+        }
+    }
 }
 
 /// Represents all macOS-specific configuration.
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct MacOS {
     pub dock: Option<Dock>,
@@ -37,12 +388,509 @@ pub struct MacOS {
     pub system: Option<SystemSettings>,
     pub mission_control: Option<MissionControl>,
     pub magic_mouse: Option<MagicMouse>,
+    pub trackpad: Option<Trackpad>,
+    pub keyboard: Option<Keyboard>,
     pub finder: Option<Finder>,
+    pub pointer: Option<Pointer>,
+    pub menu_bar: Option<MenuBar>,
+    pub window_tiling: Option<WindowTiling>,
+    pub stage_manager: Option<StageManager>,
+    pub control_center: Option<ControlCenter>,
+    pub software_update: Option<SoftwareUpdate>,
+    pub sound: Option<Sound>,
+    /// Whole preferences domains to import from `.plist` files.
+    pub import: Option<Vec<DefaultsImport>>,
+    /// `defaults` keys there's no typed setting for, written as is.
+    pub custom: Option<Vec<CustomDefault>>,
+    /// How apps are restarted after their settings change, defaults to
+    /// [`RestartStrategy::Killall`].
+    #[serde(default)]
+    pub restart_strategy: RestartStrategy,
+    /// Shell command that must exit zero for this section to be applied.
+    pub when: Option<String>,
+}
+
+impl MacOS {
+    /// The configured sections that write to preferences macOS protects, and
+    /// so need the terminal to have Full Disk Access.
+    pub fn full_disk_access_sections(&self) -> Vec<&'static str> {
+        let mut sections = Vec::new();
+        if self.safari.is_some() {
+            sections.push("macos.safari");
+        }
+        sections
+    }
+
+    /// Checks that values are within the ranges macOS accepts.
+    pub(crate) fn validate(&self, problems: &mut Vec<ConfigProblem>) {
+        if let Some(cursor_size) = self.pointer.as_ref().and_then(|p| p.cursor_size)
+            && !(1.0..=4.0).contains(&cursor_size)
+        {
+            problems.push(ConfigProblem::error(
+                "macos.pointer.cursor-size",
+                format!("must be between 1.0 and 4.0, got {cursor_size}"),
+            ));
+        }
+
+        if let Some(tracking_speed) = self.trackpad.as_ref().and_then(|t| t.tracking_speed)
+            && !(0.0..=3.0).contains(&tracking_speed)
+        {
+            problems.push(ConfigProblem::error(
+                "macos.trackpad.tracking-speed",
+                format!("must be between 0.0 and 3.0, got {tracking_speed}"),
+            ));
+        }
+
+        let persistent_apps = self
+            .dock
+            .as_ref()
+            .and_then(|dock| dock.persistent_apps.as_ref());
+        if let Some(dock) = &self.dock
+            && dock.clear_other_apps.is_some()
+            && dock.persistent_apps.is_none()
+        {
+            problems.push(ConfigProblem::warning(
+                "macos.dock.clear-other-apps",
+                "has no effect without `persistent-apps`",
+            ));
+        }
+        for app in persistent_apps.into_iter().flatten() {
+            if !(app.starts_with('/') && app.ends_with(".app")) {
+                problems.push(ConfigProblem::error(
+                    "macos.dock.persistent-apps",
+                    format!("`{app}` isn't the path of an app, like `/Applications/Safari.app`"),
+                ));
+            }
+        }
+
+        for custom in self.custom.iter().flatten() {
+            if let Err(message) = custom.typed_value() {
+                problems.push(ConfigProblem::error("macos.custom", message));
+            }
+        }
+
+        report_conflicting_writes(&planned_writes(self), problems);
+
+        let mut import_files = HashMap::new();
+        for import in self.import.iter().flatten() {
+            if let Some(other) = import_files.insert(&import.domain, &import.file)
+                && *other != import.file
+            {
+                problems.push(ConfigProblem::error(
+                    "macos.import",
+                    format!(
+                        "{} is imported from both {} and {}",
+                        import.domain,
+                        other.display(),
+                        import.file.display()
+                    ),
+                ));
+            }
+        }
+
+        let per_app = self
+            .system
+            .as_ref()
+            .and_then(|system| system.key_press_and_hold_per_app.as_ref());
+        for bundle_id in per_app.into_iter().flatten() {
+            if !is_bundle_id(bundle_id) {
+                problems.push(ConfigProblem::error(
+                    "macos.system.key-press-and-hold-per-app",
+                    format!("`{bundle_id}` doesn't look like a bundle ID, like `com.apple.Safari`"),
+                ));
+            }
+        }
+    }
+}
+
+/// Whether `id` looks like an app's bundle ID: reverse-DNS, like
+/// `com.apple.Safari`.
+fn is_bundle_id(id: &str) -> bool {
+    id.split('.').count() >= 2
+        && id.split('.').all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+impl Section for MacOS {
+    fn when(&self) -> Option<&str> {
+        self.when.as_deref()
+    }
 }
 
 /// Represents all shell installers.
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct ShellInstallers {
     pub install: Vec<ShellInstaller>,
+    /// Shell command that must exit zero for this section to be applied.
+    pub when: Option<String>,
+}
+
+impl Section for ShellInstallers {
+    fn when(&self) -> Option<&str> {
+        self.when.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::ValueEnum;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::everything(&[], &[], &Module::DEFAULT_ORDER)]
+    #[case::only(&["macos", "shell-installers"], &[], &[Module::ShellInstallers, Module::Macos])]
+    #[case::skip(&[], &["brew", "mas"], &[
+        Module::ShellInstallers,
+        Module::Dotfiles,
+        Module::Vscode,
+        Module::Npm,
+        Module::Cargo,
+        Module::Macos,
+    ])]
+    #[case::only_and_skip(&["brew", "mas"], &["mas"], &[Module::Brew])]
+    fn only_and_skip_select_modules(
+        #[case] only: &[&str],
+        #[case] skip: &[&str],
+        #[case] expected: &[Module],
+    ) {
+        let parse = |names: &[&str]| -> Vec<Module> {
+            names
+                .iter()
+                .map(|name| Module::from_str(name, false).unwrap())
+                .collect()
+        };
+        let (only, skip) = (parse(only), parse(skip));
+
+        let selected: Vec<_> = Module::DEFAULT_ORDER
+            .into_iter()
+            .filter(|module| module.selected(&only, &skip))
+            .collect();
+
+        assert_eq!(expected, selected);
+    }
+
+    #[test]
+    fn unknown_modules_are_rejected() {
+        let error = Module::from_str("dock", false).unwrap_err();
+
+        assert!(error.contains("dock"));
+    }
+
+    #[test]
+    fn is_bundle_id_accepts_reverse_dns_only() {
+        assert!(is_bundle_id("com.microsoft.VSCode"));
+        assert!(is_bundle_id("org.alacritty"));
+        assert!(!is_bundle_id("VSCode"));
+        assert!(!is_bundle_id("com..VSCode"));
+        assert!(!is_bundle_id("Visual Studio Code.app"));
+    }
+
+    const HOSTS_CONFIG: &str = r#"
+        [brew]
+        formulae = ["git"]
+        casks = ["firefox"]
+
+        [macos.dock]
+        autohide = true
+        icon-size = 48
+
+        [hosts.work-mbp.brew]
+        casks = ["slack", "firefox"]
+
+        [hosts.work-mbp.macos.dock]
+        icon-size = 36
+
+        [hosts.home-mini.mas]
+        apps = [{ id = "937984704", name = "Amphetamine" }]
+    "#;
+
+    #[test]
+    fn resolve_for_host_unions_lists_and_overrides_scalars() {
+        let system: System = toml::from_str(HOSTS_CONFIG).unwrap();
+
+        let resolved = system.resolve_for_host("work-mbp").unwrap();
+
+        assert!(resolved.hosts.is_none());
+        assert!(resolved.mas.is_none());
+        let brew = toml::Table::try_from(resolved.brew.unwrap()).unwrap();
+        assert_eq!(toml::Value::from(vec!["firefox", "slack"]), brew["casks"]);
+        let dock = resolved.macos.unwrap().dock.unwrap();
+        assert_eq!(Some(true), dock.autohide);
+        assert_eq!(Some(36), dock.icon_size);
+    }
+
+    #[test]
+    fn resolve_for_host_adds_sections_missing_from_the_base() {
+        let system: System = toml::from_str(HOSTS_CONFIG).unwrap();
+
+        let resolved = system.resolve_for_host("home-mini").unwrap();
+
+        assert_eq!(1, resolved.mas.unwrap().apps.len());
+        let dock = resolved.macos.unwrap().dock.unwrap();
+        assert_eq!(Some(48), dock.icon_size);
+    }
+
+    #[test]
+    fn resolve_for_host_ignores_other_hosts() {
+        let system: System = toml::from_str(HOSTS_CONFIG).unwrap();
+
+        let resolved = system.resolve_for_host("someone-elses-laptop").unwrap();
+
+        assert!(resolved.hosts.is_none());
+        assert!(resolved.mas.is_none());
+        let brew = toml::Table::try_from(resolved.brew.unwrap()).unwrap();
+        assert_eq!(toml::Value::from(vec!["firefox"]), brew["casks"]);
+    }
+
+    /// Writes each `(path, contents)` file into a new temporary directory.
+    fn config_tree(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (path, contents) in files {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn read_config_merges_includes_under_the_including_file() {
+        let dir = config_tree(&[
+            (
+                "system.toml",
+                r#"
+                include = ["brew.toml", "macos/dock.toml"]
+
+                [brew]
+                casks = ["firefox"]
+
+                [macos.dock]
+                icon-size = 48
+                "#,
+            ),
+            (
+                "brew.toml",
+                "[brew]\nformulae = [\"git\"]\ncasks = [\"slack\"]\n",
+            ),
+            (
+                "macos/dock.toml",
+                "include = [\"base.toml\"]\n[macos.dock]\nicon-size = 36\n",
+            ),
+            ("macos/base.toml", "[macos.dock]\nautohide = true\n"),
+        ]);
+
+        let config = read_config(&dir.path().join("system.toml")).unwrap();
+
+        let expected: toml::Table = toml::from_str(
+            r#"
+            [brew]
+            formulae = ["git"]
+            casks = ["slack", "firefox"]
+
+            [macos.dock]
+            autohide = true
+            icon-size = 48
+            "#,
+        )
+        .unwrap();
+        assert_eq!(expected, config);
+    }
+
+    #[test]
+    fn read_config_replaces_ordered_arrays_instead_of_combining_them() {
+        let dir = config_tree(&[
+            (
+                "system.toml",
+                r#"
+                include = ["base.toml"]
+                order = ["macos", "brew"]
+
+                [macos.dock]
+                persistent-apps = ["/Applications/Safari.app"]
+                "#,
+            ),
+            (
+                "base.toml",
+                r#"
+                order = ["brew", "dotfiles", "macos"]
+
+                [macos.dock]
+                persistent-apps = ["/Applications/Mail.app", "/Applications/Safari.app"]
+                "#,
+            ),
+        ]);
+
+        let config = read_config(&dir.path().join("system.toml")).unwrap();
+
+        let expected: toml::Table = toml::from_str(
+            r#"
+            order = ["macos", "brew"]
+
+            [macos.dock]
+            persistent-apps = ["/Applications/Safari.app"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(expected, config);
+    }
+
+    #[test]
+    fn read_config_rejects_circular_includes() {
+        let dir = config_tree(&[
+            ("system.toml", "include = [\"a.toml\"]"),
+            ("a.toml", "include = [\"b/b.toml\"]"),
+            ("b/b.toml", "include = [\"../a.toml\"]"),
+        ]);
+
+        let err = read_config(&dir.path().join("system.toml")).unwrap_err();
+
+        assert!(
+            err.to_string().contains("circular include"),
+            "unexpected error: {err}"
+        );
+    }
+
+    #[test]
+    fn read_config_reports_missing_includes() {
+        let dir = config_tree(&[("system.toml", "include = [\"missing.toml\"]")]);
+
+        let err = read_config(&dir.path().join("system.toml")).unwrap_err();
+
+        assert!(
+            err.to_string().contains("missing.toml"),
+            "unexpected error: {err}"
+        );
+    }
+
+    #[test]
+    fn load_parses_system_toml_in_the_config_dir() {
+        let dir = config_tree(&[(
+            "system.toml",
+            r#"
+            version = 2
+            order = ["brew", "macos"]
+
+            [brew]
+            formulae = ["git"]
+
+            [macos.dock]
+            autohide = true
+            "#,
+        )]);
+
+        let system = System::load(dir.path()).unwrap();
+
+        assert_eq!(&[Module::Brew, Module::Macos], system.order());
+        let brew = toml::Table::try_from(system.brew.unwrap()).unwrap();
+        assert_eq!(toml::Value::from(vec!["git"]), brew["formulae"]);
+        assert_eq!(Some(true), system.macos.unwrap().dock.unwrap().autohide);
+        assert!(system.mas.is_none());
+    }
+
+    #[test]
+    fn load_upgrades_older_configs() {
+        let dir = config_tree(&[(
+            "system.toml",
+            "[macos.system]\nshow-file-extensions = true\n",
+        )]);
+
+        let system = System::load(dir.path()).unwrap();
+
+        let finder = system.macos.unwrap().finder.unwrap();
+        assert_eq!(Some(true), finder.show_file_extensions);
+    }
+
+    #[rstest]
+    #[case::missing_file(&[], "system.toml")]
+    #[case::malformed_toml(&[("system.toml", "[brew\nformulae = [\"git\"]")], "system.toml")]
+    #[case::wrong_type(&[("system.toml", "[brew]\nformulae = \"git\"")], "formulae")]
+    #[case::unsupported_version(&[("system.toml", "version = 99")], "version 99")]
+    fn load_reports_bad_configs(#[case] files: &[(&str, &str)], #[case] mentions: &str) {
+        let dir = config_tree(files);
+
+        let err = System::load(dir.path()).unwrap_err();
+
+        assert!(
+            err.to_string().contains(mentions),
+            "unexpected error: {err}"
+        );
+    }
+
+    #[rstest]
+    #[case::formula_in_dotfiles("dotfiles/Formula/tool.rb", None, true)]
+    #[case::formula_elsewhere("Formula/tool.rb", None, false)]
+    #[case::dotfiles_first(
+        "dotfiles/Formula/tool.rb",
+        Some(r#"order = ["dotfiles", "brew"]"#),
+        false
+    )]
+    fn dotfiles_are_synced_before_brew_when_brew_needs_files_from_them(
+        #[case] formula: &str,
+        #[case] order: Option<&str>,
+        #[case] expected: bool,
+    ) {
+        let config = format!(
+            r#"
+            {}
+
+            [brew]
+            formulae = ["git", "{formula}"]
+
+            [dotfiles]
+            files = [".zshrc"]
+
+            [dotfiles.git]
+            repo = "https://github.com/me/dotfiles.git"
+            "#,
+            order.unwrap_or_default()
+        );
+        let mut system: System = toml::from_str(&config).unwrap();
+        system
+            .brew
+            .as_mut()
+            .unwrap()
+            .resolve_formula_paths(Path::new("/config"))
+            .unwrap();
+
+        assert_eq!(
+            expected,
+            system.sync_dotfiles_before_brew(Path::new("/config/dotfiles"))
+        );
+    }
+
+    #[test]
+    fn system_serializes_back_to_equivalent_toml() {
+        let config = r#"
+            order = ["brew", "dotfiles"]
+
+            [brew]
+            formulae = ["git"]
+            install-args = ["--quiet"]
+
+            [dotfiles]
+            files = [".zshrc", { original = ".ssh/config", link = "~/.ssh/config", method = "copy" }]
+
+            [vscode]
+            extensions = ["vscodevim.vim", { path = "~/acme.vsix", id = "acme.internal" }]
+
+            [shell-installers]
+            install = ["rustup"]
+
+            [macos.dock]
+            orientation = "left"
+        "#;
+
+        let system: System = toml::from_str(config).unwrap();
+        let printed = toml::to_string_pretty(&system).unwrap();
+        let reparsed: System = toml::from_str(&printed).unwrap();
+
+        assert_eq!(printed, toml::to_string_pretty(&reparsed).unwrap());
+        assert!(printed.contains("install-args = [\"--quiet\"]"));
+    }
 }
@@ -1,13 +1,19 @@
-use std::{path::PathBuf, process::Command, str::FromStr};
+use std::{collections::HashSet, io, path::PathBuf, str::FromStr, thread, time::Duration};
 
-use crate::errors::SetupError;
+use log::warn;
+
+use crate::{
+    errors::SetupError,
+    output::unchanged,
+    runner::{CommandOutput, CommandRunner},
+};
 
 /// Checks if a program is installed and in the PATH.
-pub(crate) fn command(program: &str) -> Result<PathBuf, SetupError> {
-    let output = Command::new("command").args(["-v", program]).output()?;
+pub(crate) fn command(runner: &dyn CommandRunner, program: &str) -> Result<PathBuf, SetupError> {
+    let output = runner.output("command", &["-v", program])?;
 
-    if output.status.success() {
-        println!("✅ {program} found");
+    if output.success() {
+        unchanged!("✅ {program} found");
         let path = String::from_utf8(output.stdout)?;
 
         Ok(PathBuf::from_str(path.trim())?)
@@ -15,3 +21,189 @@ pub(crate) fn command(program: &str) -> Result<PathBuf, SetupError> {
         Err(SetupError::ProgramFileNotFound(program.to_string()))
     }
 }
+
+/// The machine's short hostname, as printed by `hostname -s`, or `None` if it
+/// couldn't be determined.
+pub(crate) fn hostname(runner: &dyn CommandRunner) -> Result<Option<String>, SetupError> {
+    let output = runner.output("hostname", &["-s"])?;
+    if !output.success() {
+        return Ok(None);
+    }
+
+    Ok(Some(String::from_utf8(output.stdout)?.trim().to_string()))
+}
+
+/// The declared packages seen so far, so that one listed more than once is
+/// only installed once, with a warning rather than silently.
+pub(crate) struct Seen<'a> {
+    what: &'a str,
+    seen: HashSet<String>,
+}
+
+impl<'a> Seen<'a> {
+    /// `what` is the kind of package, like `brew formula`, for the warning.
+    pub(crate) fn new(what: &'a str) -> Self {
+        Seen {
+            what,
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Returns true the first time `name` is seen, and warns every time after.
+    pub(crate) fn first(&mut self, name: &str) -> bool {
+        if self.seen.insert(name.to_string()) {
+            return true;
+        }
+        warn!(
+            "⚠️  {} `{name}` is listed more than once, only installing it once",
+            self.what
+        );
+        false
+    }
+}
+
+/// How many times to retry a command that fails, like an install that hit a
+/// network blip, waiting twice as long before each retry as the one before.
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    retries: u32,
+    backoff: Duration,
+    sleep: fn(Duration),
+}
+
+impl Retry {
+    /// The wait before the first retry.
+    const BACKOFF: Duration = Duration::from_secs(1);
+
+    /// Retries a failing command up to `retries` times.
+    pub fn new(retries: u32) -> Self {
+        Retry {
+            retries,
+            backoff: Self::BACKOFF,
+            sleep: thread::sleep,
+        }
+    }
+
+    /// A `Retry` that doesn't wait between attempts.
+    #[cfg(test)]
+    pub(crate) fn without_sleeping(retries: u32) -> Self {
+        Retry {
+            sleep: |_| {},
+            ..Retry::new(retries)
+        }
+    }
+
+    /// Runs `attempt` until it succeeds, or has been retried as many times as
+    /// allowed, and returns its last output. Only a non-zero exit is retried,
+    /// not failing to run the command at all.
+    pub(crate) fn run(
+        self,
+        what: &str,
+        attempt: impl FnMut() -> io::Result<CommandOutput>,
+    ) -> io::Result<CommandOutput> {
+        self.until(
+            what,
+            attempt,
+            |output| !matches!(output, Ok(output) if !output.success()),
+        )
+    }
+
+    /// Runs `attempt` until `done` accepts what it returned, or it has been
+    /// retried as many times as allowed, and returns its last result.
+    pub(crate) fn until<T>(
+        self,
+        what: &str,
+        mut attempt: impl FnMut() -> T,
+        done: impl Fn(&T) -> bool,
+    ) -> T {
+        let mut backoff = self.backoff;
+        let mut result = attempt();
+        for retry in 1..=self.retries {
+            if done(&result) {
+                break;
+            }
+            warn!(
+                "⏳ {what} failed, retrying in {}s ({retry}/{})",
+                backoff.as_secs_f64(),
+                self.retries
+            );
+            (self.sleep)(backoff);
+            backoff *= 2;
+            result = attempt();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{output::capture_warnings, runner::MockRunner};
+
+    #[test]
+    fn retries_until_the_command_succeeds() {
+        let argv = ["brew", "install", "fish"];
+        let runner = MockRunner::new()
+            .stub(&argv, CommandOutput::failed(1, "network down"))
+            .stub(&argv, CommandOutput::failed(1, "network down"))
+            .stub(&argv, CommandOutput::ok(""));
+
+        let output = Retry::without_sleeping(3)
+            .run("Installing fish", || runner.status("brew", &argv[1..]))
+            .unwrap();
+
+        assert!(output.success());
+        assert_eq!(3, runner.calls().len());
+    }
+
+    #[test]
+    fn gives_up_after_the_last_retry() {
+        let argv = ["mas", "install", "1"];
+        let runner = MockRunner::new().stub(&argv, CommandOutput::failed(1, ""));
+
+        let output = Retry::without_sleeping(2)
+            .run("Installing app", || runner.status("mas", &argv[1..]))
+            .unwrap();
+
+        assert!(!output.success());
+        assert_eq!(3, runner.calls().len());
+    }
+
+    #[test]
+    fn seen_warns_about_every_repeat() {
+        let mut seen = Seen::new("npm package");
+        let mut first = Vec::new();
+
+        let warnings = capture_warnings(|| {
+            first = ["prettier", "prettier", "eslint", "prettier"]
+                .into_iter()
+                .map(|name| seen.first(name))
+                .collect();
+        });
+
+        assert_eq!(vec![true, false, true, false], first);
+        assert_eq!(
+            vec![
+                "⚠️  npm package `prettier` is listed more than once, only installing it once";
+                2
+            ],
+            warnings
+        );
+    }
+
+    #[test]
+    fn retries_until_done_accepts_the_result() {
+        let mut attempts = 0;
+
+        let result = Retry::without_sleeping(5).until(
+            "Linking .zshrc",
+            || {
+                attempts += 1;
+                attempts
+            },
+            |attempts| *attempts == 2,
+        );
+
+        assert_eq!(2, result);
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use pest::Parser;
+use pest_derive::Parser;
+
+use crate::errors::SetupError;
+
+#[derive(Parser)]
+#[grammar = "grammars/template.pest"]
+struct TemplateParser;
+
+/// Renders `source`, replacing each `{{ name }}` with its value from `vars`,
+/// and each `{{ op://vault/item/field }}` with what `secret` reads for it.
+/// `\{{` renders as a literal `{{`. Unknown variables and unclosed `{{` are
+/// errors, rather than being left in the output for a tool to choke on.
+pub(crate) fn render(
+    source: &str,
+    vars: &HashMap<String, String>,
+    secret: &mut dyn FnMut(&str) -> Result<String, SetupError>,
+) -> Result<String, SetupError> {
+    let template = TemplateParser::parse(Rule::template, source)
+        .map_err(|e| SetupError::DotfileError(format!("invalid template: {e}")))?
+        .next()
+        .unwrap();
+
+    let mut rendered = String::with_capacity(source.len());
+    for part in template.into_inner() {
+        match part.as_rule() {
+            Rule::text => rendered.push_str(part.as_str()),
+            Rule::escaped_braces => rendered.push_str("{{"),
+            Rule::variable => {
+                let name = part.into_inner().next().unwrap().as_str();
+                let value = vars.get(name).ok_or_else(|| {
+                    SetupError::DotfileError(format!("unknown template variable `{name}`"))
+                })?;
+                rendered.push_str(value);
+            }
+            Rule::secret => {
+                let reference = part.into_inner().next().unwrap().as_str();
+                rendered.push_str(&secret(reference)?);
+            }
+            Rule::EOI => (),
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_substitutes_variables_and_unescapes_braces() {
+        let vars = HashMap::from([
+            ("hostname".to_string(), "work-mbp".to_string()),
+            ("user".to_string(), "me".to_string()),
+        ]);
+
+        let mut no_secrets = |_: &str| -> Result<String, SetupError> { unreachable!() };
+
+        assert_eq!(
+            "Host work-mbp\n  User me\n  Format {{.Name}}\n",
+            render(
+                "Host {{ hostname }}\n  User {{user}}\n  Format \\{{.Name}}\n",
+                &vars,
+                &mut no_secrets
+            )
+            .unwrap()
+        );
+        assert!(render("{{ missing }}", &vars, &mut no_secrets).is_err());
+        assert!(render("{{ user", &vars, &mut no_secrets).is_err());
+    }
+
+    #[test]
+    fn render_reads_secret_references() {
+        let mut references = Vec::new();
+        let mut secret = |reference: &str| {
+            references.push(reference.to_string());
+            Ok("hunter2".to_string())
+        };
+
+        assert_eq!(
+            "token = hunter2",
+            render(
+                "token = {{ op://Personal/GitHub/token }}",
+                &HashMap::new(),
+                &mut secret
+            )
+            .unwrap()
+        );
+        assert_eq!(["op://Personal/GitHub/token"], references.as_slice());
+    }
+}
@@ -0,0 +1,370 @@
+//! Semantic checks of a parsed configuration, run before anything touches the
+//! system.
+
+use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    fmt,
+    path::Path,
+};
+
+use log::{error, warn};
+
+use crate::{
+    defaults::DefaultsWrite,
+    system::{Module, System},
+};
+
+/// How serious a configuration problem is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Worth pointing out, but the run can go ahead.
+    Warning,
+    /// The run would fail, or do something the user almost certainly didn't
+    /// intend.
+    Error,
+}
+
+/// A single problem found in the configuration.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConfigProblem {
+    pub severity: Severity,
+    /// Dotted path to the offending field, for example `brew.formulae`.
+    pub field: String,
+    pub message: String,
+}
+
+impl ConfigProblem {
+    pub(crate) fn warning(field: &str, message: impl Into<String>) -> Self {
+        ConfigProblem {
+            severity: Severity::Warning,
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn error(field: &str, message: impl Into<String>) -> Self {
+        ConfigProblem {
+            severity: Severity::Error,
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// The module whose section the problem is in, or `None` for problems with
+    /// the configuration as a whole, like `order`.
+    pub fn module(&self) -> Option<Module> {
+        let section = self.field.split('.').next()?;
+        Module::DEFAULT_ORDER
+            .into_iter()
+            .find(|module| module.name() == section)
+    }
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.field, self.message)
+    }
+}
+
+/// Runs every check against the configuration. The dotfiles directory is
+/// needed to check that the original side of each dotfile exists, and without
+/// it the dotfiles aren't checked.
+pub fn validate(system: &System, dotfiles_dir: Option<&Path>) -> Vec<ConfigProblem> {
+    let mut problems = Vec::new();
+
+    system.validate_order(&mut problems);
+    if let Some(brew) = &system.brew {
+        brew.validate(&mut problems);
+    }
+    if let Some(mas) = &system.mas {
+        mas.validate(&mut problems);
+    }
+    if let Some(vscode) = &system.vscode {
+        vscode.validate(&mut problems);
+    }
+    if let Some(npm) = &system.npm {
+        npm.validate(&mut problems);
+    }
+    if let Some(cargo) = &system.cargo {
+        cargo.validate(&mut problems);
+    }
+    if let Some((dotfiles, dotfiles_dir)) = system.dotfiles.as_ref().zip(dotfiles_dir) {
+        dotfiles.validate(dotfiles_dir, &mut problems);
+    }
+    if let Some(macos) = &system.macos {
+        macos.validate(&mut problems);
+    }
+
+    problems
+}
+
+/// Keeps the problems in the sections of the modules passed to `--only` and
+/// `--skip`, so a mistake in a section that isn't run doesn't stop the run.
+/// Problems with the configuration as a whole are always kept.
+pub fn for_modules(
+    problems: Vec<ConfigProblem>,
+    only: &[Module],
+    skip: &[Module],
+) -> Vec<ConfigProblem> {
+    problems
+        .into_iter()
+        .filter(|problem| {
+            problem
+                .module()
+                .is_none_or(|module| module.selected(only, skip))
+        })
+        .collect()
+}
+
+/// Prints every problem, and returns true if any of them are errors.
+pub fn report(problems: &[ConfigProblem]) -> bool {
+    for problem in problems {
+        match problem.severity {
+            Severity::Warning => warn!("⚠️  {problem}"),
+            Severity::Error => error!("❌ {problem}"),
+        }
+    }
+
+    problems
+        .iter()
+        .any(|problem| problem.severity == Severity::Error)
+}
+
+/// Adds an error to `problems` for every `defaults` key that's written more
+/// than once with different values, since whichever is written last would
+/// silently win.
+pub(crate) fn report_conflicting_writes(
+    writes: &[DefaultsWrite],
+    problems: &mut Vec<ConfigProblem>,
+) {
+    let mut first_writes: HashMap<(&str, &str), &DefaultsWrite> = HashMap::new();
+
+    for write in writes {
+        match first_writes.entry((&write.domain, &write.key)) {
+            Entry::Vacant(entry) => {
+                entry.insert(write);
+            }
+            Entry::Occupied(entry) if entry.get().value != write.value => {
+                let first = entry.get();
+                problems.push(ConfigProblem::error(
+                    &write.field,
+                    format!(
+                        "sets {}.{} to {}, but {} sets it to {}",
+                        write.domain, write.key, write.value, first.field, first.value
+                    ),
+                ));
+            }
+            Entry::Occupied(_) => {}
+        }
+    }
+}
+
+/// Adds a warning to `problems` for every entry of `items` that appears more
+/// than once.
+pub(crate) fn warn_duplicates<'a>(
+    field: &str,
+    items: impl IntoIterator<Item = &'a str>,
+    problems: &mut Vec<ConfigProblem>,
+) {
+    let mut seen = HashSet::new();
+    let mut reported = HashSet::new();
+
+    for item in items {
+        if !seen.insert(item) && reported.insert(item) {
+            problems.push(ConfigProblem::warning(
+                field,
+                format!("`{item}` is listed more than once"),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(config: &str) -> System {
+        toml::from_str(config).unwrap()
+    }
+
+    #[test]
+    fn valid_config_has_no_problems() {
+        let system = parse(
+            r#"
+            [brew]
+            formulae = ["git", "fish"]
+
+            [[mas.apps]]
+            name = "Amphetamine"
+            id = "937984704"
+            "#,
+        );
+
+        assert!(validate(&system, Some(Path::new("."))).is_empty());
+    }
+
+    #[test]
+    fn duplicate_formulae_are_reported_once_as_warnings() {
+        let system = parse(
+            r#"
+            [brew]
+            formulae = ["git", "fish", "git", "git"]
+            "#,
+        );
+
+        assert_eq!(
+            vec![ConfigProblem::warning(
+                "brew.formulae",
+                "`git` is listed more than once"
+            )],
+            validate(&system, Some(Path::new(".")))
+        );
+    }
+
+    #[test]
+    fn non_numeric_mas_id_is_an_error() {
+        let system = parse(
+            r#"
+            [[mas.apps]]
+            name = "Amphetamine"
+            id = "amphetamine"
+            "#,
+        );
+
+        let problems = validate(&system, Some(Path::new(".")));
+
+        assert_eq!(1, problems.len());
+        assert_eq!(Severity::Error, problems[0].severity);
+        assert_eq!("mas.apps", problems[0].field);
+    }
+
+    #[test]
+    fn conflicting_defaults_writes_are_errors() {
+        let write = |field: &str, domain: &str, value: &str| DefaultsWrite {
+            field: field.to_string(),
+            domain: domain.to_string(),
+            key: "ApplePressAndHoldEnabled".to_string(),
+            value: value.to_string(),
+            type_flag: "-bool",
+        };
+        let writes = [
+            write("macos.system.key-press-and-hold", "NSGlobalDomain", "false"),
+            write("macos.custom", "NSGlobalDomain", "false"),
+            write("macos.custom", "NSGlobalDomain", "true"),
+            write("macos.custom", "com.microsoft.VSCode", "true"),
+        ];
+        let mut problems = Vec::new();
+
+        report_conflicting_writes(&writes, &mut problems);
+
+        assert_eq!(
+            vec![ConfigProblem::error(
+                "macos.custom",
+                "sets NSGlobalDomain.ApplePressAndHoldEnabled to true, but macos.system.key-press-and-hold sets it to false"
+            )],
+            problems
+        );
+    }
+
+    #[test]
+    fn order_checks_omitted_and_brew_dependent_modules() {
+        let system = parse(
+            r#"
+            order = ["mas", "brew"]
+
+            [brew]
+            formulae = ["mas"]
+
+            [vscode]
+            extensions = ["vscodevim.vim"]
+            "#,
+        );
+
+        assert_eq!(
+            vec![
+                ConfigProblem::warning(
+                    "order",
+                    "`[vscode]` is configured but not in the order, so it won't run"
+                ),
+                ConfigProblem::error("order", "`mas` must come after `brew`, which installs it"),
+            ],
+            validate(&system, Some(Path::new(".")))
+        );
+    }
+
+    #[test]
+    fn broken_config_reports_every_problem() {
+        let dotfiles_dir = tempfile::tempdir().unwrap();
+        std::fs::write(dotfiles_dir.path().join(".zshrc"), "").unwrap();
+        let system = parse(
+            r#"
+            [brew]
+            formulae = ["git", "git"]
+
+            [[mas.apps]]
+            name = "Amphetamine"
+            id = "937984704"
+
+            [[mas.apps]]
+            name = "Amphetamine again"
+            id = "937984704"
+
+            [dotfiles]
+            files = [".zshrc", ".config/missing.toml"]
+            "#,
+        );
+
+        let problems = validate(&system, Some(dotfiles_dir.path()));
+
+        let fields: Vec<_> = problems
+            .iter()
+            .map(|problem| (problem.severity, problem.field.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                (Severity::Warning, "brew.formulae"),
+                (Severity::Warning, "mas.apps"),
+                (Severity::Error, "dotfiles.files"),
+            ],
+            fields
+        );
+        assert!(problems[2].message.contains("missing.toml"));
+    }
+
+    #[test]
+    fn dotfiles_are_not_checked_without_a_dotfiles_dir() {
+        let system = parse(
+            r#"
+            [dotfiles]
+            files = [".config/missing.toml"]
+            "#,
+        );
+
+        assert!(validate(&system, None).is_empty());
+    }
+
+    #[test]
+    fn problems_outside_the_selected_modules_are_dropped() {
+        let problems = vec![
+            ConfigProblem::error("brew.formulae", "broken"),
+            ConfigProblem::error("dotfiles.files", "broken"),
+            ConfigProblem::error("order", "broken"),
+        ];
+
+        let fields: Vec<_> = for_modules(problems, &[Module::Dotfiles], &[Module::Brew])
+            .into_iter()
+            .map(|problem| problem.field)
+            .collect();
+
+        assert_eq!(vec!["dotfiles.files", "order"], fields);
+    }
+
+    #[test]
+    fn problems_with_the_whole_config_have_no_module() {
+        assert_eq!(None, ConfigProblem::warning("order", "").module());
+        assert_eq!(
+            Some(Module::Macos),
+            ConfigProblem::warning("macos.custom", "").module()
+        );
+    }
+}
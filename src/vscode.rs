@@ -1,28 +1,222 @@
-use serde::Deserialize;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
 
-use std::{collections::HashSet, ops::Deref, process::Command};
+use std::{
+    collections::HashMap,
+    env::home_dir,
+    fmt, fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
 
-use crate::{errors::SetupError, system_utils::command};
+use crate::{
+    dotfiles::{backup_path, tilde_expand_path},
+    errors::{OnFailure, SetupError, failures},
+    output::{event, print_compared_sets, unchanged},
+    report::Changes,
+    runner::CommandRunner,
+    system::Section,
+    system_utils::{Seen, command},
+    validate::{ConfigProblem, warn_duplicates},
+};
+
+const CODE_PROGRAM_NAME: &str = "code";
 
 /// Represents the VS Code configuration, specifying which extensions to
-/// install.
-#[derive(Deserialize, Debug)]
+/// install, and which settings to keep in its `settings.json`.
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Vscode {
-    pub extensions: Vec<ExtensionIdentifier>,
+    pub extensions: Vec<Extension>,
+    /// Settings to keep in the user `settings.json`, like `"editor.fontSize" =
+    /// 14`. Settings that aren't listed are left as they are.
+    pub settings: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Shell command that must exit zero for this section to be applied.
+    pub when: Option<String>,
+}
+
+impl Vscode {
+    /// Checks for duplicate extensions. Extension identifiers are case
+    /// insensitive, so `Foo.Bar` and `foo.bar` are duplicates. Also checks that
+    /// `.vsix` files exist.
+    pub(crate) fn validate(&self, problems: &mut Vec<ConfigProblem>) {
+        let extensions: Vec<_> = self
+            .extensions
+            .iter()
+            .map(|e| e.id().to_lowercase())
+            .collect();
+        warn_duplicates(
+            "vscode.extensions",
+            extensions.iter().map(|e| e.as_str()),
+            problems,
+        );
+
+        for extension in &self.extensions {
+            if let Extension::Vsix { path, .. } = extension {
+                match extension.vsix_path() {
+                    Ok(Some(expanded)) if !expanded.is_file() => {
+                        problems.push(ConfigProblem::error(
+                            "vscode.extensions",
+                            format!("`{}` does not exist", path.display()),
+                        ));
+                    }
+                    Err(e) => {
+                        problems.push(ConfigProblem::error("vscode.extensions", e.to_string()))
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+impl Vscode {
+    /// A configuration declaring exactly the installed extensions.
+    pub fn from_installed(runner: &dyn CommandRunner) -> Result<Self, SetupError> {
+        let mut extensions: Vec<_> = get_installed_extensions(runner)?
+            .into_values()
+            .map(Extension::Marketplace)
+            .collect();
+        extensions.sort_unstable_by(|a, b| a.id().as_str().cmp(b.id().as_str()));
+
+        Ok(Vscode {
+            extensions,
+            settings: None,
+            when: None,
+        })
+    }
 }
 
-/// A VSCode extension unique identifier. Has the form `{publisher}.{name}``,
-/// but we don't bother parsing it, just passing it directly to the `code`
-/// commandline for installation.
-#[derive(Deserialize, Debug, PartialEq, Eq, Hash)]
+impl Section for Vscode {
+    fn when(&self) -> Option<&str> {
+        self.when.as_deref()
+    }
+}
+
+/// A VSCode extension unique identifier, of the form `{publisher}.{name}`.
+/// It's checked when the configuration is parsed, so a typo like a missing
+/// publisher is reported up front, rather than by `code` at install time.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Hash)]
+#[serde(try_from = "String")]
 pub struct ExtensionIdentifier(String);
 
+/// An extension identifier that isn't of the form `{publisher}.{name}`.
+#[derive(Debug, thiserror::Error)]
+#[error("`{0}` is not a VS Code extension identifier, which looks like `publisher.name`")]
+pub struct InvalidExtensionIdentifier(String);
+
+impl TryFrom<String> for ExtensionIdentifier {
+    type Error = InvalidExtensionIdentifier;
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        match id.split_once('.') {
+            Some((publisher, name))
+                if !publisher.is_empty() && !name.is_empty() && !name.contains('.') =>
+            {
+                Ok(ExtensionIdentifier(id))
+            }
+            _ => Err(InvalidExtensionIdentifier(id)),
+        }
+    }
+}
+
 impl ExtensionIdentifier {
+    /// The publisher, like `vscodevim` in `vscodevim.vim`.
+    pub fn publisher(&self) -> &str {
+        self.0
+            .split_once('.')
+            .map_or(&self.0, |(publisher, _)| publisher)
+    }
+
+    /// The extension's name, like `vim` in `vscodevim.vim`.
+    pub fn name(&self) -> &str {
+        self.0.split_once('.').map_or("", |(_, name)| name)
+    }
+
+    /// The identifier extensions are matched by, since identifiers are case
+    /// insensitive.
     fn to_lowercase(&self) -> Self {
         ExtensionIdentifier(self.as_str().to_lowercase())
     }
 }
 
+/// An extension to install, either from the Marketplace by its identifier, or
+/// from a local `.vsix` file. A `.vsix` file has to be declared along with the
+/// identifier of the extension inside it, so it can be skipped once installed.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+#[serde(untagged, try_from = "ExtensionEntry")]
+pub enum Extension {
+    Marketplace(ExtensionIdentifier),
+    Vsix {
+        /// Path to the `.vsix` file, which may start with `~/`.
+        path: PathBuf,
+        id: ExtensionIdentifier,
+    },
+}
+
+/// An [`Extension`] as it's written in the configuration, before its
+/// identifier is checked. Parsing the identifier separately keeps the error
+/// for a bad one from being swallowed by the untagged enum, which would only
+/// say that the entry didn't match any form.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ExtensionEntry {
+    Marketplace(String),
+    Vsix { path: PathBuf, id: String },
+}
+
+impl TryFrom<ExtensionEntry> for Extension {
+    type Error = String;
+
+    fn try_from(entry: ExtensionEntry) -> Result<Self, Self::Error> {
+        match entry {
+            ExtensionEntry::Marketplace(id) if id.ends_with(".vsix") => Err(format!(
+                "`{id}` looks like a .vsix file, declare it as {{ path = \"{id}\", id = \"publisher.name\" }}"
+            )),
+            ExtensionEntry::Marketplace(id) => Ok(Extension::Marketplace(
+                id.try_into()
+                    .map_err(|e: InvalidExtensionIdentifier| e.to_string())?,
+            )),
+            ExtensionEntry::Vsix { path, id } => Ok(Extension::Vsix {
+                path,
+                id: id
+                    .try_into()
+                    .map_err(|e: InvalidExtensionIdentifier| e.to_string())?,
+            }),
+        }
+    }
+}
+
+impl Extension {
+    /// The identifier the extension is listed under once installed.
+    pub fn id(&self) -> &ExtensionIdentifier {
+        match self {
+            Extension::Marketplace(id) | Extension::Vsix { id, .. } => id,
+        }
+    }
+
+    /// The `.vsix` file to install from, with any `~/` expanded, or `None` for
+    /// Marketplace extensions.
+    fn vsix_path(&self) -> Result<Option<PathBuf>, SetupError> {
+        let Extension::Vsix { path, .. } = self else {
+            return Ok(None);
+        };
+        let home = home_dir().ok_or_else(|| {
+            SetupError::InstallFailed("Could not determine home directory.".to_string())
+        })?;
+
+        tilde_expand_path(path, &home).map(Some)
+    }
+}
+
+impl fmt::Display for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Extension::Marketplace(id) => write!(f, "{}", id.as_str()),
+            Extension::Vsix { path, id } => write!(f, "{} ({})", id.as_str(), path.display()),
+        }
+    }
+}
+
 impl Deref for ExtensionIdentifier {
     type Target = String;
 
@@ -32,48 +226,259 @@ impl Deref for ExtensionIdentifier {
 }
 
 impl Vscode {
-    pub fn install_missing_extensions(&self) -> Result<(), SetupError> {
-        command("code")?;
+    /// Returns the extensions that aren't installed yet.
+    pub fn find_missing_extensions(
+        &self,
+        runner: &dyn CommandRunner,
+    ) -> Result<Vec<&Extension>, SetupError> {
+        command(runner, CODE_PROGRAM_NAME)?;
 
-        println!("Checking VS Code extensions...");
-        let installed_extensions = get_installed_extensions()?;
-        let missing_extensions = self
-            .extensions
-            .iter()
-            .filter(|&e| !installed_extensions.contains(&e.to_lowercase()))
-            .collect::<Vec<_>>();
+        info!("Checking VS Code extensions...");
+        let installed_extensions = get_installed_extensions(runner)?;
+        print_compared_sets(
+            "vscode extensions",
+            self.extensions.iter().map(|e| e.id().as_str()),
+            installed_extensions.values().map(|e| e.as_str()),
+        );
 
+        let mut seen = Seen::new("VS Code extension");
+        let mut missing = Vec::new();
+        for extension in &self.extensions {
+            let normalized = extension.id().to_lowercase();
+            match installed_extensions.get(&normalized) {
+                Some(installed) if installed_under_different_case(extension.id(), installed) => {
+                    warn!(
+                        "⚠️  VS Code extension `{}` is installed as `{}`",
+                        extension.id().as_str(),
+                        installed.as_str()
+                    );
+                }
+                Some(_) => {}
+                None if seen.first(&normalized) => missing.push(extension),
+                None => {}
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Installs the extensions that aren't installed yet, stopping at the
+    /// first failure or attempting every extension depending on `on_failure`.
+    pub fn install_missing_extensions(
+        &self,
+        runner: &dyn CommandRunner,
+        on_failure: OnFailure,
+    ) -> Result<Changes, SetupError> {
+        let missing_extensions = self.find_missing_extensions(runner)?;
+
+        let mut changes = Changes::default();
+        let mut failed = Vec::new();
         if missing_extensions.is_empty() {
-            println!("All VS Code extensions are installed.");
+            unchanged!("All VS Code extensions are installed.");
         } else {
-            println!("Installing missing VS Code extensions...");
+            info!("Installing missing VS Code extensions...");
             for extension in missing_extensions {
-                println!("Installing vscode extension: {extension:?}");
-                let status = Command::new("code")
-                    .args(["--install-extension", extension])
-                    .status()?;
+                let id = extension.id().as_str();
+                event!(
+                    "install",
+                    id,
+                    started,
+                    "Installing vscode extension: {extension}"
+                );
+                let vsix_path = extension.vsix_path()?;
+                let source = match &vsix_path {
+                    Some(path) => path_str(path)?,
+                    None => id,
+                };
+                let status = runner.status(CODE_PROGRAM_NAME, &["--install-extension", source])?;
                 if !status.success() {
-                    return Err(SetupError::InstallFailed(format!(
-                        "vscode extension install failed: {extension:?}"
-                    )));
+                    event!(
+                        "install",
+                        id,
+                        failed,
+                        "❌ Failed to install vscode extension: {extension}"
+                    );
+                    on_failure.record(format!("vscode extension {extension}"), &mut failed)?;
+                } else {
+                    event!(
+                        "install",
+                        id,
+                        ok,
+                        "✅ Installed vscode extension: {extension}"
+                    );
+                    changes.package_installed("code", id);
+                }
+            }
+        }
+
+        failures(&failed)?;
+
+        Ok(changes)
+    }
+}
+
+/// Where VS Code keeps the user `settings.json`.
+pub fn settings_path() -> Result<PathBuf, SetupError> {
+    let home = home_dir().ok_or_else(|| {
+        SetupError::VscodeSettingsError("Could not determine home directory.".to_string())
+    })?;
+
+    Ok(home.join("Library/Application Support/Code/User/settings.json"))
+}
+
+impl Vscode {
+    /// Merges the configured settings into the `settings.json` at `path`,
+    /// creating it if it doesn't exist. Configured settings replace what's
+    /// there, and the rest are kept in place. The file is only written when
+    /// that changes it, after backing it up to `settings.json.omiros.bak`.
+    /// Like VS Code, comments and trailing commas are accepted, but they're
+    /// dropped when the file is written.
+    pub fn sync_settings(&self, path: &Path) -> Result<Changes, SetupError> {
+        let mut changes = Changes::default();
+        let Some(settings) = &self.settings else {
+            return Ok(changes);
+        };
+
+        let current = match fs::read_to_string(path) {
+            Ok(json) => Some(
+                serde_json::from_str::<serde_json::Map<_, _>>(&strip_jsonc(&json)).map_err(
+                    |e| {
+                        SetupError::VscodeSettingsError(format!(
+                            "couldn't parse {}: {e}",
+                            path.display()
+                        ))
+                    },
+                )?,
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut merged = current.clone().unwrap_or_default();
+        merged.extend(
+            settings
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone())),
+        );
+        if current.as_ref() == Some(&merged) {
+            unchanged!("ℹ️  VS Code settings are up to date");
+            return Ok(changes);
+        }
+
+        if current.is_some() {
+            let backup = backup_path(path);
+            fs::copy(path, &backup)?;
+            info!("📦 Backed up {} to {}", path.display(), backup.display());
+        } else if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut json = serde_json::to_string_pretty(&merged)
+            .map_err(|e| SetupError::VscodeSettingsError(e.to_string()))?;
+        json.push('\n');
+        fs::write(path, json)?;
+        event!(
+            "write",
+            path.to_string_lossy().as_ref(),
+            ok,
+            "🔧 Updated {}",
+            path.display()
+        );
+        changes.other_change(format!("Updated {}", path.display()));
+
+        Ok(changes)
+    }
+}
+
+/// Turns VS Code's JSON with comments into plain JSON, by removing `//` and
+/// `/* */` comments, and commas before a closing `}` or `]`.
+fn strip_jsonc(jsonc: &str) -> String {
+    let mut json = String::with_capacity(jsonc.len());
+    let mut chars = jsonc.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            json.push(c);
+            match c {
+                '\\' => json.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                json.push(c);
+            }
+            ('/', Some('/')) => while chars.next_if(|&c| c != '\n').is_some() {},
+            ('/', Some('*')) => {
+                chars.next();
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
                 }
             }
+            _ => json.push(c),
         }
+    }
 
-        Ok(())
+    let mut without_trailing_commas = String::with_capacity(json.len());
+    let mut in_string = false;
+    let mut chars = json.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if in_string {
+            without_trailing_commas.push(c);
+            match c {
+                '\\' => without_trailing_commas.extend(chars.next().map(|(_, c)| c)),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        let trailing =
+            c == ',' && matches!(json[i + 1..].trim_start().chars().next(), Some('}' | ']'));
+        if !trailing {
+            without_trailing_commas.push(c);
+        }
+        in_string = c == '"';
     }
+
+    without_trailing_commas
 }
 
-/// Gets all installed VSCode extensions. Note VSCode extension identifiers are
-/// case sensitive IDs. However, using the command line to get a list of these
-/// identifiers returns all lower-case list of extension identifiers.
-fn get_installed_extensions() -> Result<HashSet<ExtensionIdentifier>, SetupError> {
-    let output = Command::new("code").arg("--list-extensions").output()?;
-    if output.status.success() {
+/// Whether an extension is installed under a different spelling than it's
+/// declared with. Older versions of `code` list every extension in lower case,
+/// so that alone doesn't count.
+fn installed_under_different_case(
+    declared: &ExtensionIdentifier,
+    installed: &ExtensionIdentifier,
+) -> bool {
+    installed != declared && *installed != installed.to_lowercase()
+}
+
+/// Converts a path to a string to pass it as an argument to `code`.
+fn path_str(path: &Path) -> Result<&str, SetupError> {
+    path.to_str().ok_or_else(|| {
+        SetupError::InstallFailed(format!("path is not valid UTF-8: {}", path.display()))
+    })
+}
+
+/// Gets all installed VSCode extensions, keyed by their lower-case identifier
+/// to match them case insensitively, with the identifier as `code` lists it.
+/// Depending on its version, that's either as published, like
+/// `GitHub.copilot`, or all lower case.
+fn get_installed_extensions(
+    runner: &dyn CommandRunner,
+) -> Result<HashMap<ExtensionIdentifier, ExtensionIdentifier>, SetupError> {
+    let output = runner.output(CODE_PROGRAM_NAME, &["--list-extensions"])?;
+    if output.success() {
         let stdout = String::from_utf8(output.stdout)?;
         let extensions = stdout
             .lines()
             .map(|extension| ExtensionIdentifier(extension.trim().to_string()))
+            .map(|extension| (extension.to_lowercase(), extension))
             .collect();
         Ok(extensions)
     } else {
@@ -83,3 +488,222 @@ fn get_installed_extensions() -> Result<HashSet<ExtensionIdentifier>, SetupError
         )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        output::capture_warnings,
+        runner::{CommandOutput, MockRunner},
+    };
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("vscodevim.vim", "vscodevim", "vim")]
+    #[case("rust-lang.rust-analyzer", "rust-lang", "rust-analyzer")]
+    #[case("MS-Python.Python", "MS-Python", "Python")]
+    fn extension_identifier_accepts_publisher_and_name(
+        #[case] id: &str,
+        #[case] publisher: &str,
+        #[case] name: &str,
+    ) {
+        let id = ExtensionIdentifier::try_from(id.to_string()).unwrap();
+
+        assert_eq!(publisher, id.publisher());
+        assert_eq!(name, id.name());
+    }
+
+    #[rstest]
+    #[case::missing_publisher("rust-analyzer")]
+    #[case::empty_publisher(".vim")]
+    #[case::empty_name("vscodevim.")]
+    #[case::too_many_parts("ms-python.python.debug")]
+    #[case::empty("")]
+    fn extension_identifier_rejects_malformed_ids(#[case] id: &str) {
+        assert!(ExtensionIdentifier::try_from(id.to_string()).is_err());
+    }
+
+    #[test]
+    fn duplicate_extensions_are_only_missing_once() {
+        let vscode: Vscode = toml::from_str(
+            r#"extensions = ["vscodevim.vim", "rust-lang.rust-analyzer", "VSCodeVim.Vim"]"#,
+        )
+        .unwrap();
+        let runner = MockRunner::new().stub(
+            &["code", "--list-extensions"],
+            CommandOutput::ok("rust-lang.rust-analyzer\n"),
+        );
+
+        let mut missing = None;
+        let warnings =
+            capture_warnings(|| missing = Some(vscode.find_missing_extensions(&runner).unwrap()));
+
+        let ids: Vec<_> = missing.unwrap().iter().map(|e| e.id().as_str()).collect();
+        assert_eq!(vec!["vscodevim.vim"], ids);
+        assert_eq!(
+            vec![
+                "⚠️  VS Code extension `vscodevim.vim` is listed more than once, only installing \
+                 it once"
+            ],
+            warnings
+        );
+    }
+
+    #[test]
+    fn installed_extensions_match_declared_ones_in_any_case() {
+        let vscode: Vscode = toml::from_str(
+            r#"extensions = ["github.copilot", "MS-Python.Python", "vscodevim.vim"]"#,
+        )
+        .unwrap();
+        let runner = MockRunner::new().stub(
+            &["code", "--list-extensions"],
+            CommandOutput::ok("GitHub.copilot\nms-python.python\n"),
+        );
+
+        let missing = vscode.find_missing_extensions(&runner).unwrap();
+
+        let ids: Vec<_> = missing.iter().map(|e| e.id().as_str()).collect();
+        assert_eq!(vec!["vscodevim.vim"], ids);
+    }
+
+    #[test]
+    fn from_installed_keeps_the_listed_spelling() {
+        let runner = MockRunner::new().stub(
+            &["code", "--list-extensions"],
+            CommandOutput::ok("GitHub.copilot\n"),
+        );
+
+        let vscode = Vscode::from_installed(&runner).unwrap();
+
+        assert_eq!("GitHub.copilot", vscode.extensions[0].id().as_str());
+    }
+
+    #[rstest]
+    #[case::same("GitHub.copilot", "GitHub.copilot", false)]
+    #[case::listed_in_lower_case("GitHub.copilot", "github.copilot", false)]
+    #[case::declared_in_lower_case("github.copilot", "GitHub.copilot", true)]
+    #[case::different_case("Github.Copilot", "GitHub.copilot", true)]
+    fn case_mismatches_only_count_when_code_lists_the_original_case(
+        #[case] declared: &str,
+        #[case] installed: &str,
+        #[case] expected: bool,
+    ) {
+        let declared = ExtensionIdentifier::try_from(declared.to_string()).unwrap();
+        let installed = ExtensionIdentifier::try_from(installed.to_string()).unwrap();
+
+        assert_eq!(
+            expected,
+            installed_under_different_case(&declared, &installed)
+        );
+    }
+
+    const SETTINGS_CONFIG: &str = r#"
+        extensions = []
+
+        [settings]
+        "editor.fontSize" = 14
+        "files.exclude" = { "**/target" = true }
+    "#;
+
+    #[test]
+    fn sync_settings_merges_into_existing_settings() {
+        let vscode: Vscode = toml::from_str(SETTINGS_CONFIG).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings.json");
+        let existing = r#"{"workbench.colorTheme": "Solarized Dark", "editor.fontSize": 12}"#;
+        fs::write(&path, existing).unwrap();
+
+        assert!(vscode.sync_settings(&path).unwrap().has_changes());
+
+        let written: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "workbench.colorTheme": "Solarized Dark",
+                "editor.fontSize": 14,
+                "files.exclude": { "**/target": true },
+            }),
+            written
+        );
+        let backup = dir.path().join("settings.json.omiros.bak");
+        assert_eq!(existing, fs::read_to_string(backup).unwrap());
+    }
+
+    #[test]
+    fn sync_settings_creates_missing_settings() {
+        let vscode: Vscode = toml::from_str(SETTINGS_CONFIG).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Code/User/settings.json");
+
+        assert!(vscode.sync_settings(&path).unwrap().has_changes());
+
+        assert_eq!(
+            "{\n  \"editor.fontSize\": 14,\n  \"files.exclude\": {\n    \"**/target\": true\n  }\n}\n",
+            fs::read_to_string(&path).unwrap()
+        );
+        assert_eq!(1, fs::read_dir(path.parent().unwrap()).unwrap().count());
+    }
+
+    #[test]
+    fn sync_settings_leaves_up_to_date_settings_alone() {
+        let vscode: Vscode = toml::from_str(SETTINGS_CONFIG).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings.json");
+        let existing = r#"{"files.exclude": {"**/target": true}, "editor.fontSize": 14}"#;
+        fs::write(&path, existing).unwrap();
+
+        assert!(!vscode.sync_settings(&path).unwrap().has_changes());
+
+        assert_eq!(existing, fs::read_to_string(&path).unwrap());
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn sync_settings_reads_settings_with_comments_and_trailing_commas() {
+        let vscode: Vscode = toml::from_str(SETTINGS_CONFIG).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings.json");
+        let existing = r#"{
+    // Set by hand, keep it.
+    "workbench.colorTheme": "Solarized Dark", /* for now */
+    "http.proxy": "http://proxy//not-a-comment,}",
+    "editor.fontSize": 12,
+}
+"#;
+        fs::write(&path, existing).unwrap();
+
+        assert!(vscode.sync_settings(&path).unwrap().has_changes());
+
+        let written: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "workbench.colorTheme": "Solarized Dark",
+                "http.proxy": "http://proxy//not-a-comment,}",
+                "editor.fontSize": 14,
+                "files.exclude": { "**/target": true },
+            }),
+            written
+        );
+        let backup = dir.path().join("settings.json.omiros.bak");
+        assert_eq!(existing, fs::read_to_string(backup).unwrap());
+    }
+
+    #[test]
+    fn bad_extension_identifiers_are_reported_when_parsing() {
+        let error = toml::from_str::<Vscode>(r#"extensions = ["vscodevim.vim", "rust-analyzer"]"#)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("`rust-analyzer` is not a VS Code extension identifier"));
+
+        let error = toml::from_str::<Vscode>(r#"extensions = ["~/Downloads/acme-1.2.vsix"]"#)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("looks like a .vsix file"));
+
+        let vscode: Vscode =
+            toml::from_str(r#"extensions = [{ path = "~/acme.vsix", id = "acme.internal" }]"#)
+                .unwrap();
+        assert_eq!("acme", vscode.extensions[0].id().publisher());
+    }
+}
@@ -0,0 +1,245 @@
+//! Exercises the install/diff/apply flows against a [`MockRunner`] so that no
+//! real `brew`, `mas`, or `defaults` commands are run.
+
+use omiros::{
+    brew::{Brew, find_missing_packages, get_installed_brew_packages, install_missing_packages},
+    errors::OnFailure,
+    export::export_system,
+    macos::{Dock, Pointer, apply_dock_settings, apply_pointer_settings},
+    report::ChangedDefault,
+    runner::{CommandOutput, MockRunner},
+    system::System,
+    system_utils::Retry,
+    vscode::Vscode,
+};
+
+fn argv(args: &[&str]) -> Vec<String> {
+    args.iter().map(|s| s.to_string()).collect()
+}
+
+#[test]
+fn brew_installs_only_missing_packages() {
+    let runner = MockRunner::new()
+        .stub(&["brew", "leaves"], CommandOutput::ok("git\nneovim\n"))
+        .stub(
+            &["brew", "list", "--casks"],
+            CommandOutput::ok("alacritty\n"),
+        );
+    let desired: Brew = toml::from_str(
+        r#"
+        formulae = ["git", "fish"]
+        casks = ["alacritty", "slack"]
+        "#,
+    )
+    .unwrap();
+
+    let installed = get_installed_brew_packages(&runner).unwrap();
+    let missing = find_missing_packages(&desired, &installed);
+    let changes =
+        install_missing_packages(&runner, &missing, OnFailure::KeepGoing, 1, Retry::new(0))
+            .unwrap();
+
+    assert_eq!(
+        runner.calls(),
+        vec![
+            argv(&["brew", "leaves"]),
+            argv(&["brew", "list", "--casks"]),
+            argv(&["brew", "install", "fish"]),
+            argv(&["brew", "install", "--cask", "slack"]),
+        ]
+    );
+    let installed: Vec<_> = changes
+        .packages_installed
+        .iter()
+        .map(|package| (package.manager.as_str(), package.name.as_str()))
+        .collect();
+    assert_eq!(vec![("brew", "fish"), ("brew", "slack")], installed);
+}
+
+#[test]
+fn brew_install_failure_is_reported() {
+    let runner = MockRunner::new().stub(
+        &["brew", "install", "fish"],
+        CommandOutput::failed(1, "Error: No available formula with the name \"fish\""),
+    );
+    let desired: Brew = toml::from_str(r#"formulae = ["fish"]"#).unwrap();
+    let installed = get_installed_brew_packages(&runner).unwrap();
+    let missing = find_missing_packages(&desired, &installed);
+
+    assert!(
+        install_missing_packages(&runner, &missing, OnFailure::KeepGoing, 1, Retry::new(0))
+            .is_err()
+    );
+}
+
+#[test]
+fn brew_install_only_continues_past_failures_when_keeping_going() {
+    let desired: Brew = toml::from_str(r#"formulae = ["fish", "git"]"#).unwrap();
+    let runner = || {
+        MockRunner::new().stub(
+            &["brew", "install", "fish"],
+            CommandOutput::failed(1, "Error: No available formula with the name \"fish\""),
+        )
+    };
+    let install_git = argv(&["brew", "install", "git"]);
+
+    let keep_going = runner();
+    let installed = get_installed_brew_packages(&keep_going).unwrap();
+    let missing = find_missing_packages(&desired, &installed);
+    let err = install_missing_packages(
+        &keep_going,
+        &missing,
+        OnFailure::KeepGoing,
+        1,
+        Retry::new(0),
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("1 failure(s), continued"));
+    assert!(keep_going.calls().contains(&install_git));
+
+    let fail_fast = runner();
+    let err = install_missing_packages(&fail_fast, &missing, OnFailure::FailFast, 1, Retry::new(0))
+        .unwrap_err();
+    assert!(err.to_string().contains("stopped early due to --fail-fast"));
+    assert!(!fail_fast.calls().contains(&install_git));
+}
+
+#[test]
+fn vscode_installs_vsix_files_unless_their_id_is_installed() {
+    let runner = MockRunner::new().stub(
+        &["code", "--list-extensions"],
+        CommandOutput::ok("vscodevim.vim\n"),
+    );
+    let vscode: Vscode = toml::from_str(
+        r#"
+        extensions = [
+            "vscodevim.vim",
+            { path = "/tmp/vim.vsix", id = "VSCodeVim.Vim" },
+            { path = "/tmp/internal.vsix", id = "acme.internal" },
+        ]
+        "#,
+    )
+    .unwrap();
+
+    let changes = vscode
+        .install_missing_extensions(&runner, OnFailure::KeepGoing)
+        .unwrap();
+
+    assert_eq!(
+        runner.calls(),
+        vec![
+            argv(&["command", "-v", "code"]),
+            argv(&["code", "--list-extensions"]),
+            argv(&["code", "--install-extension", "/tmp/internal.vsix"]),
+        ]
+    );
+    let installed: Vec<_> = changes
+        .packages_installed
+        .iter()
+        .map(|package| (package.manager.as_str(), package.name.as_str()))
+        .collect();
+    assert_eq!(vec![("code", "acme.internal")], installed);
+}
+
+#[test]
+fn dock_settings_only_write_values_that_differ() {
+    let runner = MockRunner::new()
+        .stub(
+            &["defaults", "read", "com.apple.dock", "orientation"],
+            CommandOutput::ok("bottom\n"),
+        )
+        .stub(
+            &["defaults", "read", "com.apple.dock", "autohide"],
+            CommandOutput::ok("1\n"),
+        );
+    let dock: Dock = toml::from_str(
+        r#"
+        orientation = "left"
+        autohide = true
+        "#,
+    )
+    .unwrap();
+
+    let changes = apply_dock_settings(&runner, &dock).unwrap();
+
+    assert_eq!(
+        changes.defaults_changed,
+        vec![ChangedDefault {
+            domain: "com.apple.dock".to_string(),
+            key: "orientation".to_string(),
+            value: "left".to_string(),
+        }]
+    );
+    assert_eq!(
+        runner.calls(),
+        vec![
+            argv(&["defaults", "read", "com.apple.dock", "orientation"]),
+            argv(&[
+                "defaults",
+                "write",
+                "com.apple.dock",
+                "orientation",
+                "-string",
+                "left"
+            ]),
+            argv(&["defaults", "read", "com.apple.dock", "autohide"]),
+        ]
+    );
+}
+
+#[test]
+fn pointer_cursor_size_out_of_range_is_rejected_before_writing() {
+    let runner = MockRunner::new();
+    let pointer: Pointer = toml::from_str("cursor-size = 5.0").unwrap();
+
+    assert!(apply_pointer_settings(&runner, &pointer).is_err());
+    assert!(runner.calls().is_empty());
+}
+
+#[test]
+fn export_declares_installed_packages_and_skips_missing_tools() {
+    let runner = MockRunner::new()
+        .stub(&["brew", "leaves"], CommandOutput::ok("neovim\ngit\n"))
+        .stub(
+            &["brew", "list", "--casks"],
+            CommandOutput::ok("alacritty\n"),
+        )
+        .stub(&["command", "-v", "mas"], CommandOutput::failed(1, ""))
+        .stub(
+            &["code", "--list-extensions"],
+            CommandOutput::ok("vscodevim.vim\n"),
+        );
+
+    let system = export_system(&runner, false).unwrap();
+
+    assert!(system.mas.is_none());
+    assert!(system.macos.is_none());
+    let exported = toml::to_string_pretty(&system).unwrap();
+    assert!(exported.contains("formulae = [\n    \"git\",\n    \"neovim\",\n]"));
+    assert!(exported.contains("extensions = [\"vscodevim.vim\"]"));
+}
+
+#[test]
+fn exported_config_loads_back_unchanged() {
+    let runner = MockRunner::new()
+        .stub(&["brew", "leaves"], CommandOutput::ok("neovim\ngit\n"))
+        .stub(
+            &["brew", "list", "--casks"],
+            CommandOutput::ok("alacritty\n"),
+        )
+        .stub(
+            &["mas", "list"],
+            CommandOutput::ok("937984704   Amphetamine  (5.3.2)\n"),
+        )
+        .stub(
+            &["code", "--list-extensions"],
+            CommandOutput::ok("vscodevim.vim\n"),
+        );
+    let config_dir = tempfile::tempdir().unwrap();
+
+    let exported = toml::to_string_pretty(&export_system(&runner, false).unwrap()).unwrap();
+    std::fs::write(config_dir.path().join("system.toml"), &exported).unwrap();
+    let loaded = System::load(config_dir.path()).unwrap();
+
+    assert_eq!(exported, toml::to_string_pretty(&loaded).unwrap());
+}
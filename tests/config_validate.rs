@@ -0,0 +1,27 @@
+//! Runs `omiros config validate` against configurations with and without
+//! problems.
+
+use std::{fs, process::Command};
+
+fn validate(system_toml: &str) -> Option<i32> {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("system.toml"), system_toml).unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_omiros"))
+        .args(["config", "validate", "--system-config-dir"])
+        .arg(dir.path())
+        .output()
+        .unwrap()
+        .status
+        .code()
+}
+
+#[test]
+fn valid_config_exits_with_0() {
+    assert_eq!(Some(0), validate("[brew]\nformulae = [\"git\"]\n"));
+}
+
+#[test]
+fn duplicates_exit_with_1_even_though_they_are_only_warnings() {
+    assert_eq!(Some(1), validate("[brew]\nformulae = [\"git\", \"git\"]\n"));
+}
@@ -0,0 +1,68 @@
+//! Runs the `omiros` binary with `--detect-changes` against a configuration
+//! with only dotfiles, in a temporary home directory, so nothing outside of it
+//! is touched.
+
+use std::{fs, path::Path, process::Command};
+
+use tempfile::TempDir;
+
+/// A config directory with a `system.toml` symlinking `.zshrc`, a dotfiles
+/// directory with that `.zshrc`, and an empty home directory.
+fn fixture() -> TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("config")).unwrap();
+    fs::create_dir_all(dir.path().join("dotfiles")).unwrap();
+    fs::create_dir_all(dir.path().join("home")).unwrap();
+    fs::write(
+        dir.path().join("config/system.toml"),
+        "[dotfiles]\nfiles = [\".zshrc\"]\n",
+    )
+    .unwrap();
+    fs::write(dir.path().join("dotfiles/.zshrc"), "export EDITOR=vim\n").unwrap();
+    dir
+}
+
+fn run_detecting_changes(dir: &Path) -> Option<i32> {
+    Command::new(env!("CARGO_BIN_EXE_omiros"))
+        .args(["run", "--detect-changes", "--system-config-dir"])
+        .arg(dir.join("config"))
+        .arg("--dotfiles-dir")
+        .arg(dir.join("dotfiles"))
+        .env("HOME", dir.join("home"))
+        .output()
+        .unwrap()
+        .status
+        .code()
+}
+
+#[test]
+fn out_of_sync_machine_exits_with_2() {
+    let dir = fixture();
+
+    assert_eq!(Some(2), run_detecting_changes(dir.path()));
+    assert!(dir.path().join("home/.zshrc").is_symlink());
+}
+
+#[test]
+fn in_sync_machine_exits_with_0() {
+    let dir = fixture();
+    std::os::unix::fs::symlink(
+        dir.path().join("dotfiles/.zshrc").canonicalize().unwrap(),
+        dir.path().join("home/.zshrc"),
+    )
+    .unwrap();
+
+    assert_eq!(Some(0), run_detecting_changes(dir.path()));
+}
+
+#[test]
+fn invalid_configuration_exits_with_1() {
+    let dir = fixture();
+    fs::write(
+        dir.path().join("config/system.toml"),
+        "[dotfiles]\nfiles = [\".zshrc\", \".missing\"]\n",
+    )
+    .unwrap();
+
+    assert_eq!(Some(1), run_detecting_changes(dir.path()));
+}